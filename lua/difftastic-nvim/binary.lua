@@ -120,6 +120,19 @@ M.state = "ready"
 --- Cached library reference
 local cached_lib = nil
 
+--- Callbacks waiting for the library to be loaded
+local load_callbacks = {}
+
+--- Run `callback` with the library once it's loaded, right away if it already is.
+--- @param callback fun(lib: table)
+function M.on_load(callback)
+    if cached_lib then
+        callback(cached_lib)
+    else
+        table.insert(load_callbacks, callback)
+    end
+end
+
 --- Check for available updates (async, background).
 --- @param paths table Path configuration
 local function check_for_updates(paths)
@@ -314,6 +327,11 @@ function M.get()
     cached_lib = try_load_lib(paths.data_dir, ext) or try_load_lib(paths.release_dir, ext)
 
     if cached_lib then
+        local callbacks = load_callbacks
+        load_callbacks = {}
+        for _, callback in ipairs(callbacks) do
+            callback(cached_lib)
+        end
         return cached_lib
     end
 
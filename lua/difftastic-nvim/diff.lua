@@ -7,6 +7,11 @@ local FILLER = string.rep("╱", 500)
 --- @type number[]
 M.hunk_positions = {}
 
+--- Line positions where difftastic's chunks start (1-indexed), which group changes
+--- structurally rather than by contiguous rows
+--- @type number[]
+M.section_positions = {}
+
 --- Maps difftastic language names to Vim filetypes
 local FILETYPES = {
     Rust = "rust",
@@ -30,6 +35,27 @@ local FILETYPES = {
     Clojure = "clojure",
 }
 
+--- Get the difftastic language name for a Vim filetype.
+--- @param ft string Vim filetype
+--- @return string|nil Language name, or nil if unknown
+function M.language_for_filetype(ft)
+    for language, filetype in pairs(FILETYPES) do
+        if filetype == ft then
+            return language
+        end
+    end
+    return nil
+end
+
+--- Maps syntax span kinds from the embedded highlighter to highlight groups
+local SYNTAX_GROUPS = {
+    comment = "Comment",
+    string = "String",
+    keyword = "Keyword",
+    type = "Type",
+    number = "Number",
+}
+
 --- Set buffer options for diff buffers.
 --- @param buf number Buffer handle
 local function setup_diff_buffer(buf)
@@ -39,6 +65,24 @@ local function setup_diff_buffer(buf)
     vim.bo[buf].modifiable = false
 end
 
+--- Highlight a region of a line at the priority the native library gave it, so syntax,
+--- whole-line and inline changes stack the same way on every row.
+--- @param buf number Buffer handle
+--- @param ns number Namespace
+--- @param group string Highlight group
+--- @param line number 0-indexed line
+--- @param text string Content of the line
+--- @param region table `{ start, end, kind, priority }`, `end` -1 for the rest of the line
+local function highlight_region(buf, ns, group, line, text, region)
+    local end_col = region["end"] == -1 and #text or region["end"]
+    vim.api.nvim_buf_set_extmark(buf, ns, line, region.start, {
+        end_col = end_col,
+        hl_group = group,
+        priority = region.priority,
+        strict = false,
+    })
+end
+
 --- Set window options for diff windows.
 --- @param win number Window handle
 local function setup_diff_window(win)
@@ -82,6 +126,10 @@ function M.render(state, file)
     for _, pos in ipairs(file.hunk_starts or {}) do
         table.insert(M.hunk_positions, pos + 1)
     end
+    M.section_positions = {}
+    for _, section in ipairs(file.sections or {}) do
+        table.insert(M.section_positions, section.start + 1)
+    end
 
     if #rows == 0 then
         vim.bo[state.left_buf].modifiable = true
@@ -108,7 +156,8 @@ function M.render(state, file)
 
     -- Apply syntax highlighting based on mode
     local use_treesitter = config.highlight_mode ~= "difftastic"
-    if use_treesitter then
+    local use_embedded = config.highlight_mode == "embedded"
+    if use_treesitter and not use_embedded then
         local ft = FILETYPES[file.language]
         if ft then
             vim.defer_fn(function()
@@ -139,14 +188,71 @@ function M.render(state, file)
     for i, row in ipairs(rows) do
         local line = i - 1
 
-        for _, hl in ipairs(row.left.highlights) do
-            local group = hl["end"] == -1 and removed_hl or removed_inline_hl
-            vim.api.nvim_buf_add_highlight(state.left_buf, left_ns, group, line, hl.start, hl["end"])
+        if use_embedded then
+            for _, span in ipairs(row.left.syntax or {}) do
+                highlight_region(state.left_buf, left_ns, SYNTAX_GROUPS[span.kind], line, row.left.content, span)
+            end
+            for _, span in ipairs(row.right.syntax or {}) do
+                highlight_region(state.right_buf, right_ns, SYNTAX_GROUPS[span.kind], line, row.right.content, span)
+            end
+        end
+
+        -- Rows whose text is identical on both sides only changed structurally;
+        -- mark them subtly instead of highlighting unless configured otherwise
+        local structural = row.change == "structural" and not config.structural_highlights
+        if row.change == "notice" or row.change == "gap" then
+            -- Synthetic row describing an empty or whitespace-only side, or folded lines
+            vim.api.nvim_buf_add_highlight(state.left_buf, left_ns, "DifftNotice", line, 0, -1)
+            vim.api.nvim_buf_add_highlight(state.right_buf, right_ns, "DifftNotice", line, 0, -1)
+        elseif structural then
+            for _, buf in ipairs({ { state.left_buf, left_ns }, { state.right_buf, right_ns } }) do
+                vim.api.nvim_buf_set_extmark(buf[1], buf[2], line, 0, {
+                    virt_text = { { "≈", "DifftStructural" } },
+                    virt_text_pos = "eol",
+                })
+            end
+        elseif row.change == "eol" then
+            -- Identical text with a different line ending (`\r\n` vs `\n`)
+            for _, buf in ipairs({ { state.left_buf, left_ns }, { state.right_buf, right_ns } }) do
+                vim.api.nvim_buf_set_extmark(buf[1], buf[2], line, 0, {
+                    virt_text = { { "⏎", "DifftEol" } },
+                    virt_text_pos = "eol",
+                })
+            end
+        else
+            for _, hl in ipairs(row.left.highlights) do
+                local group = hl.kind == "full" and removed_hl or removed_inline_hl
+                highlight_region(state.left_buf, left_ns, group, line, row.left.content, hl)
+            end
+
+            for _, hl in ipairs(row.right.highlights) do
+                local group = hl.kind == "full" and added_hl or added_inline_hl
+                highlight_region(state.right_buf, right_ns, group, line, row.right.content, hl)
+            end
         end
 
-        for _, hl in ipairs(row.right.highlights) do
-            local group = hl["end"] == -1 and added_hl or added_inline_hl
-            vim.api.nvim_buf_add_highlight(state.right_buf, right_ns, group, line, hl.start, hl["end"])
+        -- Describe the change in words (accessible mode)
+        if row.description then
+            vim.api.nvim_buf_set_extmark(state.right_buf, right_ns, line, 0, {
+                virt_text = { { row.description, "DifftNotice" } },
+                virt_text_pos = "eol",
+            })
+        end
+
+        -- Show invisible characters in changed regions as `<U+XXXX>`
+        for _, c in ipairs(row.left.invisibles or {}) do
+            vim.api.nvim_buf_set_extmark(state.left_buf, left_ns, line, c.column, {
+                virt_text = { { c.label, "DifftInvisible" } },
+                virt_text_pos = "inline",
+                priority = c.priority,
+            })
+        end
+        for _, c in ipairs(row.right.invisibles or {}) do
+            vim.api.nvim_buf_set_extmark(state.right_buf, right_ns, line, c.column, {
+                virt_text = { { c.label, "DifftInvisible" } },
+                virt_text_pos = "inline",
+                priority = c.priority,
+            })
         end
 
         if row.left.is_filler then
@@ -164,6 +270,21 @@ function M.render(state, file)
         end
     end
 
+    -- Show each hunk's enclosing function above it on both sides, keeping them aligned
+    if config.hunk_headers then
+        for _, hunk in ipairs(file.hunks or {}) do
+            if hunk.header then
+                local virt_lines = { { { "@@ " .. hunk.header, "DifftHunkHeader" } } }
+                for _, buf in ipairs({ { state.left_buf, left_ns }, { state.right_buf, right_ns } }) do
+                    vim.api.nvim_buf_set_extmark(buf[1], buf[2], hunk.start, 0, {
+                        virt_lines = virt_lines,
+                        virt_lines_above = true,
+                    })
+                end
+            end
+        end
+    end
+
     vim.api.nvim_win_set_cursor(state.left_win, { 1, 0 })
     vim.api.nvim_win_set_cursor(state.right_win, { 1, 0 })
 end
@@ -224,6 +345,60 @@ function M.prev_hunk(state)
     return false
 end
 
+--- The hunk the cursor is in, or the last one above it.
+--- @param state table Plugin state
+--- @return number|nil 1-indexed hunk, or nil if the cursor is above the first hunk
+function M.current_hunk(state)
+    local win = get_diff_win(state)
+    if not win then
+        return nil
+    end
+    local line = vim.api.nvim_win_get_cursor(win)[1]
+    local current = nil
+    for i, pos in ipairs(M.hunk_positions) do
+        if pos <= line then
+            current = i
+        end
+    end
+    return current
+end
+
+--- Jump to the next section, wrapping to the first one.
+--- @param state table Plugin state
+function M.next_section(state)
+    local win = get_diff_win(state)
+    if not win or #M.section_positions == 0 then
+        return
+    end
+    local line = vim.api.nvim_win_get_cursor(win)[1]
+    local target = M.section_positions[1]
+    for _, pos in ipairs(M.section_positions) do
+        if pos > line then
+            target = pos
+            break
+        end
+    end
+    vim.api.nvim_win_set_cursor(win, { target, 0 })
+end
+
+--- Jump to the previous section, wrapping to the last one.
+--- @param state table Plugin state
+function M.prev_section(state)
+    local win = get_diff_win(state)
+    if not win or #M.section_positions == 0 then
+        return
+    end
+    local line = vim.api.nvim_win_get_cursor(win)[1]
+    local target = M.section_positions[#M.section_positions]
+    for i = #M.section_positions, 1, -1 do
+        if M.section_positions[i] < line then
+            target = M.section_positions[i]
+            break
+        end
+    end
+    vim.api.nvim_win_set_cursor(win, { target, 0 })
+end
+
 --- Jump to the first hunk.
 --- @param state table Plugin state
 function M.first_hunk(state)
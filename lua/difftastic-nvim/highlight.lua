@@ -56,7 +56,23 @@ M.linked = {
     -- Tree highlights
     DifftFileAdded = { link = "Added" },
     DifftFileDeleted = { link = "Removed" },
+    DifftFileRenamed = { link = "Comment" },
     DifftDirectory = { link = "Directory" },
+
+    -- Invisible characters (zero-width spaces, BOMs, bidi controls) in changes
+    DifftInvisible = { link = "SpecialChar" },
+
+    -- Marker for rows that only changed structurally (identical text)
+    DifftStructural = { link = "Comment" },
+
+    -- Marker for rows whose line ending alone changed (`\r\n` vs `\n`)
+    DifftEol = { link = "Comment" },
+
+    -- Notice rows for empty or whitespace-only files
+    DifftNotice = { link = "Comment" },
+
+    -- Enclosing function shown above hunks (`hunk_headers`)
+    DifftHunkHeader = { link = "Comment" },
 }
 
 
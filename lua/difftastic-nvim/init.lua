@@ -11,20 +11,95 @@ local keymaps = require("difftastic-nvim.keymaps")
 M.config = {
     download = false,
     vcs = "jj",
-    --- Highlight mode: "treesitter" (full syntax) or "difftastic" (no syntax, colored changes only)
+    --- Highlight mode: "treesitter" (full syntax), "embedded" (syntax spans computed by the
+    --- native library, independent of filetype plugins) or "difftastic" (no syntax, colored changes only)
     highlight_mode = "treesitter",
     --- When true, next_hunk at last hunk wraps to next file (and prev_hunk to prev file)
     hunk_wrap_file = false,
+    --- Fully process only this many of the most-changed files when opening; the others
+    --- are loaded when first shown (nil = process everything up front)
+    preview = nil,
+    --- Total rows across all files; files past it only show stats until opened
+    --- (nil = the native library's default)
+    max_rows = nil,
+    --- When false, rows whose text is identical on both sides (structural-only changes)
+    --- get a subtle marker instead of change highlights
+    structural_highlights = false,
+    --- Words for relative commit times ("3 days ago"), e.g.
+    --- { past = "il y a {}", units = { day = { "jour", "jours" } } } (nil = English)
+    time_format = nil,
+    --- jj only: pass --ignore-working-copy to skip snapshotting the working copy. nil skips it
+    --- for revsets that don't refer to @; true/false forces it on/off
+    jj_ignore_working_copy = nil,
+    --- Full-line highlights only, with a description of each changed row ("2 words changed")
+    --- shown at the end of the line, for screen readers
+    accessible = false,
+    --- Show lines whose changes are whitespace only (e.g. re-indentation) as unchanged
+    ignore_whitespace = false,
+    --- Highlight only the identifiers within changed regions, e.g. `baz` and `y` rather
+    --- than `baz(y)`
+    split_words = false,
+    --- Highlight the words that differ within lines difftastic highlights entirely, like
+    --- `git diff --word-diff`
+    word_diff = false,
+    --- Fraction of a line's non-whitespace that, once changed, highlights the whole line
+    --- instead of its changed regions, e.g. 0.8. nil only does so when all of it changed
+    full_line_threshold = nil,
+    --- Expand tabs to spaces at this many columns, e.g. 4, moving highlights along. nil
+    --- keeps tabs as they are
+    tab_width = nil,
+    --- Show the definition line of the function enclosing each hunk above it, like the
+    --- `@@ ... @@ fn foo()` hunk headers of `git diff`
+    hunk_headers = false,
+    --- Fold unchanged lines further than this many lines from a change into one gap row,
+    --- e.g. 3. nil shows every line
+    context = nil,
+    --- git only: list the commits between the old and new commit of changed submodules,
+    --- read from the submodule's checkout
+    submodule_log = false,
+    --- Encodings of files that aren't UTF-8 by glob, e.g. { ["*.txt"] = "shift_jis" }
+    --- (nil = detect them)
+    encodings = nil,
+    --- Globs of files to leave out of diffs, e.g. { "*.lock", "dist/**" }
+    exclude = nil,
+    --- Globs of the only files to show in diffs (nil = all)
+    include = nil,
+    --- Save processed diffs of commits under stdpath("cache"), so reopening them after a
+    --- restart skips difftastic
+    disk_cache = false,
+    --- difftastic binary to run (nil = `difft` from PATH)
+    difft_path = nil,
+    --- Extra arguments for every difftastic run, e.g. { "--context", "5" }
+    difft_args = nil,
+    --- Languages difftastic parses files matching a glob as, e.g. { ["*.tpl"] = "Go" }
+    overrides = nil,
+    --- Raise difftastic's limits before it falls back to a line-based diff, e.g. for large
+    --- generated files (nil = difftastic's defaults)
+    graph_limit = nil,
+    byte_limit = nil,
+    parse_error_limit = nil,
+    --- Threads processing the files of a diff (nil = one per core)
+    max_threads = nil,
+    --- Git, jj, hg and difftastic commands running at the same time (nil = unlimited)
+    max_procs = nil,
+    --- Milliseconds a git, jj, hg or difftastic command may run before it's killed (nil = no limit)
+    timeout_ms = nil,
     keymaps = {
         next_file = "]f",
         prev_file = "[f",
         next_hunk = "]c",
         prev_hunk = "[c",
+        next_section = "]s",
+        prev_section = "[s",
         close = "q",
         focus_tree = "<Tab>",
         focus_diff = "<Tab>",
         select = "<CR>",
         goto_file = "gf",
+        expand_context = "zo",
+        stage_hunk = "<leader>hs",
+        stage_file = "<leader>hS",
+        restore_file = "<leader>hR",
     },
     tree = {
         width = 40,
@@ -48,6 +123,8 @@ M.state = {
     right_buf = nil,
     original_tabpage = nil,
     diff_tabpage = nil,
+    old_commit = nil,
+    new_commit = nil,
 }
 
 --- Initialize the plugin with user options.
@@ -68,6 +145,83 @@ function M.setup(opts)
     if opts.hunk_wrap_file ~= nil then
         M.config.hunk_wrap_file = opts.hunk_wrap_file
     end
+    if opts.preview ~= nil then
+        M.config.preview = opts.preview
+    end
+    if opts.max_rows ~= nil then
+        M.config.max_rows = opts.max_rows
+    end
+    if opts.structural_highlights ~= nil then
+        M.config.structural_highlights = opts.structural_highlights
+    end
+    if opts.time_format then
+        M.config.time_format = opts.time_format
+    end
+    if opts.accessible ~= nil then
+        M.config.accessible = opts.accessible
+    end
+    if opts.ignore_whitespace ~= nil then
+        M.config.ignore_whitespace = opts.ignore_whitespace
+    end
+    if opts.split_words ~= nil then
+        M.config.split_words = opts.split_words
+    end
+    if opts.word_diff ~= nil then
+        M.config.word_diff = opts.word_diff
+    end
+    if opts.full_line_threshold ~= nil then
+        M.config.full_line_threshold = opts.full_line_threshold
+    end
+    if opts.tab_width ~= nil then
+        M.config.tab_width = opts.tab_width
+    end
+    if opts.hunk_headers ~= nil then
+        M.config.hunk_headers = opts.hunk_headers
+    end
+    if opts.context ~= nil then
+        M.config.context = opts.context
+    end
+    if opts.submodule_log ~= nil then
+        M.config.submodule_log = opts.submodule_log
+    end
+    if opts.encodings ~= nil then
+        M.config.encodings = opts.encodings
+    end
+    if opts.exclude ~= nil then
+        M.config.exclude = opts.exclude
+    end
+    if opts.include ~= nil then
+        M.config.include = opts.include
+    end
+    if opts.disk_cache ~= nil then
+        M.config.disk_cache = opts.disk_cache
+    end
+    if opts.jj_ignore_working_copy ~= nil then
+        M.config.jj_ignore_working_copy = opts.jj_ignore_working_copy
+    end
+    if opts.difft_path ~= nil then
+        M.config.difft_path = opts.difft_path
+    end
+    if opts.difft_args ~= nil then
+        M.config.difft_args = opts.difft_args
+    end
+    if opts.overrides ~= nil then
+        M.config.overrides = opts.overrides
+    end
+    for _, limit in ipairs({ "graph_limit", "byte_limit", "parse_error_limit" }) do
+        if opts[limit] ~= nil then
+            M.config[limit] = opts[limit]
+        end
+    end
+    if opts.max_threads ~= nil then
+        M.config.max_threads = opts.max_threads
+    end
+    if opts.max_procs ~= nil then
+        M.config.max_procs = opts.max_procs
+    end
+    if opts.timeout_ms ~= nil then
+        M.config.timeout_ms = opts.timeout_ms
+    end
     if opts.keymaps then
         -- Manual merge to preserve explicit false values (tbl_extend ignores them)
         -- Note: nil values are skipped by pairs(), so they keep the default
@@ -86,29 +240,171 @@ function M.setup(opts)
 
     highlight.setup(opts.highlights)
     binary.ensure_exists(M.config.download)
-end
-
---- Open diff view for a revision/commit range.
---- @param revset string|nil jj revset or git commit range (nil = unstaged, "--staged" = staged)
-function M.open(revset)
-    if M.state.tree_win or M.state.left_win or M.state.right_win then
-        M.close()
+    -- The library may still be downloading or building
+    local config = M.config
+    if config.difft_path or config.difft_args or config.overrides or config.graph_limit or config.byte_limit or config.parse_error_limit then
+        binary.on_load(function(lib)
+            lib.set_difft({
+                path = config.difft_path,
+                args = config.difft_args,
+                overrides = config.overrides,
+                graph_limit = config.graph_limit,
+                byte_limit = config.byte_limit,
+                parse_error_limit = config.parse_error_limit,
+            })
+        end)
     end
+    -- Diffs still running when Neovim exits would leave their temporary files behind
+    binary.on_load(function(lib)
+        vim.api.nvim_create_autocmd("VimLeavePre", {
+            group = vim.api.nvim_create_augroup("DifftTempFiles", { clear = true }),
+            callback = function()
+                lib.cleanup_temp_files()
+            end,
+        })
+    end)
+    if M.config.max_threads or M.config.max_procs or M.config.timeout_ms then
+        binary.on_load(function(lib)
+            lib.set_limits({
+                max_threads = M.config.max_threads,
+                max_procs = M.config.max_procs,
+                timeout_ms = M.config.timeout_ms,
+            })
+        end)
+    end
+end
 
-    local result
-    if revset == nil then
-        result = binary.get().run_diff_unstaged(M.config.vcs)
+--- Run the diff for a revset with the native library.
+--- @param revset string|nil jj revset or git commit range (nil = unstaged, "--staged", "--index" or "--head" = working copy modes)
+--- @param opts table|nil Options passed to run_diff (`baseline` = only show hunks missing from that baseline file)
+--- @return table Result with `files`
+local function run(revset, opts)
+    if opts and opts.baseline then
+        return binary.get().run_diff_since_baseline(revset, M.config.vcs, opts.baseline, opts)
+    elseif revset == nil then
+        return binary.get().run_diff_unstaged(M.config.vcs, opts)
     elseif revset == "--staged" then
-        result = binary.get().run_diff_staged(M.config.vcs)
+        return binary.get().run_diff_staged(M.config.vcs, opts)
+    elseif revset == "--index" or revset == "--head" then
+        return binary.get().run_diff_mode(revset:sub(3), M.config.vcs, opts)
     else
-        result = binary.get().run_diff(revset, M.config.vcs)
+        return binary.get().run_diff(revset, M.config.vcs, opts)
     end
-    if not result.files or #result.files == 0 then
-        vim.notify("No changes found", vim.log.levels.INFO)
+end
+
+--- Directory processed diffs are saved in with `disk_cache`.
+local function cache_dir()
+    return vim.fn.stdpath("cache") .. "/difftastic-nvim"
+end
+
+--- Forget processed diffs, in memory and, with `disk_cache`, on disk.
+function M.clear_cache()
+    binary.get().clear_cache(M.config.disk_cache and cache_dir() or nil)
+end
+
+--- The background diff started by the latest `run_async`, if still running.
+local pending_job = nil
+
+--- Whether `set_content_provider` registered a provider, which background diffs can't call.
+local has_content_provider = false
+
+--- Like `run`, but computes commit ranges on a background thread so the UI stays responsive.
+--- Calls `callback(result)` on the main loop once done; errors are reported with vim.notify.
+--- A diff still running from an earlier call is cancelled, and its result dropped.
+--- @param revset string|nil Same as for `run`
+--- @param opts table|nil Options passed to run_diff
+--- @param callback function Called with the result table
+--- @param on_files function|nil With `opts.stream`, called with `{ index, file }` updates as files get ready
+local function run_async(revset, opts, callback, on_files)
+    if pending_job then
+        pending_job:cancel()
+        pending_job = nil
+    end
+
+    local is_range = revset ~= nil and not vim.startswith(revset, "--")
+    if not is_range or (opts and opts.baseline) or has_content_provider then
+        local result = run(revset, opts)
+        if on_files then
+            local updates = {}
+            for i, file in ipairs(result.files) do
+                updates[i] = { index = i, file = file }
+            end
+            result.files = {}
+            on_files(updates)
+        end
+        callback(result)
         return
     end
 
-    M.state.files = result.files
+    local job = binary.get().run_diff_async(revset, M.config.vcs, opts)
+    pending_job = job
+    local last_status = nil
+    local timer = vim.uv.new_timer()
+    timer:start(
+        0,
+        10,
+        vim.schedule_wrap(function()
+            if timer:is_closing() then
+                return
+            end
+            local done, result, err = job:poll()
+            if on_files and pending_job == job then
+                local updates = job:take_files()
+                if #updates > 0 then
+                    on_files(updates)
+                end
+            end
+            if not done then
+                -- Show what the diff is doing, e.g. "processing files (12/40)"
+                local progress = job:progress()
+                local status = progress.phase
+                if progress.total > 0 then
+                    status = string.format("%s (%d/%d)", status, progress.done, progress.total)
+                end
+                if status ~= last_status and pending_job == job then
+                    last_status = status
+                    vim.api.nvim_echo({ { "difftastic: " .. status } }, false, {})
+                end
+                return
+            end
+            timer:stop()
+            timer:close()
+            if last_status then
+                vim.api.nvim_echo({}, false, {})
+            end
+            if pending_job ~= job then
+                return
+            end
+            pending_job = nil
+            if err then
+                vim.notify("difftastic: " .. err, vim.log.levels.ERROR)
+            else
+                callback(result)
+            end
+        end)
+    )
+end
+
+--- Compute a diff in the background, handing over files as soon as they're ready: first a
+--- stats-only entry for every file, then each processed file, replacing its earlier entry.
+--- Starting another diff cancels this one.
+--- @param revset string|nil Same as for `run`
+--- @param opts table|nil Options passed to run_diff
+--- @param on_file function Called with `(index, file)` for every update
+--- @param on_done function|nil Called with the rest of the result (commits, ...) once all files are done
+function M.stream(revset, opts, on_file, on_done)
+    opts = vim.tbl_extend("force", opts or {}, { stream = true })
+    run_async(revset, opts, on_done or function() end, function(updates)
+        for _, update in ipairs(updates) do
+            on_file(update.index, update.file)
+        end
+    end)
+end
+
+--- Show processed files in a new diff tab.
+--- @param files table[] Files returned by the native library
+local function open_files(files)
+    M.state.files = files
     M.state.current_file_idx = 1
 
     -- Store original tabpage and create new one for diff view
@@ -126,6 +422,243 @@ function M.open(revset)
     end
 end
 
+--- Ask which candidate of a divergent change or hidden commit to diff.
+--- @param warning table `warning` of a `run_diff` result
+--- @param callback function Called with the chosen commit ID, or nil to keep the diff as is
+local function pick_candidate(warning, callback)
+    vim.ui.select(warning.candidates, {
+        prompt = warning.message .. ", diff which one?",
+        format_item = function(candidate)
+            local flags = candidate.hidden and " (hidden)" or ""
+            return string.format("%s %s%s %s", candidate.change_id, candidate.commit:sub(1, 12), flags, candidate.subject)
+        end,
+    }, function(candidate)
+        callback(candidate and candidate.commit)
+    end)
+end
+
+--- Open diff view for a revision/commit range.
+--- @param revset string|nil jj revset or git commit range (nil = unstaged, "--staged", "--index" or "--head" = working copy modes)
+--- @param opts table|nil Options passed to run_diff (e.g. `{ parent = 2 }` for merge commits)
+function M.open(revset, opts)
+    if M.state.tree_win or M.state.left_win or M.state.right_win then
+        M.close()
+    end
+
+    opts = opts or {}
+    if M.config.highlight_mode == "embedded" then
+        opts = vim.tbl_extend("keep", opts, { syntax_spans = true })
+    end
+    if M.config.accessible then
+        opts = vim.tbl_extend("keep", opts, { accessible = true })
+    end
+    if M.config.ignore_whitespace then
+        opts = vim.tbl_extend("keep", opts, { ignore_whitespace = true })
+    end
+    if M.config.split_words then
+        opts = vim.tbl_extend("keep", opts, { split_words = true })
+    end
+    if M.config.word_diff then
+        opts = vim.tbl_extend("keep", opts, { word_diff = true })
+    end
+
+    local run_opts = vim.tbl_extend("keep", opts, {
+        preview = M.config.preview,
+        max_rows = M.config.max_rows,
+        time_format = M.config.time_format,
+        ignore_working_copy = M.config.jj_ignore_working_copy,
+        cache_dir = M.config.disk_cache and cache_dir() or nil,
+        exclude = M.config.exclude,
+        include = M.config.include,
+        submodule_log = M.config.submodule_log,
+        encodings = M.config.encodings,
+        full_line_threshold = M.config.full_line_threshold,
+        tab_width = M.config.tab_width,
+        context = M.config.context,
+    })
+    run_async(revset, run_opts, function(result)
+        -- A divergent change or hidden commit may not be what the user meant: let them pick
+        if result.warning then
+            pick_candidate(result.warning, function(commit)
+                if commit then
+                    M.open(commit, opts)
+                end
+            end)
+            return
+        end
+        local hidden = result.hidden_files or 0
+        if not result.files or #result.files == 0 then
+            local suffix = hidden > 0 and string.format(" (%d files hidden)", hidden) or ""
+            vim.notify("No changes found" .. suffix, vim.log.levels.INFO)
+            return
+        end
+        if hidden > 0 then
+            vim.notify(string.format("%d files hidden by include/exclude", hidden), vim.log.levels.INFO)
+        end
+        if result.budget_exceeded then
+            vim.notify("Diff is too large, some files only show stats until opened", vim.log.levels.WARN)
+        end
+
+        M.state.revset = revset
+        M.state.diff_opts = opts
+        -- Resolved commit hashes, e.g. for building permalinks to hunks
+        M.state.old_commit = result.old_commit
+        M.state.new_commit = result.new_commit
+        open_files(result.files)
+    end)
+end
+
+--- Open one diff view for the same revset in several repositories.
+--- File paths are prefixed with each repository's path so the tree groups them by repo.
+--- @param roots string[] Repository roots
+--- @param revset string|nil jj revset or git commit range (nil = unstaged)
+function M.open_repos(roots, revset)
+    if M.state.tree_win or M.state.left_win or M.state.right_win then
+        M.close()
+    end
+
+    roots = vim.tbl_map(function(root)
+        return vim.fn.fnamemodify(vim.fn.expand(root), ":p:h")
+    end, roots)
+    local opts = M.config.highlight_mode == "embedded" and { syntax_spans = true } or {}
+    local results = binary.get().run_diff_repos(roots, revset, M.config.vcs, opts)
+
+    local files = {}
+    for _, root in ipairs(roots) do
+        local result = results[root]
+        if result.error then
+            vim.notify(root .. ": " .. result.error, vim.log.levels.WARN)
+        else
+            local prefix = vim.fn.fnamemodify(root, ":.")
+            for _, file in ipairs(result.files) do
+                file.path = prefix .. "/" .. file.path
+                table.insert(files, file)
+            end
+        end
+    end
+    if #files == 0 then
+        vim.notify("No changes found", vim.log.levels.INFO)
+        return
+    end
+    open_files(files)
+end
+
+--- Open diff view comparing two files or directories on disk, outside of any repository.
+--- @param old_path string Path of the old file or directory
+--- @param new_path string Path of the new file or directory
+function M.open_files(old_path, new_path)
+    if M.state.tree_win or M.state.left_win or M.state.right_win then
+        M.close()
+    end
+
+    local opts = M.config.highlight_mode == "embedded" and { syntax_spans = true } or {}
+    if vim.fn.isdirectory(old_path) == 1 and vim.fn.isdirectory(new_path) == 1 then
+        local result = binary.get().diff_dirs(old_path, new_path, opts)
+        if #result.files == 0 then
+            vim.notify("No changes found", vim.log.levels.INFO)
+            return
+        end
+        open_files(result.files)
+    else
+        open_files({ binary.get().diff_files(old_path, new_path, opts) })
+    end
+end
+
+--- Open diff view for difftastic JSON produced elsewhere, e.g. an artifact of a CI job.
+--- @param json_path string|table File (or named pipe) with the JSON, or `{ text = json }`
+--- @param contents_dir string Directory with the files' contents under `old/` and `new/`
+function M.open_artifact(json_path, contents_dir)
+    if M.state.tree_win or M.state.left_win or M.state.right_win then
+        M.close()
+    end
+
+    local opts = M.config.highlight_mode == "embedded" and { syntax_spans = true } or {}
+    local result = binary.get().process_artifact(json_path, contents_dir, opts)
+    if #result.files == 0 then
+        vim.notify("No changes found", vim.log.levels.INFO)
+        return
+    end
+    open_files(result.files)
+end
+
+--- Compute several diffs in one call, e.g. the staged and unstaged changes and the last
+--- commit for a dashboard.
+--- @param requests table[] `{ id, range, mode, vcs, opts }` each; `vcs` defaults to the configured one
+--- @return table Results keyed by id (or position), each a `run_diff` result or `{ error = message }`
+function M.run_diffs(requests)
+    requests = vim.tbl_map(function(request)
+        return vim.tbl_extend("keep", request, { vcs = M.config.vcs })
+    end, requests)
+    return binary.get().run_diffs(requests)
+end
+
+--- Register a VCS described by command templates, so it can be used as `vcs` in `setup`.
+--- @param name string VCS name
+--- @param spec table Command templates (`diff`, `show`, `old_rev`, ...), see the README
+function M.register_vcs(name, spec)
+    binary.get().register_vcs(name, spec)
+end
+
+--- Run every VCS and difftastic command through a wrapper, for repositories on a remote
+--- host or in a container. `nil` runs commands locally again.
+--- @param spec table|nil `{ command = { "docker", "exec", "-i", "dev" }, quote = false, paths = { [local] = remote } }`
+function M.set_command_wrapper(spec)
+    binary.get().set_command_wrapper(spec)
+end
+
+--- Read file contents through `provider(path, rev, side, source)` instead of local
+--- commands, e.g. from another Neovim over RPC. Diffs then run on the main thread, since
+--- the provider can't be called from the background. `nil` reads contents locally again.
+--- @param provider function|nil Returns the file's text, or nil if it doesn't exist on that side
+function M.set_content_provider(provider)
+    binary.get().set_content_provider(provider)
+    has_content_provider = provider ~= nil
+end
+
+--- Open the candidate commit of the bisect in progress, diffed against the last good
+--- commit, and report the bisect's bounds.
+--- @param opts table|nil Options passed to run_diff
+function M.bisect_view(opts)
+    local bisect = binary.get().bisect_state(M.config.vcs)
+    if not bisect or not bisect.range then
+        vim.notify("No bisect in progress with a good commit to diff against", vim.log.levels.WARN)
+        return
+    end
+    vim.notify(
+        string.format(
+            "Bisecting %s: %d good, bad %s",
+            bisect.candidate:sub(1, 12),
+            #bisect.good,
+            bisect.bad and bisect.bad:sub(1, 12) or "not marked yet"
+        ),
+        vim.log.levels.INFO
+    )
+    M.open(bisect.range, opts)
+end
+
+--- Save the hunks of a range diff, so `M.open(revset, { baseline = path })` later shows
+--- only the hunks that appeared since.
+--- @param revset string jj revset or git commit range
+--- @param path string Baseline file to write
+function M.save_baseline(revset, path)
+    -- The settings that decide which hunks exist, so they match the later `M.open`
+    binary.get().save_baseline(revset, M.config.vcs, path, {
+        ignore_working_copy = M.config.jj_ignore_working_copy,
+        ignore_whitespace = M.config.ignore_whitespace,
+        exclude = M.config.exclude,
+        include = M.config.include,
+        encodings = M.config.encodings,
+        context = M.config.context,
+    })
+end
+
+--- Prepare diffs in the background so opening them later is instant, e.g. the commits
+--- before and after the one being reviewed.
+--- @param revsets string[] jj revsets or git commit ranges
+function M.prefetch(revsets)
+    binary.get().prefetch(revsets, M.config.vcs)
+end
+
 --- Close the diff view.
 function M.close()
     local diff_tabpage = M.state.diff_tabpage
@@ -157,6 +690,30 @@ function M.close()
     end
 end
 
+--- Open diff view comparing two strings, e.g. unsaved buffer contents.
+--- @param old_text string Old content
+--- @param new_text string New content
+--- @param language string|nil difftastic language name (e.g. "Rust"); detected if nil
+function M.open_text(old_text, new_text, language)
+    if M.state.tree_win or M.state.left_win or M.state.right_win then
+        M.close()
+    end
+
+    local opts = M.config.highlight_mode == "embedded" and { syntax_spans = true } or {}
+    open_files({ binary.get().diff_text(old_text, new_text, language, opts) })
+end
+
+--- Open diff view comparing the unsaved contents of a buffer against its file on disk.
+--- @param buf number|nil Buffer handle (default: current buffer)
+function M.open_buffer(buf)
+    buf = buf or vim.api.nvim_get_current_buf()
+    local path = vim.api.nvim_buf_get_name(buf)
+    local disk = vim.fn.filereadable(path) == 1 and table.concat(vim.fn.readfile(path, "b"), "\n") or ""
+    local lines = vim.api.nvim_buf_get_lines(buf, 0, -1, false)
+    local text = table.concat(lines, "\n") .. (vim.bo[buf].eol and "\n" or "")
+    M.open_text(disk, text, diff.language_for_filetype(vim.bo[buf].filetype))
+end
+
 --- Show a specific file by index.
 --- @param idx number File index (1-based)
 function M.show_file(idx)
@@ -164,6 +721,29 @@ function M.show_file(idx)
         return
     end
     M.state.current_file_idx = idx
+
+    -- Files left out of a preview only have stats; load their rows now
+    local file = M.state.files[idx]
+    if file.processing_failed then
+        vim.notify("Failed to process " .. file.path .. ", showing stats only", vim.log.levels.WARN)
+    elseif file.stats_only then
+        local opts = vim.tbl_extend("force", M.state.diff_opts or {}, { paths = { file.path } })
+        local loaded = run(M.state.revset, opts).files[1]
+        if loaded then
+            M.state.files[idx] = loaded
+        end
+    end
+
+    -- Binary files have no rows to show, only their sizes
+    file = M.state.files[idx]
+    if file.binary then
+        local size = function(bytes)
+            return bytes and (bytes .. " bytes") or "none"
+        end
+        local message = string.format("Binary file: %s -> %s", size(file.old_size), size(file.new_size))
+        vim.api.nvim_echo({ { message } }, false, {})
+    end
+
     diff.render(M.state, M.state.files[idx])
     tree.highlight_current(M.state)
 end
@@ -244,6 +824,134 @@ function M.prev_hunk()
     vim.cmd("normal! zz")
 end
 
+--- Navigate to the next section (difftastic chunk), wrapping within the current file.
+function M.next_section()
+    diff.next_section(M.state)
+    vim.cmd("normal! zz")
+end
+
+--- Navigate to the previous section (difftastic chunk), wrapping within the current file.
+function M.prev_section()
+    diff.prev_section(M.state)
+    vim.cmd("normal! zz")
+end
+
+--- Show the unchanged lines of the current file folded away by `context`, keeping the
+--- cursor on the line it's on, or on the first folded line when it's on a gap row.
+function M.expand_context()
+    local file = M.state.files[M.state.current_file_idx]
+    if not file or not file.folds or #file.folds == 0 then
+        return
+    end
+    local win = vim.api.nvim_get_current_win()
+    local row = vim.api.nvim_win_get_cursor(win)[1]
+    local target = row
+    for _, fold in ipairs(file.folds) do
+        if fold.row + 1 < row then
+            target = target + fold.rows - 1
+        end
+    end
+
+    local opts = vim.tbl_extend("force", M.state.diff_opts or {}, { paths = { file.path } })
+    opts.context = nil
+    local loaded = run(M.state.revset, opts).files[1]
+    if not loaded then
+        return
+    end
+    M.state.files[M.state.current_file_idx] = loaded
+    diff.render(M.state, loaded)
+    if vim.api.nvim_win_is_valid(win) then
+        vim.api.nvim_win_set_cursor(win, { math.min(target, #loaded.rows), 0 })
+    end
+end
+
+--- Stage the hunk under the cursor into the git index, then reload the file so the hunk
+--- leaves the view. Only works when viewing unstaged git changes.
+function M.stage_hunk()
+    if M.state.revset ~= nil or M.config.vcs ~= "git" or (M.state.diff_opts or {}).baseline then
+        vim.notify("Staging hunks needs a view of unstaged git changes", vim.log.levels.WARN)
+        return
+    end
+    local file = M.state.files[M.state.current_file_idx]
+    local hunk = diff.current_hunk(M.state)
+    if not file or not hunk then
+        return
+    end
+
+    local result = binary.get().stage_hunk(file.path, hunk, M.state.diff_opts)
+    if not result.ok then
+        vim.notify("difftastic: " .. result.error, vim.log.levels.ERROR)
+        return
+    end
+    local opts = vim.tbl_extend("force", M.state.diff_opts or {}, { paths = { file.path } })
+    -- Staging the file's last hunk leaves it without unstaged changes, and out of the diff
+    local loaded = run(M.state.revset, opts).files[1]
+        or vim.tbl_extend("force", file, {
+            additions = 0,
+            deletions = 0,
+            rows = {},
+            aligned_lines = {},
+            hunk_starts = {},
+            hunks = {},
+            sections = {},
+            folds = {},
+            virtual_deletions = {},
+        })
+    M.state.files[M.state.current_file_idx] = loaded
+    diff.render(M.state, loaded)
+    tree.refresh(M.state)
+end
+
+--- Update the current file's stats in the file list from a `stage_file` or `restore_file`
+--- result, reporting failures.
+--- @param result table Result with `ok`, `unstaged` and `error`
+--- @param done string Message to show on success
+local function apply_file_update(result, done)
+    if not result.ok then
+        vim.notify("difftastic: " .. result.error, vim.log.levels.ERROR)
+        return
+    end
+    local file = M.state.files[M.state.current_file_idx]
+    file.additions = result.unstaged.additions
+    file.deletions = result.unstaged.deletions
+    tree.refresh(M.state)
+    vim.notify(done .. " " .. file.path, vim.log.levels.INFO)
+end
+
+--- Stage all changes of the current file into the git index, updating its stats in the
+--- file list. Only works when viewing unstaged git changes.
+function M.stage_file()
+    if M.state.revset ~= nil or M.config.vcs ~= "git" then
+        vim.notify("Staging files needs a view of unstaged git changes", vim.log.levels.WARN)
+        return
+    end
+    local file = M.state.files[M.state.current_file_idx]
+    if file then
+        apply_file_update(binary.get().stage_file(file.path, M.state.diff_opts), "Staged")
+    end
+end
+
+--- Discard the working-copy changes of the current file after confirming, restoring it
+--- from the index with git or the parent commit with jj, unless `rev` is given. Only works
+--- when viewing working-copy changes.
+--- @param rev string|nil Revision to restore the file from
+function M.restore_file(rev)
+    if M.state.revset ~= nil then
+        vim.notify("Restoring files needs a view of working-copy changes", vim.log.levels.WARN)
+        return
+    end
+    local file = M.state.files[M.state.current_file_idx]
+    if not file then
+        return
+    end
+    local question = "Discard the changes to " .. file.path .. "?"
+    if vim.fn.confirm(question, "&Yes\n&No", 2) ~= 1 then
+        return
+    end
+    local result = binary.get().restore_file(file.path, rev, M.config.vcs, M.state.diff_opts)
+    apply_file_update(result, "Restored")
+end
+
 --- Go to the file at the current cursor position in an editable buffer.
 --- Opens in a previous tabpage if one exists, otherwise creates a new tab.
 --- Only works from the right pane (new/working version of the file).
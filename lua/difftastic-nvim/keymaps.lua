@@ -20,12 +20,32 @@ local function setup_diff_keymaps(buf, state)
     if keys.prev_hunk then
         vim.keymap.set("n", keys.prev_hunk, difft.prev_hunk, { buffer = buf })
     end
+    if keys.next_section then
+        vim.keymap.set("n", keys.next_section, difft.next_section, { buffer = buf })
+    end
+    if keys.prev_section then
+        vim.keymap.set("n", keys.prev_section, difft.prev_section, { buffer = buf })
+    end
     if keys.close then
         vim.keymap.set("n", keys.close, difft.close, { buffer = buf })
     end
     if keys.goto_file then
         vim.keymap.set("n", keys.goto_file, difft.goto_file, { buffer = buf })
     end
+    if keys.expand_context then
+        vim.keymap.set("n", keys.expand_context, difft.expand_context, { buffer = buf })
+    end
+    if keys.stage_hunk then
+        vim.keymap.set("n", keys.stage_hunk, difft.stage_hunk, { buffer = buf })
+    end
+    if keys.stage_file then
+        vim.keymap.set("n", keys.stage_file, difft.stage_file, { buffer = buf })
+    end
+    if keys.restore_file then
+        vim.keymap.set("n", keys.restore_file, function()
+            difft.restore_file()
+        end, { buffer = buf })
+    end
     if keys.focus_tree then
         vim.keymap.set("n", keys.focus_tree, function()
             if state.tree_win and vim.api.nvim_win_is_valid(state.tree_win) then
@@ -55,6 +75,14 @@ local function setup_tree_keymaps(state)
     if keys.prev_file then
         vim.keymap.set("n", keys.prev_file, difft.prev_file, { buffer = buf })
     end
+    if keys.stage_file then
+        vim.keymap.set("n", keys.stage_file, difft.stage_file, { buffer = buf })
+    end
+    if keys.restore_file then
+        vim.keymap.set("n", keys.restore_file, function()
+            difft.restore_file()
+        end, { buffer = buf })
+    end
 end
 
 --- Setup all keymaps for the diff view.
@@ -88,6 +88,9 @@ local function build_intermediate_tree(files)
             if is_last then
                 node.file_idx = idx
                 node.status = file.status
+                if file.status == "renamed" then
+                    node.old_path = file.old_path
+                end
                 node.additions = file.additions or 0
                 node.deletions = file.deletions or 0
             end
@@ -155,6 +158,7 @@ local function convert_to_nui_nodes(node, file_to_node_id)
             is_dir = child.is_dir,
             file_idx = child.file_idx,
             status = child.status,
+            old_path = child.old_path,
             additions = child.additions,
             deletions = child.deletions,
         }, grandchildren)
@@ -193,6 +197,9 @@ local function prepare_node(node)
 
     -- Name
     line:append(node.name)
+    if node.old_path then
+        line:append(" ← " .. node.old_path, "DifftFileRenamed")
+    end
 
     -- Stats
     if node.additions > 0 or node.deletions > 0 then
@@ -314,6 +321,27 @@ function M.render(state)
     end
 end
 
+--- Rebuild the tree and its totals from `state.files`, e.g. after their stats changed.
+--- @param state table Plugin state
+function M.refresh(state)
+    if not M.tree or not state.tree_buf or not vim.api.nvim_buf_is_valid(state.tree_buf) then
+        return
+    end
+    local root = build_intermediate_tree(state.files)
+    propagate_stats(root)
+    flatten_node(root)
+    sort_node(root)
+    M.total_additions = root.additions
+    M.total_deletions = root.deletions
+
+    M.file_to_node_id = {}
+    M.tree:set_nodes(convert_to_nui_nodes(root, M.file_to_node_id))
+    vim.api.nvim_buf_set_lines(state.tree_buf, 0, M.header_lines, false, {})
+    render_header(state, root.additions, root.deletions)
+    M.tree:render(M.header_lines + 1)
+    M.highlight_current(state)
+end
+
 local function collect_visible_files(tree)
     local files = {}
 
@@ -15,9 +15,12 @@ vim.api.nvim_create_user_command("Difft", function(opts)
     if args == "" then
         -- No args: show unstaged changes
         require("difftastic-nvim").open(nil)
-    elseif args == "--staged" then
-        -- Show staged changes
+    elseif args == "--staged" or args == "--cached" then
+        -- Show staged changes (HEAD vs index), e.g. to review before committing
         require("difftastic-nvim").open("--staged")
+    elseif args == "--index" or args == "--head" then
+        -- Show index vs working tree or HEAD vs working tree
+        require("difftastic-nvim").open(args)
     else
         -- Revset/commit range
         local revset = args:gsub("^['\"](.+)['\"]$", "%1")
@@ -25,7 +28,43 @@ vim.api.nvim_create_user_command("Difft", function(opts)
     end
 end, {
     nargs = "?",
-    desc = "Open difftastic diff view (no args = unstaged, --staged = staged, or revset/commit)",
+    desc = "Open difftastic diff view (no args = unstaged, --staged/--cached, --index, --head, or revset/commit)",
+})
+
+vim.api.nvim_create_user_command("DifftFiles", function(opts)
+    if #opts.fargs ~= 2 then
+        vim.notify("DifftFiles expects two files or directories", vim.log.levels.ERROR)
+        return
+    end
+    require("difftastic-nvim").open_files(opts.fargs[1], opts.fargs[2])
+end, {
+    nargs = "+",
+    complete = "file",
+    desc = "Open difftastic diff view comparing two files or directories",
+})
+
+vim.api.nvim_create_user_command("DifftArtifact", function(opts)
+    if #opts.fargs ~= 2 then
+        vim.notify("DifftArtifact expects a JSON file and a contents directory", vim.log.levels.ERROR)
+        return
+    end
+    require("difftastic-nvim").open_artifact(opts.fargs[1], opts.fargs[2])
+end, {
+    nargs = "+",
+    complete = "file",
+    desc = "Open difftastic diff view for JSON produced elsewhere, with contents under old/ and new/",
+})
+
+vim.api.nvim_create_user_command("DifftBuffer", function()
+    require("difftastic-nvim").open_buffer()
+end, {
+    desc = "Open difftastic diff view of unsaved changes in the current buffer",
+})
+
+vim.api.nvim_create_user_command("DifftBisect", function()
+    require("difftastic-nvim").bisect_view()
+end, {
+    desc = "Open difftastic diff view of the bisect candidate against the last good commit",
 })
 
 vim.api.nvim_create_user_command("DifftClose", function()
@@ -0,0 +1,247 @@
+//! Saved baselines for re-reviewing long-running branches.
+//!
+//! A baseline records the hunks of a processed diff, identified by the text they change
+//! rather than their position, so hunks that merely moved because of edits elsewhere in the
+//! file still match. Comparing a later diff of the same range against the baseline keeps
+//! only the hunks that appeared since it was saved.
+//!
+//! Hunks are fingerprinted by the original lines of the file (see [`original_fingerprints`])
+//! rather than their displayed text, which depends on settings like `tab_width`.
+
+use crate::processor::{DisplayFile, Row, RowChange, Side};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The changed lines of a hunk, prefixed with `-` (old side) or `+` (new side).
+pub type Fingerprint = Vec<String>;
+
+/// Hunk fingerprints of every file in a diff.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Baseline {
+    files: HashMap<PathBuf, Vec<Fingerprint>>,
+}
+
+impl Baseline {
+    /// Records the hunks of processed `files`, given their fingerprints in the same order.
+    pub fn from_files(files: &[DisplayFile], fingerprints: Vec<Vec<Fingerprint>>) -> Self {
+        Self {
+            files: files
+                .iter()
+                .map(|file| file.path.clone())
+                .zip(fingerprints)
+                .collect(),
+        }
+    }
+
+    /// Reads a baseline saved with [`Baseline::write`].
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read baseline {}: {e}", path.display()))?;
+        serde_json::from_str(&json).map_err(|e| format!("Invalid baseline {}: {e}", path.display()))
+    }
+
+    /// Writes the baseline to `path`, creating parent directories if needed.
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+        }
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, json + "\n")
+            .map_err(|e| format!("Failed to write baseline {}: {e}", path.display()))
+    }
+
+    /// Drops the hunks of `files` already present in the baseline, and files left without
+    /// any new hunk. Rows are kept intact so new hunks still show in context.
+    ///
+    /// Each baseline hunk matches at most one hunk, so a hunk duplicated since the baseline
+    /// was saved still shows up as new. `fingerprints` are those of `files`, in order.
+    pub fn retain_new_hunks(
+        &self,
+        files: Vec<DisplayFile>,
+        fingerprints: Vec<Vec<Fingerprint>>,
+    ) -> Vec<DisplayFile> {
+        files
+            .into_iter()
+            .zip(fingerprints)
+            .filter_map(|(mut file, fingerprints)| {
+                let mut seen = self.files.get(&file.path).cloned().unwrap_or_default();
+                let is_new: Vec<bool> = fingerprints
+                    .into_iter()
+                    .map(
+                        |fingerprint| match seen.iter().position(|f| *f == fingerprint) {
+                            Some(i) => {
+                                seen.swap_remove(i);
+                                false
+                            }
+                            None => true,
+                        },
+                    )
+                    .collect();
+                if !is_new.contains(&true) {
+                    return None;
+                }
+
+                let mut keep = is_new.iter();
+                file.hunk_starts.retain(|_| *keep.next().unwrap_or(&true));
+                let mut keep = is_new.iter();
+                file.hunks.retain(|_| *keep.next().unwrap_or(&true));
+                Some(file)
+            })
+            .collect()
+    }
+}
+
+/// Fingerprints each hunk of `file` by the displayed text of the changed rows between its
+/// start and the next one.
+pub fn fingerprints(file: &DisplayFile) -> Vec<Fingerprint> {
+    let text = |side: &Side| (!side.is_filler).then(|| side.content.clone());
+    hunk_fingerprints(file, |row| (text(&row.left), text(&row.right)))
+}
+
+/// Fingerprints each hunk of `file` by the lines of `old` and `new`, the original contents
+/// its rows were made from, that the changed rows show. Sides without a line number, such as
+/// notices, are left out.
+pub fn original_fingerprints(
+    file: &DisplayFile,
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+) -> Vec<Fingerprint> {
+    let lines = |content: Option<&[u8]>| -> Vec<String> {
+        content
+            .unwrap_or_default()
+            .split(|&byte| byte == b'\n')
+            .map(|line| String::from_utf8_lossy(line).into_owned())
+            .collect()
+    };
+    let (old, new) = (lines(old), lines(new));
+    let line = |lines: &[String], side: &Side| {
+        let index = side.line_number?.checked_sub(1)?;
+        lines.get(index as usize).cloned()
+    };
+    hunk_fingerprints(file, |row| (line(&old, &row.left), line(&new, &row.right)))
+}
+
+/// Fingerprints each hunk of `file` by the old and new text `sides` gives for its changed
+/// rows.
+fn hunk_fingerprints(
+    file: &DisplayFile,
+    sides: impl Fn(&Row) -> (Option<String>, Option<String>),
+) -> Vec<Fingerprint> {
+    let ends = file
+        .hunk_starts
+        .iter()
+        .skip(1)
+        .copied()
+        .chain(std::iter::once(file.rows.len() as u32));
+    file.hunk_starts
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| {
+            let rows = file
+                .rows
+                .get(start as usize..end as usize)
+                .unwrap_or_default();
+            rows.iter()
+                .filter(|row| !matches!(row.change, RowChange::Unchanged | RowChange::Gap))
+                .flat_map(|row| {
+                    let (old, new) = sides(row);
+                    let old = old.map(|text| format!("-{text}"));
+                    let new = new.map(|text| format!("+{text}"));
+                    old.into_iter().chain(new)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{ProcessOptions, process_json};
+
+    /// Diffs "a\nb\nc\nd\ne" against `new`, which changes the first and last line, returning
+    /// the file and its fingerprints.
+    fn diff(first: &str, last: &str) -> (DisplayFile, Vec<Fingerprint>) {
+        diff_with(first, last, &ProcessOptions::default())
+    }
+
+    fn diff_with(
+        first: &str,
+        last: &str,
+        options: &ProcessOptions,
+    ) -> (DisplayFile, Vec<Fingerprint>) {
+        let change = |line: u32, old: &str, new: &str| {
+            format!(
+                r#"[{{"lhs":{{"line_number":{line},"changes":[{{"start":0,"end":1,"content":"{old}","highlight":"normal"}}]}},
+                     "rhs":{{"line_number":{line},"changes":[{{"start":0,"end":1,"content":"{new}","highlight":"normal"}}]}}}}]"#
+            )
+        };
+        let json = format!(
+            r#"{{"path":"f.txt","language":"Text","status":"changed",
+                "aligned_lines":[[0,0],[1,1],[2,2],[3,3],[4,4]],
+                "chunks":[{},{}]}}"#,
+            change(0, "a", &first.replace('\t', "\\t")),
+            change(4, "e", last)
+        );
+        let old = "a\nb\nc\nd\ne\n";
+        let new = format!("{first}\nb\nc\nd\n{last}\n");
+        let file =
+            process_json(&json, Some(old.as_bytes()), Some(new.as_bytes()), options).unwrap();
+        let fingerprints = original_fingerprints(&file, Some(old.as_bytes()), Some(new.as_bytes()));
+        (file, fingerprints)
+    }
+
+    fn baseline(first: &str, last: &str) -> Baseline {
+        let (file, fingerprints) = diff(first, last);
+        Baseline::from_files(&[file], vec![fingerprints])
+    }
+
+    #[test]
+    fn keeps_only_hunks_missing_from_baseline() {
+        let (file, fingerprints) = diff("A", "X");
+        let files = baseline("A", "E").retain_new_hunks(vec![file], vec![fingerprints]);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].hunk_starts, vec![4]);
+        assert_eq!(files[0].hunks.len(), 1);
+        assert_eq!(files[0].rows.len(), 5);
+    }
+
+    #[test]
+    fn drops_files_without_new_hunks() {
+        let (file, fingerprints) = diff("A", "E");
+        assert!(
+            baseline("A", "E")
+                .retain_new_hunks(vec![file], vec![fingerprints])
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn fingerprints_ignore_display_settings() {
+        let options = ProcessOptions {
+            tab_width: Some(4),
+            ..ProcessOptions::default()
+        };
+        let (plain, plain_fingerprints) = diff("\tA", "E");
+        let (expanded, expanded_fingerprints) = diff_with("\tA", "E", &options);
+
+        assert_ne!(fingerprints(&plain), fingerprints(&expanded));
+        assert_eq!(plain_fingerprints, expanded_fingerprints);
+        assert_eq!(plain_fingerprints[0], vec!["-a", "+\tA"]);
+    }
+
+    #[test]
+    fn write_then_read_round_trips() {
+        let baseline = baseline("A", "E");
+        let path = std::env::temp_dir()
+            .join(format!("difft-nvim-baseline-{}", std::process::id()))
+            .join("baseline.json");
+        baseline.write(&path).unwrap();
+
+        assert_eq!(Baseline::read(&path).unwrap(), baseline);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
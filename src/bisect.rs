@@ -0,0 +1,204 @@
+//! Detection of an in-progress bisect.
+//!
+//! `git bisect` marks its bounds with refs (`refs/bisect/bad`, `refs/bisect/good-<hash>`)
+//! and logs every step to `BISECT_LOG` in the git directory; the candidate being tested is
+//! checked out as HEAD, or recorded as `BISECT_HEAD` with `--no-checkout`. Custom terms
+//! (`--term-old`/`--term-new`) are read from `BISECT_TERMS`.
+//!
+//! `hg bisect` keeps its marks in `.hg/bisect.state` and tests the working-directory parent,
+//! as does `sl bisect` in `.sl/bisect.state`.
+//! jj has no interactive bisect, so it never reports one.
+
+use crate::repo_state::command_stdout;
+use mlua::prelude::*;
+use std::path::PathBuf;
+
+/// Bounds and candidate of a bisect in progress.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bisect {
+    /// Commit currently being tested.
+    pub candidate: Option<String>,
+
+    /// Commit marked bad (or with the custom new term).
+    pub bad: Option<String>,
+
+    /// Commits marked good (or with the custom old term).
+    pub good: Vec<String>,
+
+    /// The most recently marked good commit, which the candidate is diffed against.
+    pub last_good: Option<String>,
+
+    /// Range diffing `last_good` against `candidate`, for `run_diff`.
+    pub range: Option<String>,
+}
+
+/// The range diffing the `good` commit against `candidate`, in `vcs`'s syntax.
+fn range(vcs: &str, good: Option<&String>, candidate: Option<&String>) -> Option<String> {
+    let (good, candidate) = (good?, candidate?);
+    match vcs {
+        "git" => Some(format!("{good}..{candidate}")),
+        // hg and sl ranges diff from the parent of their first revision
+        "hg" | "sl" => Some(format!("({good}::{candidate}) - {good}")),
+        _ => None,
+    }
+}
+
+/// The `(new, old)` terms from `BISECT_TERMS`, `("bad", "good")` by default.
+fn parse_terms(terms: Option<&str>) -> (String, String) {
+    let mut lines = terms.unwrap_or_default().lines().map(str::trim);
+    match (lines.next(), lines.next()) {
+        (Some(new), Some(old)) if !new.is_empty() && !old.is_empty() => {
+            (new.to_string(), old.to_string())
+        }
+        _ => ("bad".to_string(), "good".to_string()),
+    }
+}
+
+/// The commit last marked with `term` in a `BISECT_LOG`, from the `# <term>: [<hash>]`
+/// comments git writes for every mark, including the bounds given to `git bisect start`.
+fn last_marked(log: &str, term: &str) -> Option<String> {
+    let prefix = format!("# {term}: [");
+    log.lines()
+        .rev()
+        .find_map(|line| line.strip_prefix(&prefix)?.split_once(']'))
+        .map(|(hash, _)| hash.to_string())
+}
+
+/// Inspects git's bisect state, `None` unless a bisect is in progress.
+fn git_bisect() -> Option<Bisect> {
+    let git_dir = command_stdout("git", &["rev-parse", "--absolute-git-dir"])
+        .map(|dir| crate::wrapper::to_local(PathBuf::from(dir)))?;
+    if !git_dir.join("BISECT_START").is_file() {
+        return None;
+    }
+    let read = |name: &str| std::fs::read_to_string(git_dir.join(name)).ok();
+    let (new, old) = parse_terms(read("BISECT_TERMS").as_deref());
+
+    let good: Vec<String> = command_stdout(
+        "git",
+        &[
+            "for-each-ref",
+            "--format=%(objectname)",
+            &format!("refs/bisect/{old}-*"),
+        ],
+    )
+    .map(|out| out.lines().map(str::to_string).collect())
+    .unwrap_or_default();
+    let last_good = read("BISECT_LOG")
+        .and_then(|log| last_marked(&log, &old))
+        .or_else(|| good.first().cloned());
+    // `--no-checkout` bisects record the candidate instead of checking it out
+    let candidate = command_stdout("git", &["rev-parse", "--verify", "-q", "BISECT_HEAD"])
+        .or_else(|| command_stdout("git", &["rev-parse", "--verify", "-q", "HEAD"]));
+
+    Some(Bisect {
+        bad: command_stdout(
+            "git",
+            &["rev-parse", "--verify", "-q", &format!("refs/bisect/{new}")],
+        ),
+        range: range("git", last_good.as_ref(), candidate.as_ref()),
+        candidate,
+        good,
+        last_good,
+    })
+}
+
+/// Inspects Mercurial's bisect state, or Sapling's with `program` being `sl`, `None` unless
+/// a bisect is in progress.
+fn hg_bisect(program: &str) -> Option<Bisect> {
+    let dot_dir = if program == "sl" { ".sl" } else { ".hg" };
+    let root = command_stdout(program, &["root"])
+        .map(|root| crate::wrapper::to_local(PathBuf::from(root)))?;
+    let state = std::fs::read_to_string(root.join(dot_dir).join("bisect.state")).ok()?;
+    if state.trim().is_empty() {
+        return None;
+    }
+    let nodes = |revset: &str| -> Vec<String> {
+        command_stdout(program, &["log", "-r", revset, "-T", "{node}\n"])
+            .map(|out| out.lines().map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+    let candidate = nodes(".").pop();
+    // The state file isn't chronological; the newest good revision is the closest bound
+    let last_good = nodes("max(bisect(good))").pop();
+    Some(Bisect {
+        range: range(program, last_good.as_ref(), candidate.as_ref()),
+        candidate,
+        bad: nodes("min(bisect(bad))").pop(),
+        good: nodes("bisect(good)"),
+        last_good,
+    })
+}
+
+/// Detects a bisect in progress for the given VCS.
+#[must_use]
+pub fn detect(vcs: &str) -> Option<Bisect> {
+    match vcs {
+        "git" => git_bisect(),
+        "hg" | "sl" => hg_bisect(vcs),
+        _ => None,
+    }
+}
+
+impl IntoLua for Bisect {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("candidate", self.candidate)?;
+        table.set("bad", self.bad)?;
+        table.set("good", lua.create_sequence_from(self.good)?)?;
+        table.set("last_good", self.last_good)?;
+        table.set("range", self.range)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_good_comes_from_the_log() {
+        let log = "\
+git bisect start 'main' 'v1.0'
+# status: waiting for both good and bad commits
+# bad: [1111111111111111111111111111111111111111] Break things
+# good: [2222222222222222222222222222222222222222] Release 1.0
+git bisect good 3333333333333333333333333333333333333333
+# good: [3333333333333333333333333333333333333333] Fix [brackets] in subject
+git bisect bad 4444444444444444444444444444444444444444
+# bad: [4444444444444444444444444444444444444444] Refactor
+";
+        assert_eq!(
+            last_marked(log, "good").as_deref(),
+            Some("3333333333333333333333333333333333333333")
+        );
+        assert_eq!(
+            last_marked(log, "bad").as_deref(),
+            Some("4444444444444444444444444444444444444444")
+        );
+        assert_eq!(last_marked(log, "old"), None);
+    }
+
+    #[test]
+    fn custom_terms_and_ranges() {
+        assert_eq!(
+            parse_terms(Some("broken\nfixed\n")),
+            ("broken".to_string(), "fixed".to_string())
+        );
+        assert_eq!(parse_terms(None), ("bad".to_string(), "good".to_string()));
+
+        let (good, candidate) = ("g".to_string(), "c".to_string());
+        assert_eq!(
+            range("git", Some(&good), Some(&candidate)).as_deref(),
+            Some("g..c")
+        );
+        assert_eq!(range("git", None, Some(&candidate)), None);
+        for vcs in ["hg", "sl"] {
+            assert_eq!(
+                range(vcs, Some(&good), Some(&candidate)).as_deref(),
+                Some("(g::c) - g")
+            );
+        }
+        assert_eq!(range("jj", Some(&good), Some(&candidate)), None);
+    }
+}
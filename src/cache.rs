@@ -0,0 +1,243 @@
+//! In-memory cache of processed diffs.
+//!
+//! Re-opening a diff that was already shown skips difftastic and processing entirely. Entries
+//! are keyed by the state a backend resolves the diff to (see
+//! [`crate::vcs::VcsBackend::snapshot`]), such as the commit hashes of a range, so moving a
+//! branch or amending a commit never serves a stale diff.
+//!
+//! Diffs against a working tree also record the modification times of their files, and are
+//! dropped once any of them changes. Files that become changed later show up in the
+//! snapshot itself, e.g. through `git diff --raw`.
+
+use crate::processor::ProcessOptions;
+use crate::{DiffOptions, DiffResult, repo};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Maximum number of processed diffs kept in memory.
+const CAPACITY: usize = 8;
+
+static CACHE: Mutex<DiffCache> = Mutex::new(DiffCache::new(CAPACITY));
+
+/// The state a diff was computed from, as resolved by its backend.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Snapshot {
+    /// Resolved revisions, and anything else that identifies what gets diffed.
+    pub state: String,
+
+    /// Root of the working tree the new side is read from, if any.
+    pub working_tree: Option<PathBuf>,
+}
+
+/// Options that change the processed output. Relative commit times are recomputed on every
+/// hit instead.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Settings {
+    parent: Option<usize>,
+    paths: Vec<PathBuf>,
+    filter: crate::filter::PathFilter,
+    preview: Option<usize>,
+    max_rows: Option<usize>,
+    process: ProcessOptions,
+    /// The configured difftastic binary and arguments (see [`crate::tool`]).
+    difft: Option<crate::tool::Tool>,
+    submodule_log: bool,
+}
+
+/// Cache key: repository, VCS, resolved state and settings.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    repo: Option<PathBuf>,
+    vcs: String,
+    snapshot: Snapshot,
+    settings: Settings,
+}
+
+impl Key {
+    /// Key of a diff of `snapshot` in the repository selected for the current thread.
+    pub fn new(vcs: &str, snapshot: Snapshot, opts: &DiffOptions) -> Self {
+        Self {
+            repo: repo::dir(),
+            vcs: vcs.to_string(),
+            snapshot,
+            settings: Settings {
+                parent: opts.parent,
+                paths: opts.paths.clone(),
+                filter: opts.filter.clone(),
+                preview: opts.preview,
+                max_rows: opts.max_rows,
+                process: opts.process.clone(),
+                difft: crate::tool::current(),
+                submodule_log: opts.submodule_log,
+            },
+        }
+    }
+
+    /// Text identifying the key the same way across runs and builds, for naming diffs saved
+    /// on disk (see [`crate::disk_cache`]). The derived `Hash` can change between Rust
+    /// releases and hashes encodings by address, whereas `Debug` prints them by name.
+    pub fn stable_text(&self) -> String {
+        format!("{self:?}")
+    }
+
+    /// Whether the diff only depends on commits or blobs, not on a working tree.
+    pub fn is_immutable(&self) -> bool {
+        self.snapshot.working_tree.is_none()
+    }
+}
+
+/// A processed diff and what it was computed from.
+struct Entry {
+    key: Key,
+    diff: DiffResult,
+    /// Modification times of the working tree files of the diff.
+    mtimes: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Entry {
+    fn is_fresh(&self) -> bool {
+        self.mtimes
+            .iter()
+            .all(|(path, mtime)| modified(path) == *mtime)
+    }
+}
+
+/// A least-recently-used cache of processed diffs.
+struct DiffCache {
+    capacity: usize,
+    /// Least recently used first.
+    entries: VecDeque<Entry>,
+}
+
+impl DiffCache {
+    const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Returns the diff cached for `key`, dropping it instead if its files changed since.
+    fn get(&mut self, key: &Key) -> Option<DiffResult> {
+        let i = self.entries.iter().position(|entry| entry.key == *key)?;
+        let entry = self.entries.remove(i)?;
+        if !entry.is_fresh() {
+            return None;
+        }
+        let diff = entry.diff.clone();
+        self.entries.push_back(entry);
+        Some(diff)
+    }
+
+    fn insert(&mut self, key: Key, diff: DiffResult) {
+        let mtimes = match &key.snapshot.working_tree {
+            Some(root) => diff
+                .files
+                .iter()
+                .map(|file| {
+                    let path = root.join(&file.path);
+                    let mtime = modified(&path);
+                    (path, mtime)
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        self.entries.retain(|entry| entry.key != key);
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry { key, diff, mtimes });
+    }
+}
+
+/// Modification time of `path`, `None` if it doesn't exist.
+fn modified(path: &std::path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Returns a copy of the diff cached for `key`, if it's still valid.
+pub fn get(key: &Key) -> Option<DiffResult> {
+    CACHE.lock().ok()?.get(key)
+}
+
+/// Caches `diff` under `key`, evicting the least recently used diff once full.
+pub fn insert(key: Key, diff: DiffResult) {
+    if let Ok(mut cache) = CACHE.lock() {
+        cache.insert(key, diff);
+    }
+}
+
+/// Drops every cached diff.
+pub fn clear() {
+    if let Ok(mut cache) = CACHE.lock() {
+        cache.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(state: &str, working_tree: Option<PathBuf>) -> Key {
+        Key::new(
+            "git",
+            Snapshot {
+                state: state.to_string(),
+                working_tree,
+            },
+            &DiffOptions::default(),
+        )
+    }
+
+    fn diff(path: &str) -> DiffResult {
+        let file = crate::difftastic::DifftFile {
+            path: path.into(),
+            old_path: None,
+            language: "Text".into(),
+            status: crate::difftastic::Status::Changed,
+            aligned_lines: Vec::new(),
+            chunks: Vec::new(),
+        };
+        DiffResult {
+            files: vec![crate::processor::stats_only(file, Some((1, 1)))],
+            preview: false,
+            budget_exceeded: false,
+            hidden_files: 0,
+            parents: Vec::new(),
+            old_commit: None,
+            new_commit: None,
+            warning: None,
+            highlight_memo: crate::memo::Stats::default(),
+        }
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let mut cache = DiffCache::new(2);
+        cache.insert(key("a", None), diff("a.txt"));
+        cache.insert(key("b", None), diff("b.txt"));
+        assert!(cache.get(&key("a", None)).is_some());
+        cache.insert(key("c", None), diff("c.txt"));
+
+        assert!(cache.get(&key("b", None)).is_none());
+        assert!(cache.get(&key("a", None)).is_some());
+        assert!(cache.get(&key("c", None)).is_some());
+    }
+
+    #[test]
+    fn working_tree_entries_expire_when_files_change() {
+        let root = std::env::temp_dir().join(format!("difft-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "one\n").unwrap();
+
+        let mut cache = DiffCache::new(2);
+        cache.insert(key("wt", Some(root.clone())), diff("a.txt"));
+        assert!(cache.get(&key("wt", Some(root.clone()))).is_some());
+
+        std::fs::remove_file(root.join("a.txt")).unwrap();
+        assert!(cache.get(&key("wt", Some(root.clone()))).is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
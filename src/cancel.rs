@@ -0,0 +1,243 @@
+//! Cancellation of in-flight diffs.
+//!
+//! A [`Token`] is selected for the current thread with [`with_token`], like repositories in
+//! [`crate::repo`]. Commands run through [`CommandExt::output_or_cancel`] are killed as
+//! soon as the selected token is cancelled, and long loops can bail out early by checking
+//! [`cancelled`]. Work spread over other threads must carry the token along, by capturing
+//! [`current`] and calling [`with_token`] again on each thread.
+//!
+//! The same commands are killed once they exceed the timeout configured in
+//! [`crate::limits`], failing with an [`io::ErrorKind::TimedOut`] error that names them.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// The error message of cancelled diffs.
+pub const CANCELLED: &str = "Diff was cancelled";
+
+/// The start of the error message of commands killed for running too long, followed by the
+/// limit and the command, e.g. `Command timed out after 30s: git diff HEAD`.
+pub const TIMED_OUT: &str = "Command timed out";
+
+/// How often a running command checks whether it was cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+thread_local! {
+    static TOKEN: RefCell<Option<Token>> = const { RefCell::new(None) };
+}
+
+/// A shared flag telling the work it's selected for to stop.
+#[derive(Debug, Clone, Default)]
+pub struct Token(Arc<AtomicBool>);
+
+impl Token {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The token selected for the current thread, if any.
+pub fn current() -> Option<Token> {
+    TOKEN.with(|token| token.borrow().clone())
+}
+
+/// Whether the token selected for the current thread was cancelled.
+pub fn cancelled() -> bool {
+    current().is_some_and(|token| token.is_cancelled())
+}
+
+/// Runs `f` with `token` selected for the current thread. `None` makes the work
+/// uncancellable.
+pub fn with_token<T>(token: Option<Token>, f: impl FnOnce() -> T) -> T {
+    let previous = TOKEN.with(|current| current.replace(token));
+    let result = f();
+    TOKEN.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+/// Running commands so that cancelling the current thread's token kills them.
+pub trait CommandExt {
+    /// Like [`Command::output`], but kills the command and fails with an
+    /// [`io::ErrorKind::Interrupted`] error once the selected token is cancelled, or with an
+    /// [`io::ErrorKind::TimedOut`] one once it exceeds the configured timeout.
+    fn output_or_cancel(&mut self) -> io::Result<Output>;
+
+    /// Like [`CommandExt::output_or_cancel`], but feeds `input` to the command's stdin.
+    fn output_with_input(&mut self, input: Vec<u8>) -> io::Result<Output>;
+}
+
+impl CommandExt for Command {
+    fn output_or_cancel(&mut self) -> io::Result<Output> {
+        // Commands run on the other side of a configured wrapper (see `crate::wrapper`)
+        match crate::wrapper::current() {
+            Some(wrapper) => output(&mut wrapper.wrap(self), None),
+            None => output(self, None),
+        }
+    }
+
+    fn output_with_input(&mut self, input: Vec<u8>) -> io::Result<Output> {
+        match crate::wrapper::current() {
+            Some(wrapper) => output(&mut wrapper.wrap(self), Some(input)),
+            None => output(self, Some(input)),
+        }
+    }
+}
+
+/// Runs `command` to completion, killing it once the selected token is cancelled or the
+/// timeout is exceeded.
+fn output(command: &mut Command, input: Option<Vec<u8>>) -> io::Result<Output> {
+    output_within(command, input, crate::limits::timeout())
+}
+
+/// `program args...` of `command`, for error messages.
+fn describe(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn output_within(
+    command: &mut Command,
+    input: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+) -> io::Result<Output> {
+    if let Some(output) = crate::dry_run::intercept(command) {
+        return Ok(output);
+    }
+    let token = current();
+    let cancelled = || token.as_ref().is_some_and(Token::is_cancelled);
+    // Held until the command exits, so at most `max_procs` run at once (see `crate::limits`)
+    let Some(_slot) = crate::limits::process_slot(cancelled) else {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, CANCELLED));
+    };
+    if token.is_none() && input.is_none() && timeout.is_none() {
+        return command.output();
+    }
+    if cancelled() {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, CANCELLED));
+    }
+
+    let stdin = if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    };
+    let mut child = command
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Write the input on its own thread too; dropping stdin afterwards closes it
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        std::thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+    // Drain both pipes on their own threads so a full pipe can't block the command
+    let stdout = read_to_end(child.stdout.take());
+    let stderr = read_to_end(child.stderr.take());
+    let started = Instant::now();
+    let stdout = loop {
+        if cancelled() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(io::ErrorKind::Interrupted, CANCELLED));
+        }
+        if let Some(timeout) = timeout.filter(|timeout| started.elapsed() >= *timeout) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "{TIMED_OUT} after {}s: {}",
+                    timeout.as_secs_f64(),
+                    describe(command)
+                ),
+            ));
+        }
+        match stdout.recv_timeout(POLL_INTERVAL) {
+            Ok(stdout) => break stdout,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break Vec::new(),
+        }
+    };
+    Ok(Output {
+        status: child.wait()?,
+        stdout,
+        stderr: stderr.recv().unwrap_or_default(),
+    })
+}
+
+/// Reads `pipe` to its end on a new thread, sending the bytes once it's closed.
+fn read_to_end(pipe: Option<impl Read + Send + 'static>) -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        let _ = sender.send(bytes);
+    });
+    receiver
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_reaches_stdin() {
+        let output = Command::new("cat")
+            .output_with_input(b"fed over stdin".to_vec())
+            .unwrap();
+        assert_eq!(output.stdout, b"fed over stdin");
+    }
+
+    #[test]
+    fn cancelling_kills_running_commands() {
+        let token = Token::default();
+        let output = with_token(Some(token.clone()), || {
+            Command::new("echo").arg("hi").output_or_cancel()
+        });
+        assert_eq!(output.unwrap().stdout, b"hi\n");
+
+        let started = Instant::now();
+        let output = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            });
+            with_token(Some(token.clone()), || {
+                Command::new("sleep").arg("5").output_or_cancel()
+            })
+        });
+        assert_eq!(output.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(!cancelled());
+    }
+
+    #[test]
+    fn commands_past_the_timeout_are_killed() {
+        let started = Instant::now();
+        let error = output_within(
+            Command::new("sleep").arg("5"),
+            None,
+            Some(Duration::from_millis(20)),
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(error.to_string(), "Command timed out after 0.02s: sleep 5");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,161 @@
+//! Size and estimated review time of a diff.
+//!
+//! Every `run_diff*` result carries a `complexity` table, so UIs can warn about long
+//! reviews before opening them and teams can track review load. The review time is a
+//! rough estimate from the changed lines alone, at [`LINES_PER_MINUTE`] plus
+//! [`MINUTES_PER_FILE`] for getting into each file.
+
+use crate::processor::{DisplayFile, Hunk};
+use mlua::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Changed lines reviewed per minute, about the 500 lines an hour commonly cited as the
+/// limit of effective code review.
+pub const LINES_PER_MINUTE: f64 = 8.0;
+
+/// Minutes for getting into the context of each changed file.
+pub const MINUTES_PER_FILE: f64 = 0.5;
+
+/// Complexity metrics of one diff.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Complexity {
+    pub files: u32,
+
+    /// Hunks of the files that were processed; files that only have stats (see
+    /// [`DisplayFile::stats_only`]) count none.
+    pub hunks: u32,
+
+    /// Changed lines (old and new) of the largest hunk.
+    pub max_hunk_lines: u32,
+
+    pub additions: u32,
+    pub deletions: u32,
+
+    /// Changed files per language.
+    pub languages: BTreeMap<String, u32>,
+
+    /// Estimated minutes to review the diff, rounded up.
+    pub review_minutes: u32,
+}
+
+/// Old and new lines changed by `hunk`.
+fn hunk_lines(hunk: &Hunk) -> u32 {
+    let extent = |start: Option<u32>, end: Option<u32>| match (start, end) {
+        (Some(start), Some(end)) => end.saturating_sub(start) + 1,
+        _ => 0,
+    };
+    hunk.pairs
+        .iter()
+        .map(|pair| extent(pair.old_start, pair.old_end) + extent(pair.new_start, pair.new_end))
+        .sum()
+}
+
+/// Measures the processed files of a diff.
+#[must_use]
+pub fn measure(files: &[DisplayFile]) -> Complexity {
+    let mut complexity = Complexity::default();
+    for file in files {
+        complexity.files += 1;
+        complexity.hunks += file.hunks.len() as u32;
+        complexity.additions += file.additions;
+        complexity.deletions += file.deletions;
+        if let Some(lines) = file.hunks.iter().map(hunk_lines).max() {
+            complexity.max_hunk_lines = complexity.max_hunk_lines.max(lines);
+        }
+        *complexity
+            .languages
+            .entry(file.language.clone())
+            .or_default() += 1;
+    }
+    let churn = f64::from(complexity.additions + complexity.deletions);
+    let minutes = churn / LINES_PER_MINUTE + f64::from(complexity.files) * MINUTES_PER_FILE;
+    complexity.review_minutes = minutes.ceil() as u32;
+    complexity
+}
+
+impl IntoLua for Complexity {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("files", self.files)?;
+        table.set("hunks", self.hunks)?;
+        table.set("max_hunk_lines", self.max_hunk_lines)?;
+        table.set("additions", self.additions)?;
+        table.set("deletions", self.deletions)?;
+        table.set("languages", self.languages)?;
+        table.set("review_minutes", self.review_minutes)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::LinePairing;
+
+    fn file(language: &str, stats: (u32, u32), hunks: Vec<Hunk>) -> DisplayFile {
+        let file = crate::difftastic::DifftFile {
+            path: "a".into(),
+            old_path: None,
+            language: language.to_string(),
+            status: crate::difftastic::Status::Changed,
+            aligned_lines: Vec::new(),
+            chunks: Vec::new(),
+        };
+        DisplayFile {
+            hunks,
+            ..crate::processor::stats_only(file, Some(stats))
+        }
+    }
+
+    fn hunk(pairs: Vec<LinePairing>) -> Hunk {
+        Hunk {
+            start: 0,
+            end: 0,
+            additions: 0,
+            deletions: 0,
+            kind: None,
+            label: None,
+            header: None,
+            new_start: None,
+            new_end: None,
+            pairs,
+            rename_of: None,
+        }
+    }
+
+    #[test]
+    fn measures_hunks_languages_and_review_time() {
+        let replace = LinePairing {
+            old_start: Some(3),
+            old_end: Some(5),
+            new_start: Some(3),
+            new_end: Some(4),
+        };
+        let insert = LinePairing {
+            old_start: None,
+            old_end: None,
+            new_start: Some(10),
+            new_end: Some(10),
+        };
+        let files = [
+            file(
+                "Rust",
+                (40, 20),
+                vec![hunk(vec![replace, insert]), hunk(vec![insert])],
+            ),
+            file("Rust", (3, 0), vec![hunk(vec![insert])]),
+            file("Lua", (0, 17), Vec::new()),
+        ];
+        let complexity = measure(&files);
+        assert_eq!(complexity.files, 3);
+        assert_eq!(complexity.hunks, 3);
+        assert_eq!(complexity.max_hunk_lines, 6);
+        assert_eq!(complexity.languages.get("Rust"), Some(&2));
+        assert_eq!(complexity.languages.get("Lua"), Some(&1));
+        // 80 lines at 8 a minute, plus half a minute per file
+        assert_eq!(complexity.review_minutes, 12);
+
+        assert_eq!(measure(&[]), Complexity::default());
+    }
+}
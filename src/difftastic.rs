@@ -33,21 +33,29 @@
 //! }
 //! ```
 
-use serde::Deserialize;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum Status {
     Created,
     Deleted,
     Changed,
+    /// Identical on both sides, reported when diffing directories.
+    Unchanged,
+    /// Moved from [`DifftFile::old_path`], with or without changes.
+    Renamed,
 }
 
 /// A file entry from difftastic's JSON output.
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub struct DifftFile {
+    /// Path on the new side.
     pub path: PathBuf,
+    /// Path on the old side, when it differs from `path` because the file was renamed.
+    #[serde(default)]
+    pub old_path: Option<PathBuf>,
     pub language: String,
     pub status: Status,
     /// Pre-computed line alignment: `(lhs_line, rhs_line)` pairs, `None` = filler.
@@ -58,6 +66,44 @@ pub struct DifftFile {
     pub chunks: Vec<Chunk>,
 }
 
+impl DifftFile {
+    /// Path on the old side.
+    #[must_use]
+    pub fn old_path(&self) -> &Path {
+        self.old_path.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Path on the new side.
+    #[must_use]
+    pub fn new_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Marks the file as moved from `old_path`. Files difftastic found identical or changed
+    /// become [`Status::Renamed`]; created and deleted ones keep their status.
+    pub fn rename_from(&mut self, old_path: PathBuf) {
+        if matches!(self.status, Status::Changed | Status::Unchanged) {
+            self.status = Status::Renamed;
+        }
+        self.old_path = Some(old_path);
+    }
+
+    /// Whether difftastic found the file to be binary, which it reports with the `Binary`
+    /// language and no chunks.
+    #[must_use]
+    pub fn is_binary(&self) -> bool {
+        self.language.eq_ignore_ascii_case("binary")
+    }
+
+    /// Whether difftastic diffed the file line by line, because it has no parser for it
+    /// (`Text`) or fell back to a line-based diff past one of its limits, which it notes in
+    /// the language, e.g. `Rust (exceeded DFT_GRAPH_LIMIT)`.
+    #[must_use]
+    pub fn is_textual(&self) -> bool {
+        self.language.starts_with("Text") || self.language.contains("exceeded DFT_")
+    }
+}
+
 /// A chunk (hunk) of changes within a file.
 ///
 /// A chunk represents a contiguous group of related changes, similar to a
@@ -128,12 +174,18 @@ pub struct Change {
 /// Handles two formats:
 /// - jj format: JSON array `[{...}, {...}]`
 /// - git format: newline-separated JSON objects
+///
+/// A single (possibly pretty-printed) object is also accepted, as pasted in bug reports.
 pub fn parse(json: &str) -> Result<Vec<DifftFile>, serde_json::Error> {
     // Try array format first (jj outputs this format)
     if let Ok(files) = serde_json::from_str::<Vec<DifftFile>>(json) {
         return Ok(files);
     }
 
+    if let Ok(file) = serde_json::from_str::<DifftFile>(json) {
+        return Ok(vec![file]);
+    }
+
     // Fall back to newline-separated JSON objects (git outputs this format)
     json.lines()
         .filter(|line| !line.trim().is_empty())
@@ -169,6 +221,18 @@ mod tests {
         assert!(files[0].chunks.is_empty());
     }
 
+    #[test]
+    fn renamed_file_keeps_both_paths() {
+        let json = r#"[{"path": "src/new.rs", "language": "Rust", "status": "unchanged"}]"#;
+
+        let mut file = parse(json).unwrap().remove(0);
+        assert_eq!(file.old_path(), Path::new("src/new.rs"));
+        file.rename_from("src/old.rs".into());
+        assert_eq!(file.status, Status::Renamed);
+        assert_eq!(file.old_path(), Path::new("src/old.rs"));
+        assert_eq!(file.new_path(), Path::new("src/new.rs"));
+    }
+
     #[test]
     fn parse_deleted_file() {
         let json = r#"[{
@@ -320,6 +384,19 @@ mod tests {
         assert_eq!(files[1].path, PathBuf::from("b.rs"));
     }
 
+    #[test]
+    fn parse_single_pretty_printed_object() {
+        let json = r#"{
+            "path": "a.rs",
+            "language": "Rust",
+            "status": "deleted"
+        }"#;
+
+        let files = parse(json).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].status, Status::Deleted);
+    }
+
     #[test]
     fn parse_with_aligned_lines() {
         let json = r#"[{
@@ -336,4 +413,13 @@ mod tests {
         assert_eq!(files[0].aligned_lines[1], (Some(1), None));
         assert_eq!(files[0].aligned_lines[2], (Some(2), Some(1)));
     }
+
+    #[test]
+    fn parse_unchanged_file_from_directory_diff() {
+        let json = r#"[{"path": "same.txt", "language": "Text", "status": "unchanged"}]"#;
+
+        let files = parse(json).unwrap();
+        assert_eq!(files[0].status, Status::Unchanged);
+        assert!(files[0].chunks.is_empty());
+    }
 }
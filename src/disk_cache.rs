@@ -0,0 +1,191 @@
+//! Processed diffs saved on disk, so restarting Neovim doesn't re-run difftastic for
+//! commits already reviewed.
+//!
+//! Only diffs whose [`cache::Snapshot`] names commits or blobs alone are saved, since those
+//! never change; diffs reading a working tree are left to the in-memory [`cache`]. Each diff
+//! gets a directory named after a hash of its [`cache::Key`] (see
+//! [`cache::Key::stable_text`]), holding one JSON file per diffed file and an `index.json`
+//! with the rest of the result:
+//!
+//! ```text
+//! <cache dir>/<key hash>/index.json
+//! <cache dir>/<key hash>/<path hash>.json
+//! ```
+//!
+//! The index is written last, so a diff interrupted while saving is never read back.
+
+use crate::cache;
+use crate::export::fnv1a;
+use crate::processor::DisplayFile;
+use crate::{DiffResult, ParentCommit};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Everything in a [`DiffResult`] but the files, which are saved separately.
+#[derive(Serialize, Deserialize)]
+struct Index {
+    /// Paths of the diffed files, in diff order.
+    paths: Vec<PathBuf>,
+    preview: bool,
+    budget_exceeded: bool,
+    hidden_files: usize,
+    parents: Vec<ParentCommit>,
+    old_commit: Option<String>,
+    new_commit: Option<String>,
+}
+
+/// Names must stay the same across Neovim restarts, and so across builds of the library.
+fn hash_name(text: &str) -> String {
+    format!("{:016x}", fnv1a(text.as_bytes()))
+}
+
+fn key_name(key: &cache::Key) -> String {
+    hash_name(&key.stable_text())
+}
+
+fn file_name(path: &Path) -> String {
+    format!("{}.json", hash_name(&path.to_string_lossy()))
+}
+
+fn to_json(value: &impl Serialize) -> Result<Vec<u8>, String> {
+    serde_json::to_vec(value).map_err(|e| format!("Failed to serialize diff: {e}"))
+}
+
+/// Loads the diff saved for `key` under `dir`, if any.
+pub fn load(dir: &Path, key: &cache::Key) -> Option<DiffResult> {
+    let dir = dir.join(key_name(key));
+    let index: Index = serde_json::from_slice(&std::fs::read(dir.join("index.json")).ok()?).ok()?;
+    let files = index
+        .paths
+        .iter()
+        .map(|path| {
+            let json = std::fs::read(dir.join(file_name(path))).ok()?;
+            serde_json::from_slice::<DisplayFile>(&json).ok()
+        })
+        .collect::<Option<_>>()?;
+    Some(DiffResult {
+        files,
+        preview: index.preview,
+        budget_exceeded: index.budget_exceeded,
+        hidden_files: index.hidden_files,
+        parents: index.parents,
+        old_commit: index.old_commit,
+        new_commit: index.new_commit,
+        warning: None,
+        highlight_memo: crate::memo::Stats::default(),
+    })
+}
+
+/// Saves `diff` for `key` under `dir`, unless it reads a working tree.
+pub fn store(dir: &Path, key: &cache::Key, diff: &DiffResult) -> Result<(), String> {
+    if !key.is_immutable() {
+        return Ok(());
+    }
+    let dir = dir.join(key_name(key));
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+    let write = |name: &str, json: Vec<u8>| {
+        // Renamed into place, so readers never see a partially written file
+        let path = dir.join(name);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)
+            .and_then(|()| std::fs::rename(&tmp, &path))
+            .map_err(|e| format!("Failed to write {}: {e}", path.display()))
+    };
+    for file in &diff.files {
+        write(&file_name(&file.path), to_json(file)?)?;
+    }
+    let index = Index {
+        paths: diff.files.iter().map(|file| file.path.clone()).collect(),
+        preview: diff.preview,
+        budget_exceeded: diff.budget_exceeded,
+        hidden_files: diff.hidden_files,
+        parents: diff.parents.clone(),
+        old_commit: diff.old_commit.clone(),
+        new_commit: diff.new_commit.clone(),
+    };
+    write("index.json", to_json(&index)?)
+}
+
+/// Deletes every diff saved under `dir`.
+pub fn clear(dir: &Path) -> Result<(), String> {
+    match std::fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(format!("Failed to remove {}: {e}", dir.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_diffs_load_back() {
+        let dir = std::env::temp_dir().join(format!("difft-disk-cache-{}", std::process::id()));
+        let key = |state: &str, working_tree: Option<PathBuf>| {
+            cache::Key::new(
+                "git",
+                cache::Snapshot {
+                    state: state.to_string(),
+                    working_tree,
+                },
+                &crate::DiffOptions::default(),
+            )
+        };
+        let file = crate::processor::process_json(
+            r#"{"path":"a.rs","language":"Rust","status":"changed","aligned_lines":[[0,0]],
+                "chunks":[[{"lhs":{"line_number":0,"changes":[{"start":4,"end":5,"content":"a","highlight":"normal"}]},
+                            "rhs":{"line_number":0,"changes":[{"start":4,"end":5,"content":"b","highlight":"normal"}]}}]]}"#,
+            Some(b"let a = 1;\n"),
+            Some(b"let b = 1;\n"),
+            &crate::processor::ProcessOptions {
+                syntax_spans: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let diff = DiffResult {
+            files: vec![file],
+            preview: false,
+            budget_exceeded: false,
+            hidden_files: 0,
+            parents: Vec::new(),
+            old_commit: Some("a".repeat(40)),
+            new_commit: Some("b".repeat(40)),
+            warning: None,
+            highlight_memo: crate::memo::Stats::default(),
+        };
+
+        store(&dir, &key("range a..b", None), &diff).unwrap();
+        let loaded = load(&dir, &key("range a..b", None)).unwrap();
+        assert_eq!(loaded.files, diff.files);
+        assert_eq!(loaded.new_commit, diff.new_commit);
+        assert!(load(&dir, &key("range a..c", None)).is_none());
+
+        store(&dir, &key("index", Some(dir.clone())), &diff).unwrap();
+        assert!(load(&dir, &key("index", Some(dir.clone()))).is_none());
+
+        clear(&dir).unwrap();
+        assert!(load(&dir, &key("range a..b", None)).is_none());
+    }
+
+    #[test]
+    fn names_dont_depend_on_the_build() {
+        assert_eq!(hash_name("a"), "af63dc4c8601ec8c");
+        let opts = crate::DiffOptions {
+            process: crate::processor::ProcessOptions {
+                encodings: vec![("*.txt".to_string(), encoding_rs::WINDOWS_1252)],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let snapshot = cache::Snapshot {
+            state: "range a..b".to_string(),
+            working_tree: None,
+        };
+        let key = cache::Key::new("git", snapshot, &opts);
+        assert!(key.stable_text().contains("windows-1252"));
+    }
+}
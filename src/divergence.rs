@@ -0,0 +1,201 @@
+//! Detection of divergent and hidden jj changes.
+//!
+//! A jj change ID names whichever visible commits carry it: usually one, but several once
+//! the change diverged (e.g. rewritten concurrently on two machines), in which case a diff
+//! of the change silently covers all of them. Commit IDs also resolve to hidden commits,
+//! abandoned or rewritten ones, which may not be the version the user expects.
+//!
+//! Before diffing a revset that is a single symbol, [`check`] lists the commits it resolves
+//! to and reports a [`Warning`] with the candidates, so the caller can pick one and diff it
+//! by commit ID instead.
+
+use crate::cancel::CommandExt;
+use mlua::prelude::*;
+
+/// Template printing one [`Candidate`] per line.
+const TEMPLATE: &str = r#"commit_id ++ "\t" ++ change_id ++ "\t" ++ if(divergent, "divergent") ++ "\t" ++ if(hidden, "hidden") ++ "\t" ++ committer.timestamp().format("%s") ++ "\t" ++ description.first_line() ++ "\n""#;
+
+/// A commit a revset resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub commit: String,
+    pub change_id: String,
+    pub subject: String,
+    /// Committer timestamp in Unix seconds.
+    pub timestamp: Option<i64>,
+    pub divergent: bool,
+    pub hidden: bool,
+}
+
+/// Why a revset may not diff the commit the user meant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// The change ID names several visible commits.
+    Divergent,
+    /// The revset names a hidden commit.
+    Hidden,
+}
+
+impl Kind {
+    #[must_use]
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Divergent => "divergent",
+            Self::Hidden => "hidden",
+        }
+    }
+}
+
+/// A revset that resolved to a divergent change or a hidden commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: Kind,
+    pub revset: String,
+    /// The commits the revset resolved to; diff one by its commit ID to pick it.
+    pub candidates: Vec<Candidate>,
+}
+
+impl Warning {
+    /// A one-line description for notifications.
+    #[must_use]
+    pub fn message(&self) -> String {
+        match self.kind {
+            Kind::Divergent => format!(
+                "Change {} is divergent: it names {} commits",
+                self.revset,
+                self.candidates.len()
+            ),
+            Kind::Hidden => format!("Revision {} is a hidden commit", self.revset),
+        }
+    }
+}
+
+/// Whether `revset` is a single symbol (a change ID, commit ID or bookmark), the only kind
+/// of revset that names a divergent change or a hidden commit by accident.
+fn is_symbol(revset: &str) -> bool {
+    !revset.is_empty()
+        && revset
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '/' | '.'))
+        && !revset.ends_with('-')
+}
+
+/// Parses [`TEMPLATE`] lines.
+fn parse_candidates(output: &str) -> Vec<Candidate> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(6, '\t');
+            Some(Candidate {
+                commit: fields.next()?.to_string(),
+                change_id: fields.next()?.to_string(),
+                divergent: fields.next()? == "divergent",
+                hidden: fields.next()? == "hidden",
+                timestamp: fields.next()?.parse().ok(),
+                subject: fields.next().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The warning for `revset` resolving to `candidates`, if any.
+fn warning(revset: &str, candidates: Vec<Candidate>) -> Option<Warning> {
+    let kind = if candidates.len() > 1 && candidates.iter().any(|c| c.divergent) {
+        Kind::Divergent
+    } else if candidates.iter().any(|c| c.hidden) {
+        Kind::Hidden
+    } else {
+        return None;
+    };
+    Some(Warning {
+        kind,
+        revset: revset.to_string(),
+        candidates,
+    })
+}
+
+/// Lists the commits `revset` resolves to, with their divergent and hidden status.
+pub fn resolve(revset: &str, ignore_working_copy: bool) -> Result<Vec<Candidate>, String> {
+    let output = crate::jj_command(ignore_working_copy)
+        .args(["log", "-r", revset, "--no-graph", "-T", TEMPLATE])
+        .output_or_cancel()
+        .map_err(|e| format!("Failed to run jj: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("jj command failed: {stderr}"));
+    }
+    Ok(parse_candidates(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Checks whether diffing `revset` targets a divergent change or a hidden commit. Only
+/// single symbols are checked; failures are left to the diff itself.
+pub fn check(revset: &str, ignore_working_copy: bool) -> Option<Warning> {
+    if !is_symbol(revset) {
+        return None;
+    }
+    warning(revset, resolve(revset, ignore_working_copy).ok()?)
+}
+
+impl IntoLua for Candidate {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("commit", self.commit)?;
+        table.set("change_id", self.change_id)?;
+        table.set("subject", self.subject)?;
+        table.set("timestamp", self.timestamp)?;
+        table.set("divergent", self.divergent)?;
+        table.set("hidden", self.hidden)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for Warning {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("kind", self.kind.as_str())?;
+        table.set("message", self.message())?;
+        table.set("revset", self.revset)?;
+        table.set("candidates", lua.create_sequence_from(self.candidates)?)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divergent_and_hidden_commits_warn() {
+        let output = "\
+1111111111111111111111111111111111111111\tqpvuntsm\tdivergent\t\t1700000000\tFix parser
+2222222222222222222222222222222222222222\tqpvuntsm\tdivergent\t\t1700000100\tFix parser (laptop)
+";
+        let divergent = warning("qpv", parse_candidates(output)).unwrap();
+        assert_eq!(divergent.kind, Kind::Divergent);
+        assert_eq!(divergent.candidates.len(), 2);
+        assert_eq!(divergent.candidates[1].subject, "Fix parser (laptop)");
+        assert_eq!(divergent.candidates[1].timestamp, Some(1_700_000_100));
+        assert_eq!(
+            divergent.message(),
+            "Change qpv is divergent: it names 2 commits"
+        );
+
+        let hidden = "3333333333333333333333333333333333333333\tzzzz\t\thidden\t1700000000\t\n";
+        let hidden = warning("333333", parse_candidates(hidden)).unwrap();
+        assert_eq!(hidden.kind, Kind::Hidden);
+        assert_eq!(hidden.candidates[0].subject, "");
+
+        let normal = "4444444444444444444444444444444444444444\tyyyy\t\t\t1700000000\tWork\n";
+        assert_eq!(warning("yyyy", parse_candidates(normal)), None);
+    }
+
+    #[test]
+    fn only_symbols_are_checked() {
+        assert!(is_symbol("qpvuntsm"));
+        assert!(is_symbol("feature/parser"));
+        assert!(!is_symbol("@-"));
+        assert!(!is_symbol("main..@"));
+        assert!(!is_symbol("qpv-"));
+        assert!(!is_symbol(""));
+    }
+}
@@ -0,0 +1,116 @@
+//! Recording the commands a diff would run instead of running them.
+//!
+//! While [`record`] runs, commands run through [`crate::cancel::CommandExt::output_or_cancel`]
+//! are recorded with their arguments, working directory and environment, and "succeed" with
+//! empty output. Commands that depend on the output of earlier ones (e.g. fetching the
+//! contents of each changed file) don't show up, since the recorded diff has no files.
+
+use mlua::prelude::*;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Output};
+
+thread_local! {
+    static RECORDED: RefCell<Option<Vec<RecordedCommand>>> = const { RefCell::new(None) };
+}
+
+/// A command as it would have been run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedCommand {
+    pub program: String,
+    pub args: Vec<String>,
+
+    /// Directory the command runs in, `None` for Neovim's working directory.
+    pub cwd: Option<PathBuf>,
+
+    /// Environment variables set for the command, `None` for removed ones.
+    pub env: Vec<(String, Option<String>)>,
+}
+
+impl RecordedCommand {
+    fn new(command: &Command) -> Self {
+        let lossy = |s: &std::ffi::OsStr| s.to_string_lossy().into_owned();
+        Self {
+            program: lossy(command.get_program()),
+            args: command.get_args().map(lossy).collect(),
+            cwd: command.get_current_dir().map(PathBuf::from),
+            env: command
+                .get_envs()
+                .map(|(key, value)| (lossy(key), value.map(lossy)))
+                .collect(),
+        }
+    }
+}
+
+/// Runs `f`, recording the commands it would run on the current thread instead of running
+/// them.
+pub fn record<T>(f: impl FnOnce() -> T) -> (T, Vec<RecordedCommand>) {
+    let previous = RECORDED.with(|recorded| recorded.replace(Some(Vec::new())));
+    let result = f();
+    let commands = RECORDED.with(|recorded| recorded.replace(previous));
+    (result, commands.unwrap_or_default())
+}
+
+/// Records `command` if recording, returning the empty output it gets instead of running.
+pub fn intercept(command: &Command) -> Option<Output> {
+    RECORDED.with(|recorded| {
+        recorded.borrow_mut().as_mut().map(|commands| {
+            commands.push(RecordedCommand::new(command));
+            Output {
+                status: ExitStatus::default(),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            }
+        })
+    })
+}
+
+impl IntoLua for RecordedCommand {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("program", self.program)?;
+        table.set("args", lua.create_sequence_from(self.args)?)?;
+        table.set(
+            "cwd",
+            self.cwd.map(|cwd| cwd.to_string_lossy().into_owned()),
+        )?;
+        let env = lua.create_table()?;
+        for (key, value) in self.env {
+            // Removed variables are `false`, since `nil` can't be stored in a table
+            match value {
+                Some(value) => env.set(key, value)?,
+                None => env.set(key, false)?,
+            }
+        }
+        table.set("env", env)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::CommandExt;
+
+    #[test]
+    fn recorded_commands_do_not_run() {
+        let (output, commands) = record(|| {
+            Command::new("definitely-not-a-real-program")
+                .args(["diff", "--raw"])
+                .env("DFT_DISPLAY", "json")
+                .output_or_cancel()
+        });
+
+        assert!(output.unwrap().status.success());
+        assert_eq!(
+            commands,
+            vec![RecordedCommand {
+                program: "definitely-not-a-real-program".to_string(),
+                args: vec!["diff".to_string(), "--raw".to_string()],
+                cwd: None,
+                env: vec![("DFT_DISPLAY".to_string(), Some("json".to_string()))],
+            }]
+        );
+        assert!(intercept(&Command::new("git")).is_none());
+    }
+}
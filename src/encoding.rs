@@ -0,0 +1,170 @@
+//! Decoding file contents that aren't UTF-8.
+//!
+//! Contents are fetched as bytes and decoded right before processing. UTF-8 is used as is;
+//! other contents are transcoded from the encoding configured for the file's path in
+//! `opts.encodings` (e.g. `{ ["*.txt"] = "shift_jis" }`, globs tried in sorted order), the
+//! one named by a UTF-16 byte order mark, or the one chardetng guesses.
+//!
+//! difftastic itself reads such files as UTF-8, replacing invalid bytes with U+FFFD, so the
+//! byte offsets of its changes point into that lossy view of each line. [`Remap`] translates
+//! them to the transcoded line, going through the line's original bytes.
+
+use encoding_rs::{Encoding, UTF_8};
+use std::borrow::Cow;
+use std::path::Path;
+
+/// Length in bytes of U+FFFD, which replaces each invalid sequence in difftastic's view.
+const REPLACEMENT_LEN: usize = '\u{FFFD}'.len_utf8();
+
+/// Encodings by glob, as configured in `opts.encodings`.
+pub type Encodings = Vec<(String, &'static Encoding)>;
+
+/// Looks up an encoding by its WHATWG label (e.g. `"latin1"`, `"shift_jis"`, `"utf-16le"`).
+pub fn for_label(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("Unknown encoding {label:?}"))
+}
+
+/// Decodes the contents of `path`, returning the text and the encoding it was transcoded
+/// from, `None` for UTF-8.
+pub fn decode<'a>(
+    bytes: &'a [u8],
+    path: &Path,
+    encodings: &Encodings,
+) -> (Cow<'a, str>, Option<&'static Encoding>) {
+    let path = path.to_string_lossy();
+    let configured = encodings
+        .iter()
+        .find(|(glob, _)| crate::filter::matches(glob, &path))
+        .map(|&(_, encoding)| encoding);
+    let encoding = match configured {
+        Some(encoding) => encoding,
+        None => match Encoding::for_bom(bytes) {
+            Some((encoding, _)) if encoding != UTF_8 => encoding,
+            _ if std::str::from_utf8(bytes).is_ok() => UTF_8,
+            _ => guess(bytes),
+        },
+    };
+    if encoding == UTF_8 {
+        return (String::from_utf8_lossy(bytes), None);
+    }
+    let (text, _) = encoding.decode_with_bom_removal(bytes);
+    (text, Some(encoding))
+}
+
+/// Guesses the encoding of `bytes`, which aren't valid UTF-8.
+fn guess(bytes: &[u8]) -> &'static Encoding {
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(bytes, true);
+    detector.guess(None, false)
+}
+
+/// Translates difftastic's offsets within lines of contents decoded from `encoding`.
+pub struct Remap<'a> {
+    lines: Vec<&'a [u8]>,
+    encoding: &'static Encoding,
+}
+
+impl<'a> Remap<'a> {
+    /// Remaps offsets into the lines of `bytes`, or `None` when difftastic's offsets already
+    /// point into the decoded text. That's the case for UTF-8, and for encodings that aren't
+    /// ASCII-compatible, like UTF-16, which difftastic decodes itself.
+    pub fn new(bytes: &'a [u8], encoding: Option<&'static Encoding>) -> Option<Self> {
+        let encoding = encoding.filter(|encoding| encoding.is_ascii_compatible())?;
+        Some(Self {
+            lines: bytes.split(|&b| b == b'\n').collect(),
+            encoding,
+        })
+    }
+
+    /// Translates byte `offset` into difftastic's view of the 0-indexed `line` to the same
+    /// position in the decoded line. Offsets inside a character move to its start.
+    pub fn offset(&self, line: usize, offset: u32) -> u32 {
+        let Some(bytes) = self.lines.get(line) else {
+            return offset;
+        };
+        let source = source_offset(bytes, offset as usize);
+        let (decoded, _) = self.encoding.decode_without_bom_handling(bytes);
+        let (prefix, _) = self.encoding.decode_without_bom_handling(&bytes[..source]);
+        // A prefix ending inside a multi-byte character decodes to a trailing U+FFFD
+        let mut common = prefix
+            .bytes()
+            .zip(decoded.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !decoded.is_char_boundary(common) {
+            common -= 1;
+        }
+        common as u32
+    }
+}
+
+/// The offset in `line` of byte `offset` into its lossy UTF-8 decoding.
+fn source_offset(line: &[u8], offset: usize) -> usize {
+    let (mut lossy, mut source) = (0, 0);
+    for chunk in line.utf8_chunks() {
+        let valid = chunk.valid().len();
+        if offset <= lossy + valid {
+            return source + offset - lossy;
+        }
+        lossy += valid;
+        source += valid;
+        if !chunk.invalid().is_empty() {
+            if offset < lossy + REPLACEMENT_LEN {
+                return source;
+            }
+            lossy += REPLACEMENT_LEN;
+            source += chunk.invalid().len();
+        }
+    }
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{SHIFT_JIS, UTF_16LE, WINDOWS_1252};
+
+    #[test]
+    fn decodes_configured_guessed_and_bom_encodings() {
+        let path = Path::new("docs/notes.txt");
+        let none = Encodings::new();
+
+        let (text, encoding) = decode("héllo".as_bytes(), path, &none);
+        assert_eq!((text.as_ref(), encoding), ("héllo", None));
+
+        let latin1 = b"caf\xe9 cr\xe8me br\xfbl\xe9e, d\xe9j\xe0 vu\n";
+        let (text, encoding) = decode(latin1, path, &none);
+        assert_eq!(text, "café crème brûlée, déjà vu\n");
+        assert_eq!(encoding, Some(WINDOWS_1252));
+
+        let (text, encoding) = decode(b"\xff\xfeh\0i\0", path, &none);
+        assert_eq!((text.as_ref(), encoding), ("hi", Some(UTF_16LE)));
+
+        let sjis = vec![("*.txt".to_string(), SHIFT_JIS)];
+        let (text, encoding) = decode(b"\x83\x5c\x83\x74\x83\x67", path, &sjis);
+        assert_eq!((text.as_ref(), encoding), ("ソフト", Some(SHIFT_JIS)));
+        assert!(for_label("latin1").is_ok());
+        assert!(for_label("klingon").is_err());
+    }
+
+    #[test]
+    fn remaps_offsets_from_the_lossy_view() {
+        // difftastic sees "caf\u{FFFD} = 1" and reports `1` at 9..10
+        let bytes = b"x\ncaf\xe9 = 1\n";
+        let remap = Remap::new(bytes, Some(WINDOWS_1252)).unwrap();
+        assert_eq!(remap.offset(1, 9), 8);
+        assert_eq!(remap.offset(1, 3), 3);
+        assert_eq!(remap.offset(1, 6), 5);
+        assert_eq!(remap.offset(0, 1), 1);
+
+        // "ソ" is 83 5C in Shift-JIS; difftastic sees "\u{FFFD}\\=" and `=` at 4..5
+        let remap = Remap::new(b"\x83\x5c=", Some(SHIFT_JIS)).unwrap();
+        assert_eq!(remap.offset(0, 4), 3);
+        // Inside "ソ" moves to its start
+        assert_eq!(remap.offset(0, 3), 0);
+
+        assert!(Remap::new(b"a", None).is_none());
+        assert!(Remap::new(b"\xff\xfea\0", Some(UTF_16LE)).is_none());
+    }
+}
@@ -0,0 +1,198 @@
+//! JSON export of processed diffs for tools outside Neovim.
+//!
+//! Web viewers and review bots can render the same rows, highlights and hunks the Neovim
+//! UI shows instead of re-running difftastic. An export is one JSON object:
+//!
+//! ```text
+//! {
+//!   "schema_version": 1,
+//!   "range": "main..feature",
+//!   "vcs": "git",
+//!   "old_commit": "<hash>" | null,
+//!   "new_commit": "<hash>" | null,
+//!   "preview": false,           // whether some files only have stats
+//!   "budget_exceeded": false,   // whether files past the row budget only have stats
+//!   "hidden_files": 0,          // files left out by include/exclude globs
+//!   "complexity": { "files", "hunks", "max_hunk_lines", "additions", "deletions",
+//!                   "languages": { "<language>": <files> }, "review_minutes" },
+//!   "files": [ <file>, ... ]
+//! }
+//! ```
+//!
+//! Each file has an `id` and `hunk_ids`, one per hunk, to deep-link to (see [`ExportFile`]),
+//! along with the fields of its [`DisplayFile`]: `path`, `language`, `status` (`"created"`, `"deleted"`,
+//! `"changed"`, `"renamed"` or `"unchanged"`), `additions`, `deletions`, `rows`,
+//! `hunk_starts`, `hunks`, `changed_functions`, `stats_only`, `processing_failed`,
+//! `was_empty`, `now_empty` and `aligned_lines`, plus `old_path`, `sections`, `folds`, `binary`,
+//! `old_size`, `new_size`, `mode_change`, `virtual_deletions`, `unified`, `old_line_endings`,
+//! `new_line_endings`, `old_encoding` and `new_encoding` when set. A row is
+//! `{ left, right, change }`, where each side is `{ content, is_filler, highlights:
+//! [{ start, end, kind }] }` with `kind` being `"full"` or `"partial"` and `end` being -1
+//! for a full-line highlight unless exported with `line_length_ends`, plus `syntax_kind`
+//! when difftastic gave one, and `display_start` and `display_end` with `display_columns`.
+//! Sides showing a line also have its 1-indexed `line_number`. Row and line indices are
+//! 0-indexed, hunk and section line ranges 1-indexed, as documented on each type.
+//!
+//! Fields may be added without bumping [`SCHEMA_VERSION`]; removing or changing the meaning
+//! of one bumps it.
+
+use crate::DiffResult;
+use crate::baseline;
+use crate::complexity::{self, Complexity};
+use crate::processor::DisplayFile;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Version of the export format, bumped on incompatible changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A processed diff in the export format.
+#[derive(Debug, Serialize)]
+pub struct Export<'a> {
+    pub schema_version: u32,
+    pub range: &'a str,
+    pub vcs: &'a str,
+    pub old_commit: Option<&'a str>,
+    pub new_commit: Option<&'a str>,
+    pub preview: bool,
+    pub budget_exceeded: bool,
+    pub hidden_files: usize,
+    pub complexity: Complexity,
+    pub files: Vec<ExportFile<'a>>,
+}
+
+/// A file of an export, with ids that stay the same across exports of the same content.
+///
+/// The file id is `file-` and a hash of its path. A hunk id is the file id, `-` and a hash
+/// of the lines the hunk changes, with whitespace removed like `git patch-id` does, so it
+/// doesn't depend on where the hunk is or on the rest of the diff. Hunks changing the same
+/// lines get `-2`, `-3`, ... appended in order.
+#[derive(Debug, Serialize)]
+pub struct ExportFile<'a> {
+    pub id: String,
+    pub hunk_ids: Vec<String>,
+    #[serde(flatten)]
+    pub file: &'a DisplayFile,
+}
+
+impl<'a> ExportFile<'a> {
+    fn new(file: &'a DisplayFile) -> Self {
+        let id = format!(
+            "file-{:016x}",
+            fnv1a(file.path.to_string_lossy().as_bytes())
+        );
+        let mut seen: HashMap<u64, usize> = HashMap::new();
+        let hunk_ids = baseline::fingerprints(file)
+            .iter()
+            .map(|lines| {
+                let text: String = lines
+                    .iter()
+                    .flat_map(|line| line.chars().filter(|c| !c.is_whitespace()).chain(['\n']))
+                    .collect();
+                let hash = fnv1a(text.as_bytes());
+                let count = seen.entry(hash).or_default();
+                *count += 1;
+                match *count {
+                    1 => format!("{id}-{hash:016x}"),
+                    n => format!("{id}-{hash:016x}-{n}"),
+                }
+            })
+            .collect();
+        Self { id, hunk_ids, file }
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher is guaranteed to give the same
+/// hash across Rust versions and builds.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+impl<'a> Export<'a> {
+    /// Exports `diff` of `range`, computed with `vcs`.
+    pub fn new(range: &'a str, vcs: &'a str, diff: &'a DiffResult) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            range,
+            vcs,
+            old_commit: diff.old_commit.as_deref(),
+            new_commit: diff.new_commit.as_deref(),
+            preview: diff.preview,
+            budget_exceeded: diff.budget_exceeded,
+            hidden_files: diff.hidden_files,
+            complexity: complexity::measure(&diff.files),
+            files: diff.files.iter().map(ExportFile::new).collect(),
+        }
+    }
+
+    /// Writes the export to `path`, creating parent directories if needed.
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+        }
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, json + "\n")
+            .map_err(|e| format!("Failed to write export {}: {e}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difftastic::{DifftFile, Status};
+
+    #[test]
+    fn export_wraps_files_with_schema_version() {
+        let file = DifftFile {
+            path: "src/lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Created,
+            aligned_lines: Vec::new(),
+            chunks: Vec::new(),
+        };
+        let file = crate::processor::process_contents(
+            file,
+            None,
+            Some(b"fn main() {}\n"),
+            None,
+            &Default::default(),
+        );
+        let diff = DiffResult {
+            files: vec![file],
+            preview: false,
+            budget_exceeded: false,
+            hidden_files: 0,
+            parents: Vec::new(),
+            old_commit: None,
+            new_commit: Some("abc".to_string()),
+            warning: None,
+            highlight_memo: crate::memo::Stats::default(),
+        };
+        let json = serde_json::to_value(Export::new("HEAD", "git", &diff)).unwrap();
+
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["range"], "HEAD");
+        assert_eq!(json["old_commit"], serde_json::Value::Null);
+        assert_eq!(json["new_commit"], "abc");
+        assert_eq!(json["complexity"]["additions"], 1);
+        assert_eq!(json["files"][0]["status"], "created");
+        assert_eq!(
+            json["files"][0]["rows"][0]["right"]["highlights"][0]["end"],
+            -1
+        );
+        let id = json["files"][0]["id"].as_str().unwrap();
+        assert_eq!(id, "file-2fba4152af2c3a3a");
+        assert_eq!(
+            json["files"][0]["hunk_ids"][0].as_str().unwrap(),
+            format!("{id}-{:016x}", fnv1a(b"+fnmain(){}\n"))
+        );
+        // Files read back as the processed entries they were exported from
+        let files: Vec<DisplayFile> = serde_json::from_value(json["files"].clone()).unwrap();
+        assert_eq!(files, diff.files);
+    }
+}
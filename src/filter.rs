@@ -0,0 +1,97 @@
+//! Include and exclude globs selecting the files of a diff.
+//!
+//! Lockfiles and generated code often dominate a diff without needing review. Files
+//! matching `opts.exclude` (or missing `opts.include`) are dropped right after parsing
+//! difftastic's output, before their contents are fetched or processed, and the result
+//! counts them in `hidden_files`.
+//!
+//! Globs use `*` for any characters but `/`, `**` for any characters including `/`, and
+//! `?` for one character. Like in `.gitignore`, globs without a `/` match the file name at
+//! any depth (`*.lock`), while others match the whole repository-relative path (`dist/**`).
+
+use mlua::prelude::*;
+use std::path::Path;
+
+/// Globs a file must match (`include`, if any) and must not match (`exclude`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct PathFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl PathFilter {
+    /// Whether `path`, relative to the repository root, is kept.
+    #[must_use]
+    pub fn keeps(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        let matches = |globs: &[String]| globs.iter().any(|glob| matches(glob, &path));
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+
+    /// Reads `include` and `exclude` from a `run_diff` options table.
+    pub fn from_table(table: &LuaTable) -> LuaResult<Self> {
+        Ok(Self {
+            include: table.get::<Option<_>>("include")?.unwrap_or_default(),
+            exclude: table.get::<Option<_>>("exclude")?.unwrap_or_default(),
+        })
+    }
+}
+
+/// Whether `path` matches `glob`, by file name for globs without a `/`.
+pub fn matches(glob: &str, path: &str) -> bool {
+    let glob = glob.trim_start_matches('/');
+    if glob.contains('/') {
+        return matches_from(glob.as_bytes(), path.as_bytes());
+    }
+    let name = path.rsplit('/').next().unwrap_or(path);
+    matches_from(glob.as_bytes(), name.as_bytes())
+}
+
+fn matches_from(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `dir/**/x` also matches `dir/x`
+            let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+            (0..=path.len()).any(|i| matches_from(rest, &path[i..]))
+        }
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != b'/')
+            .any(|i| matches_from(rest, &path[i..])),
+        [b'?', rest @ ..] => {
+            matches!(path, [c, path @ ..] if *c != b'/' && matches_from(rest, path))
+        }
+        [c, rest @ ..] => matches!(path, [p, path @ ..] if p == c && matches_from(rest, path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_names_or_paths() {
+        assert!(matches("*.lock", "Cargo.lock"));
+        assert!(matches("*.lock", "web/yarn.lock"));
+        assert!(!matches("*.lock", "lock.rs"));
+        assert!(matches("dist/**", "dist/app.js"));
+        assert!(matches("dist/**", "dist/js/app.js"));
+        assert!(!matches("dist/**", "src/dist/app.js"));
+        assert!(matches("src/**/*.rs", "src/lib.rs"));
+        assert!(matches("src/**/*.rs", "src/a/b/mod.rs"));
+        assert!(!matches("src/*.rs", "src/a/mod.rs"));
+        assert!(matches("/docs/?.md", "docs/a.md"));
+    }
+
+    #[test]
+    fn filter_applies_include_then_exclude() {
+        let filter = PathFilter {
+            include: vec!["src/**".to_string()],
+            exclude: vec!["*.snap".to_string()],
+        };
+        assert!(filter.keeps(Path::new("src/lib.rs")));
+        assert!(!filter.keeps(Path::new("src/snapshots/a.snap")));
+        assert!(!filter.keeps(Path::new("README.md")));
+        assert!(PathFilter::default().keeps(Path::new("README.md")));
+    }
+}
@@ -0,0 +1,182 @@
+//! Recording and replaying diff fixtures.
+//!
+//! A fixture is a directory capturing everything the processor consumes for one diff:
+//! difftastic's raw JSON output, the old and new contents of each file, and the VCS line
+//! stats. Replaying a fixture runs the same processing without a repository or any
+//! subprocess, so alignment and highlight bugs reported by users can be turned into
+//! regression tests by dropping the recorded directory into `tests/fixtures/`.
+//!
+//! ## Layout
+//!
+//! - `difftastic.json` - Raw difftastic output, exactly as printed by `git diff`/`jj diff`
+//! - `contents.json` - Old/new contents and stats for each file path
+//! - `expected.json` - Processed output at recording time, compared against on replay
+//!
+//! Set `DIFFT_UPDATE_FIXTURES=1` when running the Rust tests to rewrite `expected.json`
+//! after an intentional change to the processed output.
+
+use crate::difftastic;
+use crate::processor::{self, DisplayFile, ProcessOptions};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const DIFFTASTIC_FILE: &str = "difftastic.json";
+const CONTENTS_FILE: &str = "contents.json";
+const EXPECTED_FILE: &str = "expected.json";
+
+/// Inputs fetched from the VCS for one file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileContents {
+    pub path: PathBuf,
+
+    /// Old content, `None` if the file didn't exist.
+    pub old: Option<String>,
+
+    /// New content, `None` if the file doesn't exist anymore.
+    pub new: Option<String>,
+
+    /// VCS line stats: `(additions, deletions)`.
+    pub stats: Option<(u32, u32)>,
+}
+
+/// All inputs of a diff, replayable without a repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixture {
+    /// Raw difftastic JSON output.
+    pub json: String,
+
+    pub contents: Vec<FileContents>,
+}
+
+/// Result of replaying a fixture.
+#[derive(Debug)]
+pub struct Replay {
+    pub files: Vec<DisplayFile>,
+
+    /// Whether the output equals the recorded `expected.json`.
+    pub matches: bool,
+}
+
+impl Fixture {
+    /// Runs the fixture's inputs through the processor.
+    pub fn process(&self) -> Result<Vec<DisplayFile>, String> {
+        let contents: HashMap<&Path, &FileContents> = self
+            .contents
+            .iter()
+            .map(|c| (c.path.as_path(), c))
+            .collect();
+        let files = difftastic::parse(&self.json)
+            .map_err(|e| format!("Failed to parse difftastic JSON: {e}"))?;
+
+        Ok(files
+            .into_iter()
+            .map(|file| {
+                let inputs = contents.get(file.path.as_path());
+                let stats = inputs.and_then(|c| c.stats);
+                processor::process_contents(
+                    file,
+                    inputs.and_then(|c| c.old.as_deref()).map(str::as_bytes),
+                    inputs.and_then(|c| c.new.as_deref()).map(str::as_bytes),
+                    stats,
+                    &ProcessOptions::default(),
+                )
+            })
+            .collect())
+    }
+
+    /// Reads a fixture directory.
+    pub fn read(dir: &Path) -> Result<Self, String> {
+        let json = read_file(&dir.join(DIFFTASTIC_FILE))?;
+        let contents = serde_json::from_str(&read_file(&dir.join(CONTENTS_FILE))?)
+            .map_err(|e| format!("Invalid {CONTENTS_FILE} in {}: {e}", dir.display()))?;
+        Ok(Self { json, contents })
+    }
+
+    /// Writes the fixture and its processed `output` to `dir`, creating it if needed.
+    pub fn write(&self, dir: &Path, output: &[DisplayFile]) -> Result<(), String> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+        write_file(&dir.join(DIFFTASTIC_FILE), &self.json)?;
+        write_file(&dir.join(CONTENTS_FILE), &to_pretty_json(&self.contents)?)?;
+        write_file(&dir.join(EXPECTED_FILE), &to_pretty_json(output)?)
+    }
+}
+
+/// Replays the fixture in `dir` and compares the output against `expected.json`.
+pub fn replay(dir: &Path) -> Result<Replay, String> {
+    let files = Fixture::read(dir)?.process()?;
+    let expected: serde_json::Value =
+        serde_json::from_str(&read_file(&dir.join(EXPECTED_FILE))?)
+            .map_err(|e| format!("Invalid {EXPECTED_FILE} in {}: {e}", dir.display()))?;
+    let actual = serde_json::to_value(&files).map_err(|e| e.to_string())?;
+    Ok(Replay {
+        files,
+        matches: actual == expected,
+    })
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))
+}
+
+fn write_file(path: &Path, content: &str) -> Result<(), String> {
+    std::fs::write(path, content).map_err(|e| format!("Failed to write {}: {e}", path.display()))
+}
+
+fn to_pretty_json<T: Serialize + ?Sized>(value: &T) -> Result<String, String> {
+    serde_json::to_string_pretty(value)
+        .map(|json| json + "\n")
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_then_replay_round_trips() {
+        let fixture = Fixture {
+            json: r#"{"path":"a.txt","language":"Text","status":"created","chunks":[]}"#.into(),
+            contents: vec![FileContents {
+                path: "a.txt".into(),
+                old: None,
+                new: Some("one\ntwo\n".into()),
+                stats: Some((2, 0)),
+            }],
+        };
+        let dir = std::env::temp_dir().join(format!("difft-nvim-fixture-{}", std::process::id()));
+        let output = fixture.process().unwrap();
+        fixture.write(&dir, &output).unwrap();
+
+        assert_eq!(Fixture::read(&dir).unwrap(), fixture);
+        let replay = replay(&dir).unwrap();
+        assert!(replay.matches);
+        assert_eq!(replay.files, output);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Replays every fixture under `tests/fixtures`, failing on any output change.
+    #[test]
+    fn recorded_fixtures_replay_unchanged() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let Ok(entries) = std::fs::read_dir(&root) else {
+            return;
+        };
+        let update = std::env::var_os("DIFFT_UPDATE_FIXTURES").is_some();
+
+        for dir in entries.map(|e| e.unwrap().path()).filter(|p| p.is_dir()) {
+            if update {
+                let fixture = Fixture::read(&dir).unwrap();
+                fixture.write(&dir, &fixture.process().unwrap()).unwrap();
+                continue;
+            }
+            let replay = replay(&dir).unwrap();
+            assert!(
+                replay.matches,
+                "fixture {} no longer matches expected.json (set DIFFT_UPDATE_FIXTURES=1 to update)",
+                dir.display()
+            );
+        }
+    }
+}
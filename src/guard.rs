@@ -0,0 +1,178 @@
+//! Catching panics before they reach Lua.
+//!
+//! A panic unwinding out of a Lua callback would take Neovim down with it, so every exported
+//! function runs through [`function`] (methods of returned objects through [`call`], and
+//! diffs on background threads through [`catch`]), which turns a panic into a Lua error.
+//! The error names the function, the panic message and location, the file being diffed
+//! when it happened, if any, and a backtrace.
+//!
+//! The location and backtrace are only known while panicking, so a panic hook records them,
+//! along with the file selected for the panicking thread with [`with_file`]. The hook
+//! replaces Rust's default one, which would print over Neovim's screen.
+
+use mlua::MaybeSend;
+use mlua::prelude::*;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe, PanicHookInfo};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once, PoisonError};
+
+thread_local! {
+    static FILE: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// Recent panics on any thread, most recent last. A panic on a worker thread only reaches
+/// the caller once its scope ends, when other threads may have panicked too, so [`call`]
+/// picks the latest one with the message it caught.
+static PANICS: Mutex<Vec<Panic>> = Mutex::new(Vec::new());
+
+/// Panics kept in [`PANICS`]; most are caught on the spot, e.g. per file while processing.
+const MAX_PANICS: usize = 16;
+
+static INSTALL_HOOK: Once = Once::new();
+
+/// What the panic hook captured about a panic.
+#[derive(Debug)]
+struct Panic {
+    message: String,
+    location: String,
+    file: Option<PathBuf>,
+    backtrace: String,
+}
+
+/// Runs `f` with `path` recorded as the file being diffed on the current thread, so panics
+/// report it.
+pub fn with_file<T>(path: &Path, f: impl FnOnce() -> T) -> T {
+    /// Restores the previous file even when `f` panics, as threads are reused.
+    struct Restore(Option<PathBuf>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            FILE.with(|file| *file.borrow_mut() = self.0.take());
+        }
+    }
+    let _restore = Restore(FILE.with(|file| file.replace(Some(path.to_path_buf()))));
+    f()
+}
+
+/// Creates a Lua function calling `f` through [`call`].
+pub fn function<F, A, R>(lua: &Lua, name: &'static str, f: F) -> LuaResult<LuaFunction>
+where
+    F: Fn(&Lua, A) -> LuaResult<R> + MaybeSend + 'static,
+    A: FromLuaMulti,
+    R: IntoLuaMulti,
+{
+    lua.create_function(move |lua, args: A| call(name, || f(lua, args)))
+}
+
+/// Runs `f`, the body of the Lua function `name`, turning a panic into an error.
+pub fn call<T>(name: &str, f: impl FnOnce() -> LuaResult<T>) -> LuaResult<T> {
+    catch(name, f).unwrap_or_else(|report| Err(LuaError::RuntimeError(report)))
+}
+
+/// Runs `f` on behalf of the Lua function `name`, returning the report of its panic if it
+/// panics, e.g. for work finished on another thread.
+pub fn catch<T>(name: &str, f: impl FnOnce() -> T) -> Result<T, String> {
+    INSTALL_HOOK.call_once(|| {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            record(info);
+            // The test harness captures the default output
+            if cfg!(test) {
+                default(info);
+            }
+        }));
+    });
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| (*message).to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        let mut panics = PANICS.lock().unwrap_or_else(PoisonError::into_inner);
+        let captured = panics
+            .iter()
+            .rposition(|panic| panic.message == message)
+            .map(|i| panics.remove(i));
+        report(name, &message, captured)
+    })
+}
+
+/// The panic hook, recording the panic for [`call`].
+fn record(info: &PanicHookInfo) {
+    let panic = Panic {
+        message: info.payload_as_str().unwrap_or("unknown panic").to_string(),
+        location: info
+            .location()
+            .map_or_else(String::new, ToString::to_string),
+        file: FILE.with(|file| file.borrow().clone()),
+        backtrace: Backtrace::force_capture().to_string(),
+    };
+    let mut panics = PANICS.lock().unwrap_or_else(PoisonError::into_inner);
+    if panics.len() == MAX_PANICS {
+        panics.remove(0);
+    }
+    panics.push(panic);
+}
+
+/// The error message of a panic in `name`, e.g. `"difftastic-nvim: run_diff panicked at
+/// src/processor.rs:10:5 while diffing src/lib.rs: index out of bounds"`, followed by the
+/// backtrace.
+fn report(name: &str, message: &str, panic: Option<Panic>) -> String {
+    let mut report = format!("difftastic-nvim: {name} panicked");
+    if let Some(panic) = &panic {
+        if !panic.location.is_empty() {
+            report += &format!(" at {}", panic.location);
+        }
+        if let Some(file) = &panic.file {
+            report += &format!(" while diffing {}", file.display());
+        }
+    }
+    report += &format!(": {message}");
+    if let Some(panic) = panic {
+        report += &format!("\n\nstack backtrace:\n{}", panic.backtrace);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_become_errors_naming_the_function_and_file() {
+        let result: LuaResult<()> = call("run_diff", || {
+            with_file(Path::new("src/lib.rs"), || {
+                panic!("index {} out of bounds", 3)
+            })
+        });
+        let Err(LuaError::RuntimeError(message)) = result else {
+            panic!("expected a runtime error, got {result:?}");
+        };
+        let first_line = message.lines().next().unwrap();
+        assert!(first_line.starts_with("difftastic-nvim: run_diff panicked at src/guard.rs:"));
+        assert!(first_line.ends_with(" while diffing src/lib.rs: index 3 out of bounds"));
+        assert!(message.contains("stack backtrace:"));
+        // The file doesn't stick to the thread after unwinding
+        assert_eq!(FILE.with(|file| file.borrow().clone()), None);
+
+        assert_eq!(call("run_diff", || Ok(1)).unwrap(), 1);
+    }
+
+    #[test]
+    fn panics_on_worker_threads_are_reported_by_the_caller() {
+        let result: LuaResult<()> = call("run_diffs", || {
+            std::thread::scope(|scope| {
+                scope
+                    .spawn(|| with_file(Path::new("a.rs"), || panic!("worker failed")))
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic))
+            });
+            Ok(())
+        });
+        let Err(LuaError::RuntimeError(message)) = result else {
+            panic!("expected a runtime error, got {result:?}");
+        };
+        assert!(message.contains(" while diffing a.rs: worker failed"));
+    }
+}
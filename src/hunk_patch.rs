@@ -0,0 +1,358 @@
+//! Unified diff text of single hunks, the foundation for staging and reverting hunks from
+//! the viewer with `git apply`.
+//!
+//! The processed rows say which old and new lines a hunk changes and which unchanged lines
+//! surround it, but their content may have been rewritten for display (tabs expanded,
+//! carriage returns dropped). The patch lines are therefore taken from the original
+//! contents, by the line numbers of the rows, so the patch applies to the file as stored.
+
+use crate::difftastic::Status;
+use crate::processor::{DisplayFile, RowChange};
+use mlua::prelude::*;
+
+/// Context lines around a hunk, as in `git diff`.
+pub const DEFAULT_CONTEXT: usize = 3;
+
+/// The parts of a processed file a hunk patch is built from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchFile {
+    /// Path before the change, `None` for created files.
+    pub old_path: Option<String>,
+    /// Path after the change, `None` for deleted files.
+    pub new_path: Option<String>,
+    pub rows: Vec<PatchRow>,
+    pub hunks: Vec<PatchRange>,
+}
+
+/// What a patch needs to know of a row.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PatchRow {
+    /// 1-indexed old and new line the row shows. For gap rows, the last line they fold.
+    pub old_line: Option<u32>,
+    pub new_line: Option<u32>,
+    /// Whether the row's text changed (see [`RowChange`]).
+    pub changed: bool,
+    /// Whether the row is a gap row standing in for folded rows.
+    pub gap: bool,
+}
+
+/// The rows of a hunk (0-indexed, inclusive) and the header shown after its `@@` line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchRange {
+    pub start: usize,
+    pub end: usize,
+    pub header: Option<String>,
+}
+
+impl From<&DisplayFile> for PatchFile {
+    fn from(file: &DisplayFile) -> Self {
+        let path = |path: &std::path::PathBuf| path.to_string_lossy().into_owned();
+        let rows = file
+            .rows
+            .iter()
+            .enumerate()
+            .map(
+                |(i, row)| match file.folds.iter().find(|f| f.row as usize == i) {
+                    Some(fold) => PatchRow {
+                        old_line: fold.lines.old_end,
+                        new_line: fold.lines.new_end,
+                        changed: false,
+                        gap: true,
+                    },
+                    None => PatchRow {
+                        old_line: row.left.line_number,
+                        new_line: row.right.line_number,
+                        changed: matches!(
+                            row.change,
+                            RowChange::Line | RowChange::Intraline | RowChange::Eol
+                        ),
+                        gap: false,
+                    },
+                },
+            )
+            .collect();
+        Self {
+            old_path: (file.status != Status::Created)
+                .then(|| path(file.old_path.as_ref().unwrap_or(&file.path))),
+            new_path: (file.status != Status::Deleted).then(|| path(&file.path)),
+            rows,
+            hunks: file
+                .hunks
+                .iter()
+                .map(|hunk| PatchRange {
+                    start: hunk.start as usize,
+                    end: hunk.end as usize,
+                    header: hunk.header.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Reads the file table `run_diff` returns.
+impl FromLua for PatchFile {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(table) = value else {
+            return Err(LuaError::RuntimeError(format!(
+                "file must be a table, got {}",
+                value.type_name()
+            )));
+        };
+        let status: String = table.get("status")?;
+        let mut rows = Vec::new();
+        for row in table.get::<LuaTable>("rows")?.sequence_values::<LuaTable>() {
+            let row = row?;
+            let change: String = row.get("change")?;
+            rows.push(PatchRow {
+                old_line: row.get("lhs_line_number")?,
+                new_line: row.get("rhs_line_number")?,
+                changed: matches!(change.as_str(), "line" | "intraline" | "eol"),
+                gap: false,
+            });
+        }
+        let folds: Option<LuaTable> = table.get("folds")?;
+        for fold in folds
+            .iter()
+            .flat_map(|folds| folds.sequence_values::<LuaTable>())
+        {
+            let fold = fold?;
+            if let Some(row) = rows.get_mut(fold.get::<usize>("row")?) {
+                *row = PatchRow {
+                    old_line: fold.get("old_end")?,
+                    new_line: fold.get("new_end")?,
+                    changed: false,
+                    gap: true,
+                };
+            }
+        }
+        let mut hunks = Vec::new();
+        for hunk in table
+            .get::<LuaTable>("hunks")?
+            .sequence_values::<LuaTable>()
+        {
+            let hunk = hunk?;
+            hunks.push(PatchRange {
+                start: hunk.get("start")?,
+                end: hunk.get("end")?,
+                header: hunk.get("header")?,
+            });
+        }
+        Ok(Self {
+            old_path: (status != "created")
+                .then(|| table.get("old_path"))
+                .transpose()?,
+            new_path: (status != "deleted")
+                .then(|| table.get("new_path"))
+                .transpose()?,
+            rows,
+            hunks,
+        })
+    }
+}
+
+/// Builds a unified diff of hunk `index` (0-indexed) of `file` alone, with up to `context`
+/// unchanged lines around it, that applies to `old` with `git apply`.
+///
+/// `old` and `new` are the original contents, `None` for a side the file doesn't exist on.
+/// The `@@` line numbers are those of the two versions, as in `git diff`, and the hunk's
+/// header follows the `@@` line when it has one.
+pub fn hunk_patch<'a>(
+    file: &PatchFile,
+    index: usize,
+    old: Option<&'a str>,
+    new: Option<&'a str>,
+    context: usize,
+) -> Result<String, String> {
+    let name = || {
+        file.new_path
+            .as_deref()
+            .or(file.old_path.as_deref())
+            .unwrap_or_default()
+    };
+    let hunk = file.hunks.get(index).ok_or_else(|| {
+        format!(
+            "No hunk {} in {}: it has {} hunk(s)",
+            index + 1,
+            name(),
+            file.hunks.len()
+        )
+    })?;
+    if hunk.start > hunk.end || hunk.end >= file.rows.len() {
+        return Err(format!(
+            "Hunk {} of {} is out of its rows",
+            index + 1,
+            name()
+        ));
+    }
+
+    let is_context = |row: &&PatchRow| {
+        !row.changed && !row.gap && row.old_line.is_some() && row.new_line.is_some()
+    };
+    let before = file.rows[..hunk.start]
+        .iter()
+        .rev()
+        .take(context)
+        .take_while(is_context)
+        .count();
+    let after = file.rows[hunk.end + 1..]
+        .iter()
+        .take(context)
+        .take_while(is_context)
+        .count();
+    let first = hunk.start - before;
+    let rows = &file.rows[first..=hunk.end + after];
+
+    let old_lines: Vec<&str> = old.unwrap_or_default().split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.unwrap_or_default().split_inclusive('\n').collect();
+    let line = |lines: &[&'a str], ln: u32, side: &str| -> Result<&'a str, String> {
+        let text = (ln as usize).checked_sub(1).and_then(|i| lines.get(i));
+        text.copied().ok_or_else(|| {
+            format!(
+                "Line {ln} is past the end of the {side} version of {}",
+                name()
+            )
+        })
+    };
+
+    let mut body: Vec<(char, &str)> = Vec::new();
+    let mut added = Vec::new();
+    for row in rows {
+        if row.changed {
+            if let Some(ln) = row.old_line {
+                body.push(('-', line(&old_lines, ln, "old")?));
+            }
+            if let Some(ln) = row.new_line {
+                added.push(('+', line(&new_lines, ln, "new")?));
+            }
+            continue;
+        }
+        body.append(&mut added);
+        // Rows without text, such as notices, are left out
+        if let (Some(old_ln), Some(new_ln)) = (row.old_line, row.new_line) {
+            let old_text = line(&old_lines, old_ln, "old")?;
+            let new_text = line(&new_lines, new_ln, "new")?;
+            // A context line has to end the same way in both versions
+            if old_text.ends_with('\n') == new_text.ends_with('\n') {
+                body.push((' ', old_text));
+            } else {
+                body.push(('-', old_text));
+                added.push(('+', new_text));
+            }
+        }
+    }
+    body.append(&mut added);
+
+    let old_count = body.iter().filter(|(origin, _)| *origin != '+').count();
+    let new_count = body.iter().filter(|(origin, _)| *origin != '-').count();
+    // Where each side starts: its first line in the patch, or the line the hunk's lines are
+    // inserted after when it has none
+    let start = |line_of: fn(&PatchRow) -> Option<u32>, count: usize| match rows
+        .iter()
+        .find_map(line_of)
+        .filter(|_| count > 0)
+    {
+        Some(ln) => ln,
+        None => file.rows[..first]
+            .iter()
+            .rev()
+            .find_map(line_of)
+            .unwrap_or(0),
+    };
+    let old_start = start(|row| row.old_line, old_count);
+    let new_start = start(|row| row.new_line, new_count);
+
+    let path = |path: Option<&str>, prefix: &str| {
+        path.map_or("/dev/null".to_string(), |path| format!("{prefix}/{path}"))
+    };
+    let mut patch = format!(
+        "--- {}\n+++ {}\n@@ -{old_start},{old_count} +{new_start},{new_count} @@",
+        path(file.old_path.as_deref(), "a"),
+        path(file.new_path.as_deref(), "b"),
+    );
+    if let Some(header) = &hunk.header {
+        patch.push(' ');
+        patch.push_str(header);
+    }
+    patch.push('\n');
+    for (origin, text) in body {
+        patch.push(origin);
+        patch.push_str(text);
+        if !text.ends_with('\n') {
+            patch.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+    Ok(patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(old_line: Option<u32>, new_line: Option<u32>, changed: bool) -> PatchRow {
+        PatchRow {
+            old_line,
+            new_line,
+            changed,
+            gap: false,
+        }
+    }
+
+    fn file(rows: Vec<PatchRow>, hunks: Vec<PatchRange>) -> PatchFile {
+        PatchFile {
+            old_path: Some("f.rs".to_string()),
+            new_path: Some("f.rs".to_string()),
+            rows,
+            hunks,
+        }
+    }
+
+    #[test]
+    fn patches_hunk_with_context_and_header() {
+        let old = "a\nb\nc\nd\ne\nf\n";
+        let new = "a\nb\nC\nd\ne\nf\n";
+        let rows = (1..=6).map(|ln| row(Some(ln), Some(ln), ln == 3)).collect();
+        let hunk = PatchRange {
+            start: 2,
+            end: 2,
+            header: Some("fn main() {".to_string()),
+        };
+        let file = file(rows, vec![hunk]);
+
+        assert_eq!(
+            hunk_patch(&file, 0, Some(old), Some(new), 2).unwrap(),
+            "--- a/f.rs\n+++ b/f.rs\n@@ -1,5 +1,5 @@ fn main() {\n a\n b\n-c\n+C\n d\n e\n"
+        );
+        assert!(hunk_patch(&file, 1, Some(old), Some(new), 2).is_err());
+    }
+
+    #[test]
+    fn patches_insertions_after_folds_and_missing_newlines() {
+        let old = "a\nb\nc";
+        let new = "a\nb\nc\nd\n";
+        let gap = PatchRow {
+            old_line: Some(2),
+            new_line: Some(2),
+            changed: false,
+            gap: true,
+        };
+        let rows = vec![gap, row(Some(3), Some(3), false), row(None, Some(4), true)];
+        let hunk = PatchRange {
+            start: 2,
+            end: 2,
+            header: None,
+        };
+        let file = file(rows, vec![hunk]);
+
+        // Context stops at the fold, and the last old line gains a line break, so it
+        // can't be context either
+        assert_eq!(
+            hunk_patch(&file, 0, Some(old), Some(new), DEFAULT_CONTEXT).unwrap(),
+            "--- a/f.rs\n+++ b/f.rs\n@@ -3,1 +3,2 @@\n-c\n\\ No newline at end of file\n+c\n+d\n"
+        );
+        // Without context, the insertion is placed after the last old line
+        assert_eq!(
+            hunk_patch(&file, 0, Some(old), Some(new), 0).unwrap(),
+            "--- a/f.rs\n+++ b/f.rs\n@@ -3,0 +4,1 @@\n+d\n"
+        );
+    }
+}
@@ -4,14 +4,49 @@
 //!
 //! This crate provides Lua bindings for parsing [difftastic](https://difftastic.wilfred.me.uk/)
 //! JSON output and processing it into a display-ready format. It supports both
-//! [jj](https://github.com/martinvonz/jj) and [git](https://git-scm.com/) version control systems.
+//! [jj](https://github.com/martinvonz/jj), [git](https://git-scm.com/),
+//! [Mercurial](https://www.mercurial-scm.org/) and [Sapling](https://sapling-scm.com/)
+//! version control systems.
 //!
 //! ## Architecture
 //!
-//! The crate is organized into three modules:
+//! The crate is organized into the following modules:
 //!
 //! - `difftastic` - Types and parsing for difftastic's JSON output format
 //! - `processor` - Transforms parsed data into aligned side-by-side display rows
+//! - `encoding` - Decoding contents that aren't UTF-8 and remapping difftastic's offsets
+//! - `export` - Versioned JSON export of processed diffs for external tools
+//! - `outline` - Heuristic detection of definitions and imports for hunk labels
+//! - `syntax` - Embedded lexical highlighter for full-line syntax spans
+//! - `vcs` - The `VcsBackend` trait, built-in backend registry and user-defined backends
+//! - `timestamp` - Relative formatting of commit timestamps
+//! - `complexity` - Size metrics and estimated review time of a diff
+//! - `filter` - Include and exclude globs selecting the files of a diff
+//! - `repo` - Selecting the repository VCS commands run in, for multi-repo diffs
+//! - `repo_state` - Detection of in-progress rebases, merges and conflicts
+//! - `bisect` - Detection of an in-progress bisect and its bounds
+//! - `divergence` - Detection of divergent and hidden jj changes a revset resolves to
+//! - `stack` - Per-change stats of a stack of jj changes and the files they share
+//! - `fixture` - Recording and replaying diff inputs for regression tests
+//! - `patch` - Applying several hunks of one file in a single pass
+//! - `hunk_patch` - Unified diff text of single hunks, for staging them with `git apply`
+//! - `stage` - Staging hunks and files and restoring files from the viewer
+//! - `unified_diff` - Parsing patch files and rebuilding the files they change
+//! - `prefetch` - Background preparation and caching of range diffs
+//! - `cache` - Processed diffs kept in memory for instant re-opening
+//! - `disk_cache` - Processed diffs of commits saved across Neovim restarts
+//! - `dry_run` - Recording the commands a diff would run, for debugging
+//! - `temp` - Temporary files for diffing strings, cleaned up on drop and exit
+//! - `memo` - Memoized highlights of changed lines repeated across a diff
+//! - `object_db` - In-process revision and blob lookups through gix (`gix` feature)
+//! - `wrapper` - Running commands through SSH or a container, for remote repositories
+//! - `tool` - The difftastic binary and extra arguments diffs run with
+//! - `limits` - Bounding the threads and concurrent commands of diffs
+//! - `provider` - Fetching file contents through a Lua callback, for remote repositories
+//! - `baseline` - Saved hunks of a diff, to re-review only what changed since
+//! - `review` - Reviewing a branch commit by commit, with stats of every commit up front
+//! - `series` - Reviewing a `format-patch` series or mbox patch by patch, like a mail thread
+//! - `guard` - Turning panics into Lua errors at every exported function
 //! - `lib` (this module) - Lua bindings and VCS integration
 //!
 //! ## Usage from Lua
@@ -27,6 +62,374 @@
 //!
 //! -- Get diff for a git commit range
 //! local result = difft.run_diff("main..feature", "git")
+//!
+//! -- Get diff for a stash entry, including files stashed with --include-untracked
+//! local result = difft.run_diff("stash@{0}", "git")
+//!
+//! -- Get diff for a Mercurial revision (via the bundled extdiff extension)
+//! local result = difft.run_diff(".", "hg")
+//!
+//! -- Get diff for a Sapling commit, run the same way through `sl`
+//! local result = difft.run_diff(".", "sl")
+//!
+//! -- Options table form: the VCS is detected from `cwd` (or Neovim's working directory)
+//! -- unless given
+//! local result = difft.run_diff("HEAD", { cwd = "/work/plugin", ignore_whitespace = true })
+//! local result = difft.run_diff("@-", { vcs = "jj", paths = { "src/lib.rs" } })
+//!
+//! -- Compute a diff on a background thread, polling for the result from the main loop
+//! local job = difft.run_diff_async("main..feature", "git")
+//! local timer = vim.uv.new_timer()
+//! timer:start(0, 10, vim.schedule_wrap(function()
+//!     local done, result, err = job:poll()
+//!     if done then
+//!         timer:close()
+//!     end
+//! end))
+//! -- What it's doing, e.g. { phase = "processing files", done = 12, total = 40 }
+//! local status = job:progress()
+//! -- Stop it when it's no longer needed, killing its jj/git/difft processes
+//! job:cancel()
+//!
+//! -- Stream files as they're ready: `take_files` first returns stats-only entries for every
+//! -- file, then each processed file as it finishes; the final result has no files
+//! local job = difft.run_diff_async("main..feature", { vcs = "git", stream = true })
+//! for _, update in ipairs(job:take_files()) do
+//!     files[update.index] = update.file
+//! end
+//!
+//! -- Working copy modes: "index" (index vs worktree), "staged" (HEAD vs index)
+//! -- or "head" (HEAD vs worktree)
+//! local result = difft.run_diff_mode("head", "git")
+//!
+//! -- Review a change spanning several repositories, keyed by repository root
+//! local results = difft.run_diff_repos({ "/work/plugin-a", "/work/plugin-b" }, "main..feature", "git")
+//!
+//! -- Several diffs in one call, keyed by id: the result of `run_diff` or { error = message }
+//! local results = difft.run_diffs({
+//!     { id = "unstaged", mode = "index", vcs = "git" },
+//!     { id = "staged", mode = "staged", vcs = "git" },
+//!     { id = "last", range = "HEAD", vcs = "git", opts = { preview = 20 } },
+//! })
+//! print(#results.staged.files)
+//!
+//! -- Review what a jj operation changed, one entry per rewritten or created change
+//! for _, change in ipairs(difft.run_op_diff("@", {})) do
+//!     print(change.change_id, change.description, #change.files)
+//! end
+//!
+//! -- Review how a jj change evolved, one diff per rewrite, oldest first
+//! for _, step in ipairs(difft.run_evolution_diff("xyz", {})) do
+//!     print(step.commit, step.relative_time, #step.files)
+//! end
+//!
+//! -- jj diffs of a divergent change or a hidden commit carry a warning listing the candidate
+//! -- commits; diff one of them by commit ID instead
+//! local result = difft.run_diff("xyz", "jj")
+//! if result.warning then
+//!     print(result.warning.kind, result.warning.message) -- "divergent" or "hidden"
+//!     result = difft.run_diff(result.warning.candidates[1].commit, "jj")
+//! end
+//! local candidates = difft.jj_resolve("xyz", {}) -- { { commit, change_id, divergent, hidden, ... } }
+//!
+//! -- Size of each change of a jj stack (`trunk()..@` by default), bottom up, and the files
+//! -- touched by several of them, e.g. to decide what to squash or split
+//! local stack = difft.stack_stats(nil, {})
+//! for _, overlap in ipairs(stack.overlaps) do
+//!     print(overlap.path, table.concat(overlap.changes, ", "))
+//! end
+//!
+//! -- Review a git branch commit by commit: first-parent commits oldest first, with their
+//! -- stats up front, and each commit's diff against its first parent loaded on demand
+//! local review = difft.branch_review("main..feature", "git")
+//! for i, commit in ipairs(review:commits()) do
+//!     print(i, commit.subject, commit.files, commit.additions, commit.deletions)
+//! end
+//! local result = review:load_commit_diff(1) -- run_diff result plus index, commit, subject
+//!
+//! -- Compare two releases, with the commits in between and changes grouped by directory
+//! local tags = difft.list_tags("git", {})
+//! local release = difft.release_diff(tags[2].name, tags[1].name, "git", {})
+//! for _, commit in ipairs(release.commits) do
+//!     print(commit.subject)
+//! end
+//!
+//! -- Wire up another VCS with command templates
+//! difft.register_vcs("fossil", {
+//!     diff = { "fossil", "diff", "--from", "{old}", "--to", "{rev}", "--command", "{difft}" },
+//!     show = { "fossil", "cat", "{path}", "-r", "{rev}" },
+//!     old_rev = "{rev}^",
+//! })
+//! local result = difft.run_diff("trunk", "fossil")
+//!
+//! -- Build a permalink to the first hunk of a file from the resolved commit and line range
+//! local result = difft.run_diff("HEAD", "git")
+//! local hunk = result.files[1].hunks[1]
+//! local url = string.format("%s/blob/%s/%s#L%d-L%d", repo_url, result.new_commit,
+//!     result.files[1].path, hunk.new_start, hunk.new_end)
+//!
+//! -- Each hunk spans rows `start` to `end` (0-indexed, inclusive), with its own stats
+//! print(hunk.start, hunk["end"], "+" .. hunk.additions, "-" .. hunk.deletions)
+//!
+//! -- `header` is the enclosing function's definition line, like git's `@@ ... @@ fn foo()`
+//! print(hunk.header) -- e.g. "pub fn run_diff(revset: &str) -> Result<Diff, String> {"
+//!
+//! -- Renamed files (detected by git) have status "renamed" and the path on each side
+//! for _, file in ipairs(result.files) do
+//!     if file.status == "renamed" then
+//!         print(file.old_path .. " -> " .. file.new_path)
+//!     end
+//! end
+//!
+//! -- Mode changes (e.g. a script becoming executable) get a notice row and `mode_change`
+//! for _, file in ipairs(result.files) do
+//!     if file.mode_change then
+//!         print(file.path, file.mode_change.old .. " -> " .. file.mode_change.new)
+//!     end
+//! end
+//!
+//! -- Submodules show their old and new commit (`submodule = { old, new, log }`); with
+//! -- `submodule_log`, `log` lists the commits in between as "> subject" or "< subject"
+//! local result = difft.run_diff("HEAD", "git", { submodule_log = true })
+//!
+//! -- Files that aren't UTF-8 are transcoded from the encoding set for their glob, or the
+//! -- detected one, reported as `old_encoding`/`new_encoding` (e.g. "Shift_JIS")
+//! local result = difft.run_diff("HEAD", "git", { encodings = { ["legacy/**"] = "latin1" } })
+//!
+//! -- Jump by difftastic's chunks, which may split or span the hunks
+//! for _, section in ipairs(result.files[1].sections) do
+//!     print(section.start, section["end"], section.old_start, section.new_start)
+//! end
+//!
+//! -- Diff a merge commit against its second parent
+//! local result = difft.run_diff("HEAD", "git", { parent = 2 })
+//!
+//! -- Include syntax spans for unchanged text on every row
+//! local result = difft.run_diff("HEAD", "git", { syntax_spans = true })
+//!
+//! -- Full-line highlights and a description of each changed row, for screen readers
+//! local result = difft.run_diff("HEAD", "git", { accessible = true })
+//!
+//! -- Removed lines grouped by the new line they were removed above, to show them as
+//! -- virtual lines in a buffer of the new version
+//! local result = difft.run_diff("HEAD", "git", { virtual_deletions = true })
+//! for _, deletion in ipairs(result.files[1].virtual_deletions or {}) do
+//!     print(deletion.new_line, #deletion.lines)
+//! end
+//!
+//! -- Preview a huge range: rows for the 20 most-changed files, stats for the rest
+//! local result = difft.run_diff("main..feature", "git", { preview = 20 })
+//! local file = difft.run_diff("main..feature", "git", { paths = { "src/lib.rs" } }).files[1]
+//!
+//! -- Size of a diff and its estimated review time, e.g. to warn before long reviews:
+//! -- { files, hunks, max_hunk_lines, additions, deletions, languages = { Rust = 3 }, review_minutes }
+//! local complexity = difft.run_diff("main..feature", "git").complexity
+//!
+//! -- How often identical changed lines reused memoized highlights, e.g. for a bulk rename
+//! -- { hits = 120, misses = 30, hit_rate = 0.8 }
+//! local memo = difft.run_diff("main..feature", "git").highlight_memo
+//!
+//! -- Binary files have no rows, but `binary = true` and their sizes in bytes
+//! for _, file in ipairs(difft.run_diff("HEAD", "git").files) do
+//!     if file.binary then print(file.path, file.old_size, file.new_size, file.size_delta) end
+//! end
+//!
+//! -- Leave out lockfiles and generated code; result.hidden_files counts them
+//! local result = difft.run_diff("HEAD", "git", { exclude = { "*.lock", "dist/**" } })
+//! local result = difft.run_diff("HEAD", "git", { include = { "src/**" } })
+//!
+//! -- Cap the rows of a diff: files past the budget only get stats (result.budget_exceeded)
+//! local result = difft.run_diff("main..feature", "git", { max_rows = 50000 })
+//!
+//! -- Keep `\r` and the empty line after a final line break, so rows can be written back
+//! -- exactly (each file also reports `old_line_endings` and `new_line_endings`)
+//! local result = difft.run_diff("HEAD", "git", { keep_cr = true, keep_final_empty_line = true })
+//!
+//! -- Rows whose line only switched between `\r\n` and `\n` are flagged either way
+//! for _, row in ipairs(result.files[1].rows) do
+//!     if row.change == "eol" then print("line ending changed") end
+//! end
+//!
+//! -- Highlight the identifiers of dense expression edits on their own: `foo.bar(x)` to
+//! -- `foo.baz(y)` highlights `baz` and `y` instead of `baz(y)`
+//! local result = difft.run_diff("HEAD", "git", { split_words = true })
+//!
+//! -- Narrow whole replaced lines down to the words that differ, like `git diff --word-diff`:
+//! -- `let total = sum(xs)` to `let amount = sum(ys)` highlights `total`/`xs`, `amount`/`ys`
+//! local result = difft.run_diff("HEAD", "git", { word_diff = true })
+//!
+//! -- Collapse partial highlights into a full-line one once 80% of a line's non-whitespace
+//! -- changed, instead of only when all of it did
+//! local result = difft.run_diff("HEAD", "git", { full_line_threshold = 0.8 })
+//!
+//! -- Expand tabs to spaces at 4 columns, with highlights moved to the expanded lines
+//! local result = difft.run_diff("HEAD", "git", { tab_width = 4 })
+//!
+//! -- Keep 3 unchanged rows around changes, folding longer runs into one `change = "gap"`
+//! -- row; `folds` has each gap's row (0-indexed), folded row count and old/new lines
+//! local result = difft.run_diff("HEAD", "git", { context = 3 })
+//! for _, fold in ipairs(result.files[1].folds) do
+//!     print(fold.row, fold.rows, fold.new_start, fold.new_end)
+//! end
+//!
+//! -- Also get each highlight's display cells, e.g. for virtual columns: `名前` is 6 bytes
+//! -- (start = 0, end = 6) but 4 cells (display_start = 0, display_end = 4)
+//! local result = difft.run_diff("HEAD", "git", { display_columns = true })
+//!
+//! -- Also get the rows as a single column, like `git diff`: each run of changed rows as its
+//! -- removed lines, then its added lines, with the row each line comes from (0-indexed)
+//! local result = difft.run_diff("HEAD", "git", { view = "unified" })
+//! for _, line in ipairs(result.files[1].unified) do
+//!     print(line.origin, line.row, line.content) -- "context", "removed", "added" or "notice"
+//! end
+//!
+//! -- Rows carry the 1-indexed line numbers they show, nil for fillers, e.g. for a gutter
+//! for _, row in ipairs(result.files[1].rows) do
+//!     print(row.lhs_line_number, row.rhs_line_number) -- also row.left.line_number, ...
+//! end
+//!
+//! -- Every highlight has `kind = "full"` or `"partial"`; full-line ones end at -1, or at the
+//! -- line's length in bytes with `line_length_ends`
+//! local result = difft.run_diff("HEAD", "git", { line_length_ends = true })
+//!
+//! -- Highlights also carry difftastic's syntax kind of their changes, e.g. to color changed
+//! -- strings differently from changed keywords
+//! for _, hl in ipairs(result.files[1].rows[1].right.highlights) do
+//!     print(hl.start, hl["end"], hl.syntax_kind) -- "keyword", "string", ... or nil
+//! end
+//!
+//! -- Label hunks that only rename one identifier, so mass renames can be skimmed
+//! local result = difft.run_diff("HEAD", "git", { symbol_renames = true })
+//! for _, hunk in ipairs(result.files[1].hunks) do
+//!     if hunk.rename_of then print(hunk.rename_of.old, "->", hunk.rename_of.new) end
+//! end
+//!
+//! -- Run every command in a container, mapping the local checkout to its path inside;
+//! -- use `{ command = { "ssh", "devbox", "--" }, quote = true }` for remote hosts
+//! difft.set_command_wrapper({
+//!     command = { "docker", "exec", "-i", "dev" },
+//!     paths = { ["/home/me/src"] = "/src" },
+//! })
+//! difft.set_command_wrapper(nil)
+//!
+//! -- Run another difftastic binary, with extra arguments for every diff
+//! difft.set_difft({ path = "/opt/difftastic/difft", args = { "--context", "5" } })
+//!
+//! -- Raise the limits past which difftastic falls back to a line-based diff, e.g. for large
+//! -- generated files (DFT_GRAPH_LIMIT, DFT_BYTE_LIMIT and DFT_PARSE_ERROR_LIMIT)
+//! difft.set_difft({ graph_limit = 10000000, byte_limit = 4000000, parse_error_limit = 10 })
+//!
+//! -- Parse files difftastic doesn't recognize as a given language (`--override`)
+//! difft.set_difft({ overrides = { ["*.tpl"] = "Go", ["Jenkinsfile"] = "Groovy" } })
+//!
+//! -- Process files on 4 threads and run at most 8 commands at once, killing commands that
+//! -- take over 30 seconds ("Command timed out after 30s: git ..."); nil fields are unlimited
+//! difft.set_limits({ max_threads = 4, max_procs = 8, timeout_ms = 30000 })
+//!
+//! -- Read file contents through Lua instead, e.g. from another Neovim over RPC; `rev` is
+//! -- nil for the index and working tree. Only used by diffs computed on the main thread
+//! difft.set_content_provider(function(path, rev, side, source)
+//!     return remote_read(rev, path)
+//! end)
+//! difft.set_content_provider(nil)
+//!
+//! -- List the commands a diff would run, with their working directory and environment,
+//! -- without running them: { commands = { { program, args, cwd, env }, ... } }
+//! local plan = difft.run_diff("main..feature", "git", { dry_run = true })
+//!
+//! -- Skip jj's working-copy snapshot even though the revset refers to @
+//! local result = difft.run_diff("@-", "jj", { ignore_working_copy = true })
+//!
+//! -- Prepare the next and previous commits in the background for instant navigation
+//! difft.prefetch({ "HEAD~2", "HEAD" }, "git")
+//!
+//! -- Re-opening a diff reuses its processed files while its commits and files stay the same;
+//! -- drop every cached and prefetched diff, e.g. after changing difftastic's settings
+//! difft.clear_cache()
+//!
+//! -- Also save diffs of commits on disk, so they're instant after restarting Neovim, and
+//! -- delete them again
+//! local dir = vim.fn.stdpath("cache") .. "/difftastic-nvim"
+//! local result = difft.run_diff("HEAD~10..HEAD", "git", { cache_dir = dir })
+//! difft.clear_cache(dir)
+//!
+//! -- Save the current state of a branch, then later show only hunks that appeared since
+//! difft.save_baseline("main..feature", "git", ".difft/feature.json", {})
+//! local result = difft.run_diff_since_baseline("main..feature", "git", ".difft/feature.json")
+//!
+//! -- Export the processed files (rows, highlights, hunks, stats) for external tools such as
+//! -- web viewers or review bots; the schema is documented in the `export` module. Files and
+//! -- hunks get ids derived from their content, so links to them survive re-exports
+//! difft.export_json("main..feature", "git", "review/diff.json")
+//!
+//! -- Compare two files outside of any repository
+//! local file = difft.diff_files("a/config.toml", "b/config.toml")
+//!
+//! -- Compare two directories, e.g. extracted archives
+//! local result = difft.diff_dirs("build-old", "build-new")
+//!
+//! -- Process difftastic JSON produced elsewhere, e.g. downloaded from CI, with the files'
+//! -- contents under artifact/old/<path> and artifact/new/<path>; JSON that isn't in a file
+//! -- is passed as { text = json }
+//! local result = difft.process_artifact("artifact/diff.json", "artifact", {})
+//!
+//! -- Review a patch received by email or from `git format-patch` (or { text = patch }). Files are
+//! -- rebuilt from the blobs or working tree the patch applies to; files only shown as their
+//! -- hunks, because the patch doesn't apply to anything available, are in `partial_files`
+//! local result = difft.import_patch("0001-fix-parser.patch", { cwd = "/work/plugin" })
+//!
+//! -- Review a series from `git format-patch` or an mbox thread patch by patch, each applied
+//! -- on top of the ones before it; the `[PATCH 0/N]` cover letter is kept aside
+//! local series = difft.patch_series("outgoing/", { cwd = "/work/plugin" })
+//! for i, patch in ipairs(series:patches()) do
+//!     print(i, patch.tag, patch.subject, patch.author, patch.additions, patch.deletions)
+//! end
+//! local cover = series:cover_letter() -- { subject, message, ... } or nil
+//! local result = series:load_patch_diff(2) -- import_patch result plus index and subject
+//!
+//! -- Compare two strings, e.g. an unsaved buffer against the file on disk
+//! local file = difft.diff_text(old_text, new_text, "Rust")
+//!
+//! -- Remove the temporary files of diffs still running, e.g. when Neovim exits
+//! difft.cleanup_temp_files()
+//!
+//! -- Compare two selected snippets in a floating window sized from the result
+//! local file = difft.diff_selections(selection_a, selection_b, "Rust")
+//! vim.api.nvim_open_win(buf, true, { relative = "editor", width = 2 * file.width + 3, height = file.height, row = 2, col = 2 })
+//!
+//! -- During a git or hg bisect: the candidate, its bounds, and a diff of the candidate
+//! -- against the last good commit
+//! local bisect = difft.bisect_state("git") -- { candidate, bad, good = {...}, last_good, range }
+//! local result = difft.run_diff_bisect("git", {})
+//!
+//! -- Three-way view (base, ours, theirs) of the files with merge conflicts
+//! local files = difft.run_conflict_diff("git")
+//!
+//! -- Apply several hunks to a file's text at once, tolerating shifted line numbers
+//! local text = difft.apply_hunks(text, {
+//!     { old_start = 3, old = { "a", "b" }, new = { "a", "c" } },
+//!     { old_start = 40, old = { "x" }, new = {} },
+//! })
+//!
+//! -- Unified diff of a file's second hunk, from its original contents, with 3 lines of
+//! -- context unless `context` is given
+//! local patch = difft.hunk_patch(file, 2, { old = old_text, new = new_text, context = 0 })
+//!
+//! -- Stage the second hunk of a file's unstaged changes, diffed with the same options the
+//! -- viewer used so hunks are numbered the same
+//! local result = difft.stage_hunk("src/main.rs", 2, opts)
+//! if not result.ok then
+//!     vim.notify(result.error, vim.log.levels.ERROR)
+//! end
+//!
+//! -- Stage a whole file, or restore it in the working copy (from the index with git and
+//! -- the parent of the working-copy commit with jj, unless a revision is given). Both
+//! -- return the file's stats afterwards, e.g. `result.unstaged.additions`
+//! local result = difft.stage_file("src/main.rs", opts)
+//! local result = difft.restore_file("src/main.rs", "HEAD", "git", opts)
+//!
+//! -- Process difftastic JSON and file contents directly, without a repository
+//! local file = difft.process_json(json, old_text, new_text)
 //! ```
 //!
 //! ## Environment Variables
@@ -35,15 +438,63 @@
 //!
 //! - `DFT_DISPLAY=json` - Enables JSON output mode
 //! - `DFT_UNSTABLE=yes` - Enables unstable features (required for JSON output)
+//! - `DFT_GRAPH_LIMIT`, `DFT_BYTE_LIMIT`, `DFT_PARSE_ERROR_LIMIT` - Only when set with
+//!   `set_difft`, to raise the limits past which difftastic falls back to a line-based diff
+//!
+//! For git, difftastic is passed via `GIT_EXTERNAL_DIFF` as an absolute path resolved from
+//! `PATH` (or the binary set with `set_difft`, followed by its extra arguments). The
+//! environment variable takes precedence over any `diff.external` the user has configured,
+//! so the plugin never ends up wrapped inside another external diff tool.
 
+use cancel::CommandExt;
 use mlua::prelude::*;
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+mod baseline;
+mod bisect;
+mod cache;
+mod cancel;
+mod complexity;
 mod difftastic;
+mod disk_cache;
+mod divergence;
+mod dry_run;
+mod encoding;
+mod export;
+mod filter;
+mod fixture;
+mod guard;
+mod hunk_patch;
+mod limits;
+mod memo;
+#[cfg(feature = "gix")]
+mod object_db;
+mod outline;
+mod patch;
+mod prefetch;
 mod processor;
+mod progress;
+mod provider;
+mod repo;
+mod repo_state;
+mod review;
+mod series;
+mod stack;
+mod stage;
+mod syntax;
+mod temp;
+mod timestamp;
+mod tool;
+mod unified_diff;
+mod vcs;
+mod wrapper;
+
+/// A file's content as fetched, `None` if it doesn't exist on that side.
+type Content = Option<Vec<u8>>;
 
 /// Splits file content into individual lines, or empty vector if `None`.
 #[inline]
@@ -53,65 +504,200 @@ fn into_lines(content: Option<String>) -> Vec<String> {
         .unwrap_or_default()
 }
 
+/// Creates a jj command, passing `--ignore-working-copy` to skip snapshotting the working
+/// copy when `ignore_working_copy` is set.
+fn jj_command(ignore_working_copy: bool) -> Command {
+    let mut command = repo::command("jj");
+    if ignore_working_copy {
+        command.arg("--ignore-working-copy");
+    }
+    command
+}
+
+/// Whether `revset` may refer to a working-copy commit (`@`, `@-`, `workspace@`,
+/// `working_copies()`), in which case jj must snapshot the working copy first.
+/// Remote bookmarks like `main@origin` don't count.
+fn jj_revset_uses_working_copy(revset: &str) -> bool {
+    revset.contains("working_copies")
+        || revset.match_indices('@').any(|(i, _)| {
+            !revset[i + 1..]
+                .starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '"' || c == '\'')
+        })
+}
+
 /// Fetches file content from jj at a specific revision via `jj file show`.
 /// Returns `None` if the command fails or the file doesn't exist.
-fn jj_file_content(revset: &str, path: &Path) -> Option<String> {
-    Command::new("jj")
+fn jj_file_content(revset: &str, path: &Path, ignore_working_copy: bool) -> Option<Vec<u8>> {
+    jj_command(ignore_working_copy)
         .args(["file", "show", "-r", revset])
         .arg(path)
-        .output()
+        .output_or_cancel()
         .ok()
         .filter(|output| output.status.success())
-        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .map(|output| output.stdout)
 }
 
 /// Fetches file content from git at a specific commit via `git show`.
 /// Returns `None` if the command fails or the file doesn't exist.
-fn git_file_content(commit: &str, path: &Path) -> Option<String> {
-    Command::new("git")
+fn git_file_content(commit: &str, path: &Path) -> Option<Vec<u8>> {
+    repo::command("git")
         .arg("show")
         .arg(format!("{commit}:{}", path.display()))
-        .output()
+        .output_or_cancel()
         .ok()
         .filter(|output| output.status.success())
-        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .map(|output| output.stdout)
 }
 
 /// Fetches file content from git index (staged version).
 /// Returns `None` if the command fails or the file doesn't exist in the index.
-fn git_index_content(path: &Path) -> Option<String> {
-    Command::new("git")
+fn git_index_content(path: &Path) -> Option<Vec<u8>> {
+    repo::command("git")
         .arg("show")
         .arg(format!(":{}", path.display()))
-        .output()
+        .output_or_cancel()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| output.stdout)
+}
+
+/// Reads the size in bytes of the blob `spec` (`<rev>:<path>`) with `git cat-file -s`,
+/// from `git_dir` or the current repository. Returns `None` if it doesn't name a blob.
+fn git_blob_size(git_dir: Option<&Path>, spec: &str) -> Option<u64> {
+    let mut command = repo::command("git");
+    if let Some(git_dir) = git_dir {
+        command.arg("--git-dir").arg(git_dir);
+    }
+    command
+        .args(["cat-file", "-s", spec])
+        .output_or_cancel()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8_lossy(&output.stdout).trim().parse().ok())
+}
+
+/// Fetches many blobs through one `git cat-file --batch` process, one `<rev>:<path>` spec
+/// per entry, from `git_dir` or the current repository. Entries are `None` for specs that
+/// don't name a blob. Returns `None` if the batch can't run, so callers fall back to
+/// fetching one file at a time.
+fn git_cat_file_batch(git_dir: Option<&Path>, specs: &[String]) -> Option<Vec<Option<Vec<u8>>>> {
+    if specs.is_empty() {
+        return Some(Vec::new());
+    }
+    // Specs are newline-separated on stdin, so paths containing one can't be batched
+    if specs.iter().any(|spec| spec.contains('\n')) {
+        return None;
+    }
+    #[cfg(feature = "gix")]
+    if let Some(blobs) = object_db::blobs(git_dir, specs) {
+        return Some(blobs);
+    }
+    let input: String = specs.iter().map(|spec| format!("{spec}\n")).collect();
+    let mut command = repo::command("git");
+    if let Some(git_dir) = git_dir {
+        command.arg("--git-dir").arg(git_dir);
+    }
+    let output = command
+        .args(["cat-file", "--batch"])
+        .output_with_input(input.into_bytes())
+        .ok()
+        .filter(|output| output.status.success())?;
+    parse_cat_file_batch(&output.stdout, specs.len())
+}
+
+/// Parses the output of `git cat-file --batch` for `count` requests: a
+/// `<oid> <type> <size>` header followed by the object and a newline for each object
+/// found, or a `<spec> missing` line for each one that isn't.
+fn parse_cat_file_batch(mut output: &[u8], count: usize) -> Option<Vec<Option<Vec<u8>>>> {
+    let mut blobs = Vec::with_capacity(count);
+    for _ in 0..count {
+        let end = output.iter().position(|&b| b == b'\n')?;
+        let header = std::str::from_utf8(&output[..end]).ok()?;
+        output = &output[end + 1..];
+        if header.ends_with(" missing") || header.ends_with(" ambiguous") {
+            blobs.push(None);
+            continue;
+        }
+        let mut fields = header.split(' ');
+        let (_oid, kind, size) = (fields.next()?, fields.next()?, fields.next()?);
+        let size: usize = size.parse().ok()?;
+        let object = output.get(..size)?;
+        output = output.get(size + 1..)?;
+        blobs.push((kind == "blob").then(|| object.to_vec()));
+    }
+    Some(blobs)
+}
+
+/// Fetches one stage of a conflicted file from the git index: 1 for the common ancestor,
+/// 2 for ours and 3 for theirs. Returns `None` if the stage doesn't exist (e.g. the file
+/// was added on one side only).
+fn git_stage_content(stage: u8, path: &Path) -> Option<Vec<u8>> {
+    repo::command("git")
+        .arg("show")
+        .arg(format!(":{stage}:{}", path.display()))
+        .output_or_cancel()
         .ok()
         .filter(|output| output.status.success())
-        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .map(|output| output.stdout)
+}
+
+/// Lists files with unmerged entries in the git index, relative to the repository root.
+fn git_conflicted_paths() -> Vec<PathBuf> {
+    repo::command("git")
+        .args(["diff", "--name-only", "--diff-filter=U", "-z"])
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| {
+            String::from_utf8_lossy(&o.stdout)
+                .split('\0')
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 /// Gets the git repository root directory.
 fn git_root() -> Option<PathBuf> {
-    Command::new("git")
+    repo::command("git")
         .args(["rev-parse", "--show-toplevel"])
-        .output()
+        .output_or_cancel()
         .ok()
         .filter(|o| o.status.success())
-        .map(|o| PathBuf::from(String::from_utf8_lossy(&o.stdout).trim()))
+        .map(|o| wrapper::to_local(PathBuf::from(String::from_utf8_lossy(&o.stdout).trim())))
 }
 
 /// Gets the jj repository root directory.
 fn jj_root() -> Option<PathBuf> {
-    Command::new("jj")
+    repo::command("jj")
         .args(["root"])
-        .output()
+        .output_or_cancel()
         .ok()
         .filter(|o| o.status.success())
-        .map(|o| PathBuf::from(String::from_utf8_lossy(&o.stdout).trim()))
+        .map(|o| wrapper::to_local(PathBuf::from(String::from_utf8_lossy(&o.stdout).trim())))
 }
 
 /// Stats for a single file: (additions, deletions).
 type FileStats = HashMap<PathBuf, (u32, u32)>;
 
+/// Old path of each renamed file, by new path.
+type Renames = HashMap<PathBuf, PathBuf>;
+
+/// File mode changes, such as a script becoming executable, by new path.
+type Modes = HashMap<PathBuf, processor::ModeChange>;
+
+/// Submodules whose recorded commit changed, by path.
+type Submodules = HashMap<PathBuf, processor::SubmoduleChange>;
+
+/// What `git diff --raw` reports beyond difftastic's output.
+#[derive(Default)]
+struct FileChanges {
+    renames: Renames,
+    modes: Modes,
+    submodules: Submodules,
+}
+
 /// Gets diff stats from git using `--numstat`.
 /// Output format: "additions\tdeletions\tpath"
 ///
@@ -123,29 +709,111 @@ fn git_diff_stats(extra_args: &[&str]) -> FileStats {
     let mut args = vec!["diff", "--numstat"];
     args.extend(extra_args);
 
-    let output = Command::new("git").args(&args).output().ok();
+    let output = repo::command("git").args(&args).output_or_cancel().ok();
 
     let Some(output) = output.filter(|o| o.status.success()) else {
         return HashMap::new();
     };
+    parse_numstat(&String::from_utf8_lossy(&output.stdout))
+}
 
-    String::from_utf8_lossy(&output.stdout)
+/// Parses `--numstat` output: one "additions\tdeletions\tpath" line per file.
+/// Binary files, reported with `-` counts, are skipped. Renamed files are counted under
+/// their new path.
+fn parse_numstat(output: &str) -> FileStats {
+    output
         .lines()
         .filter_map(|line| {
             let mut parts = line.split('\t');
             let add = parts.next()?.parse().ok()?;
             let del = parts.next()?.parse().ok()?;
             let path = parts.next()?;
-            Some((PathBuf::from(path), (add, del)))
+            Some((renamed_to(path), (add, del)))
         })
         .collect()
 }
 
+/// New path of a `--numstat` path, which shows renames as `old => new` or, around a common
+/// prefix and suffix, `src/{old => new}/lib.rs`.
+fn renamed_to(path: &str) -> PathBuf {
+    let Some((old, new)) = path.split_once(" => ") else {
+        return PathBuf::from(path);
+    };
+    match (old.rfind('{'), new.find('}')) {
+        (Some(open), Some(close)) => {
+            let joined = format!("{}{}{}", &old[..open], &new[..close], &new[close + 1..]);
+            // An empty side leaves a double slash, as in `src/{ => a}/lib.rs`
+            PathBuf::from(joined.replace("//", "/"))
+        }
+        _ => PathBuf::from(new),
+    }
+}
+
+/// Lists the renames, file mode changes and submodule updates `git diff` detects for
+/// `extra_args`.
+fn git_file_changes(extra_args: &[&str]) -> FileChanges {
+    git_raw_diff(&[&["--no-abbrev"], extra_args].concat())
+        .map(|raw| parse_raw_diff(&raw))
+        .unwrap_or_default()
+}
+
+/// Parses `--raw -z` output: a `:<old mode> <new mode> <old hash> <new hash> <status>`
+/// header followed by one path, or by the old and new paths for renames (`R<score>`) and
+/// copies (`C<score>`), each ending with a NUL byte. Created and deleted files, which have
+/// a `000000` mode on one side, don't count as mode changes. Submodules have mode `160000`
+/// and the hashes of their recorded commits.
+fn parse_raw_diff(output: &str) -> FileChanges {
+    const ABSENT: &str = "000000";
+    const SUBMODULE: &str = "160000";
+    let mut fields = output.split('\0');
+    let mut changes = FileChanges::default();
+    while let Some(header) = fields.next() {
+        let parts: Vec<&str> = header.trim_start_matches(':').split(' ').collect();
+        let [old_mode, new_mode, old_hash, new_hash, status] = parts[..] else {
+            break;
+        };
+        let Some(mut path) = fields.next() else {
+            break;
+        };
+        if status.starts_with('R') || status.starts_with('C') {
+            let Some(new) = fields.next() else {
+                break;
+            };
+            if status.starts_with('R') {
+                changes
+                    .renames
+                    .insert(PathBuf::from(new), PathBuf::from(path));
+            }
+            path = new;
+        }
+        let is_submodule = |mode: &str| mode == SUBMODULE || mode == ABSENT;
+        if is_submodule(old_mode) && is_submodule(new_mode) {
+            let commit = |mode: &str, hash: &str| (mode != ABSENT).then(|| hash.to_string());
+            let change = processor::SubmoduleChange {
+                old: commit(old_mode, old_hash),
+                new: commit(new_mode, new_hash),
+                log: Vec::new(),
+            };
+            changes.submodules.insert(PathBuf::from(path), change);
+        } else if old_mode != new_mode && old_mode != ABSENT && new_mode != ABSENT {
+            let change = processor::ModeChange {
+                old: old_mode.to_string(),
+                new: new_mode.to_string(),
+            };
+            changes.modes.insert(PathBuf::from(path), change);
+        }
+    }
+    changes
+}
+
 /// Gets diff stats for jj uncommitted changes.
 fn jj_diff_stats_uncommitted() -> FileStats {
     // jj diff without -r shows uncommitted changes; use git for stats
     // For uncommitted changes, we compare working copy to the current commit
-    let output = Command::new("jj").args(["diff", "--stat"]).output().ok();
+    let output = repo::command("jj")
+        .args(["diff", "--stat"])
+        .output_or_cancel()
+        .ok();
 
     // jj --stat output is different, so we just return empty for now
     // The diff will still work, just without inline stats
@@ -155,10 +823,10 @@ fn jj_diff_stats_uncommitted() -> FileStats {
 
 /// Translates a jj revset to a git commit hash.
 /// Uses `jj log -r <revset> --no-graph -T 'commit_id'`.
-fn jj_to_git_commit(revset: &str) -> Option<String> {
-    let output = Command::new("jj")
+fn jj_to_git_commit(revset: &str, ignore_working_copy: bool) -> Option<String> {
+    let output = jj_command(ignore_working_copy)
         .args(["log", "-r", revset, "--no-graph", "-T", "commit_id"])
-        .output()
+        .output_or_cancel()
         .ok()?;
 
     if !output.status.success() {
@@ -170,11 +838,80 @@ fn jj_to_git_commit(revset: &str) -> Option<String> {
     (commit.len() == 40 && commit.chars().all(|c| c.is_ascii_hexdigit())).then_some(commit)
 }
 
+/// The git repository a jj repository stores its commits in, given its `.jj` directory.
+/// `None` for other backends.
+fn jj_git_dir(jj_dir: &Path) -> Option<PathBuf> {
+    let mut repo_dir = jj_dir.join("repo");
+    // Secondary workspaces hold the path of the main workspace's repository instead
+    if repo_dir.is_file() {
+        repo_dir = jj_dir.join(std::fs::read_to_string(&repo_dir).ok()?.trim());
+    }
+    let store = repo_dir.join("store");
+    let target = std::fs::read_to_string(store.join("git_target")).ok()?;
+    Some(store.join(target.trim()))
+}
+
+/// Resolves a single-commit jj revset to its git repository and commit, to read its files
+/// in one batch instead of one `jj file show` each. `None` for conflicted commits, whose git
+/// trees hold jj's conflict representation rather than the files with conflict markers.
+fn jj_git_commit(revset: &str, ignore_working_copy: bool) -> Option<(PathBuf, String)> {
+    let output = jj_command(ignore_working_copy)
+        .args([
+            "log",
+            "-r",
+            revset,
+            "--no-graph",
+            "-T",
+            r#"commit_id ++ " " ++ if(conflict, "conflict", "clean") ++ "\n""#,
+        ])
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    let (commit, state) = lines.next()?.split_once(' ')?;
+    if lines.next().is_some() || state != "clean" {
+        return None;
+    }
+    Some((jj_git_dir(&jj_root()?.join(".jj"))?, commit.to_string()))
+}
+
+/// Lists the commit ids of every commit in `revset`, one per line.
+fn jj_commit_ids(revset: &str, ignore_working_copy: bool) -> Option<String> {
+    jj_command(ignore_working_copy)
+        .args([
+            "log",
+            "-r",
+            revset,
+            "--no-graph",
+            "-T",
+            r#"commit_id ++ "\n""#,
+        ])
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .filter(|ids| !ids.is_empty())
+}
+
 /// Gets diff stats from jj by translating revsets to git commits.
 /// For colocated repos, uses `git diff --numstat` for accurate stats.
-fn jj_diff_stats(revset: &str) -> FileStats {
-    let old_commit = jj_to_git_commit(&format!("roots({revset})-"));
-    let new_commit = jj_to_git_commit(&format!("heads({revset})"));
+fn jj_diff_stats(revset: &str, ignore_working_copy: bool) -> FileStats {
+    jj_diff_stats_between(
+        &format!("roots({revset})-"),
+        &format!("heads({revset})"),
+        ignore_working_copy,
+    )
+}
+
+/// Gets diff stats between two jj revsets, each resolving to a single commit.
+fn jj_diff_stats_between(
+    old_revset: &str,
+    new_revset: &str,
+    ignore_working_copy: bool,
+) -> FileStats {
+    let old_commit = jj_to_git_commit(old_revset, ignore_working_copy);
+    let new_commit = jj_to_git_commit(new_revset, ignore_working_copy);
 
     match (old_commit, new_commit) {
         (Some(old), Some(new)) => git_diff_stats(&[&format!("{old}..{new}")]),
@@ -183,14 +920,23 @@ fn jj_diff_stats(revset: &str) -> FileStats {
     }
 }
 
-/// Runs difftastic via jj and parses the JSON output.
-/// Executes `jj diff -r <revset> --tool difft` with JSON output mode enabled.
-fn run_jj_diff(revset: &str) -> Result<Vec<difftastic::DifftFile>, String> {
-    let output = Command::new("jj")
-        .args(["diff", "-r", revset, "--tool", "difft"])
+/// Runs difftastic via jj and returns its raw JSON output.
+/// Executes `jj diff --tool difft` with JSON output mode enabled.
+///
+/// Pass additional arguments to select what to diff:
+/// - `&["-r", "@-"]` for a revision
+/// - `&["--from", "abc", "--to", "def"]` for two explicit revisions
+/// - `&[]` for uncommitted changes (working copy)
+fn jj_diff_json(extra_args: &[&str], ignore_working_copy: bool) -> Result<String, String> {
+    let output = jj_command(ignore_working_copy)
+        .arg("diff")
+        .args(extra_args)
+        .args(tool::jj_config(difft_binary()))
+        .args(["--tool", "difft"])
         .env("DFT_DISPLAY", "json")
         .env("DFT_UNSTABLE", "yes")
-        .output()
+        .envs(tool::env())
+        .output_or_cancel()
         .map_err(|e| format!("Failed to run jj: {e}"))?;
 
     if !output.status.success() {
@@ -198,45 +944,101 @@ fn run_jj_diff(revset: &str) -> Result<Vec<difftastic::DifftFile>, String> {
         return Err(format!("jj command failed: {stderr}"));
     }
 
-    difftastic::parse(&String::from_utf8_lossy(&output.stdout))
-        .map_err(|e| format!("Failed to parse difftastic JSON: {e}"))
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Searches the directories in `path_var` (formatted like `PATH`) for an executable `name`.
+/// Relative directories are skipped, as they would depend on Neovim's working directory.
+fn find_in_path(name: &str, path_var: &OsStr) -> Option<PathBuf> {
+    let candidates: &[&str] = if cfg!(windows) { &["", ".exe"] } else { &[""] };
+    std::env::split_paths(path_var)
+        .filter(|dir| dir.is_absolute())
+        .flat_map(|dir| {
+            candidates
+                .iter()
+                .map(move |ext| dir.join(format!("{name}{ext}")))
+        })
+        .find(|candidate| is_executable(candidate))
+}
+
+/// Whether `path` is a file that can be run: on Unix, one with an execute bit set.
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+/// Resolves the difftastic binary to an absolute path: the one configured with `set_difft`
+/// (see [`tool`]), or `difft`. Bare names are looked up on `PATH`, falling back to the name
+/// if they can't be found. With a command wrapper, they're looked up on the other side
+/// instead.
+fn difft_binary() -> PathBuf {
+    let binary = tool::path().unwrap_or_else(|| PathBuf::from("difft"));
+    if wrapper::current().is_some() || binary.components().count() != 1 {
+        return binary;
+    }
+    std::env::var_os("PATH")
+        .and_then(|path| find_in_path(&binary.to_string_lossy(), &path))
+        .unwrap_or(binary)
 }
 
-/// Runs difftastic via jj for uncommitted changes (working copy).
-/// Executes `jj diff` with no revision argument.
-fn run_jj_diff_uncommitted() -> Result<Vec<difftastic::DifftFile>, String> {
-    let output = Command::new("jj")
-        .args(["diff", "--tool", "difft"])
+/// Runs difftastic directly on two files and returns its raw JSON output.
+///
+/// `language` forces the language difftastic parses both files as (e.g. `"Rust"`) instead of
+/// detecting it from the file names.
+fn difft_files_json(
+    old_path: &Path,
+    new_path: &Path,
+    language: Option<&str>,
+) -> Result<String, String> {
+    let output = Command::new(difft_binary())
+        .args(tool::args())
+        .args(language.map(|language| format!("--override=*:{language}")))
+        .arg(old_path)
+        .arg(new_path)
         .env("DFT_DISPLAY", "json")
         .env("DFT_UNSTABLE", "yes")
-        .output()
-        .map_err(|e| format!("Failed to run jj: {e}"))?;
+        .envs(tool::env())
+        .output_or_cancel()
+        .map_err(|e| format!("Failed to run difft: {e}"))?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("jj command failed: {stderr}"));
+        return Err(format!("difft command failed: {stderr}"));
     }
 
-    difftastic::parse(&String::from_utf8_lossy(&output.stdout))
-        .map_err(|e| format!("Failed to parse difftastic JSON: {e}"))
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
 }
 
-/// Runs difftastic via git and parses the JSON output.
+/// Runs difftastic via git and returns its raw JSON output.
 /// Executes `git diff` with difftastic as the external diff tool.
 ///
+/// Difftastic is passed through `GIT_EXTERNAL_DIFF`, which overrides any user-configured
+/// `diff.external`. `--ext-diff` forces the external tool even if the user disabled it, and
+/// `diff.trustExitCode` is turned off so a wrapper's exit status can't fail the diff.
+///
 /// Pass additional arguments to customize the diff:
 /// - `&["HEAD^..HEAD"]` for a commit range
 /// - `&[]` for unstaged changes (working tree vs index)
 /// - `&["--cached"]` for staged changes (index vs HEAD)
-fn run_git_diff(extra_args: &[&str]) -> Result<Vec<difftastic::DifftFile>, String> {
-    let mut args = vec!["-c", "diff.external=difft", "diff"];
+fn git_diff_json(extra_args: &[&str]) -> Result<String, String> {
+    let mut args = vec!["-c", "diff.trustExitCode=false", "diff", "--ext-diff"];
     args.extend(extra_args);
 
-    let output = Command::new("git")
+    let output = repo::command("git")
         .args(&args)
+        .env("GIT_EXTERNAL_DIFF", tool::git_external_diff(difft_binary()))
         .env("DFT_DISPLAY", "json")
         .env("DFT_UNSTABLE", "yes")
-        .output()
+        .envs(tool::env())
+        .output_or_cancel()
         .map_err(|e| format!("Failed to run git: {e}"))?;
 
     if !output.status.success() {
@@ -244,194 +1046,3471 @@ fn run_git_diff(extra_args: &[&str]) -> Result<Vec<difftastic::DifftFile>, Strin
         return Err(format!("git command failed: {stderr}"));
     }
 
-    difftastic::parse(&String::from_utf8_lossy(&output.stdout))
-        .map_err(|e| format!("Failed to parse difftastic JSON: {e}"))
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Lists the files `git diff` would show for `extra_args`, with their blob hashes: a cheap
+/// fingerprint of a diff, without running difftastic. Working tree files are listed without
+/// hashes, so their modification times must be checked separately.
+fn git_raw_diff(extra_args: &[&str]) -> Option<String> {
+    repo::command("git")
+        .args(["diff", "--raw", "-z", "--no-ext-diff"])
+        .args(extra_args)
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+}
+
+/// Lists the commits between the old and new commit of a submodule, as `> subject` for
+/// added ones and `< subject` for removed ones, like `git diff --submodule=log`. Empty if
+/// the submodule isn't checked out or lacks either commit.
+fn git_submodule_log(path: &Path, change: &processor::SubmoduleChange) -> Vec<String> {
+    let (Some(old), Some(new)) = (&change.old, &change.new) else {
+        return Vec::new();
+    };
+    repo::command("git")
+        .arg("-C")
+        .arg(path)
+        .args(["log", "--left-right", "--format=%m %s"])
+        .arg(format!("{old}...{new}"))
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| {
+            String::from_utf8_lossy(&o.stdout)
+                .lines()
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Resolves a git revision to its full commit hash.
+fn git_rev_parse(rev: &str) -> Option<String> {
+    #[cfg(feature = "gix")]
+    if let Some(commit) = object_db::rev_parse(rev) {
+        return Some(commit);
+    }
+    repo::command("git")
+        .args(["rev-parse", "--verify", "--quiet"])
+        .arg(format!("{rev}^{{commit}}"))
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+}
+
+/// Whether `rev` names a stash entry (`stash`, `stash@{1}`, `refs/stash@{0}`, ...).
+fn is_git_stash(rev: &str) -> bool {
+    let name = rev.strip_prefix("refs/").unwrap_or(rev);
+    name == "stash" || name.starts_with("stash@{")
+}
+
+/// The commit holding the untracked files of a stash made with `--include-untracked`,
+/// which is the stash's third parent, or `None` if the stash has no untracked part.
+fn git_stash_untracked(stash: &str) -> Option<String> {
+    let commit = format!("{stash}^3");
+    repo::command("git")
+        .args(["rev-parse", "--verify", "--quiet", &commit])
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|_| commit)
+}
+
+/// Hash of the empty tree, which works as the old side of a diff adding every file.
+fn git_empty_tree() -> Option<String> {
+    repo::command("git")
+        .args(["hash-object", "-t", "tree", "--stdin"])
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
 }
 
 /// Gets the merge-base of two git refs.
 fn git_merge_base(a: &str, b: &str) -> Option<String> {
-    Command::new("git")
+    repo::command("git")
         .args(["merge-base", a, b])
-        .output()
+        .output_or_cancel()
         .ok()
         .filter(|o| o.status.success())
         .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
 }
 
-/// Parses a git commit range into `(old_commit, new_commit)` references.
-///
-/// Handles single commits, `A..B` ranges, and `A...B` (merge-base) ranges.
-#[inline]
-fn parse_git_range(range: &str) -> (String, String) {
-    if let Some((a, b)) = range.split_once("...") {
-        let base = git_merge_base(a, b).unwrap_or_else(|| format!("{a}^"));
-        (base, b.to_string())
-    } else if let Some((old, new)) = range.split_once("..") {
-        (old.to_string(), new.to_string())
-    } else {
-        (format!("{range}^"), range.to_string())
+/// A parent of the diffed commit, listed so the caller can pick one for merge commits.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct ParentCommit {
+    /// Full commit hash of the parent.
+    commit: String,
+    /// First line of the parent's commit message.
+    subject: String,
+    /// Committer timestamp in Unix seconds.
+    timestamp: Option<i64>,
+    /// `timestamp` formatted relative to now (e.g. `"3 days ago"`).
+    relative_time: Option<String>,
+}
+
+impl IntoLua for ParentCommit {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("commit", self.commit)?;
+        table.set("subject", self.subject)?;
+        table.set("timestamp", self.timestamp)?;
+        table.set("relative_time", self.relative_time)?;
+        Ok(LuaValue::Table(table))
     }
 }
 
-/// The type of diff to perform.
-enum DiffMode {
-    /// A commit range (e.g., "HEAD^..HEAD" for git, "@" for jj).
-    Range(String),
-    /// Unstaged changes: working tree vs index (git) or working copy vs @ (jj).
-    Unstaged,
-    /// Staged changes: index vs HEAD (git only, jj falls back to @).
-    Staged,
+/// Parses `hash<TAB>subject[<TAB>timestamp]` lines into parent commits, skipping blank lines.
+/// Only the first word of the timestamp is read, so hg's `hgdate` format works as is.
+fn parse_parents(output: &str) -> Vec<ParentCommit> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (commit, rest) = line.split_once('\t').unwrap_or((line, ""));
+            let (subject, timestamp) = match rest.rsplit_once('\t') {
+                Some((subject, timestamp)) => (subject, timestamp.split_whitespace().next()),
+                None => (rest, None),
+            };
+            ParentCommit {
+                commit: commit.trim().to_string(),
+                subject: subject.to_string(),
+                timestamp: timestamp.and_then(|t| t.parse().ok()),
+                relative_time: None,
+            }
+        })
+        .collect()
 }
 
-/// Fetches file content from the working tree, using the appropriate VCS root.
-fn working_tree_content_for_vcs(path: &Path, vcs: &str) -> Option<String> {
-    let root = if vcs == "git" { git_root() } else { jj_root() }?;
-    std::fs::read_to_string(root.join(path)).ok()
+/// Lists the parents of a single git commit in parent order.
+/// Uses `git log --no-walk=unsorted <commit>^@` so the order matches `^1`, `^2`, ...
+fn git_parents(commit: &str) -> Vec<ParentCommit> {
+    repo::command("git")
+        .args(["log", "--no-walk=unsorted", "--format=%H%x09%s%x09%ct"])
+        .arg(format!("{commit}^@"))
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| parse_parents(&String::from_utf8_lossy(&o.stdout)))
+        .unwrap_or_default()
 }
 
-/// Unified implementation for running difftastic with any diff mode.
-/// Handles git and jj VCS, fetches file contents, and processes files in parallel.
-fn run_diff_impl(lua: &Lua, mode: DiffMode, vcs: &str) -> LuaResult<LuaTable> {
-    // Get files and stats based on mode and VCS
-    let (files, stats) = match (&mode, vcs) {
-        (DiffMode::Range(range), "git") => {
-            let files = run_git_diff(&[range]).map_err(LuaError::RuntimeError)?;
-            let stats = git_diff_stats(&[range]);
-            (files, stats)
-        }
-        (DiffMode::Range(range), _) => {
-            let files = run_jj_diff(range).map_err(LuaError::RuntimeError)?;
-            let stats = jj_diff_stats(range);
-            (files, stats)
-        }
-        (DiffMode::Unstaged, "git") => {
-            let files = run_git_diff(&[]).map_err(LuaError::RuntimeError)?;
-            let stats = git_diff_stats(&[]);
-            (files, stats)
-        }
-        (DiffMode::Unstaged, _) => {
-            let files = run_jj_diff_uncommitted().map_err(LuaError::RuntimeError)?;
-            let stats = jj_diff_stats_uncommitted();
-            (files, stats)
-        }
-        (DiffMode::Staged, "git") => {
-            let files = run_git_diff(&["--cached"]).map_err(LuaError::RuntimeError)?;
-            let stats = git_diff_stats(&["--cached"]);
-            (files, stats)
-        }
-        (DiffMode::Staged, _) => {
-            // jj doesn't have a staging area concept, so show current revision
-            let files = run_jj_diff("@").map_err(LuaError::RuntimeError)?;
-            let stats = jj_diff_stats("@");
-            (files, stats)
-        }
-    };
-
-    // Process files based on mode and VCS
-    let display_files: Vec<_> = match (&mode, vcs) {
-        (DiffMode::Range(range), "git") => {
-            let (old_ref, new_ref) = parse_git_range(range);
-            files
-                .into_par_iter()
-                .map(|file| {
-                    let file_stats = stats.get(&file.path).copied();
-                    let old_lines = into_lines(git_file_content(&old_ref, &file.path));
-                    let new_lines = into_lines(git_file_content(&new_ref, &file.path));
-                    processor::process_file(file, old_lines, new_lines, file_stats)
-                })
-                .collect()
-        }
-        (DiffMode::Range(range), _) => {
-            let old_ref = format!("roots({range})-");
-            let new_ref = format!("heads({range})");
-            files
-                .into_par_iter()
-                .map(|file| {
-                    let file_stats = stats.get(&file.path).copied();
-                    let old_lines = into_lines(jj_file_content(&old_ref, &file.path));
-                    let new_lines = into_lines(jj_file_content(&new_ref, &file.path));
-                    processor::process_file(file, old_lines, new_lines, file_stats)
-                })
-                .collect()
-        }
-        (DiffMode::Unstaged, "git") => files
-            .into_par_iter()
-            .map(|file| {
-                let file_stats = stats.get(&file.path).copied();
-                let old_lines = into_lines(git_index_content(&file.path));
-                let new_lines = into_lines(working_tree_content_for_vcs(&file.path, "git"));
-                processor::process_file(file, old_lines, new_lines, file_stats)
-            })
-            .collect(),
-        (DiffMode::Unstaged, _) => files
-            .into_par_iter()
-            .map(|file| {
-                let file_stats = stats.get(&file.path).copied();
-                let old_lines = into_lines(jj_file_content("@", &file.path));
-                let new_lines = into_lines(working_tree_content_for_vcs(&file.path, "jj"));
-                processor::process_file(file, old_lines, new_lines, file_stats)
-            })
-            .collect(),
-        (DiffMode::Staged, "git") => files
-            .into_par_iter()
-            .map(|file| {
-                let file_stats = stats.get(&file.path).copied();
-                let old_lines = into_lines(git_file_content("HEAD", &file.path));
-                let new_lines = into_lines(git_index_content(&file.path));
-                processor::process_file(file, old_lines, new_lines, file_stats)
-            })
-            .collect(),
-        (DiffMode::Staged, _) => files
-            .into_par_iter()
-            .map(|file| {
-                let file_stats = stats.get(&file.path).copied();
-                let old_lines = into_lines(jj_file_content("@-", &file.path));
-                let new_lines = into_lines(jj_file_content("@", &file.path));
-                processor::process_file(file, old_lines, new_lines, file_stats)
-            })
-            .collect(),
+/// Lists the parents of a jj revision in parent order.
+/// Returns an empty list if the revset doesn't resolve to exactly one commit.
+fn jj_parents(revset: &str, ignore_working_copy: bool) -> Vec<ParentCommit> {
+    let template = r#"parents.map(|c| c.commit_id() ++ "\t" ++ c.description().first_line() ++ "\t" ++ c.committer().timestamp().format("%s")).join("\n") ++ "\0""#;
+    let Some(output) = jj_command(ignore_working_copy)
+        .args(["log", "-r", revset, "--no-graph", "-T", template])
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+    else {
+        return Vec::new();
     };
 
-    let files_table = lua.create_table()?;
-    for (i, file) in display_files.into_iter().enumerate() {
-        files_table.set(i + 1, file.into_lua(lua)?)?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let records: Vec<&str> = stdout
+        .split('\0')
+        .filter(|r| !r.trim().is_empty())
+        .collect();
+    match records.as_slice() {
+        [single] => parse_parents(single),
+        _ => Vec::new(),
     }
-
-    let result = lua.create_table()?;
-    result.set("files", files_table)?;
-    Ok(result)
 }
 
-/// Runs difftastic for a commit range.
-fn run_diff(lua: &Lua, (range, vcs): (String, String)) -> LuaResult<LuaTable> {
-    run_diff_impl(lua, DiffMode::Range(range), &vcs)
+/// Fetches file content from Mercurial at a specific revision via `hg cat`.
+/// The `path:` pattern makes the path relative to the repository root.
+/// Returns `None` if the command fails or the file doesn't exist.
+fn hg_file_content(program: &str, rev: &str, path: &Path) -> Option<Vec<u8>> {
+    repo::command(program)
+        .args(["cat", "-r", rev])
+        .arg(format!("path:{}", path.display()))
+        .output_or_cancel()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| output.stdout)
 }
 
-/// Runs difftastic for unstaged changes.
-fn run_diff_unstaged(lua: &Lua, vcs: String) -> LuaResult<LuaTable> {
-    run_diff_impl(lua, DiffMode::Unstaged, &vcs)
+/// Gets the Mercurial repository root directory.
+fn hg_root(program: &str) -> Option<PathBuf> {
+    repo::command(program)
+        .arg("root")
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| wrapper::to_local(PathBuf::from(String::from_utf8_lossy(&o.stdout).trim())))
 }
 
-/// Runs difftastic for staged changes.
-fn run_diff_staged(lua: &Lua, vcs: String) -> LuaResult<LuaTable> {
-    run_diff_impl(lua, DiffMode::Staged, &vcs)
-}
+/// Counts added and removed lines per file in a `--git` style unified diff.
+///
+/// This is the equivalent of `git diff --numstat` for tools that lack it. Paths come from
+/// the `diff --git a/<path> b/<path>` headers, using the new path for renames. The `---` and
+/// `+++` lines are only headers before a file's first `@@` line: inside hunks they are
+/// removed `-- comment` or added `++ x` lines.
+fn parse_unified_numstat(diff: &str) -> FileStats {
+    let mut stats = FileStats::new();
+    let mut current: Option<&mut (u32, u32)> = None;
+    let mut in_hunks = false;
 
-/// Creates the Lua module exports. Called by mlua when loaded via `require("difftastic_nvim")`.
+    for line in diff.lines() {
+        if let Some(header) = line.strip_prefix("diff --git ") {
+            current = header
+                .split_once(" b/")
+                .map(|(_, path)| stats.entry(PathBuf::from(path)).or_insert((0, 0)));
+            in_hunks = false;
+        } else if line.starts_with("@@") {
+            in_hunks = true;
+        } else if !in_hunks {
+            continue;
+        } else if let Some((additions, deletions)) = current.as_deref_mut() {
+            if line.starts_with('+') {
+                *additions += 1;
+            } else if line.starts_with('-') {
+                *deletions += 1;
+            }
+        }
+    }
+
+    stats
+}
+
+/// Gets diff stats from Mercurial by counting lines in `hg diff --git`.
+fn hg_diff_stats(program: &str, extra_args: &[&str]) -> FileStats {
+    let output = repo::command(program)
+        .args(["diff", "--git"])
+        .args(extra_args)
+        .output_or_cancel()
+        .ok();
+
+    output
+        .filter(|o| o.status.success())
+        .map(|o| parse_unified_numstat(&String::from_utf8_lossy(&o.stdout)))
+        .unwrap_or_default()
+}
+
+/// Runs difftastic via Mercurial's `extdiff` extension and returns its raw JSON output.
+///
+/// `extdiff` snapshots both revisions into temporary directories and hands them to
+/// difftastic, so reported paths may be prefixed with a snapshot directory. Use
+/// [`relativize_paths`] to map them back to repository-relative paths.
+///
+/// Pass additional arguments to select what to diff:
+/// - `&["-r", "A", "-r", "B"]` for two revisions
+/// - `&["-r", "A"]` for the working copy against a revision
+/// - `&[]` for uncommitted changes
+fn hg_diff_json(program: &str, extra_args: &[&str]) -> Result<String, String> {
+    let output = repo::command(program)
+        .args(["--config", "extensions.extdiff=", "extdiff", "-p"])
+        .arg(difft_binary())
+        .args(
+            tool::args()
+                .into_iter()
+                .flat_map(|arg| ["-o".to_string(), arg]),
+        )
+        .args(extra_args)
+        .env("DFT_DISPLAY", "json")
+        .env("DFT_UNSTABLE", "yes")
+        .envs(tool::env())
+        .output_or_cancel()
+        .map_err(|e| format!("Failed to run {program}: {e}"))?;
+
+    // extdiff exits with difftastic's status, which is non-zero when files differ
+    if !output.status.success() && output.stdout.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{program} command failed: {stderr}"));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Maps absolute paths reported by difftastic back to repository-relative paths.
+///
+/// When only one file changed, `extdiff` passes files instead of directories and difftastic
+/// reports their absolute snapshot paths. The longest stats path that such a path ends with
+/// is used instead. Relative paths are left alone.
+fn relativize_paths(files: &mut [difftastic::DifftFile], stats: &FileStats) {
+    for file in files.iter_mut().filter(|f| f.path.is_absolute()) {
+        let known = stats
+            .keys()
+            .filter(|known| file.path.ends_with(known))
+            .max_by_key(|known| known.components().count());
+        if let Some(known) = known {
+            file.path = known.clone();
+        }
+    }
+}
+
+/// Marks the files in `renames` as renamed. Difftastic reports a renamed file under one
+/// of its paths, so files reported under their old path are moved to the new one.
+fn apply_renames(files: &mut [difftastic::DifftFile], renames: &Renames) {
+    let new_paths: HashMap<&Path, &Path> = renames
+        .iter()
+        .map(|(new, old)| (old.as_path(), new.as_path()))
+        .collect();
+    for file in files {
+        let old = match (renames.get(&file.path), new_paths.get(file.path.as_path())) {
+            (Some(old), _) => old.clone(),
+            (None, Some(new)) => std::mem::replace(&mut file.path, new.to_path_buf()),
+            (None, None) => continue,
+        };
+        file.rename_from(old);
+    }
+}
+
+/// Lists the changed files of the working directory, as a fingerprint of a working
+/// directory diff. Pass `["--rev", rev]` to compare against another revision.
+fn hg_status(program: &str, extra_args: &[&str]) -> Option<String> {
+    repo::command(program)
+        .arg("status")
+        .args(extra_args)
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+}
+
+/// Resolves a Mercurial revision to its full changeset hash.
+fn hg_node(program: &str, rev: &str) -> Option<String> {
+    repo::command(program)
+        .args(["log", "-r", rev, "-T", "{node}"])
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .filter(|node| !node.is_empty())
+}
+
+/// Lists the parents of a Mercurial revision in parent order.
+/// Returns an empty list if the revset doesn't resolve to exactly one revision.
+fn hg_parents(program: &str, revset: &str) -> Vec<ParentCommit> {
+    let template = r#"{parents % "{node}\t{desc|firstline}\t{date|hgdate}\n"}\0"#;
+    let Some(output) = repo::command(program)
+        .args(["log", "-r", revset, "-T", template])
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+    else {
+        return Vec::new();
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let records: Vec<&str> = stdout
+        .split('\0')
+        .filter(|r| !r.trim().is_empty())
+        .collect();
+    match records.as_slice() {
+        [single] => parse_parents(single),
+        _ => Vec::new(),
+    }
+}
+
+/// Builds the `git diff` revision arguments for a range.
+///
+/// Ranges (`A..B`, `A...B`) are passed through as-is. A single commit is diffed against its
+/// first parent, or against `parent_ref` when the caller selected a different merge parent.
+fn git_range_args(range: &str, parent_ref: Option<&str>) -> Vec<String> {
+    if range.contains("..") {
+        vec![range.to_string()]
+    } else {
+        let old = parent_ref.map_or_else(|| format!("{range}^"), String::from);
+        vec![old, range.to_string()]
+    }
+}
+
+/// Parses a git commit range into `(old_commit, new_commit)` references.
+///
+/// Handles single commits, `A..B` ranges, and `A...B` (merge-base) ranges.
+#[inline]
+fn parse_git_range(range: &str) -> (String, String) {
+    if let Some((a, b)) = range.split_once("...") {
+        let base = git_merge_base(a, b).unwrap_or_else(|| format!("{a}^"));
+        (base, b.to_string())
+    } else if let Some((old, new)) = range.split_once("..") {
+        (old.to_string(), new.to_string())
+    } else {
+        (format!("{range}^"), range.to_string())
+    }
+}
+
+/// The type of diff to perform.
+enum DiffMode {
+    /// A commit range (e.g., "HEAD^..HEAD" for git, "@" for jj).
+    Range(String),
+    /// Unstaged changes: working tree vs index (git) or working copy vs @ (jj).
+    Unstaged,
+    /// Staged changes: index vs HEAD (git only, jj falls back to @).
+    Staged,
+    /// Working tree vs a specific commit (e.g. the commit being applied by a rebase).
+    WorkingTree(String),
+}
+
+impl DiffMode {
+    /// Resolves one of the working copy modes by name:
+    ///
+    /// - `"index"` - Index vs working tree, the old side read from the index (plain `git diff`)
+    /// - `"staged"` (or `"cached"`) - HEAD vs index (`git diff --cached`), the new side read
+    ///   from the index, for reviewing what is about to be committed
+    /// - `"head"` - HEAD vs working tree (`git diff HEAD`)
+    ///
+    /// jj and hg have no index, so `"index"` and `"head"` both show the uncommitted changes.
+    fn named(name: &str, vcs: &str) -> Result<Self, String> {
+        match name {
+            "index" => Ok(Self::Unstaged),
+            "staged" | "cached" => Ok(Self::Staged),
+            "head" if vcs == "git" => Ok(Self::WorkingTree("HEAD".to_string())),
+            "head" => Ok(Self::Unstaged),
+            _ => Err(format!(
+                "Unknown diff mode '{name}', expected 'index', 'staged' or 'head'"
+            )),
+        }
+    }
+}
+
+/// Optional settings passed as the last argument to the `run_diff*` functions.
+#[derive(Debug, Default)]
+struct DiffOptions {
+    /// VCS to diff with when `run_diff` is called with an options table instead of a VCS
+    /// name. Detected from the repository if unset.
+    vcs: Option<String>,
+
+    /// Repository to run in, instead of Neovim's working directory.
+    cwd: Option<PathBuf>,
+
+    /// 1-indexed parent to diff a merge commit against. Defaults to the first parent.
+    parent: Option<usize>,
+
+    /// Optional processing steps applied to every file.
+    process: processor::ProcessOptions,
+
+    /// Restrict the diff to these paths, relative to the repository root.
+    paths: Vec<PathBuf>,
+
+    /// Globs of files to keep or drop after parsing difftastic's output.
+    filter: filter::PathFilter,
+
+    /// Fully process only the N most-changed files; the rest get stats-only entries.
+    preview: Option<usize>,
+
+    /// Total rows allowed across all files, [`DEFAULT_MAX_ROWS`] if unset. Files past the
+    /// budget get stats-only entries.
+    max_rows: Option<usize>,
+
+    /// Words used for relative commit times.
+    time_format: timestamp::TimeFormat,
+
+    /// Whether jj commands skip snapshotting the working copy. Defaults to skipping it for
+    /// ranges that don't refer to a working-copy commit.
+    ignore_working_copy: Option<bool>,
+
+    /// Whether `run_diff_async` hands files over through `DiffJob:take_files` as they're
+    /// ready instead of in the result.
+    stream: bool,
+
+    /// Whether the `run_diff*` functions only list the commands they would run.
+    dry_run: bool,
+
+    /// Directory to save processed diffs of commits in, so they survive restarts.
+    cache_dir: Option<PathBuf>,
+
+    /// Whether changed submodules list the commits between their old and new commit, read
+    /// from the submodule's own repository.
+    submodule_log: bool,
+}
+
+impl FromLua for DiffOptions {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Nil => Ok(Self::default()),
+            LuaValue::Table(table) => Ok(Self {
+                vcs: table.get("vcs")?,
+                cwd: table.get::<Option<String>>("cwd")?.map(PathBuf::from),
+                parent: table.get("parent")?,
+                process: processor::ProcessOptions {
+                    syntax_spans: table
+                        .get::<Option<bool>>("syntax_spans")?
+                        .unwrap_or_default(),
+                    accessible: table.get::<Option<bool>>("accessible")?.unwrap_or_default(),
+                    ignore_whitespace: table
+                        .get::<Option<bool>>("ignore_whitespace")?
+                        .unwrap_or_default(),
+                    virtual_deletions: table
+                        .get::<Option<bool>>("virtual_deletions")?
+                        .unwrap_or_default(),
+                    keep_cr: table.get::<Option<bool>>("keep_cr")?.unwrap_or_default(),
+                    keep_final_empty_line: table
+                        .get::<Option<bool>>("keep_final_empty_line")?
+                        .unwrap_or_default(),
+                    split_words: table
+                        .get::<Option<bool>>("split_words")?
+                        .unwrap_or_default(),
+                    word_diff: table.get::<Option<bool>>("word_diff")?.unwrap_or_default(),
+                    full_line_threshold: full_line_threshold(&table)?,
+                    symbol_renames: table
+                        .get::<Option<bool>>("symbol_renames")?
+                        .unwrap_or_default(),
+                    line_length_ends: table
+                        .get::<Option<bool>>("line_length_ends")?
+                        .unwrap_or_default(),
+                    display_columns: table
+                        .get::<Option<bool>>("display_columns")?
+                        .unwrap_or_default(),
+                    context: table.get("context")?,
+                    view: match table.get::<Option<String>>("view")?.as_deref() {
+                        None | Some("side_by_side") => processor::View::SideBySide,
+                        Some("unified") => processor::View::Unified,
+                        Some(view) => {
+                            return Err(LuaError::RuntimeError(format!(
+                                "Unknown view '{view}', expected 'side_by_side' or 'unified'"
+                            )));
+                        }
+                    },
+                    tab_width: match table.get::<Option<u32>>("tab_width")? {
+                        Some(0) => {
+                            return Err(LuaError::RuntimeError(
+                                "tab_width must be at least 1".to_string(),
+                            ));
+                        }
+                        width => width,
+                    },
+                    encodings: encodings(&table)?,
+                },
+                paths: table
+                    .get::<Option<Vec<String>>>("paths")?
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(PathBuf::from)
+                    .collect(),
+                filter: filter::PathFilter::from_table(&table)?,
+                preview: table.get("preview")?,
+                max_rows: table.get("max_rows")?,
+                time_format: table.get::<Option<_>>("time_format")?.unwrap_or_default(),
+                ignore_working_copy: table.get("ignore_working_copy")?,
+                stream: table.get::<Option<bool>>("stream")?.unwrap_or_default(),
+                dry_run: table.get::<Option<bool>>("dry_run")?.unwrap_or_default(),
+                cache_dir: table.get::<Option<String>>("cache_dir")?.map(PathBuf::from),
+                submodule_log: table
+                    .get::<Option<bool>>("submodule_log")?
+                    .unwrap_or_default(),
+            }),
+            other => Err(LuaError::RuntimeError(format!(
+                "run_diff options must be a table, got {}",
+                other.type_name()
+            ))),
+        }
+    }
+}
+
+/// Reads `encodings`, a table of globs to encoding labels, sorted by glob.
+fn encodings(table: &LuaTable) -> LuaResult<encoding::Encodings> {
+    let labels: Option<HashMap<String, String>> = table.get("encodings")?;
+    let mut encodings = labels
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(glob, label)| {
+            let encoding = encoding::for_label(&label)
+                .map_err(|e| LuaError::RuntimeError(format!("{e} for {glob}")))?;
+            Ok((glob, encoding))
+        })
+        .collect::<LuaResult<encoding::Encodings>>()?;
+    encodings.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(encodings)
+}
+
+/// Reads `full_line_threshold`, a fraction between 0 and 1, as a whole percentage.
+fn full_line_threshold(table: &LuaTable) -> LuaResult<Option<u8>> {
+    let Some(fraction) = table.get::<Option<f64>>("full_line_threshold")? else {
+        return Ok(None);
+    };
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(LuaError::RuntimeError(format!(
+            "full_line_threshold must be between 0 and 1, got {fraction}"
+        )));
+    }
+    Ok(Some((fraction * 100.0).round() as u8))
+}
+
+/// Picks the commit to use as the old side when a specific merge parent was requested.
+fn select_parent(
+    parents: &[ParentCommit],
+    parent: Option<usize>,
+) -> Result<Option<String>, String> {
+    let Some(n) = parent else {
+        return Ok(None);
+    };
+    n.checked_sub(1)
+        .and_then(|i| parents.get(i))
+        .map(|p| Some(p.commit.clone()))
+        .ok_or_else(|| {
+            format!(
+                "Cannot diff against parent {n}: revision has {} parent(s)",
+                parents.len()
+            )
+        })
+}
+
+/// Where to read one side's file contents from.
+#[derive(Debug, Clone)]
+enum ContentSource {
+    /// A git commit, read with `git cat-file --batch` or `git show <commit>:<path>`.
+    GitCommit(String),
+    /// The git index, read with `git cat-file --batch` or `git show :<path>`.
+    GitIndex,
+    /// A git stash: tracked files read from the stash commit, untracked files from its
+    /// third parent.
+    GitStash(String),
+    /// A jj revision, read in bulk from jj's git store or with `jj file show`, and whether
+    /// to pass `--ignore-working-copy`.
+    Jj(String, bool),
+    /// A Mercurial revision, read with `hg cat`, or a Sapling one with `sl cat`: the
+    /// program to run and the revision.
+    Hg(&'static str, String),
+    /// Files on disk under the repository root.
+    WorkingTree(Option<PathBuf>),
+    /// The output of a user-defined command, with `{path}` in its arguments replaced by the
+    /// file path.
+    Command(Vec<String>),
+}
+
+impl ContentSource {
+    /// Resolves this side to a full commit hash, or `None` for the index, the working tree
+    /// and user-defined commands. jj revisions resolve to their git commit, for permalinks.
+    fn resolve_commit(&self) -> Option<String> {
+        match self {
+            Self::GitCommit(rev) | Self::GitStash(rev) => git_rev_parse(rev),
+            Self::Jj(revset, ignore_working_copy) => jj_to_git_commit(revset, *ignore_working_copy),
+            Self::Hg(program, rev) => hg_node(program, rev),
+            Self::GitIndex | Self::WorkingTree(_) | Self::Command(_) => None,
+        }
+    }
+
+    /// Fetches the content of `path`, or `None` if it doesn't exist on this side.
+    fn fetch(&self, path: &Path) -> Option<Vec<u8>> {
+        match self {
+            Self::GitCommit(commit) => git_file_content(commit, path),
+            Self::GitIndex => git_index_content(path),
+            Self::GitStash(stash) => git_file_content(stash, path)
+                .or_else(|| git_file_content(&format!("{stash}^3"), path)),
+            Self::Jj(revset, ignore_working_copy) => {
+                jj_file_content(revset, path, *ignore_working_copy)
+            }
+            Self::Hg(program, rev) => hg_file_content(program, rev, path),
+            Self::WorkingTree(root) => std::fs::read(root.as_ref()?.join(path)).ok(),
+            Self::Command(argv) => {
+                let path = path.to_string_lossy();
+                vcs::run_template(&vcs::fill(argv, &[("path", &path)]), false)
+                    .ok()
+                    .map(String::into_bytes)
+            }
+        }
+    }
+
+    /// Size in bytes of `path`, or `None` if it doesn't exist on this side. Sources backed by
+    /// a git object database read the blob size without the contents, which decoding as
+    /// text would alter for binary files; others fall back to the fetched contents' length.
+    fn size(&self, path: &Path) -> Option<u64> {
+        let spec = |rev: &str| format!("{rev}:{}", path.display());
+        let size = match self {
+            Self::GitCommit(commit) => git_blob_size(None, &spec(commit)),
+            Self::GitIndex => git_blob_size(None, &spec("")),
+            Self::GitStash(stash) => git_blob_size(None, &spec(stash))
+                .or_else(|| git_blob_size(None, &spec(&format!("{stash}^3")))),
+            Self::Jj(revset, ignore_working_copy) => jj_git_commit(revset, *ignore_working_copy)
+                .and_then(|(git_dir, commit)| git_blob_size(Some(&git_dir), &spec(&commit))),
+            Self::WorkingTree(root) => {
+                return std::fs::metadata(root.as_ref()?.join(path))
+                    .ok()
+                    .map(|metadata| metadata.len());
+            }
+            Self::Hg(..) | Self::Command(_) => None,
+        };
+        size.or_else(|| self.fetch(path).map(|content| content.len() as u64))
+    }
+
+    /// Fetches the contents of all `paths` at once, for sources read from a git object
+    /// database (including jj's git backend). Returns `None` for other sources, or if the
+    /// batch fails.
+    fn fetch_batch(&self, paths: &[&Path]) -> Option<HashMap<PathBuf, Content>> {
+        let specs = |rev: &str| -> Vec<String> {
+            paths
+                .iter()
+                .map(|path| format!("{rev}:{}", path.display()))
+                .collect()
+        };
+        let mut blobs = match self {
+            Self::GitCommit(commit) | Self::GitStash(commit) => {
+                git_cat_file_batch(None, &specs(commit))?
+            }
+            Self::GitIndex => git_cat_file_batch(None, &specs(""))?,
+            // jj's git backend stores commits in a git repository, read without jj
+            Self::Jj(revset, ignore_working_copy) => {
+                let (git_dir, commit) = jj_git_commit(revset, *ignore_working_copy)?;
+                git_cat_file_batch(Some(&git_dir), &specs(&commit))?
+            }
+            _ => return None,
+        };
+        // Files missing from a stash commit may be untracked ones, stored in its third parent
+        if let Self::GitStash(stash) = self {
+            let missing: Vec<usize> = (0..paths.len()).filter(|&i| blobs[i].is_none()).collect();
+            let untracked: Vec<String> = missing
+                .iter()
+                .map(|&i| format!("{stash}^3:{}", paths[i].display()))
+                .collect();
+            for (i, blob) in missing
+                .into_iter()
+                .zip(git_cat_file_batch(None, &untracked)?)
+            {
+                blobs[i] = blob;
+            }
+        }
+        Some(
+            paths
+                .iter()
+                .map(|path| path.to_path_buf())
+                .zip(blobs)
+                .collect(),
+        )
+    }
+}
+
+/// Builds the trailing arguments that restrict a diff to `paths` (relative to the repo root).
+///
+/// git gets `:(top)` pathspecs after `--` so the paths don't depend on the current directory;
+/// jj gets `root-file:` filesets and hg and sl get `path:` patterns for the same reason.
+fn pathspec_args(vcs: &str, paths: &[PathBuf]) -> Vec<String> {
+    if paths.is_empty() {
+        return Vec::new();
+    }
+    let specs = paths.iter().map(|path| {
+        let path = path.to_string_lossy();
+        if vcs == "git" {
+            format!(":(top){path}")
+        } else if vcs == "hg" || vcs == "sl" {
+            format!("path:{path}")
+        } else {
+            let escaped = path.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("root-file:\"{escaped}\"")
+        }
+    });
+    let separator = (vcs == "git").then(|| "--".to_string());
+    separator.into_iter().chain(specs).collect()
+}
+
+/// Everything needed to build a diff: difftastic's output and where to read both sides.
+struct DiffPlan {
+    /// Raw difftastic JSON output.
+    json: String,
+    stats: FileStats,
+    old: ContentSource,
+    new: ContentSource,
+    /// Parents of the diffed commit, for single-commit diffs.
+    parents: Vec<ParentCommit>,
+    /// Old path of each file the VCS detected as renamed, by new path.
+    renames: Renames,
+    /// Files whose mode changed, if the VCS reports modes.
+    modes: Modes,
+    /// Submodules whose recorded commit changed, if the VCS reports them.
+    submodules: Submodules,
+}
+
+/// Runs difftastic for `mode` with the backend registered for `vcs`.
+fn plan_diff(mode: &DiffMode, vcs: &str, opts: &DiffOptions) -> Result<DiffPlan, String> {
+    progress::start(progress::Phase::Difftastic, 0);
+    vcs::backend(vcs).plan(mode, opts)
+}
+
+/// Built-in git backend.
+struct GitBackend;
+
+impl vcs::VcsBackend for GitBackend {
+    fn plan(&self, mode: &DiffMode, opts: &DiffOptions) -> Result<DiffPlan, String> {
+        // List parents of single-commit diffs so merge commits can target any parent
+        let parents = match mode {
+            DiffMode::Range(range) if !range.contains("..") => git_parents(range),
+            DiffMode::Range(range) if opts.parent.is_some() => {
+                return Err(format!(
+                    "Parent selection requires a single commit, got range '{range}'"
+                ));
+            }
+            _ => Vec::new(),
+        };
+        let parent_ref = select_parent(&parents, opts.parent)?;
+
+        // Restrict both difftastic and the stats to the requested paths, detecting renames
+        // even if `diff.renames` is off so both agree on them
+        let pathspec = pathspec_args("git", &opts.paths);
+        let pathspec: Vec<&str> = pathspec.iter().map(String::as_str).collect();
+        let git_diff = |args: &[&str]| -> Result<(String, FileStats, FileChanges), String> {
+            let args = [&["-M"], args, &pathspec].concat();
+            Ok((
+                git_diff_json(&args)?,
+                git_diff_stats(&args),
+                git_file_changes(&args),
+            ))
+        };
+
+        let ((json, stats, changes), old, new) = match mode {
+            // The stash commit only holds tracked files; untracked ones live in a third parent
+            DiffMode::Range(range) if is_git_stash(range) && parent_ref.is_none() => {
+                let (mut json, mut stats, changes) = git_diff(&[&format!("{range}^1"), range])?;
+                if let Some(untracked) = git_stash_untracked(range)
+                    && let Some(empty) = git_empty_tree()
+                {
+                    let (untracked_json, untracked_stats, _) = git_diff(&[&empty, &untracked])?;
+                    json.push('\n');
+                    json.push_str(&untracked_json);
+                    stats.extend(untracked_stats);
+                }
+                (
+                    (json, stats, changes),
+                    ContentSource::GitCommit(format!("{range}^1")),
+                    ContentSource::GitStash(range.clone()),
+                )
+            }
+            DiffMode::Range(range) => {
+                let args = git_range_args(range, parent_ref.as_deref());
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                let (old_ref, new_ref) = match &parent_ref {
+                    Some(parent) => (parent.clone(), range.clone()),
+                    None => parse_git_range(range),
+                };
+                // Count lines in-process when possible instead of running `git diff --numstat`
+                #[cfg(feature = "gix")]
+                let stats = object_db::numstat(&old_ref, &new_ref, &opts.paths);
+                #[cfg(not(feature = "gix"))]
+                let stats = None;
+                let diff = match stats {
+                    Some(stats) => {
+                        let args = [&["-M"], &args[..], &pathspec].concat();
+                        (git_diff_json(&args)?, stats, git_file_changes(&args))
+                    }
+                    None => git_diff(&args)?,
+                };
+                (
+                    diff,
+                    ContentSource::GitCommit(old_ref),
+                    ContentSource::GitCommit(new_ref),
+                )
+            }
+            DiffMode::Unstaged => (
+                git_diff(&[])?,
+                ContentSource::GitIndex,
+                ContentSource::WorkingTree(git_root()),
+            ),
+            DiffMode::Staged => (
+                git_diff(&["--cached"])?,
+                ContentSource::GitCommit("HEAD".to_string()),
+                ContentSource::GitIndex,
+            ),
+            DiffMode::WorkingTree(commit) => (
+                git_diff(&[commit])?,
+                ContentSource::GitCommit(commit.clone()),
+                ContentSource::WorkingTree(git_root()),
+            ),
+        };
+
+        Ok(DiffPlan {
+            json,
+            stats,
+            old,
+            new,
+            parents,
+            renames: changes.renames,
+            modes: changes.modes,
+            submodules: changes.submodules,
+        })
+    }
+
+    fn snapshot(&self, mode: &DiffMode, _opts: &DiffOptions) -> Option<cache::Snapshot> {
+        let (state, working_tree) = match mode {
+            DiffMode::Range(range) => {
+                // Single commits list their parents, ranges don't
+                let kind = if range.contains("..") {
+                    "range"
+                } else {
+                    "commit"
+                };
+                let (old, new) = parse_git_range(range);
+                let (old, new) = (git_rev_parse(&old)?, git_rev_parse(&new)?);
+                (format!("{kind} {old}..{new}"), None)
+            }
+            DiffMode::Unstaged => (format!("index\n{}", git_raw_diff(&[])?), git_root()),
+            DiffMode::Staged => {
+                let head = git_rev_parse("HEAD").unwrap_or_default();
+                (
+                    format!("staged {head}\n{}", git_raw_diff(&["--cached"])?),
+                    None,
+                )
+            }
+            DiffMode::WorkingTree(commit) => {
+                let commit = git_rev_parse(commit)?;
+                let raw = git_raw_diff(&[&commit])?;
+                (format!("worktree {commit}\n{raw}"), git_root())
+            }
+        };
+        Some(cache::Snapshot {
+            state,
+            working_tree,
+        })
+    }
+}
+
+/// Built-in jj backend, also used for unknown VCS names.
+struct JjBackend;
+
+/// Whether jj commands for `mode` skip snapshotting the working copy, which is slow in
+/// large repos and only needed when it's diffed.
+fn jj_ignores_working_copy(mode: &DiffMode, opts: &DiffOptions) -> bool {
+    opts.ignore_working_copy.unwrap_or(match mode {
+        DiffMode::Range(range) => !jj_revset_uses_working_copy(range),
+        _ => false,
+    })
+}
+
+impl vcs::VcsBackend for JjBackend {
+    fn plan(&self, mode: &DiffMode, opts: &DiffOptions) -> Result<DiffPlan, String> {
+        let iwc = jj_ignores_working_copy(mode, opts);
+
+        let parents = match mode {
+            DiffMode::Range(range) => jj_parents(range, iwc),
+            _ => Vec::new(),
+        };
+        let parent_ref = select_parent(&parents, opts.parent)?;
+
+        let pathspec = pathspec_args("jj", &opts.paths);
+        let pathspec: Vec<&str> = pathspec.iter().map(String::as_str).collect();
+        let jj_diff = |args: &[&str]| jj_diff_json(&[args, &pathspec].concat(), iwc);
+
+        let ((json, stats), old, new) = match mode {
+            DiffMode::Range(range) => match &parent_ref {
+                Some(parent) => (
+                    (
+                        jj_diff(&["--from", parent, "--to", range])?,
+                        jj_diff_stats_between(parent, range, iwc),
+                    ),
+                    ContentSource::Jj(parent.clone(), iwc),
+                    ContentSource::Jj(range.clone(), iwc),
+                ),
+                None => (
+                    (jj_diff(&["-r", range])?, jj_diff_stats(range, iwc)),
+                    ContentSource::Jj(format!("roots({range})-"), iwc),
+                    ContentSource::Jj(format!("heads({range})"), iwc),
+                ),
+            },
+            DiffMode::Unstaged => (
+                (jj_diff(&[])?, jj_diff_stats_uncommitted()),
+                ContentSource::Jj("@".to_string(), iwc),
+                ContentSource::WorkingTree(jj_root()),
+            ),
+            // jj doesn't have a staging area concept, so show the current revision
+            DiffMode::Staged => (
+                (jj_diff(&["-r", "@"])?, jj_diff_stats("@", iwc)),
+                ContentSource::Jj("@-".to_string(), iwc),
+                ContentSource::Jj("@".to_string(), iwc),
+            ),
+            DiffMode::WorkingTree(rev) => (
+                (
+                    jj_diff(&["--from", rev])?,
+                    jj_diff_stats_between(rev, "@", iwc),
+                ),
+                ContentSource::Jj(rev.clone(), iwc),
+                ContentSource::WorkingTree(jj_root()),
+            ),
+        };
+
+        Ok(DiffPlan {
+            json,
+            stats,
+            old,
+            new,
+            parents,
+            renames: HashMap::new(),
+            modes: HashMap::new(),
+            submodules: HashMap::new(),
+        })
+    }
+
+    // Snapshotting gives the working copy a new commit whenever a file changes, so commit
+    // ids identify working copy diffs too
+    fn snapshot(&self, mode: &DiffMode, opts: &DiffOptions) -> Option<cache::Snapshot> {
+        let iwc = jj_ignores_working_copy(mode, opts);
+        let (state, working_tree) = match mode {
+            DiffMode::Range(range) => (format!("range {}", jj_commit_ids(range, iwc)?), None),
+            DiffMode::Unstaged => (
+                format!("uncommitted {}", jj_commit_ids("@", iwc)?),
+                jj_root(),
+            ),
+            DiffMode::Staged => (format!("staged {}", jj_commit_ids("@", iwc)?), None),
+            DiffMode::WorkingTree(rev) => (
+                format!(
+                    "worktree {} {}",
+                    jj_commit_ids(rev, iwc)?,
+                    jj_commit_ids("@", iwc)?
+                ),
+                jj_root(),
+            ),
+        };
+        Some(cache::Snapshot {
+            state,
+            working_tree,
+        })
+    }
+}
+
+/// Built-in Mercurial backend, also used for Sapling, whose `sl` command line descends from
+/// Mercurial's and takes the same revsets, templates and `extdiff` extension.
+struct HgBackend {
+    /// The command to run: `hg`, or `sl` for Sapling.
+    program: &'static str,
+}
+
+impl vcs::VcsBackend for HgBackend {
+    fn plan(&self, mode: &DiffMode, opts: &DiffOptions) -> Result<DiffPlan, String> {
+        let parents = match mode {
+            DiffMode::Range(range) => hg_parents(self.program, range),
+            _ => Vec::new(),
+        };
+        let parent_ref = select_parent(&parents, opts.parent)?;
+
+        let pathspec = pathspec_args(self.program, &opts.paths);
+        let pathspec: Vec<&str> = pathspec.iter().map(String::as_str).collect();
+        let hg_diff = |args: &[&str]| -> Result<(String, FileStats), String> {
+            let args = [args, &pathspec].concat();
+            Ok((
+                hg_diff_json(self.program, &args)?,
+                hg_diff_stats(self.program, &args),
+            ))
+        };
+
+        let ((json, stats), old, new) = match mode {
+            DiffMode::Range(range) => {
+                // Diff from the parent of the range's first revision to its last revision
+                let old = parent_ref
+                    .clone()
+                    .unwrap_or_else(|| format!("p1(min({range}))"));
+                let new = format!("max({range})");
+                (
+                    hg_diff(&["-r", &old, "-r", &new])?,
+                    ContentSource::Hg(self.program, old),
+                    ContentSource::Hg(self.program, new),
+                )
+            }
+            DiffMode::Unstaged => (
+                hg_diff(&[])?,
+                ContentSource::Hg(self.program, ".".to_string()),
+                ContentSource::WorkingTree(hg_root(self.program)),
+            ),
+            // Mercurial doesn't have a staging area concept, so show the current revision
+            DiffMode::Staged => (
+                hg_diff(&["-r", "p1(.)", "-r", "."])?,
+                ContentSource::Hg(self.program, "p1(.)".to_string()),
+                ContentSource::Hg(self.program, ".".to_string()),
+            ),
+            DiffMode::WorkingTree(rev) => (
+                hg_diff(&["-r", rev])?,
+                ContentSource::Hg(self.program, rev.clone()),
+                ContentSource::WorkingTree(hg_root(self.program)),
+            ),
+        };
+
+        Ok(DiffPlan {
+            json,
+            stats,
+            old,
+            new,
+            parents,
+            renames: HashMap::new(),
+            modes: HashMap::new(),
+            submodules: HashMap::new(),
+        })
+    }
+
+    fn snapshot(&self, mode: &DiffMode, _opts: &DiffOptions) -> Option<cache::Snapshot> {
+        let (state, working_tree) = match mode {
+            DiffMode::Range(range) => {
+                // The parent of a root revision is the null revision
+                let old = hg_node(self.program, &format!("p1(min({range}))")).unwrap_or_default();
+                let new = hg_node(self.program, &format!("max({range})"))?;
+                (format!("range {old}..{new}"), None)
+            }
+            DiffMode::Unstaged => (
+                format!(
+                    "uncommitted {}\n{}",
+                    hg_node(self.program, ".")?,
+                    hg_status(self.program, &[])?
+                ),
+                hg_root(self.program),
+            ),
+            DiffMode::Staged => (format!("staged {}", hg_node(self.program, ".")?), None),
+            DiffMode::WorkingTree(rev) => {
+                let node = hg_node(self.program, rev)?;
+                let status = hg_status(self.program, &["--rev", &node])?;
+                (format!("worktree {node}\n{status}"), hg_root(self.program))
+            }
+        };
+        Some(cache::Snapshot {
+            state,
+            working_tree,
+        })
+    }
+}
+
+/// Picks the `n` most-changed files for a preview, returning a per-file selection mask.
+///
+/// Files are ranked by VCS line stats, falling back to difftastic's own counts, with ties
+/// broken by path so the same diff always previews the same files.
+fn preview_selection(files: &[difftastic::DifftFile], stats: &FileStats, n: usize) -> Vec<bool> {
+    let size = |file: &difftastic::DifftFile| {
+        let (additions, deletions) = stats
+            .get(&file.path)
+            .copied()
+            .unwrap_or_else(|| processor::chunk_stats(&file.chunks));
+        additions + deletions
+    };
+    let mut ranked: Vec<usize> = (0..files.len()).collect();
+    ranked.sort_by(|&a, &b| {
+        size(&files[b])
+            .cmp(&size(&files[a]))
+            .then_with(|| files[a].path.cmp(&files[b].path))
+    });
+
+    let mut selected = vec![false; files.len()];
+    for &i in ranked.iter().take(n) {
+        selected[i] = true;
+    }
+    selected
+}
+
+/// Rows a diff may produce before the remaining files are degraded to stats-only entries,
+/// which keeps pathological diffs from building huge Lua tables.
+const DEFAULT_MAX_ROWS: usize = 200_000;
+
+/// Deselects files once the rows of the selected files add up to more than `max_rows`,
+/// returning whether any file was dropped.
+///
+/// Rows are estimated from difftastic's alignment, or from the changed lines when it has
+/// none. Files are kept in order, so the first files of a diff are always complete.
+fn budget_selection(
+    files: &[difftastic::DifftFile],
+    stats: &FileStats,
+    selected: &mut [bool],
+    max_rows: usize,
+) -> bool {
+    let rows = |file: &difftastic::DifftFile| {
+        if !file.aligned_lines.is_empty() {
+            return file.aligned_lines.len();
+        }
+        let (additions, deletions) = stats
+            .get(&file.path)
+            .copied()
+            .unwrap_or_else(|| processor::chunk_stats(&file.chunks));
+        (additions + deletions) as usize
+    };
+
+    let mut total = 0;
+    let mut exceeded = false;
+    for (file, selected) in files.iter().zip(selected) {
+        if !*selected {
+            continue;
+        }
+        total += rows(file);
+        if exceeded || total > max_rows {
+            *selected = false;
+            exceeded = true;
+        }
+    }
+    exceeded
+}
+
+impl DiffPlan {
+    /// Parses difftastic's output into files with repository-relative paths, marking the
+    /// VCS's renames. Identical files, listed when diffing directories, are left out unless
+    /// their mode changed; mode changes missing from the output are added as identical files.
+    fn files(&self) -> Result<Vec<difftastic::DifftFile>, String> {
+        let mut files = difftastic::parse(&self.json)
+            .map_err(|e| format!("Failed to parse difftastic JSON: {e}"))?;
+        relativize_paths(&mut files, &self.stats);
+        apply_renames(&mut files, &self.renames);
+        files.retain(|file| {
+            file.status != difftastic::Status::Unchanged || self.modes.contains_key(&file.path)
+        });
+        // Mode changes and submodules may not reach difftastic at all
+        let listed: HashSet<PathBuf> = files.iter().map(|file| file.path.clone()).collect();
+        let mut missing: Vec<&PathBuf> = self
+            .modes
+            .keys()
+            .chain(self.submodules.keys())
+            .filter(|path| !listed.contains(*path))
+            .collect();
+        missing.sort();
+        missing.dedup();
+        for path in missing {
+            let mut file = difftastic::DifftFile {
+                path: path.clone(),
+                old_path: None,
+                language: String::new(),
+                status: difftastic::Status::Unchanged,
+                aligned_lines: Vec::new(),
+                chunks: Vec::new(),
+            };
+            if let Some(old) = self.renames.get(path) {
+                file.rename_from(old.clone());
+            }
+            files.push(file);
+        }
+        Ok(files)
+    }
+}
+
+/// Processed output of a diff.
+#[derive(Clone)]
+struct DiffResult {
+    files: Vec<processor::DisplayFile>,
+    /// Whether some files only have stats because of `DiffOptions::preview`.
+    preview: bool,
+    /// Whether some files only have stats because of `DiffOptions::max_rows`.
+    budget_exceeded: bool,
+    /// Files left out by `DiffOptions::filter`.
+    hidden_files: usize,
+    /// Parents of the diffed commit, for single-commit diffs.
+    parents: Vec<ParentCommit>,
+    /// Full commit hashes of the old and new sides, when they are commits. Together with
+    /// file paths and hunk line ranges this is enough to build permalinks.
+    old_commit: Option<String>,
+    new_commit: Option<String>,
+    /// Set when a jj range names a divergent change or a hidden commit.
+    warning: Option<divergence::Warning>,
+    /// How often processing found a changed line's highlights in the run's memo (see
+    /// [`memo`]). Zero for diffs reused from a cache.
+    highlight_memo: memo::Stats,
+}
+
+/// Runs difftastic for `mode`, fetches file contents and processes files in parallel.
+/// Only one diff of a repository runs at a time (see [`repo::serialized`]).
+/// Fails with [`cancel::CANCELLED`] if the current thread's token gets cancelled.
+///
+/// Processed diffs are cached by what the VCS backend resolves `mode` to (see
+/// [`cache`]), so re-opening an unchanged diff skips difftastic entirely.
+///
+/// jj ranges naming a divergent change or a hidden commit get a warning listing the
+/// candidate commits (see [`divergence`]), checked on every run as it can change anytime.
+fn compute_diff(mode: &DiffMode, vcs: &str, opts: &DiffOptions) -> Result<DiffResult, String> {
+    let diff = repo::serialized(|| {
+        let warning = match mode {
+            DiffMode::Range(range) if vcs == "jj" => {
+                divergence::check(range, jj_ignores_working_copy(mode, opts))
+            }
+            _ => None,
+        };
+        compute_diff_unwarned(mode, vcs, opts).map(|diff| DiffResult { warning, ..diff })
+    });
+    // Commands killed by a cancellation fail with errors of their own
+    if cancel::cancelled() {
+        return Err(cancel::CANCELLED.to_string());
+    }
+    diff
+}
+
+/// [`compute_diff`] without the divergence check, to be called in [`repo::serialized`].
+fn compute_diff_unwarned(
+    mode: &DiffMode,
+    vcs: &str,
+    opts: &DiffOptions,
+) -> Result<DiffResult, String> {
+    let key = vcs::backend(vcs)
+        .snapshot(mode, opts)
+        .map(|snapshot| cache::Key::new(vcs, snapshot, opts));
+    if let Some(diff) = key.as_ref().and_then(cache::get) {
+        return Ok(reuse_cached(diff, opts));
+    }
+    if let (Some(dir), Some(key)) = (&opts.cache_dir, &key)
+        && let Some(diff) = disk_cache::load(dir, key)
+    {
+        cache::insert(key.clone(), diff.clone());
+        return Ok(reuse_cached(diff, opts));
+    }
+    let prepared = match prefetch::cached(mode, vcs, opts) {
+        Some(prepared) => prepared,
+        None => std::sync::Arc::new(prefetch::PreparedDiff::new(plan_diff(mode, vcs, opts)?)),
+    };
+    let diff = process_plan(&prepared, opts)?;
+    // Streamed diffs hand their files over instead of keeping them
+    let streamed = progress::current().is_some_and(|tracker| tracker.streams());
+    if let Some(key) = key
+        && !streamed
+        && !cancel::cancelled()
+    {
+        // Failing to save only costs a later re-run
+        if let Some(dir) = &opts.cache_dir {
+            let _ = disk_cache::store(dir, &key, &diff);
+        }
+        cache::insert(key, diff.clone());
+    }
+    Ok(diff)
+}
+
+/// Prepares a cached diff for another caller: refreshes relative commit times, and hands the
+/// files over to a streaming tracker like [`process_plan`] does.
+fn reuse_cached(mut diff: DiffResult, opts: &DiffOptions) -> DiffResult {
+    diff.parents = relative_times(diff.parents, &opts.time_format);
+    diff.highlight_memo = memo::Stats::default();
+    if let Some(tracker) = progress::current().filter(progress::Tracker::streams) {
+        tracker.start(progress::Phase::Files, diff.files.len());
+        for (index, file) in std::mem::take(&mut diff.files).into_iter().enumerate() {
+            tracker.deliver(index, file);
+            tracker.advance();
+        }
+    }
+    diff
+}
+
+/// Fills in the relative commit time of each parent, e.g. "3 days ago".
+fn relative_times(parents: Vec<ParentCommit>, format: &timestamp::TimeFormat) -> Vec<ParentCommit> {
+    parents
+        .into_iter()
+        .map(|parent| ParentCommit {
+            relative_time: parent.timestamp.map(|t| format.relative_to_now(t)),
+            ..parent
+        })
+        .collect()
+}
+
+/// Files whose contents may be held at once while processing a diff, fetched but not yet
+/// processed or processed but waiting for an earlier file. Bounds the memory of diffs with
+/// many huge files.
+const MAX_FILES_IN_FLIGHT: usize = 64;
+
+/// Processes the files of a prepared diff in parallel.
+///
+/// Contents are fetched in windows of [`MAX_FILES_IN_FLIGHT`] files, and at most that many
+/// files are processed or waiting at a time. Processed files come out in diff order: a
+/// streaming tracker gets each one as soon as the files before it are done.
+fn process_plan(
+    prepared: &prefetch::PreparedDiff,
+    opts: &DiffOptions,
+) -> Result<DiffResult, String> {
+    let plan = &prepared.plan;
+    let mut files = plan.files()?;
+    let listed = files.len();
+    files.retain(|file| opts.filter.keeps(&file.path));
+    let hidden_files = listed - files.len();
+
+    let mut selected = match opts.preview {
+        Some(n) if files.len() > n => preview_selection(&files, &plan.stats, n),
+        _ => vec![true; files.len()],
+    };
+    let preview = selected.contains(&false);
+    let budget_exceeded = budget_selection(
+        &files,
+        &plan.stats,
+        &mut selected,
+        opts.max_rows.unwrap_or(DEFAULT_MAX_ROWS),
+    );
+
+    progress::start(progress::Phase::Files, files.len());
+    let repo = repo::current();
+    let token = cancel::current();
+    let memo = std::sync::Arc::new(memo::Memo::default());
+    let tracker = progress::current();
+    // Streamed files go to the tracker instead of the result, listed before any is processed
+    let stream = tracker.as_ref().filter(|tracker| tracker.streams());
+    if let Some(tracker) = stream {
+        for (index, file) in files.iter().enumerate() {
+            tracker.deliver(
+                index,
+                processor::listing(file, plan.stats.get(&file.path).copied()),
+            );
+        }
+    }
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    // A bounded channel used as a semaphore: one message per file in flight
+    let (slot_tx, slot_rx) = std::sync::mpsc::sync_channel(MAX_FILES_IN_FLIGHT);
+    let (files, produced) = std::thread::scope(|threads| {
+        // Collects the processed files in diff order, handing each over as soon as the files
+        // before it are done, and frees its slot
+        let tracker = tracker.as_ref();
+        let collector = threads.spawn(move || {
+            let mut pending = std::collections::BTreeMap::new();
+            let mut files = Vec::new();
+            for (index, file) in done_rx {
+                pending.insert(index, file);
+                while let Some(file) = pending.remove(&files.len()) {
+                    let index = files.len();
+                    let _ = slot_rx.recv();
+                    if let Some(tracker) = tracker {
+                        tracker.advance();
+                    }
+                    match stream {
+                        Some(tracker) => {
+                            tracker.deliver(index, file);
+                            files.push(None);
+                        }
+                        None => files.push(Some(file)),
+                    }
+                }
+            }
+            files.into_iter().flatten().collect::<Vec<_>>()
+        });
+        let produced = limits::in_place_scope(|scope| {
+            let mut files = files.into_iter().zip(selected).enumerate();
+            loop {
+                let window: Vec<_> = files.by_ref().take(MAX_FILES_IN_FLIGHT).collect();
+                if window.is_empty() {
+                    return Ok(());
+                }
+                // Read the window's selected contents in bulk where the sources allow, instead
+                // of one command per file and side
+                let batch = std::sync::Arc::new(
+                    prepared.fetch_batch(
+                        window
+                            .iter()
+                            .filter(|(_, (file, selected))| {
+                                *selected
+                                    && file.status != difftastic::Status::Unchanged
+                                    && !plan.submodules.contains_key(&file.path)
+                            })
+                            .map(|(_, (file, _))| (file.old_path(), file.new_path())),
+                    )?,
+                );
+                for (index, (file, selected)) in window {
+                    if slot_tx.send(()).is_err() {
+                        return Err("Processing files stopped unexpectedly".to_string());
+                    }
+                    let file_stats = plan.stats.get(&file.path).copied();
+                    let mode_change = plan.modes.get(&file.path).cloned();
+                    let with_mode = |mut display| {
+                        if let Some(change) = mode_change {
+                            processor::add_mode_change(&mut display, change);
+                        }
+                        display
+                    };
+                    // Once cancelled, skip the remaining work; the result is thrown away anyway
+                    if !selected || token.as_ref().is_some_and(cancel::Token::is_cancelled) {
+                        let display = with_mode(processor::stats_only(file, file_stats));
+                        let _ = done_tx.send((index, display));
+                        continue;
+                    }
+                    if let Some(change) = plan.submodules.get(&file.path) {
+                        let (done_tx, mut change) = (done_tx.clone(), change.clone());
+                        let repo = repo.as_deref();
+                        scope.spawn(move |_| {
+                            if opts.submodule_log {
+                                change.log =
+                                    repo::in_repo(repo, || git_submodule_log(&file.path, &change));
+                            }
+                            let _ = done_tx.send((index, processor::submodule(file, change)));
+                        });
+                        continue;
+                    }
+                    // Files only listed for a mode change have no content to diff
+                    if file.status == difftastic::Status::Unchanged
+                        && let Some(change) = plan.modes.get(&file.path)
+                    {
+                        let display = processor::mode_only(file, change.clone());
+                        let _ = done_tx.send((index, display));
+                        continue;
+                    }
+                    let (done_tx, batch) = (done_tx.clone(), batch.clone());
+                    let (repo, token, memo) = (repo.as_deref(), token.clone(), memo.clone());
+                    if file.is_binary() {
+                        scope.spawn(move |_| {
+                            let (old, new) = cancel::with_token(token, || {
+                                repo::in_repo(repo, || {
+                                    (plan.old.size(file.old_path()), plan.new.size(&file.path))
+                                })
+                            });
+                            let _ =
+                                done_tx.send((index, with_mode(processor::binary(file, old, new))));
+                        });
+                        continue;
+                    }
+                    scope.spawn(move |_| {
+                        let path = file.path.clone();
+                        let file = guard::with_file(&path, || {
+                            let (old, new) = cancel::with_token(token, || {
+                                repo::in_repo(repo, || {
+                                    prepared.contents(&batch, file.old_path(), file.new_path())
+                                })
+                            });
+                            memo::with_memo(Some(memo), || {
+                                processor::process_or_degrade(file, file_stats, |file| {
+                                    processor::process_contents(
+                                        file,
+                                        old.as_deref(),
+                                        new.as_deref(),
+                                        file_stats,
+                                        &opts.process,
+                                    )
+                                })
+                            })
+                        });
+                        let _ = done_tx.send((index, with_mode(file)));
+                    });
+                }
+            }
+        });
+        // The collector finishes once the last spawned file is done
+        drop(done_tx);
+        let files = collector
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        (files, produced)
+    });
+    produced?;
+
+    let parents = relative_times(plan.parents.clone(), &opts.time_format);
+
+    Ok(DiffResult {
+        files,
+        preview,
+        budget_exceeded,
+        hidden_files,
+        parents,
+        old_commit: plan.old.resolve_commit(),
+        new_commit: plan.new.resolve_commit(),
+        warning: None,
+        highlight_memo: memo.stats(),
+    })
+}
+
+/// Unified implementation for running difftastic with any diff mode.
+/// Handles git, jj and hg, and converts the processed files into a Lua result table.
+fn run_diff_impl(lua: &Lua, mode: DiffMode, vcs: &str, opts: &DiffOptions) -> LuaResult<LuaTable> {
+    if opts.dry_run {
+        return dry_run_table(lua, &mode, vcs, opts);
+    }
+    let diff = repo::in_repo(opts.cwd.as_deref(), || compute_diff(&mode, vcs, opts))
+        .map_err(LuaError::RuntimeError)?;
+    diff_result_table(lua, diff)
+}
+
+/// Records the commands running difftastic for `mode` would take, returned as
+/// `{ commands = { { program, args, cwd, env }, ... } }`. Skips the cache, which would
+/// hide them.
+fn dry_run_table(lua: &Lua, mode: &DiffMode, vcs: &str, opts: &DiffOptions) -> LuaResult<LuaTable> {
+    let (_, commands) =
+        dry_run::record(|| repo::in_repo(opts.cwd.as_deref(), || plan_diff(mode, vcs, opts)));
+    let result = lua.create_table()?;
+    result.set("commands", lua.create_sequence_from(commands)?)?;
+    Ok(result)
+}
+
+/// Builds the `{ files, preview, budget_exceeded, hidden_files, parents, old_commit, new_commit, complexity, warning, highlight_memo }` table returned by the `run_diff*` functions.
+fn diff_result_table(lua: &Lua, diff: DiffResult) -> LuaResult<LuaTable> {
+    let complexity = complexity::measure(&diff.files);
+    let files_table = lua.create_table()?;
+    for (i, file) in diff.files.into_iter().enumerate() {
+        files_table.set(i + 1, file.into_lua(lua)?)?;
+    }
+
+    let result = lua.create_table()?;
+    result.set("files", files_table)?;
+    result.set("preview", diff.preview)?;
+    result.set("budget_exceeded", diff.budget_exceeded)?;
+    result.set("hidden_files", diff.hidden_files)?;
+    result.set("old_commit", diff.old_commit)?;
+    result.set("new_commit", diff.new_commit)?;
+    result.set("complexity", complexity)?;
+    result.set("warning", diff.warning)?;
+    result.set("highlight_memo", diff.highlight_memo)?;
+    if !diff.parents.is_empty() {
+        result.set("parents", lua.create_sequence_from(diff.parents)?)?;
+    }
+    Ok(result)
+}
+
+/// Runs difftastic for a commit range.
+///
+/// Called either as `run_diff(range, vcs, opts)` or as `run_diff(range, opts)` with the VCS
+/// in `opts.vcs`, detected from the repository when left out.
+fn run_diff(
+    lua: &Lua,
+    (range, vcs_or_opts, opts): (String, LuaValue, LuaValue),
+) -> LuaResult<LuaTable> {
+    let (vcs, opts) = range_args(lua, vcs_or_opts, opts)?;
+    run_diff_impl(lua, DiffMode::Range(range), &vcs, &opts)
+}
+
+/// Reads the VCS and options of `run_diff`, whose second argument is either the VCS name or
+/// the options table.
+fn range_args(
+    lua: &Lua,
+    vcs_or_opts: LuaValue,
+    opts: LuaValue,
+) -> LuaResult<(String, DiffOptions)> {
+    Ok(match vcs_or_opts {
+        LuaValue::String(vcs) => (vcs.to_str()?.to_string(), DiffOptions::from_lua(opts, lua)?),
+        opts => {
+            let opts = DiffOptions::from_lua(opts, lua)?;
+            let vcs = opts.vcs.clone().unwrap_or_else(|| {
+                repo::in_repo(opts.cwd.as_deref(), repo::dir)
+                    .as_deref()
+                    .and_then(vcs::detect)
+                    .unwrap_or("jj")
+                    .to_string()
+            });
+            (vcs, opts)
+        }
+    })
+}
+
+/// A diff running on a background thread, returned by `run_diff_async`.
+struct DiffJob {
+    result: std::sync::mpsc::Receiver<Result<DiffResult, String>>,
+    /// Stops the diff: kills its commands and skips the files not processed yet.
+    cancel: cancel::Token,
+    progress: progress::Tracker,
+    /// Whether `poll` already returned the outcome.
+    delivered: bool,
+}
+
+impl LuaUserData for DiffJob {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // Returns `false` while running, then `true, result` or `true, nil, error` once
+        methods.add_method_mut("poll", |lua, job, ()| {
+            use std::sync::mpsc::TryRecvError;
+            if job.delivered {
+                return Ok((true, None, Some("Result was already delivered".to_string())));
+            }
+            let outcome = match job.result.try_recv() {
+                Err(TryRecvError::Empty) => return Ok((false, None, None)),
+                Err(TryRecvError::Disconnected) => Err("Diff panicked".to_string()),
+                Ok(outcome) => outcome,
+            };
+            job.delivered = true;
+            match outcome {
+                Ok(diff) => Ok((true, Some(diff_result_table(lua, diff)?), None)),
+                Err(error) => Ok((true, None, Some(error))),
+            }
+        });
+        // `{ phase = "processing files", done = 12, total = 40 }`, total 0 if uncounted
+        // `{ { index = 3, file = ... }, ... }` for the files ready since the last call, when
+        // streaming; a file's processed entry replaces its earlier stats-only one
+        methods.add_method("take_files", |lua, job, ()| {
+            let updates = lua.create_table()?;
+            for (i, update) in job.progress.take_files().into_iter().enumerate() {
+                let table = lua.create_table()?;
+                table.set("index", update.index + 1)?;
+                table.set("file", update.file.into_lua(lua)?)?;
+                updates.set(i + 1, table)?;
+            }
+            Ok(updates)
+        });
+        methods.add_method("progress", |lua, job, ()| {
+            let status = job.progress.status();
+            let table = lua.create_table()?;
+            table.set("phase", status.phase.as_str())?;
+            table.set("done", status.done)?;
+            table.set("total", status.total)?;
+            Ok(table)
+        });
+        // Later polls report the cancellation once the thread has stopped
+        methods.add_method("cancel", |_, job, ()| {
+            job.cancel.cancel();
+            Ok(())
+        });
+    }
+}
+
+/// Runs difftastic for a commit range on a background thread, so subprocesses and
+/// processing don't block Neovim. Takes the same arguments as `run_diff` and returns a
+/// [`DiffJob`] to poll from the main loop, e.g. from a `vim.uv` timer.
+fn run_diff_async(
+    lua: &Lua,
+    (range, vcs_or_opts, opts): (String, LuaValue, LuaValue),
+) -> LuaResult<LuaAnyUserData> {
+    let (vcs, opts) = range_args(lua, vcs_or_opts, opts)?;
+    // Neovim's working directory may change before the thread runs
+    let dir = opts.cwd.clone().or_else(repo::dir);
+    let (sender, result) = std::sync::mpsc::channel();
+    let cancel = cancel::Token::default();
+    let progress = if opts.stream {
+        progress::Tracker::streaming()
+    } else {
+        progress::Tracker::default()
+    };
+    let (token, tracker) = (cancel.clone(), progress.clone());
+    std::thread::spawn(move || {
+        let mode = DiffMode::Range(range);
+        let diff = guard::catch("run_diff_async", || {
+            cancel::with_token(Some(token), || {
+                progress::with_tracker(Some(tracker), || {
+                    repo::in_repo(dir.as_deref(), || compute_diff(&mode, &vcs, &opts))
+                })
+            })
+        })
+        .and_then(|diff| diff);
+        // The job may have been dropped by Lua in the meantime
+        let _ = sender.send(diff);
+    });
+    lua.create_userdata(DiffJob {
+        result,
+        cancel,
+        progress,
+        delivered: false,
+    })
+}
+
+/// Runs difftastic for unstaged changes.
+fn run_diff_unstaged(lua: &Lua, (vcs, opts): (String, DiffOptions)) -> LuaResult<LuaTable> {
+    run_diff_impl(lua, DiffMode::Unstaged, &vcs, &opts)
+}
+
+/// Runs difftastic for staged changes.
+fn run_diff_staged(lua: &Lua, (vcs, opts): (String, DiffOptions)) -> LuaResult<LuaTable> {
+    run_diff_impl(lua, DiffMode::Staged, &vcs, &opts)
+}
+
+/// Runs difftastic for a named working copy mode (see [`DiffMode::named`]).
+fn run_diff_mode(
+    lua: &Lua,
+    (mode, vcs, opts): (String, String, DiffOptions),
+) -> LuaResult<LuaTable> {
+    let mode = DiffMode::named(&mode, &vcs).map_err(LuaError::RuntimeError)?;
+    run_diff_impl(lua, mode, &vcs, &opts)
+}
+
+/// Registers a user-defined VCS described by command templates (see the `vcs` module).
+fn register_vcs(_: &Lua, (name, spec): (String, vcs::CommandSpec)) -> LuaResult<()> {
+    vcs::register(name, std::sync::Arc::new(spec)).map_err(LuaError::RuntimeError)
+}
+
+/// Saves the hunks of a range diff to the baseline file `path`.
+fn save_baseline(
+    _: &Lua,
+    (range, vcs, path, opts): (String, String, String, DiffOptions),
+) -> LuaResult<()> {
+    let cwd = opts.cwd.clone();
+    let (diff, fingerprints) = repo::in_repo(cwd.as_deref(), || {
+        baseline_diff(&DiffMode::Range(range), &vcs, opts)
+    })
+    .map_err(LuaError::RuntimeError)?;
+    baseline::Baseline::from_files(&diff.files, fingerprints)
+        .write(Path::new(&path))
+        .map_err(LuaError::RuntimeError)
+}
+
+/// Writes the processed files of a range diff to `path` as JSON (see the `export` module).
+/// All files are fully processed unless `opts` ask for a preview or a row budget.
+fn export_json(
+    _: &Lua,
+    (range, vcs, path, opts): (String, String, String, DiffOptions),
+) -> LuaResult<()> {
+    let diff = repo::in_repo(opts.cwd.as_deref(), || {
+        compute_diff(&DiffMode::Range(range.clone()), &vcs, &opts)
+    })
+    .map_err(LuaError::RuntimeError)?;
+    export::Export::new(&range, &vcs, &diff)
+        .write(Path::new(&path))
+        .map_err(LuaError::RuntimeError)
+}
+
+/// Runs difftastic for a range, keeping only hunks missing from the baseline at `path`.
+fn run_diff_since_baseline(
+    lua: &Lua,
+    (range, vcs, path, opts): (String, String, String, DiffOptions),
+) -> LuaResult<LuaTable> {
+    let baseline = baseline::Baseline::read(Path::new(&path)).map_err(LuaError::RuntimeError)?;
+    let cwd = opts.cwd.clone();
+    let (mut diff, fingerprints) = repo::in_repo(cwd.as_deref(), || {
+        baseline_diff(&DiffMode::Range(range), &vcs, opts)
+    })
+    .map_err(LuaError::RuntimeError)?;
+    diff.files = baseline.retain_new_hunks(diff.files, fingerprints);
+    diff_result_table(lua, diff)
+}
+
+/// Processes a diff for comparing against a baseline, along with the fingerprints of each
+/// file's hunks (see [`baseline::original_fingerprints`]). Files are always fully
+/// processed, since hunks of stats-only files can't be compared.
+fn baseline_diff(
+    mode: &DiffMode,
+    vcs: &str,
+    opts: DiffOptions,
+) -> Result<(DiffResult, Vec<Vec<baseline::Fingerprint>>), String> {
+    let opts = DiffOptions {
+        preview: None,
+        max_rows: Some(usize::MAX),
+        ..opts
+    };
+    let result = repo::serialized(|| {
+        let prepared = match prefetch::cached(mode, vcs, &opts) {
+            Some(prepared) => prepared,
+            None => std::sync::Arc::new(prefetch::PreparedDiff::new(plan_diff(mode, vcs, &opts)?)),
+        };
+        let diff = process_plan(&prepared, &opts)?;
+        let paths: Vec<(&Path, &Path)> = diff
+            .files
+            .iter()
+            .map(|file| {
+                (
+                    file.old_path.as_deref().unwrap_or(&file.path),
+                    file.path.as_path(),
+                )
+            })
+            .collect();
+        let batch = prepared.fetch_batch(paths.iter().copied())?;
+        let fingerprints = diff
+            .files
+            .iter()
+            .zip(&paths)
+            .map(|(file, (old_path, new_path))| {
+                let (old, new) = prepared.contents(&batch, old_path, new_path);
+                baseline::original_fingerprints(file, old.as_deref(), new.as_deref())
+            })
+            .collect();
+        Ok((diff, fingerprints))
+    });
+    // Commands killed by a cancellation fail with errors of their own
+    if cancel::cancelled() {
+        return Err(cancel::CANCELLED.to_string());
+    }
+    result
+}
+
+/// Drops every processed and prefetched diff kept in memory, and the diffs saved in
+/// `cache_dir` if given.
+fn clear_cache(_: &Lua, cache_dir: Option<String>) -> LuaResult<()> {
+    cache::clear();
+    prefetch::clear();
+    match cache_dir {
+        Some(dir) => disk_cache::clear(Path::new(&dir)).map_err(LuaError::RuntimeError),
+        None => Ok(()),
+    }
+}
+
+/// Prepares the diffs of `ranges` in the background so later `run_diff` calls are instant.
+fn prefetch(_: &Lua, (ranges, vcs): (Vec<String>, String)) -> LuaResult<()> {
+    prefetch::spawn(vcs, ranges);
+    Ok(())
+}
+
+/// Runs the same diff in several repositories in parallel, e.g. a workspace of plugins.
+///
+/// `range` is a commit range, or `nil` for unstaged changes. Returns a table keyed by
+/// repository root, holding either the usual result of `run_diff` or `{ error = message }`,
+/// so one broken repository doesn't hide the others.
+fn run_diff_repos(
+    lua: &Lua,
+    (roots, range, vcs, opts): (Vec<String>, Option<String>, String, DiffOptions),
+) -> LuaResult<LuaTable> {
+    let mode = range.map_or(DiffMode::Unstaged, DiffMode::Range);
+    // Plain threads rather than rayon jobs, which could deadlock on the per-repo lock
+    let diffs: Vec<_> = std::thread::scope(|scope| {
+        let handles: Vec<_> = roots
+            .iter()
+            .map(|root| {
+                scope.spawn(|| {
+                    repo::in_repo(Some(Path::new(root)), || compute_diff(&mode, &vcs, &opts))
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err("Diff panicked".to_string()))
+            })
+            .collect()
+    });
+
+    let result = lua.create_table()?;
+    for (root, diff) in roots.into_iter().zip(diffs) {
+        result.set(root, outcome_table(lua, diff)?)?;
+    }
+    Ok(result)
+}
+
+/// The result table of a diff, or `{ error = message }` if it failed.
+fn outcome_table(lua: &Lua, diff: Result<DiffResult, String>) -> LuaResult<LuaTable> {
+    match diff {
+        Ok(diff) => diff_result_table(lua, diff),
+        Err(error) => {
+            let table = lua.create_table()?;
+            table.set("error", error)?;
+            Ok(table)
+        }
+    }
+}
+
+/// One diff of a `run_diffs` batch.
+struct DiffRequest {
+    mode: DiffMode,
+    vcs: String,
+    opts: DiffOptions,
+}
+
+/// Reads a `run_diffs` request: `{ id, range, mode, vcs, opts }`. `range` is a revset or
+/// commit range, `mode` one of the working copy modes of [`DiffMode::named`]; without
+/// either, the request diffs the uncommitted changes. The VCS is detected like for
+/// `run_diff` unless given.
+fn diff_request(lua: &Lua, request: &LuaTable) -> LuaResult<DiffRequest> {
+    let opts: LuaValue = request.get("opts")?;
+    let (vcs, opts) = match request.get::<LuaValue>("vcs")? {
+        vcs @ LuaValue::String(_) => range_args(lua, vcs, opts)?,
+        _ => range_args(lua, opts, LuaValue::Nil)?,
+    };
+    let mode = match (
+        request.get::<Option<String>>("range")?,
+        request.get::<Option<String>>("mode")?,
+    ) {
+        (Some(range), _) => DiffMode::Range(range),
+        (None, Some(mode)) => DiffMode::named(&mode, &vcs).map_err(LuaError::RuntimeError)?,
+        (None, None) => DiffMode::Unstaged,
+    };
+    Ok(DiffRequest { mode, vcs, opts })
+}
+
+/// Runs several diffs in one call, e.g. the staged and unstaged changes and the last commit
+/// for a dashboard.
+///
+/// Returns a table keyed by each request's `id` (its position if unset), holding the usual
+/// result of `run_diff` or `{ error = message }`. Diffs share the caches and the thread
+/// pool processing files; diffs of one repository run in request order.
+fn run_diffs(lua: &Lua, requests: Vec<LuaTable>) -> LuaResult<LuaTable> {
+    let mut ids = Vec::with_capacity(requests.len());
+    let mut jobs = Vec::with_capacity(requests.len());
+    for (i, request) in requests.iter().enumerate() {
+        let id = match request.get::<LuaValue>("id")? {
+            LuaValue::Nil => LuaValue::Integer((i + 1) as LuaInteger),
+            id => id,
+        };
+        ids.push(id);
+        jobs.push(diff_request(lua, request)?);
+    }
+
+    // One thread per repository running its diffs in order, since they'd wait for the
+    // repository's lock anyway. Plain threads rather than rayon jobs, which could deadlock
+    // on that lock
+    let mut by_repo: Vec<(Option<PathBuf>, Vec<usize>)> = Vec::new();
+    for (i, job) in jobs.iter().enumerate() {
+        let dir = repo::in_repo(job.opts.cwd.as_deref(), repo::dir);
+        match by_repo.iter_mut().find(|(repo, _)| *repo == dir) {
+            Some((_, indices)) => indices.push(i),
+            None => by_repo.push((dir, vec![i])),
+        }
+    }
+    let mut diffs: Vec<Option<Result<DiffResult, String>>> = jobs.iter().map(|_| None).collect();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = by_repo
+            .iter()
+            .map(|(dir, indices)| {
+                let jobs = &jobs;
+                scope.spawn(move || {
+                    repo::in_repo(dir.as_deref(), || {
+                        indices
+                            .iter()
+                            .map(|&i| (i, compute_diff(&jobs[i].mode, &jobs[i].vcs, &jobs[i].opts)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+            })
+            .collect();
+        for handle in handles {
+            // A panicking repository leaves its diffs unset, reported below
+            for (i, diff) in handle.join().unwrap_or_default() {
+                diffs[i] = Some(diff);
+            }
+        }
+    });
+
+    let result = lua.create_table()?;
+    for (id, diff) in ids.into_iter().zip(diffs) {
+        let diff = diff.unwrap_or_else(|| Err("Diff panicked".to_string()));
+        result.set(id, outcome_table(lua, diff)?)?;
+    }
+    Ok(result)
+}
+
+/// A mutable commit visible at some jj operation.
+struct JjOpCommit {
+    change_id: String,
+    commit_id: String,
+    description: String,
+}
+
+/// Lists the mutable commits visible at operation `op`. Reading the repository at an
+/// operation never snapshots the working copy.
+fn jj_commits_at_op(op: &str) -> Result<Vec<JjOpCommit>, String> {
+    let template = r#"change_id ++ "\t" ++ commit_id ++ "\t" ++ description.first_line() ++ "\n""#;
+    let output = jj_command(true)
+        .args([
+            "--at-op",
+            op,
+            "log",
+            "--no-graph",
+            "-r",
+            "mutable()",
+            "-T",
+            template,
+        ])
+        .output_or_cancel()
+        .map_err(|e| format!("Failed to run jj: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("jj command failed: {stderr}"));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            Some(JjOpCommit {
+                change_id: fields.next()?.to_string(),
+                commit_id: fields.next()?.to_string(),
+                description: fields.next().unwrap_or_default().to_string(),
+            })
+        })
+        .collect())
+}
+
+/// Diffs jj commit `new` against an earlier version `old` of the same change, or against
+/// its parents when `old` is `None`. Commits are read by id, so hidden ones work too and
+/// the working copy is never snapshotted.
+fn jj_commit_diff(old: Option<&str>, new: &str, opts: &DiffOptions) -> Result<DiffResult, String> {
+    let pathspec = pathspec_args("jj", &opts.paths);
+    let pathspec: Vec<&str> = pathspec.iter().map(String::as_str).collect();
+    let (old, json) = match old {
+        Some(old) => (
+            old.to_string(),
+            jj_diff_json(
+                &[&["--from", old, "--to", new], &pathspec[..]].concat(),
+                true,
+            )?,
+        ),
+        None => (
+            format!("roots({new})-"),
+            jj_diff_json(&[&["-r", new], &pathspec[..]].concat(), true)?,
+        ),
+    };
+    let plan = DiffPlan {
+        json,
+        stats: jj_diff_stats_between(&old, new, true),
+        old: ContentSource::Jj(old, true),
+        new: ContentSource::Jj(new.to_string(), true),
+        parents: Vec::new(),
+        renames: HashMap::new(),
+        modes: HashMap::new(),
+        submodules: HashMap::new(),
+    };
+    process_plan(&prefetch::PreparedDiff::new(plan), opts)
+}
+
+/// Diffs what jj operation `op` changed: every mutable change it rewrote is diffed from
+/// its version before the operation to its version after, and every change it created
+/// against its parents. Abandoned changes aren't listed.
+///
+/// Returns one entry per change, in `jj log` order, with the usual `run_diff` result plus
+/// `change_id` and `description`.
+fn run_op_diff(lua: &Lua, (op, opts): (String, DiffOptions)) -> LuaResult<LuaTable> {
+    let diffs = repo::serialized(|| {
+        let before: HashMap<String, String> = jj_commits_at_op(&format!("{op}-"))?
+            .into_iter()
+            .map(|commit| (commit.change_id, commit.commit_id))
+            .collect();
+        let mut diffs = Vec::new();
+        for commit in jj_commits_at_op(&op)? {
+            let old = before.get(&commit.change_id);
+            if old == Some(&commit.commit_id) {
+                continue;
+            }
+            let diff = jj_commit_diff(old.map(String::as_str), &commit.commit_id, &opts)?;
+            diffs.push((commit, diff));
+        }
+        Ok::<_, String>(diffs)
+    })
+    .map_err(LuaError::RuntimeError)?;
+
+    let entries = diffs
+        .into_iter()
+        .map(|(commit, diff)| {
+            let table = diff_result_table(lua, diff)?;
+            table.set("change_id", commit.change_id)?;
+            table.set("description", commit.description)?;
+            Ok(table)
+        })
+        .collect::<LuaResult<Vec<_>>>()?;
+    lua.create_sequence_from(entries)
+}
+
+/// Lists the versions of a jj change from oldest to newest, from `jj evolog`, as
+/// `(commit, description, timestamp)` summaries.
+fn jj_evolution(change: &str) -> Result<Vec<ParentCommit>, String> {
+    // jj 0.30 made evolog templates take an evolution entry instead of a commit
+    let fields = |commit: &str| {
+        format!(
+            r#"{commit}commit_id() ++ "\t" ++ {commit}description().first_line() ++ "\t" ++ {commit}committer().timestamp().format("%s") ++ "\n""#
+        )
+    };
+    let mut error = String::new();
+    for template in [fields("commit."), fields("self.")] {
+        let output = jj_command(true)
+            .args(["evolog", "-r", change, "--no-graph", "-T", &template])
+            .output_or_cancel()
+            .map_err(|e| format!("Failed to run jj: {e}"))?;
+        if output.status.success() {
+            let mut versions = parse_parents(&String::from_utf8_lossy(&output.stdout));
+            versions.reverse();
+            return Ok(versions);
+        }
+        error = String::from_utf8_lossy(&output.stderr).into_owned();
+    }
+    Err(format!("jj command failed: {error}"))
+}
+
+/// Diffs each step in the evolution of a jj change (rewrites, rebases, squashes, ...).
+///
+/// Returns one entry per version, oldest first, with the usual `run_diff` result plus
+/// `commit`, `previous_commit`, `description`, `timestamp` and `relative_time`. The first
+/// version is diffed against its parents, later ones against the previous version.
+fn run_evolution_diff(lua: &Lua, (change, opts): (String, DiffOptions)) -> LuaResult<LuaTable> {
+    let steps = repo::serialized(|| {
+        let versions = jj_evolution(&change)?;
+        let mut previous: Option<String> = None;
+        let mut steps = Vec::with_capacity(versions.len());
+        for version in versions {
+            let diff = jj_commit_diff(previous.as_deref(), &version.commit, &opts)?;
+            steps.push((previous.replace(version.commit.clone()), version, diff));
+        }
+        Ok::<_, String>(steps)
+    })
+    .map_err(LuaError::RuntimeError)?;
+
+    let entries = steps
+        .into_iter()
+        .map(|(previous, version, diff)| {
+            let table = diff_result_table(lua, diff)?;
+            table.set("commit", version.commit)?;
+            table.set("previous_commit", previous)?;
+            table.set("description", version.subject)?;
+            table.set("timestamp", version.timestamp)?;
+            table.set(
+                "relative_time",
+                version
+                    .timestamp
+                    .map(|t| opts.time_format.relative_to_now(t)),
+            )?;
+            Ok(table)
+        })
+        .collect::<LuaResult<Vec<_>>>()?;
+    lua.create_sequence_from(entries)
+}
+
+/// Lists the commits a jj revset resolves to, as
+/// `{ { commit, change_id, subject, timestamp, divergent, hidden }, ... }`, to pick one of
+/// the candidates of a divergent change or check whether a commit is hidden.
+fn jj_resolve(lua: &Lua, (revset, opts): (String, DiffOptions)) -> LuaResult<LuaTable> {
+    let mode = DiffMode::Range(revset.clone());
+    let candidates = repo::in_repo(opts.cwd.as_deref(), || {
+        divergence::resolve(&revset, jj_ignores_working_copy(&mode, &opts))
+    })
+    .map_err(LuaError::RuntimeError)?;
+    lua.create_sequence_from(candidates)
+}
+
+/// Stats of each change of the jj stack `revset` (`trunk()..@` when `nil`), bottom up, as
+/// `{ changes = { { change_id, commit, subject, files, additions, deletions, paths } },
+/// overlaps = { { path, changes } } }`, where overlaps are the files several changes touch.
+fn stack_stats(lua: &Lua, (revset, opts): (Option<String>, DiffOptions)) -> LuaResult<LuaValue> {
+    let revset = revset.unwrap_or_else(|| stack::DEFAULT_REVSET.to_string());
+    let iwc = jj_ignores_working_copy(&DiffMode::Range(revset.clone()), &opts);
+    repo::in_repo(opts.cwd.as_deref(), || {
+        repo::serialized(|| stack::collect(&revset, iwc))
+    })
+    .map_err(LuaError::RuntimeError)?
+    .into_lua(lua)
+}
+
+/// Opens a git branch for review commit by commit (see the `review` module). Takes the same
+/// arguments as `run_diff`, with a range such as `main..feature`, and returns a
+/// [`review::BranchReview`] listing the commits.
+fn branch_review(
+    lua: &Lua,
+    (range, vcs_or_opts, opts): (String, LuaValue, LuaValue),
+) -> LuaResult<LuaAnyUserData> {
+    let (vcs, opts) = range_args(lua, vcs_or_opts, opts)?;
+    if vcs != "git" {
+        return Err(LuaError::RuntimeError(format!(
+            "Branch review requires git, got '{vcs}'"
+        )));
+    }
+    let dir = opts.cwd.clone().or_else(repo::dir);
+    let commits = repo::in_repo(dir.as_deref(), || {
+        repo::serialized(|| review::list(&range, &opts.paths))
+    })
+    .map_err(LuaError::RuntimeError)?;
+    let commits = commits
+        .into_iter()
+        .map(|commit| review::Commit {
+            relative_time: commit
+                .timestamp
+                .map(|t| opts.time_format.relative_to_now(t)),
+            ..commit
+        })
+        .collect();
+    lua.create_userdata(review::BranchReview { commits, dir, opts })
+}
+
+/// A tag, as listed by `list_tags`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Tag {
+    name: String,
+    /// Full hash of the tagged commit.
+    commit: String,
+    /// First line of the tag's message, or of the commit message for lightweight tags.
+    message: String,
+    /// Creation timestamp in Unix seconds.
+    timestamp: Option<i64>,
+    /// `timestamp` formatted relative to now (e.g. `"3 days ago"`).
+    relative_time: Option<String>,
+}
+
+impl IntoLua for Tag {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("name", self.name)?;
+        table.set("commit", self.commit)?;
+        table.set("message", self.message)?;
+        table.set("timestamp", self.timestamp)?;
+        table.set("relative_time", self.relative_time)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Parses `names<TAB>commit<TAB>timestamp<TAB>message` lines into tags, newest first.
+/// `names` may hold several space-separated tags of the same commit; hg's `tip` is skipped.
+fn parse_tags(output: &str) -> Vec<Tag> {
+    let mut tags: Vec<Tag> = output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            let names = fields.next()?;
+            let commit = fields.next()?.trim();
+            let timestamp = fields.next()?.split_whitespace().next();
+            let message = fields.next().unwrap_or_default();
+            Some(
+                names
+                    .split_whitespace()
+                    .filter(|&name| name != "tip")
+                    .map(|name| Tag {
+                        name: name.to_string(),
+                        commit: commit.to_string(),
+                        message: message.to_string(),
+                        timestamp: timestamp.and_then(|t| t.parse().ok()),
+                        relative_time: None,
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .flatten()
+        .collect();
+    tags.sort_by(|a, b| {
+        b.timestamp
+            .cmp(&a.timestamp)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    tags
+}
+
+/// Lists the repository's tags, newest first.
+fn tags(vcs: &str) -> Result<Vec<Tag>, String> {
+    let mut command = match vcs {
+        "git" => {
+            let mut command = repo::command("git");
+            // Annotated tags point to tag objects; `*objectname` is the commit they tag
+            command.args([
+                "for-each-ref",
+                "refs/tags",
+                "--format=%(refname:short)%09%(if)%(*objectname)%(then)%(*objectname)%(else)%(objectname)%(end)%09%(creatordate:unix)%09%(contents:subject)",
+            ]);
+            command
+        }
+        "jj" => {
+            let mut command = jj_command(true);
+            command.args([
+                "log",
+                "--no-graph",
+                "-r",
+                "tags()",
+                "-T",
+                r#"tags.map(|t| t.name()).join(" ") ++ "\t" ++ commit_id ++ "\t" ++ committer.timestamp().format("%s") ++ "\t" ++ description.first_line() ++ "\n""#,
+            ]);
+            command
+        }
+        "hg" | "sl" => {
+            let mut command = repo::command(vcs);
+            command.args([
+                "log",
+                "-r",
+                "tag()",
+                "-T",
+                "{tags}\t{node}\t{date|hgdate}\t{desc|firstline}\n",
+            ]);
+            command
+        }
+        _ => return Err(format!("Listing tags isn't supported for {vcs}")),
+    };
+    let output = command
+        .output_or_cancel()
+        .map_err(|e| format!("Failed to run {vcs}: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{vcs} command failed: {stderr}"));
+    }
+    Ok(parse_tags(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Lists tags with their commit, message and date, newest first.
+fn list_tags(lua: &Lua, (vcs, opts): (String, DiffOptions)) -> LuaResult<LuaTable> {
+    let tags = repo::serialized(|| tags(&vcs)).map_err(LuaError::RuntimeError)?;
+    lua.create_sequence_from(tags.into_iter().map(|tag| Tag {
+        relative_time: tag.timestamp.map(|t| opts.time_format.relative_to_now(t)),
+        ..tag
+    }))
+}
+
+/// The range of commits in `to` but not in `from`, in the revset syntax of `vcs`.
+fn release_range(vcs: &str, from: &str, to: &str) -> String {
+    match vcs {
+        "hg" | "sl" => format!("only({to}, {from})"),
+        _ => format!("{from}..{to}"),
+    }
+}
+
+/// Lists the commits of `range` (see [`release_range`]) as summaries, newest first.
+fn range_commits(vcs: &str, range: &str) -> Result<Vec<ParentCommit>, String> {
+    let mut command = match vcs {
+        "git" => {
+            let mut command = repo::command("git");
+            command.args(["log", "--format=%H%x09%s%x09%ct", range]);
+            command
+        }
+        "jj" => {
+            let mut command = jj_command(true);
+            command.args([
+                "log",
+                "--no-graph",
+                "-r",
+                range,
+                "-T",
+                r#"commit_id ++ "\t" ++ description.first_line() ++ "\t" ++ committer.timestamp().format("%s") ++ "\n""#,
+            ]);
+            command
+        }
+        "hg" | "sl" => {
+            let mut command = repo::command(vcs);
+            command.args([
+                "log",
+                "-r",
+                &format!("reverse({range})"),
+                "-T",
+                "{node}\t{desc|firstline}\t{date|hgdate}\n",
+            ]);
+            command
+        }
+        _ => return Err(format!("Listing commits isn't supported for {vcs}")),
+    };
+    let output = command
+        .output_or_cancel()
+        .map_err(|e| format!("Failed to run {vcs}: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{vcs} command failed: {stderr}"));
+    }
+    Ok(parse_parents(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Changed files and line stats of one directory, for changelog-style summaries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DirectorySummary {
+    /// Repository-relative directory, `""` for the root.
+    directory: String,
+    files: u32,
+    additions: u32,
+    deletions: u32,
+}
+
+impl IntoLua for DirectorySummary {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("directory", self.directory)?;
+        table.set("files", self.files)?;
+        table.set("additions", self.additions)?;
+        table.set("deletions", self.deletions)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Groups changed files by directory, sorted by path.
+fn summarize_directories(files: &[processor::DisplayFile]) -> Vec<DirectorySummary> {
+    let mut directories: std::collections::BTreeMap<String, DirectorySummary> =
+        std::collections::BTreeMap::new();
+    for file in files {
+        let directory = file
+            .path
+            .parent()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let summary = directories
+            .entry(directory.clone())
+            .or_insert_with(|| DirectorySummary {
+                directory,
+                files: 0,
+                additions: 0,
+                deletions: 0,
+            });
+        summary.files += 1;
+        summary.additions += file.additions;
+        summary.deletions += file.deletions;
+    }
+    directories.into_values().collect()
+}
+
+/// Diffs two tags (or any two revisions) like a release: the usual `run_diff` result plus a
+/// changelog summary with the `commits` in the range, newest first, and the changed files
+/// grouped by `directories`.
+fn release_diff(
+    lua: &Lua,
+    (from, to, vcs, opts): (String, String, String, DiffOptions),
+) -> LuaResult<LuaTable> {
+    let range = release_range(&vcs, &from, &to);
+    let (diff, commits) = repo::in_repo(opts.cwd.as_deref(), || {
+        let diff = compute_diff(&DiffMode::Range(range.clone()), &vcs, &opts)?;
+        let commits = repo::serialized(|| range_commits(&vcs, &range))?;
+        Ok::<_, String>((diff, commits))
+    })
+    .map_err(LuaError::RuntimeError)?;
+
+    let directories = summarize_directories(&diff.files);
+    let commits = commits.into_iter().map(|commit| ParentCommit {
+        relative_time: commit
+            .timestamp
+            .map(|t| opts.time_format.relative_to_now(t)),
+        ..commit
+    });
+    let table = diff_result_table(lua, diff)?;
+    table.set("commits", lua.create_sequence_from(commits)?)?;
+    table.set("directories", lua.create_sequence_from(directories)?)?;
+    Ok(table)
+}
+
+/// Reports HEAD, branch, in-progress operation and conflicted files.
+fn repo_state(lua: &Lua, vcs: String) -> LuaResult<LuaValue> {
+    repo::serialized(|| repo_state::detect(&vcs)).into_lua(lua)
+}
+
+/// Diffs the current conflict resolution in the working tree against the commit being
+/// applied by the in-progress operation (e.g. `REBASE_HEAD` during a rebase).
+fn run_diff_operation(lua: &Lua, vcs: String) -> LuaResult<LuaTable> {
+    let state = repo::serialized(|| repo_state::detect(&vcs));
+    let Some(head) = state.operation_head else {
+        return Err(LuaError::RuntimeError(
+            "No rebase, merge, cherry-pick or revert in progress".to_string(),
+        ));
+    };
+    run_diff_impl(
+        lua,
+        DiffMode::WorkingTree(head),
+        &vcs,
+        &DiffOptions::default(),
+    )
+}
+
+/// Reports the bisect in progress, or `nil` if there's none.
+fn bisect_state(lua: &Lua, vcs: String) -> LuaResult<LuaValue> {
+    repo::serialized(|| bisect::detect(&vcs)).into_lua(lua)
+}
+
+/// Diffs the candidate commit of the bisect in progress against the last good commit.
+fn run_diff_bisect(lua: &Lua, (vcs, opts): (String, DiffOptions)) -> LuaResult<LuaTable> {
+    let bisect = repo::serialized(|| bisect::detect(&vcs));
+    let Some(range) = bisect.and_then(|bisect| bisect.range) else {
+        return Err(LuaError::RuntimeError(
+            "No bisect in progress with a good commit and a candidate".to_string(),
+        ));
+    };
+    run_diff_impl(lua, DiffMode::Range(range), &vcs, &opts)
+}
+
+/// Processes a single file's difftastic JSON and contents without running any VCS command.
+fn process_json(
+    lua: &Lua,
+    (json, old_text, new_text, opts): (String, Option<String>, Option<String>, DiffOptions),
+) -> LuaResult<LuaValue> {
+    processor::process_json(
+        &json,
+        old_text.as_deref().map(str::as_bytes),
+        new_text.as_deref().map(str::as_bytes),
+        &opts.process,
+    )
+    .map_err(LuaError::RuntimeError)?
+    .into_lua(lua)
+}
+
+/// Compares two files on disk, outside of any repository.
+fn diff_files(
+    lua: &Lua,
+    (old_path, new_path, opts): (String, String, DiffOptions),
+) -> LuaResult<LuaValue> {
+    let (old_path, new_path) = (Path::new(&old_path), Path::new(&new_path));
+    let json = difft_files_json(old_path, new_path, None).map_err(LuaError::RuntimeError)?;
+    let old = std::fs::read(old_path).ok();
+    let new = std::fs::read(new_path).ok();
+    processor::process_json(&json, old.as_deref(), new.as_deref(), &opts.process)
+        .map_err(LuaError::RuntimeError)?
+        .into_lua(lua)
+}
+
+/// Compares two directories on disk, e.g. extracted archives or build outputs.
+/// File contents are read from the directories; paths are relative to them.
+fn diff_dirs(
+    lua: &Lua,
+    (old_dir, new_dir, opts): (String, String, DiffOptions),
+) -> LuaResult<LuaTable> {
+    let (old_dir, new_dir) = (PathBuf::from(old_dir), PathBuf::from(new_dir));
+    let json = difft_files_json(&old_dir, &new_dir, None).map_err(LuaError::RuntimeError)?;
+    let plan = DiffPlan {
+        json,
+        stats: FileStats::new(),
+        old: ContentSource::WorkingTree(Some(old_dir)),
+        new: ContentSource::WorkingTree(Some(new_dir)),
+        parents: Vec::new(),
+        renames: HashMap::new(),
+        modes: HashMap::new(),
+        submodules: HashMap::new(),
+    };
+    let diff =
+        process_plan(&prefetch::PreparedDiff::new(plan), &opts).map_err(LuaError::RuntimeError)?;
+    diff_result_table(lua, diff)
+}
+
+/// Text passed from Lua as a file path, or as `{ text = ... }` for text that isn't in a
+/// file, e.g. piped into Neovim. Reading the process's stdin would compete with Neovim's
+/// own use of it.
+enum Input {
+    Path(PathBuf),
+    Text(String),
+}
+
+impl FromLua for Input {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::String(path) => Ok(Self::Path(PathBuf::from(path.to_str()?.to_string()))),
+            LuaValue::Table(table) => Ok(Self::Text(table.get("text")?)),
+            other => Err(LuaError::RuntimeError(format!(
+                "expected a path or a table with text, got {}",
+                other.type_name()
+            ))),
+        }
+    }
+}
+
+impl Input {
+    fn read(self) -> Result<String, String> {
+        match self {
+            Self::Path(path) => std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {e}", path.display())),
+            Self::Text(text) => Ok(text),
+        }
+    }
+}
+
+/// Processes difftastic JSON generated elsewhere, e.g. an artifact of a CI job, without
+/// any repository. The JSON is read from a file, which may be a named pipe, or given as
+/// text (see [`Input`]). File contents are read from `contents_dir/old/<path>` and
+/// `contents_dir/new/<path>`; files missing there count as created or deleted.
+fn process_artifact(
+    lua: &Lua,
+    (json, contents_dir, opts): (Input, String, DiffOptions),
+) -> LuaResult<LuaTable> {
+    let json = json.read().map_err(LuaError::RuntimeError)?;
+    let contents_dir = PathBuf::from(contents_dir);
+    let plan = DiffPlan {
+        json,
+        stats: FileStats::new(),
+        old: ContentSource::WorkingTree(Some(contents_dir.join("old"))),
+        new: ContentSource::WorkingTree(Some(contents_dir.join("new"))),
+        parents: Vec::new(),
+        renames: HashMap::new(),
+        modes: HashMap::new(),
+        submodules: HashMap::new(),
+    };
+    let diff =
+        process_plan(&prefetch::PreparedDiff::new(plan), &opts).map_err(LuaError::RuntimeError)?;
+    diff_result_table(lua, diff)
+}
+
+/// Reviews a patch file (see the `unified_diff` module), e.g. one received by email.
+/// The patch is a `.patch`/`.diff` file or given as text (see [`Input`]). Returns the usual
+/// `run_diff` result plus `partial_files`, the paths only shown as their hunks.
+fn import_patch(lua: &Lua, (patch, opts): (Input, DiffOptions)) -> LuaResult<LuaTable> {
+    let text = patch.read().map_err(LuaError::RuntimeError)?;
+    let patches = unified_diff::parse(&text).map_err(LuaError::RuntimeError)?;
+    let diff = repo::in_repo(opts.cwd.as_deref(), || {
+        repo::serialized(|| patch_diff(&patches, &HashMap::new(), &opts))
+    })
+    .map_err(LuaError::RuntimeError)?;
+    patch_result_table(lua, diff)
+}
+
+/// Opens a patch series for review patch by patch (see the `series` module). `path` is a
+/// directory of `git format-patch` output or an mbox file. Returns a
+/// [`series::PatchSeries`] listing the patches.
+fn patch_series(lua: &Lua, (path, opts): (String, DiffOptions)) -> LuaResult<LuaAnyUserData> {
+    let (patches, cover_letter) = series::read(Path::new(&path)).map_err(LuaError::RuntimeError)?;
+    let dir = opts.cwd.clone().or_else(repo::dir);
+    lua.create_userdata(series::PatchSeries {
+        patches,
+        cover_letter,
+        dir,
+        opts,
+    })
+}
+
+/// The `run_diff` result of a [`patch_diff`], plus `partial_files`.
+fn patch_result_table(
+    lua: &Lua,
+    (diff, partial): (DiffResult, Vec<PathBuf>),
+) -> LuaResult<LuaTable> {
+    let table = diff_result_table(lua, diff)?;
+    table.set(
+        "partial_files",
+        partial
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+    )?;
+    Ok(table)
+}
+
+/// The version of `file` its patch applies to: the one an earlier patch of a series left in
+/// `earlier`, the blob the patch names, or the file in the working tree under `root`.
+fn patch_base(
+    file: &unified_diff::FilePatch,
+    earlier: &HashMap<PathBuf, String>,
+    root: Option<&Path>,
+    opts: &DiffOptions,
+) -> Option<String> {
+    let old_path = file.old_path.as_ref()?;
+    if let Some(version) = earlier.get(old_path) {
+        return Some(version.clone());
+    }
+    let blob = file.old_blob.as_ref().and_then(|blob| {
+        git_cat_file_batch(None, std::slice::from_ref(blob))?
+            .pop()
+            .flatten()
+    });
+    let bytes = blob.or_else(|| std::fs::read(root?.join(old_path)).ok())?;
+    Some(
+        encoding::decode(&bytes, old_path, &opts.process.encodings)
+            .0
+            .into_owned(),
+    )
+}
+
+/// Diffs the files a unified diff changes, rebuilt in a temporary directory. Each file's
+/// old version comes from [`patch_base`], and is only used if the patch applies to it.
+/// Returns the diff and the files that could only be rebuilt from their hunks.
+fn patch_diff(
+    patches: &[unified_diff::FilePatch],
+    earlier: &HashMap<PathBuf, String>,
+    opts: &DiffOptions,
+) -> Result<(DiffResult, Vec<PathBuf>), String> {
+    let dir = temp::TempDir::new()?;
+    let root = git_root().or_else(repo::dir);
+    let mut partial = Vec::new();
+    for file in patches {
+        let Some(path) = file.path().filter(|_| !file.binary) else {
+            continue;
+        };
+        let base = patch_base(file, earlier, root.as_deref(), opts);
+        let (old, new, complete) = file.rebuild(base.as_deref());
+        if !complete {
+            partial.push(path.clone());
+        }
+        // Both sides are written under the new path, so difftastic pairs renamed files
+        for (side, content) in [("old", old), ("new", new)] {
+            if let Some(content) = content {
+                dir.write(&format!("{side}/{}", path.display()), &content)?;
+            }
+        }
+    }
+    let (old_dir, new_dir) = (dir.path().join("old"), dir.path().join("new"));
+    for side in [&old_dir, &new_dir] {
+        std::fs::create_dir_all(side)
+            .map_err(|e| format!("Failed to create {}: {e}", side.display()))?;
+    }
+    let plan = DiffPlan {
+        json: difft_files_json(&old_dir, &new_dir, None)?,
+        stats: FileStats::new(),
+        old: ContentSource::WorkingTree(Some(old_dir)),
+        new: ContentSource::WorkingTree(Some(new_dir)),
+        parents: Vec::new(),
+        renames: HashMap::new(),
+        modes: HashMap::new(),
+        submodules: HashMap::new(),
+    };
+    let mut diff = process_plan(&prefetch::PreparedDiff::new(plan), opts)?;
+    let renamed_from = |file: &unified_diff::FilePatch| {
+        file.old_path
+            .clone()
+            .filter(|old_path| file.new_path.as_ref() != Some(old_path))
+    };
+    for display in &mut diff.files {
+        let Some(file) = patches
+            .iter()
+            .find(|file| file.path() == Some(&display.path))
+        else {
+            continue;
+        };
+        if let Some(old_path) = renamed_from(file) {
+            display.old_path = Some(old_path);
+            display.status = difftastic::Status::Renamed;
+        }
+        if let Some(change) = file.mode_change.clone() {
+            processor::add_mode_change(display, change);
+        }
+    }
+    // Renames and mode changes without content changes, and binary patches, which can't
+    // be rebuilt, still show up
+    let listed: HashSet<PathBuf> = diff.files.iter().map(|file| file.path.clone()).collect();
+    for file in patches {
+        let Some(path) = file.path().filter(|path| !listed.contains(*path)) else {
+            continue;
+        };
+        let mut entry = difftastic::DifftFile {
+            path: path.clone(),
+            old_path: None,
+            language: String::new(),
+            status: match (&file.old_path, &file.new_path) {
+                (None, _) => difftastic::Status::Created,
+                (_, None) => difftastic::Status::Deleted,
+                _ if file.binary => difftastic::Status::Changed,
+                _ => difftastic::Status::Unchanged,
+            },
+            aligned_lines: Vec::new(),
+            chunks: Vec::new(),
+        };
+        if let Some(old_path) = renamed_from(file) {
+            entry.rename_from(old_path);
+        }
+        let display = if file.binary {
+            entry.language = "Binary".to_string();
+            processor::binary(entry, None, None)
+        } else if let Some(change) = file.mode_change.clone() {
+            processor::mode_only(entry, change)
+        } else {
+            processor::DisplayFile {
+                stats_only: false,
+                ..processor::stats_only(entry, Some((0, 0)))
+            }
+        };
+        diff.files.push(display);
+    }
+    Ok((diff, partial))
+}
+
+/// Compares two strings, e.g. unsaved buffer contents against the file on disk.
+///
+/// The strings are written to temporary files named `text` for difftastic, so the returned
+/// file's path is `text`. Pass `language` (e.g. `"Rust"`) to parse them as that language.
+fn diff_text(
+    lua: &Lua,
+    (old_text, new_text, language, opts): (String, String, Option<String>, DiffOptions),
+) -> LuaResult<LuaValue> {
+    diff_strings(&old_text, &new_text, language.as_deref(), &opts)
+        .map_err(LuaError::RuntimeError)?
+        .into_lua(lua)
+}
+
+/// Runs difftastic on two strings through temporary files and processes the result.
+fn diff_strings(
+    old_text: &str,
+    new_text: &str,
+    language: Option<&str>,
+    opts: &DiffOptions,
+) -> Result<processor::DisplayFile, String> {
+    let dir = temp::TempDir::new()?;
+    let old_path = dir.write("old/text", old_text)?;
+    let new_path = dir.write("new/text", new_text)?;
+    let json = difft_files_json(&old_path, &new_path, language)?;
+
+    let mut file = processor::process_json(
+        &json,
+        Some(old_text.as_bytes()),
+        Some(new_text.as_bytes()),
+        &opts.process,
+    )?;
+    file.path = PathBuf::from("text");
+    Ok(file)
+}
+
+/// Compares two snippets, e.g. code blocks selected in different buffers, for display in a
+/// floating window.
+///
+/// Each snippet is dedented first, so blocks copied from different nesting levels line up
+/// and don't waste columns. Besides the usual file fields, the result has `width` (the
+/// widest line in characters, per side) and `height` (the number of rows) for sizing the
+/// window.
+fn diff_selections(
+    lua: &Lua,
+    (text_a, text_b, language, opts): (String, String, Option<String>, DiffOptions),
+) -> LuaResult<LuaValue> {
+    let (text_a, text_b) = (dedent(&text_a), dedent(&text_b));
+    let file = diff_strings(&text_a, &text_b, language.as_deref(), &opts)
+        .map_err(LuaError::RuntimeError)?;
+
+    let width = file
+        .rows
+        .iter()
+        .flat_map(|row| [&row.left.content, &row.right.content])
+        .map(|content| content.chars().count())
+        .max()
+        .unwrap_or(0);
+    let height = file.rows.len();
+
+    let LuaValue::Table(table) = file.into_lua(lua)? else {
+        unreachable!("DisplayFile converts to a table");
+    };
+    table.set("width", width)?;
+    table.set("height", height)?;
+    Ok(LuaValue::Table(table))
+}
+
+/// Removes the leading whitespace shared by all non-blank lines of `text`.
+fn dedent(text: &str) -> String {
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    text.split_inclusive('\n')
+        .map(|line| {
+            line.get(indent..)
+                .unwrap_or(line.trim_start_matches([' ', '\t']))
+        })
+        .collect()
+}
+
+/// Builds three-way views of the files with merge conflicts, with the base, ours and theirs
+/// versions read from index stages 1, 2 and 3. Only git keeps these stages.
+fn run_conflict_diff(lua: &Lua, (vcs, opts): (String, DiffOptions)) -> LuaResult<LuaTable> {
+    if vcs != "git" {
+        return Err(LuaError::RuntimeError(format!(
+            "Conflict view requires git, got '{vcs}'"
+        )));
+    }
+    let files = repo::serialized(|| {
+        let mut paths = git_conflicted_paths();
+        if !opts.paths.is_empty() {
+            paths.retain(|path| opts.paths.contains(path));
+        }
+        let repo = repo::current();
+        limits::install(|| {
+            paths
+                .par_iter()
+                .map(|path| repo::in_repo(repo.as_deref(), || conflict_file(path)))
+                .collect::<Result<Vec<_>, String>>()
+        })
+    })
+    .map_err(LuaError::RuntimeError)?;
+    lua.create_sequence_from(files)
+}
+
+/// Diffs the base version of a conflicted file against ours and theirs with difftastic,
+/// through temporary files that keep the file's name so its language is detected.
+fn conflict_file(path: &Path) -> Result<processor::ConflictFile, String> {
+    let [base, ours, theirs] = [1, 2, 3].map(|stage| {
+        let bytes = git_stage_content(stage, path).unwrap_or_default();
+        encoding::decode(&bytes, path, &Vec::new()).0.into_owned()
+    });
+
+    let dir = temp::TempDir::new()?;
+    let write = |side: &str, text: &str| dir.write(&format!("{side}/{}", path.display()), text);
+    let base_path = write("base", &base)?;
+    let diff = |side: &str, text: &str| -> Result<difftastic::DifftFile, String> {
+        let json = difft_files_json(&base_path, &write(side, text)?, None)?;
+        let file = difftastic::parse(&json)
+            .map_err(|e| format!("Failed to parse difftastic JSON: {e}"))?
+            .into_iter()
+            .next();
+        // Identical files may produce no output at all
+        Ok(file.unwrap_or_else(|| difftastic::DifftFile {
+            path: path.to_path_buf(),
+            old_path: None,
+            language: String::new(),
+            status: difftastic::Status::Unchanged,
+            aligned_lines: Vec::new(),
+            chunks: Vec::new(),
+        }))
+    };
+    let (ours_file, theirs_file) = (diff("ours", &ours)?, diff("theirs", &theirs)?);
+
+    let lines = |text: &str| into_lines(Some(text.to_string()));
+    let mut file = processor::process_conflict(
+        &ours_file,
+        &theirs_file,
+        &lines(&base),
+        &lines(&ours),
+        &lines(&theirs),
+    );
+    file.path = path.to_path_buf();
+    Ok(file)
+}
+
+/// Applies hunks of one file to `text` in a single pass (see the `patch` module).
+fn apply_hunks(_: &Lua, (text, hunks): (String, Vec<patch::PatchHunk>)) -> LuaResult<String> {
+    patch::apply_hunks(&text, &hunks).map_err(LuaError::RuntimeError)
+}
+
+/// Unified diff of the 1-indexed hunk of a file table `run_diff` returned, built from the
+/// original contents in `opts` (see the `hunk_patch` module).
+fn hunk_patch(
+    _: &Lua,
+    (file, index, opts): (hunk_patch::PatchFile, usize, Option<LuaTable>),
+) -> LuaResult<String> {
+    let index = index
+        .checked_sub(1)
+        .ok_or_else(|| LuaError::RuntimeError("Hunks are numbered from 1".to_string()))?;
+    let (old, new, context) = match opts {
+        Some(opts) => (
+            opts.get::<Option<String>>("old")?,
+            opts.get::<Option<String>>("new")?,
+            opts.get::<Option<usize>>("context")?,
+        ),
+        None => (None, None, None),
+    };
+    hunk_patch::hunk_patch(
+        &file,
+        index,
+        old.as_deref(),
+        new.as_deref(),
+        context.unwrap_or(hunk_patch::DEFAULT_CONTEXT),
+    )
+    .map_err(LuaError::RuntimeError)
+}
+
+/// Stages the 1-indexed hunk of the unstaged changes of `path` (relative to the repository
+/// root) into the git index, returning `{ ok, path, hunk, patch, error }` (see the `stage`
+/// module). `opts` should be those the hunk was diffed with, so hunks are numbered the same.
+fn stage_hunk(lua: &Lua, (path, hunk, opts): (String, usize, DiffOptions)) -> LuaResult<LuaValue> {
+    let mut staged = stage::Staged {
+        path: path.clone(),
+        hunk,
+        ..Default::default()
+    };
+    let cwd = opts.cwd.clone();
+    let result = repo::in_repo(cwd.as_deref(), || {
+        let patch = unstaged_hunk_patch(PathBuf::from(&path), hunk, opts)?;
+        staged.patch = Some(patch.clone());
+        let root = git_root();
+        repo::in_repo(root.as_deref(), || {
+            repo::serialized(|| stage::apply_to_index(&patch))
+        })
+    });
+    staged.error = result.err();
+    staged.into_lua(lua)
+}
+
+/// The patch of the 1-indexed hunk of the unstaged changes of `path`, diffed with `opts`.
+fn unstaged_hunk_patch(
+    path: PathBuf,
+    hunk: usize,
+    mut opts: DiffOptions,
+) -> Result<String, String> {
+    let index = hunk
+        .checked_sub(1)
+        .ok_or_else(|| "Hunks are numbered from 1".to_string())?;
+    opts.paths = vec![path.clone()];
+    let diff = compute_diff(&DiffMode::Unstaged, "git", &opts)?;
+    let file = diff
+        .files
+        .iter()
+        .find(|file| file.path == path)
+        .ok_or_else(|| format!("{} has no unstaged changes", path.display()))?;
+    // The patch has to reproduce the stored bytes, which decoding would change
+    let text = |source: ContentSource| {
+        source
+            .fetch(&path)
+            .map(|bytes| {
+                String::from_utf8(bytes)
+                    .map_err(|_| format!("Can't stage hunks of {}: it isn't UTF-8", path.display()))
+            })
+            .transpose()
+    };
+    let old = text(ContentSource::GitIndex)?;
+    let new = text(ContentSource::WorkingTree(git_root()))?;
+    hunk_patch::hunk_patch(
+        &file.into(),
+        index,
+        old.as_deref(),
+        new.as_deref(),
+        hunk_patch::DEFAULT_CONTEXT,
+    )
+}
+
+/// Stages all changes of `path` (relative to the repository root) with `git add`, returning
+/// `{ ok, path, unstaged, staged, error }` with the file's stats afterwards (see the `stage`
+/// module).
+fn stage_file(lua: &Lua, (path, opts): (String, DiffOptions)) -> LuaResult<LuaValue> {
+    let file = PathBuf::from(&path);
+    let update = repo::in_repo(opts.cwd.as_deref(), || {
+        repo::serialized(|| {
+            let result = stage::add(&pathspec_args("git", std::slice::from_ref(&file)));
+            file_update(path, "git", &file, result)
+        })
+    });
+    update.into_lua(lua)
+}
+
+/// Restores `path` (relative to the repository root) in the working copy to its version in
+/// `rev` with `git restore` or `jj restore`, returning the same as [`stage_file`].
+fn restore_file(
+    lua: &Lua,
+    (path, rev, vcs, opts): (String, Option<String>, String, DiffOptions),
+) -> LuaResult<LuaValue> {
+    let file = PathBuf::from(&path);
+    let update = repo::in_repo(opts.cwd.as_deref(), || {
+        repo::serialized(|| {
+            let pathspec = pathspec_args(&vcs, std::slice::from_ref(&file));
+            let result = stage::restore(&vcs, &pathspec, rev.as_deref());
+            file_update(path, &vcs, &file, result)
+        })
+    });
+    update.into_lua(lua)
+}
+
+/// The outcome of staging or restoring `file`, with its stats afterwards if it succeeded.
+fn file_update(
+    path: String,
+    vcs: &str,
+    file: &Path,
+    result: Result<(), String>,
+) -> stage::FileUpdate {
+    if let Err(error) = result {
+        return stage::FileUpdate {
+            path,
+            error: Some(error),
+            ..Default::default()
+        };
+    }
+    let stats = |stats: FileStats| stats.get(file).copied().unwrap_or_default();
+    let (unstaged, staged) = if vcs == "git" {
+        let pathspec = pathspec_args(vcs, &[file.to_path_buf()]);
+        let mut args: Vec<&str> = pathspec.iter().map(String::as_str).collect();
+        let unstaged = stats(git_diff_stats(&args));
+        args.insert(0, "--cached");
+        (unstaged, Some(stats(git_diff_stats(&args))))
+    } else {
+        (stats(jj_diff_stats("@", false)), None)
+    };
+    stage::FileUpdate {
+        path,
+        unstaged: Some(unstaged),
+        staged,
+        error: None,
+    }
+}
+
+/// Contents as stored in a fixture: decoded as UTF-8 the way difftastic read them, so its
+/// recorded offsets still apply.
+fn fixture_text(bytes: Vec<u8>) -> String {
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Records the inputs and processed output of a range diff into the fixture directory `path`.
+fn record_fixture(_: &Lua, (range, vcs, path): (String, String, String)) -> LuaResult<()> {
+    let (plan, contents) = repo::serialized(|| {
+        let plan = plan_diff(&DiffMode::Range(range), &vcs, &DiffOptions::default())?;
+        let files = plan.files()?;
+        let contents = limits::install(|| {
+            files
+                .par_iter()
+                .map(|file| fixture::FileContents {
+                    path: file.path.clone(),
+                    old: plan.old.fetch(file.old_path()).map(fixture_text),
+                    new: plan.new.fetch(&file.path).map(fixture_text),
+                    stats: plan.stats.get(&file.path).copied(),
+                })
+                .collect()
+        });
+        Ok((plan, contents))
+    })
+    .map_err(LuaError::RuntimeError)?;
+
+    let fixture = fixture::Fixture {
+        json: plan.json,
+        contents,
+    };
+    let output = fixture.process().map_err(LuaError::RuntimeError)?;
+    fixture
+        .write(Path::new(&path), &output)
+        .map_err(LuaError::RuntimeError)
+}
+
+/// Replays a recorded fixture without touching any repository.
+/// Returns the processed files and whether they match the recorded output.
+fn replay_fixture(lua: &Lua, path: String) -> LuaResult<LuaTable> {
+    let replay = fixture::replay(Path::new(&path)).map_err(LuaError::RuntimeError)?;
+    let files: Vec<LuaValue> = replay
+        .files
+        .into_iter()
+        .map(|f| f.into_lua(lua))
+        .collect::<LuaResult<_>>()?;
+
+    let result = lua.create_table()?;
+    result.set("files", lua.create_sequence_from(files)?)?;
+    result.set("matches", replay.matches)?;
+    Ok(result)
+}
+
+/// Creates the Lua module exports. Called by mlua when loaded via `require("difftastic_nvim")`.
 #[mlua::lua_module]
 fn difftastic_nvim(lua: &Lua) -> LuaResult<LuaTable> {
     let exports = lua.create_table()?;
     exports.set(
         "run_diff",
-        lua.create_function(|lua, args: (String, String)| run_diff(lua, args))?,
+        guard::function(
+            lua,
+            "run_diff",
+            |lua, args: (String, LuaValue, LuaValue)| run_diff(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "run_diff_async",
+        guard::function(
+            lua,
+            "run_diff_async",
+            |lua, args: (String, LuaValue, LuaValue)| run_diff_async(lua, args),
+        )?,
     )?;
     exports.set(
         "run_diff_unstaged",
-        lua.create_function(|lua, vcs: String| run_diff_unstaged(lua, vcs))?,
+        guard::function(
+            lua,
+            "run_diff_unstaged",
+            |lua, args: (String, DiffOptions)| run_diff_unstaged(lua, args),
+        )?,
     )?;
     exports.set(
         "run_diff_staged",
-        lua.create_function(|lua, vcs: String| run_diff_staged(lua, vcs))?,
+        guard::function(
+            lua,
+            "run_diff_staged",
+            |lua, args: (String, DiffOptions)| run_diff_staged(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "run_diff_mode",
+        guard::function(
+            lua,
+            "run_diff_mode",
+            |lua, args: (String, String, DiffOptions)| run_diff_mode(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "run_diff_repos",
+        guard::function(
+            lua,
+            "run_diff_repos",
+            |lua, args: (Vec<String>, Option<String>, String, DiffOptions)| {
+                run_diff_repos(lua, args)
+            },
+        )?,
+    )?;
+    exports.set(
+        "run_diffs",
+        guard::function(lua, "run_diffs", |lua, requests: Vec<LuaTable>| {
+            run_diffs(lua, requests)
+        })?,
+    )?;
+    exports.set(
+        "register_vcs",
+        guard::function(
+            lua,
+            "register_vcs",
+            |lua, args: (String, vcs::CommandSpec)| register_vcs(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "export_json",
+        guard::function(
+            lua,
+            "export_json",
+            |lua, args: (String, String, String, DiffOptions)| export_json(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "import_patch",
+        guard::function(lua, "import_patch", |lua, args: (Input, DiffOptions)| {
+            import_patch(lua, args)
+        })?,
+    )?;
+    exports.set(
+        "patch_series",
+        guard::function(lua, "patch_series", |lua, args: (String, DiffOptions)| {
+            patch_series(lua, args)
+        })?,
+    )?;
+    exports.set(
+        "save_baseline",
+        guard::function(
+            lua,
+            "save_baseline",
+            |lua, args: (String, String, String, DiffOptions)| save_baseline(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "run_diff_since_baseline",
+        guard::function(
+            lua,
+            "run_diff_since_baseline",
+            |lua, args: (String, String, String, DiffOptions)| run_diff_since_baseline(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "prefetch",
+        guard::function(lua, "prefetch", |lua, args: (Vec<String>, String)| {
+            prefetch(lua, args)
+        })?,
+    )?;
+    exports.set(
+        "clear_cache",
+        guard::function(lua, "clear_cache", clear_cache)?,
+    )?;
+    exports.set(
+        "cleanup_temp_files",
+        guard::function(lua, "cleanup_temp_files", |_, ()| {
+            temp::cleanup();
+            Ok(())
+        })?,
+    )?;
+    exports.set(
+        "set_command_wrapper",
+        guard::function(
+            lua,
+            "set_command_wrapper",
+            |_, wrapper: Option<wrapper::Wrapper>| {
+                wrapper::set(wrapper);
+                Ok(())
+            },
+        )?,
+    )?;
+    exports.set(
+        "set_difft",
+        guard::function(lua, "set_difft", |_, tool: Option<tool::Tool>| {
+            tool::set(tool);
+            Ok(())
+        })?,
+    )?;
+    exports.set(
+        "set_limits",
+        guard::function(lua, "set_limits", |_, limits: limits::Limits| {
+            limits::set(limits).map_err(LuaError::RuntimeError)
+        })?,
+    )?;
+    exports.set(
+        "set_content_provider",
+        guard::function(
+            lua,
+            "set_content_provider",
+            |_, provider: Option<LuaFunction>| {
+                // Diffs read through the previous provider (or locally) may no longer match
+                cache::clear();
+                prefetch::clear();
+                provider::set(provider);
+                Ok(())
+            },
+        )?,
+    )?;
+    exports.set(
+        "repo_state",
+        guard::function(lua, "repo_state", |lua, vcs: String| repo_state(lua, vcs))?,
+    )?;
+    exports.set(
+        "run_diff_operation",
+        guard::function(lua, "run_diff_operation", |lua, vcs: String| {
+            run_diff_operation(lua, vcs)
+        })?,
+    )?;
+    exports.set(
+        "bisect_state",
+        guard::function(lua, "bisect_state", |lua, vcs: String| {
+            bisect_state(lua, vcs)
+        })?,
+    )?;
+    exports.set(
+        "run_diff_bisect",
+        guard::function(
+            lua,
+            "run_diff_bisect",
+            |lua, args: (String, DiffOptions)| run_diff_bisect(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "run_op_diff",
+        guard::function(lua, "run_op_diff", |lua, args: (String, DiffOptions)| {
+            run_op_diff(lua, args)
+        })?,
+    )?;
+    exports.set(
+        "run_evolution_diff",
+        guard::function(
+            lua,
+            "run_evolution_diff",
+            |lua, args: (String, DiffOptions)| run_evolution_diff(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "jj_resolve",
+        guard::function(lua, "jj_resolve", |lua, args: (String, DiffOptions)| {
+            jj_resolve(lua, args)
+        })?,
+    )?;
+    exports.set(
+        "stack_stats",
+        guard::function(
+            lua,
+            "stack_stats",
+            |lua, args: (Option<String>, DiffOptions)| stack_stats(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "branch_review",
+        guard::function(
+            lua,
+            "branch_review",
+            |lua, args: (String, LuaValue, LuaValue)| branch_review(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "list_tags",
+        guard::function(lua, "list_tags", |lua, args: (String, DiffOptions)| {
+            list_tags(lua, args)
+        })?,
+    )?;
+    exports.set(
+        "release_diff",
+        guard::function(
+            lua,
+            "release_diff",
+            |lua, args: (String, String, String, DiffOptions)| release_diff(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "process_json",
+        guard::function(
+            lua,
+            "process_json",
+            |lua, args: (String, Option<String>, Option<String>, DiffOptions)| {
+                process_json(lua, args)
+            },
+        )?,
+    )?;
+    exports.set(
+        "diff_files",
+        guard::function(
+            lua,
+            "diff_files",
+            |lua, args: (String, String, DiffOptions)| diff_files(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "diff_dirs",
+        guard::function(
+            lua,
+            "diff_dirs",
+            |lua, args: (String, String, DiffOptions)| diff_dirs(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "process_artifact",
+        guard::function(
+            lua,
+            "process_artifact",
+            |lua, args: (Input, String, DiffOptions)| process_artifact(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "diff_text",
+        guard::function(
+            lua,
+            "diff_text",
+            |lua, args: (String, String, Option<String>, DiffOptions)| diff_text(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "diff_selections",
+        guard::function(
+            lua,
+            "diff_selections",
+            |lua, args: (String, String, Option<String>, DiffOptions)| diff_selections(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "run_conflict_diff",
+        guard::function(
+            lua,
+            "run_conflict_diff",
+            |lua, args: (String, DiffOptions)| run_conflict_diff(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "apply_hunks",
+        guard::function(
+            lua,
+            "apply_hunks",
+            |lua, args: (String, Vec<patch::PatchHunk>)| apply_hunks(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "hunk_patch",
+        guard::function(
+            lua,
+            "hunk_patch",
+            |lua, args: (hunk_patch::PatchFile, usize, Option<LuaTable>)| hunk_patch(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "stage_hunk",
+        guard::function(
+            lua,
+            "stage_hunk",
+            |lua, args: (String, usize, DiffOptions)| stage_hunk(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "stage_file",
+        guard::function(lua, "stage_file", |lua, args: (String, DiffOptions)| {
+            stage_file(lua, args)
+        })?,
+    )?;
+    exports.set(
+        "restore_file",
+        guard::function(
+            lua,
+            "restore_file",
+            |lua, args: (String, Option<String>, String, DiffOptions)| restore_file(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "record_fixture",
+        guard::function(
+            lua,
+            "record_fixture",
+            |lua, args: (String, String, String)| record_fixture(lua, args),
+        )?,
+    )?;
+    exports.set(
+        "replay_fixture",
+        guard::function(lua, "replay_fixture", |lua, path: String| {
+            replay_fixture(lua, path)
+        })?,
     )?;
     Ok(exports)
 }
@@ -440,6 +4519,73 @@ fn difftastic_nvim(lua: &Lua) -> LuaResult<LuaTable> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_process_plan_keeps_diff_order() {
+        let count = 3 * MAX_FILES_IN_FLIGHT + 5;
+        let json = (0..count)
+            .map(|i| format!(r#"{{"path":"f{i}.txt","language":"Text","status":"created"}}"#))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let prepared = prefetch::PreparedDiff::new(DiffPlan {
+            json,
+            stats: FileStats::new(),
+            old: ContentSource::WorkingTree(Some(std::env::temp_dir().join("difft-nvim-none"))),
+            new: ContentSource::WorkingTree(Some(std::env::temp_dir().join("difft-nvim-none"))),
+            parents: Vec::new(),
+            renames: HashMap::new(),
+            modes: HashMap::new(),
+            submodules: HashMap::new(),
+        });
+        let paths = |files: Vec<processor::DisplayFile>| -> Vec<PathBuf> {
+            files.into_iter().map(|file| file.path).collect()
+        };
+        let expected: Vec<PathBuf> = (0..count).map(|i| format!("f{i}.txt").into()).collect();
+
+        let diff = process_plan(&prepared, &DiffOptions::default()).unwrap();
+        assert_eq!(paths(diff.files), expected);
+
+        // Streamed files arrive after their listings, in order
+        let tracker = progress::Tracker::streaming();
+        let diff = progress::with_tracker(Some(tracker.clone()), || {
+            process_plan(&prepared, &DiffOptions::default())
+        })
+        .unwrap();
+        assert!(diff.files.is_empty());
+        let updates = tracker.take_files();
+        assert_eq!(updates.len(), 2 * count);
+        let processed: Vec<usize> = updates[count..].iter().map(|update| update.index).collect();
+        assert_eq!(processed, (0..count).collect::<Vec<_>>());
+        assert_eq!(tracker.status().done, count);
+    }
+
+    #[test]
+    fn test_process_plan_hides_excluded_files() {
+        let json = ["Cargo.lock", "src/lib.rs", "dist/app.js"]
+            .map(|path| format!(r#"{{"path":"{path}","language":"Text","status":"created"}}"#))
+            .join("\n");
+        let prepared = prefetch::PreparedDiff::new(DiffPlan {
+            json,
+            stats: FileStats::new(),
+            old: ContentSource::WorkingTree(Some(std::env::temp_dir().join("difft-nvim-none"))),
+            new: ContentSource::WorkingTree(Some(std::env::temp_dir().join("difft-nvim-none"))),
+            parents: Vec::new(),
+            renames: HashMap::new(),
+            modes: HashMap::new(),
+            submodules: HashMap::new(),
+        });
+        let opts = DiffOptions {
+            filter: filter::PathFilter {
+                include: Vec::new(),
+                exclude: vec!["*.lock".to_string(), "dist/**".to_string()],
+            },
+            ..DiffOptions::default()
+        };
+        let diff = process_plan(&prepared, &opts).unwrap();
+        assert_eq!(diff.files.len(), 1);
+        assert_eq!(diff.files[0].path, Path::new("src/lib.rs"));
+        assert_eq!(diff.hidden_files, 2);
+    }
+
     #[test]
     fn test_into_lines_with_content() {
         let lines = into_lines(Some("line1\nline2\nline3".to_string()));
@@ -458,6 +4604,37 @@ mod tests {
         assert_eq!(lines, vec!["single"]);
     }
 
+    #[test]
+    fn test_jj_revset_uses_working_copy() {
+        for revset in ["@", "@-", "trunk()..@", "default@", "working_copies()"] {
+            assert!(jj_revset_uses_working_copy(revset), "{revset}");
+        }
+        for revset in [
+            "abc123",
+            "main@origin",
+            "trunk()..main@origin",
+            "\"main\"@\"origin\"",
+        ] {
+            assert!(!jj_revset_uses_working_copy(revset), "{revset}");
+        }
+    }
+
+    #[test]
+    fn test_is_git_stash() {
+        for rev in [
+            "stash",
+            "stash@{0}",
+            "stash@{12}",
+            "refs/stash",
+            "refs/stash@{1}",
+        ] {
+            assert!(is_git_stash(rev), "{rev}");
+        }
+        for rev in ["HEAD", "stashed-work", "main..stash", "feature/stash"] {
+            assert!(!is_git_stash(rev), "{rev}");
+        }
+    }
+
     #[test]
     fn test_parse_git_range_single_commit() {
         let (old, new) = parse_git_range("abc123");
@@ -472,10 +4649,418 @@ mod tests {
         assert_eq!(new, "feature");
     }
 
+    #[test]
+    fn test_find_in_path_resolves_absolute_path() {
+        let dir = std::env::temp_dir().join(format!("difft-nvim-path-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = if cfg!(windows) { "difft.exe" } else { "difft" };
+        std::fs::write(dir.join(name), "").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(0o755);
+            std::fs::set_permissions(dir.join(name), permissions).unwrap();
+        }
+
+        let path_var = std::env::join_paths([Path::new("/nonexistent"), &dir]).unwrap();
+        let found = find_in_path("difft", &path_var);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(found, Some(dir.join(name)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_in_path_skips_relative_and_non_executable() {
+        let dir = std::env::temp_dir().join(format!("difft-nvim-noexec-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("bin")).unwrap();
+        std::fs::write(dir.join("bin/difft"), "").unwrap();
+
+        // Not executable
+        let path_var = std::env::join_paths([dir.join("bin")]).unwrap();
+        assert_eq!(find_in_path("difft", &path_var), None);
+        // Executable, but only reachable through a relative entry
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(0o755);
+        std::fs::set_permissions(dir.join("bin/difft"), permissions).unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        let up: PathBuf = cwd.components().skip(1).map(|_| "..").collect();
+        let relative = up.join(dir.join("bin").strip_prefix("/").unwrap());
+        assert!(relative.join("difft").is_file());
+        let relative_var = std::env::join_paths([relative]).unwrap();
+        assert_eq!(find_in_path("difft", &relative_var), None);
+        assert_eq!(
+            find_in_path("difft", &path_var),
+            Some(dir.join("bin/difft"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_in_path_missing() {
+        let path_var = std::env::join_paths([Path::new("/nonexistent")]).unwrap();
+        assert_eq!(find_in_path("difft", &path_var), None);
+    }
+
+    #[test]
+    fn test_parse_parents_merge_commit() {
+        let parents = parse_parents("aaa\tMain line\nbbb\tMerge branch 'feature'\n");
+        assert_eq!(parents.len(), 2);
+        assert_eq!(parents[0].commit, "aaa");
+        assert_eq!(parents[1].subject, "Merge branch 'feature'");
+    }
+
+    #[test]
+    fn test_parse_parents_with_timestamps() {
+        let parents =
+            parse_parents("aaa\tFix\tthe bug\t1700000000\nbbb\tOther\t1600000000 -3600\n");
+        assert_eq!(parents[0].subject, "Fix\tthe bug");
+        assert_eq!(parents[0].timestamp, Some(1_700_000_000));
+        assert_eq!(parents[1].timestamp, Some(1_600_000_000));
+    }
+
+    #[test]
+    fn test_parse_tags_newest_first() {
+        let tags = parse_tags(
+            "v1.0\taaa\t1600000000\tFirst release\n\
+             v1.1 tip\tbbb\t1700000000 0\tSecond\trelease\n",
+        );
+        let names: Vec<&str> = tags.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(names, ["v1.1", "v1.0"]);
+        assert_eq!(tags[0].commit, "bbb");
+        assert_eq!(tags[0].message, "Second\trelease");
+        assert_eq!(tags[0].timestamp, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn test_summarize_directories() {
+        let files: Vec<_> = [("src/a.rs", 1, 2), ("src/b.rs", 3, 0), ("README.md", 1, 1)]
+            .into_iter()
+            .map(|(path, additions, deletions)| {
+                processor::stats_only(preview_file(path, 0), Some((additions, deletions)))
+            })
+            .collect();
+
+        let directories = summarize_directories(&files);
+        assert_eq!(directories.len(), 2);
+        assert_eq!(directories[0].directory, "");
+        assert_eq!(
+            directories[1],
+            DirectorySummary {
+                directory: "src".into(),
+                files: 2,
+                additions: 4,
+                deletions: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_git_range_args_single_commit_uses_first_parent() {
+        assert_eq!(git_range_args("HEAD", None), vec!["HEAD^", "HEAD"]);
+    }
+
+    #[test]
+    fn test_git_range_args_selected_parent() {
+        assert_eq!(git_range_args("HEAD", Some("bbb")), vec!["bbb", "HEAD"]);
+    }
+
+    #[test]
+    fn test_git_range_args_range_passthrough() {
+        assert_eq!(git_range_args("main..feature", None), vec!["main..feature"]);
+    }
+
+    #[test]
+    fn test_select_parent_out_of_range() {
+        let parents = parse_parents("aaa\tone\n");
+        assert_eq!(select_parent(&parents, None), Ok(None));
+        assert_eq!(
+            select_parent(&parents, Some(1)),
+            Ok(Some("aaa".to_string()))
+        );
+        assert!(select_parent(&parents, Some(2)).is_err());
+        assert!(select_parent(&parents, Some(0)).is_err());
+    }
+
     #[test]
     fn test_parse_git_range_empty_left() {
         let (old, new) = parse_git_range("..HEAD");
         assert_eq!(old, "");
         assert_eq!(new, "HEAD");
     }
+
+    fn preview_file(path: &str, changed_lines: u32) -> difftastic::DifftFile {
+        let line = difftastic::DiffLine {
+            lhs: None,
+            rhs: Some(difftastic::Side {
+                line_number: 0,
+                changes: Vec::new(),
+            }),
+        };
+        difftastic::DifftFile {
+            path: path.into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: difftastic::Status::Changed,
+            aligned_lines: Vec::new(),
+            chunks: vec![vec![line; changed_lines as usize]],
+        }
+    }
+
+    #[test]
+    fn test_preview_selection_prefers_most_changed() {
+        let files = [
+            preview_file("a.rs", 1),
+            preview_file("b.rs", 5),
+            preview_file("c.rs", 1),
+            preview_file("d.rs", 3),
+        ];
+        let mut stats = FileStats::new();
+        stats.insert("c.rs".into(), (10, 10));
+
+        assert_eq!(
+            preview_selection(&files, &stats, 2),
+            vec![false, true, true, false]
+        );
+        // Ties are broken by path
+        assert_eq!(
+            preview_selection(
+                &[preview_file("z.rs", 1), preview_file("y.rs", 1)],
+                &stats,
+                1
+            ),
+            vec![false, true]
+        );
+    }
+
+    #[test]
+    fn test_budget_selection_drops_files_past_the_budget() {
+        let files = [
+            preview_file("a.rs", 3),
+            preview_file("b.rs", 3),
+            preview_file("c.rs", 3),
+            preview_file("d.rs", 1),
+        ];
+        let stats = FileStats::new();
+
+        let mut selected = vec![true, false, true, true];
+        assert!(budget_selection(&files, &stats, &mut selected, 5));
+        // Later files stay stats-only even when they would fit
+        assert_eq!(selected, vec![true, false, false, false]);
+
+        let mut selected = vec![true; 4];
+        assert!(!budget_selection(&files, &stats, &mut selected, 10));
+        assert_eq!(selected, vec![true; 4]);
+    }
+
+    #[test]
+    fn test_diff_mode_named() {
+        assert!(matches!(
+            DiffMode::named("index", "git"),
+            Ok(DiffMode::Unstaged)
+        ));
+        assert!(matches!(
+            DiffMode::named("staged", "git"),
+            Ok(DiffMode::Staged)
+        ));
+        assert!(matches!(
+            DiffMode::named("cached", "git"),
+            Ok(DiffMode::Staged)
+        ));
+        assert!(matches!(
+            DiffMode::named("head", "git"),
+            Ok(DiffMode::WorkingTree(rev)) if rev == "HEAD"
+        ));
+        assert!(matches!(
+            DiffMode::named("head", "jj"),
+            Ok(DiffMode::Unstaged)
+        ));
+        assert!(DiffMode::named("worktree", "git").is_err());
+    }
+
+    #[test]
+    fn test_dedent() {
+        assert_eq!(
+            dedent("    if a {\n\n        b();\n    }\n"),
+            "if a {\n\n    b();\n}\n"
+        );
+        assert_eq!(dedent("x\n  y"), "x\n  y");
+    }
+
+    #[test]
+    fn test_pathspec_args() {
+        let paths = [PathBuf::from("src/lib.rs"), PathBuf::from("a \"b\".txt")];
+        assert!(pathspec_args("git", &[]).is_empty());
+        assert_eq!(
+            pathspec_args("git", &paths),
+            vec!["--", ":(top)src/lib.rs", ":(top)a \"b\".txt"]
+        );
+        assert_eq!(
+            pathspec_args("jj", &paths),
+            vec!["root-file:\"src/lib.rs\"", r#"root-file:"a \"b\".txt""#]
+        );
+        for vcs in ["hg", "sl"] {
+            assert_eq!(
+                pathspec_args(vcs, &paths),
+                vec!["path:src/lib.rs", "path:a \"b\".txt"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_release_range() {
+        assert_eq!(release_range("git", "v1.0", "v1.1"), "v1.0..v1.1");
+        assert_eq!(release_range("jj", "v1.0", "v1.1"), "v1.0..v1.1");
+        assert_eq!(release_range("hg", "1.0", "1.1"), "only(1.1, 1.0)");
+        assert_eq!(release_range("sl", "1.0", "1.1"), "only(1.1, 1.0)");
+        assert_eq!(
+            range_commits("fossil", "1.0..1.1").unwrap_err(),
+            "Listing commits isn't supported for fossil"
+        );
+    }
+
+    #[test]
+    fn test_jj_git_dir() {
+        let root = std::env::temp_dir().join(format!("difft-jj-git-dir-{}", std::process::id()));
+        let store = root.join("main/.jj/repo/store");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::create_dir_all(root.join("second/.jj")).unwrap();
+        std::fs::write(store.join("git_target"), "../../../.git").unwrap();
+        std::fs::write(
+            root.join("second/.jj/repo"),
+            root.join("main/.jj/repo").to_str().unwrap(),
+        )
+        .unwrap();
+
+        let expected = store.join("../../../.git");
+        assert_eq!(jj_git_dir(&root.join("main/.jj")), Some(expected.clone()));
+        assert_eq!(jj_git_dir(&root.join("second/.jj")), Some(expected));
+        assert_eq!(jj_git_dir(&root.join("missing/.jj")), None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_cat_file_batch() {
+        let output = b"aaaa blob 6\nhello\n\nHEAD:gone.txt missing\nbbbb blob 0\n\ncccc tree 10\n0123456789\n";
+        assert_eq!(
+            parse_cat_file_batch(output, 4),
+            Some(vec![
+                Some(b"hello\n".to_vec()),
+                None,
+                Some(Vec::new()),
+                None
+            ])
+        );
+        // Truncated output can't be trusted
+        assert_eq!(parse_cat_file_batch(b"aaaa blob 6\nhel", 1), None);
+        assert_eq!(parse_cat_file_batch(b"", 1), None);
+    }
+
+    #[test]
+    fn test_parse_unified_numstat() {
+        let diff = "\
+diff --git a/src/lib.rs b/src/lib.rs
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,3 +1,4 @@
+ context
+-old
+--- comment
++new
++added
++++ x
+diff --git a/old name.txt b/new name.txt
+rename from old name.txt
+rename to new name.txt
+--- a/old name.txt
++++ b/new name.txt
+@@ -1 +0,0 @@
+-gone
+";
+        let stats = parse_unified_numstat(diff);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[Path::new("src/lib.rs")], (3, 2));
+        assert_eq!(stats[Path::new("new name.txt")], (0, 1));
+    }
+
+    #[test]
+    fn test_relativize_absolute_snapshot_paths() {
+        let mut stats = FileStats::new();
+        stats.insert("src/lib.rs".into(), (1, 1));
+        stats.insert("lib.rs".into(), (1, 1));
+        let mut files = vec![
+            preview_file("/tmp/extdiff.abc/repo.123/src/lib.rs", 1),
+            preview_file("lib.rs", 1),
+        ];
+        relativize_paths(&mut files, &stats);
+        assert_eq!(files[0].path, Path::new("src/lib.rs"));
+        assert_eq!(files[1].path, Path::new("lib.rs"));
+    }
+
+    #[test]
+    fn test_parse_raw_diff() {
+        let output = concat!(
+            ":100644 100644 abc def M\0src/lib.rs\0",
+            ":100644 100755 abc def R087\0old.sh\0new.sh\0",
+            ":100644 100644 abc abc C100\0a.rs\0b.rs\0",
+            ":100755 100644 abc abc M\0run.sh\0",
+            ":000000 100755 000 abc A\0added.sh\0",
+            ":100644 000000 abc 000 D\0gone.rs\0",
+            ":160000 160000 aaa bbb M\0vendor/lib\0",
+            ":000000 160000 000 ccc A\0vendor/new\0",
+        );
+        let FileChanges {
+            renames,
+            modes,
+            submodules,
+        } = parse_raw_diff(output);
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[Path::new("new.sh")], Path::new("old.sh"));
+        assert_eq!(modes.len(), 2);
+        assert_eq!(modes[Path::new("new.sh")].new, "100755");
+        assert_eq!(modes[Path::new("run.sh")].old, "100755");
+        assert_eq!(modes[Path::new("run.sh")].new, "100644");
+        assert_eq!(submodules.len(), 2);
+        let updated = &submodules[Path::new("vendor/lib")];
+        assert_eq!(
+            (updated.old.as_deref(), updated.new.as_deref()),
+            (Some("aaa"), Some("bbb"))
+        );
+        let added = &submodules[Path::new("vendor/new")];
+        assert_eq!(
+            (added.old.as_deref(), added.new.as_deref()),
+            (None, Some("ccc"))
+        );
+    }
+
+    #[test]
+    fn test_parse_numstat_counts_renames_under_new_path() {
+        let stats = parse_numstat(
+            "1\t2\told.rs => new.rs\n3\t0\tsrc/{a => b}/lib.rs\n0\t1\tsrc/{ => sub}/mod.rs\n",
+        );
+        assert_eq!(stats[Path::new("new.rs")], (1, 2));
+        assert_eq!(stats[Path::new("src/b/lib.rs")], (3, 0));
+        assert_eq!(stats[Path::new("src/sub/mod.rs")], (0, 1));
+    }
+
+    #[test]
+    fn test_apply_renames_under_either_path() {
+        let renames = Renames::from([
+            ("new.rs".into(), "old.rs".into()),
+            ("moved.rs".into(), "orig.rs".into()),
+        ]);
+        let mut files = vec![
+            preview_file("new.rs", 1),
+            preview_file("orig.rs", 1),
+            preview_file("other.rs", 1),
+        ];
+        apply_renames(&mut files, &renames);
+        assert_eq!(files[0].old_path(), Path::new("old.rs"));
+        assert_eq!(files[0].new_path(), Path::new("new.rs"));
+        assert_eq!(files[1].old_path(), Path::new("orig.rs"));
+        assert_eq!(files[1].new_path(), Path::new("moved.rs"));
+        assert_eq!(files[1].status, difftastic::Status::Renamed);
+        assert_eq!(files[2].old_path, None);
+        assert_eq!(files[2].status, difftastic::Status::Changed);
+    }
 }
@@ -0,0 +1,204 @@
+//! Limits on the threads and processes diffs use.
+//!
+//! By default files are processed on rayon's global pool (one thread per core) and every
+//! thread may run VCS commands at once, which can peg all cores of a laptop and trip
+//! antivirus scanners watching process creation. `set_limits` from Lua gives file
+//! processing a dedicated pool of `max_threads` threads and lets at most `max_procs`
+//! commands run at a time; further commands wait for a slot. With `timeout_ms`, commands
+//! running longer (e.g. stuck on a hung credential helper) are killed and fail with a
+//! [`crate::cancel::TIMED_OUT`] error naming the command.
+
+use mlua::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+
+static POOL: RwLock<Option<Arc<rayon::ThreadPool>>> = RwLock::new(None);
+
+/// Command timeout in milliseconds, 0 for none.
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+static PROCESSES: Slots = Slots {
+    state: Mutex::new(SlotState {
+        limit: None,
+        running: 0,
+    }),
+    freed: Condvar::new(),
+};
+
+/// Limits set from Lua; `None` fields are unlimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Threads processing the files of a diff.
+    pub max_threads: Option<usize>,
+
+    /// Commands running at the same time.
+    pub max_procs: Option<usize>,
+
+    /// Milliseconds a command may run before it's killed.
+    pub timeout_ms: Option<u64>,
+}
+
+/// Applies `limits` to every later diff.
+pub fn set(limits: Limits) -> Result<(), String> {
+    let pool = match limits.max_threads {
+        Some(threads) => Some(Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|i| format!("difftastic-nvim-{i}"))
+                .build()
+                .map_err(|e| format!("Failed to start {threads} threads: {e}"))?,
+        )),
+        None => None,
+    };
+    *POOL.write().unwrap_or_else(PoisonError::into_inner) = pool;
+    PROCESSES.set_limit(limits.max_procs);
+    TIMEOUT_MS.store(limits.timeout_ms.unwrap_or(0), Ordering::Relaxed);
+    Ok(())
+}
+
+/// How long a command may run before it's killed, if limited.
+pub fn timeout() -> Option<Duration> {
+    match TIMEOUT_MS.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// Runs `f` in the configured pool, so its parallel iterators use that pool's threads.
+/// Without a configured pool `f` runs on the current thread and uses the global pool.
+///
+/// Thread-local context (see [`crate::repo::in_repo`]) doesn't carry over to the pool's
+/// threads: capture it before and restore it inside, like for any parallel iterator.
+pub fn install<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    let pool = POOL.read().unwrap_or_else(PoisonError::into_inner).clone();
+    match pool {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+/// Runs `f` on the current thread with a scope whose spawned jobs run in the configured
+/// pool, or in the global one without. Unlike [`install`], `f` keeps the current thread's
+/// context.
+pub fn in_place_scope<'scope, T>(f: impl FnOnce(&rayon::Scope<'scope>) -> T) -> T {
+    let pool = POOL.read().unwrap_or_else(PoisonError::into_inner).clone();
+    match pool {
+        Some(pool) => pool.in_place_scope(f),
+        None => rayon::in_place_scope(f),
+    }
+}
+
+/// Waits for a slot to run a command, giving up once `cancelled` returns `true`. The slot
+/// is freed when the returned permit is dropped.
+pub fn process_slot(cancelled: impl Fn() -> bool) -> Option<Permit> {
+    PROCESSES.acquire(cancelled)
+}
+
+struct SlotState {
+    limit: Option<usize>,
+    running: usize,
+}
+
+/// A counting semaphore whose size can change while slots are taken.
+struct Slots {
+    state: Mutex<SlotState>,
+    freed: Condvar,
+}
+
+impl Slots {
+    fn set_limit(&self, limit: Option<usize>) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .limit = limit;
+        // A larger limit may let waiting commands start
+        self.freed.notify_all();
+    }
+
+    fn acquire(&'static self, cancelled: impl Fn() -> bool) -> Option<Permit> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while state
+            .limit
+            .is_some_and(|limit| state.running >= limit.max(1))
+        {
+            if cancelled() {
+                return None;
+            }
+            // Wake up now and then to notice cancellation
+            state = self
+                .freed
+                .wait_timeout(state, Duration::from_millis(10))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        state.running += 1;
+        Some(Permit(self))
+    }
+
+    fn release(&self) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .running -= 1;
+        self.freed.notify_one();
+    }
+}
+
+/// A taken command slot, freed on drop.
+pub struct Permit(&'static Slots);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+impl FromLua for Limits {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(table) = value else {
+            return Ok(Self::default());
+        };
+        let positive = |name: &str| -> LuaResult<Option<u64>> {
+            match table.get::<Option<u64>>(name)? {
+                Some(0) => Err(LuaError::RuntimeError(format!("{name} must be at least 1"))),
+                n => Ok(n),
+            }
+        };
+        let count = |name: &str| -> LuaResult<Option<usize>> {
+            Ok(positive(name)?.map(|n| usize::try_from(n).unwrap_or(usize::MAX)))
+        };
+        Ok(Self {
+            max_threads: count("max_threads")?,
+            max_procs: count("max_procs")?,
+            timeout_ms: positive("timeout_ms")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_wait_for_a_free_one() {
+        static SLOTS: Slots = Slots {
+            state: Mutex::new(SlotState {
+                limit: Some(1),
+                running: 0,
+            }),
+            freed: Condvar::new(),
+        };
+        let first = SLOTS.acquire(|| false).unwrap();
+        // Full: a cancelled waiter gives up instead of blocking
+        assert!(SLOTS.acquire(|| true).is_none());
+
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| SLOTS.acquire(|| false).is_some());
+            std::thread::sleep(Duration::from_millis(20));
+            drop(first);
+            assert!(waiter.join().unwrap());
+        });
+        assert_eq!(SLOTS.state.lock().unwrap().running, 0);
+    }
+}
@@ -0,0 +1,177 @@
+//! Memoized highlight merging for changed lines repeated across a diff.
+//!
+//! Boilerplate edits repeated over many files (a renamed import, a bumped version, a new
+//! license header) give the same changed line with the same changes again and again.
+//! A [`Memo`] remembers the highlights computed for each line content and set of changes
+//! during one run, keyed by their hashes, and counts hits and misses so the hit rate can be
+//! reported with the result.
+//!
+//! Like tokens in [`crate::cancel`], a memo is selected for the current thread with
+//! [`with_memo`]. Without one, highlights are computed every time.
+
+use crate::difftastic::Change;
+use crate::processor::Highlights;
+use mlua::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Lines remembered per run. Once full, lines missing from the memo are computed without
+/// being added.
+const MAX_ENTRIES: usize = 65_536;
+
+thread_local! {
+    static MEMO: RefCell<Option<Arc<Memo>>> = const { RefCell::new(None) };
+}
+
+/// Highlights of one line, along with the inputs (the changes' offsets and syntax kinds), so
+/// hash collisions can't mix up lines.
+struct Entry {
+    content: Box<str>,
+    regions: Box<[(u32, u32, Box<str>)]>,
+    highlights: Highlights,
+}
+
+/// Highlights computed during one run, by hashes of the line content and its changes.
+#[derive(Default)]
+pub struct Memo {
+    entries: Mutex<HashMap<(u64, u64), Entry>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// How often a run found a line's highlights in its memo.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl Stats {
+    /// Share of lookups that were hits, 0 without lookups.
+    #[must_use]
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+impl Memo {
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The highlights remembered for `content` and `changes`, or the ones `compute` returns.
+    fn get_or_compute(
+        &self,
+        content: &str,
+        changes: &[Change],
+        compute: impl FnOnce() -> Highlights,
+    ) -> Highlights {
+        let regions: Box<[(u32, u32, Box<str>)]> = changes
+            .iter()
+            .map(|c| (c.start, c.end, c.highlight.as_str().into()))
+            .collect();
+        let key = (hash(content), hash(&regions));
+        let entries = || self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = entries().get(&key)
+            && *entry.content == *content
+            && entry.regions == regions
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return entry.highlights.clone();
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // Computed without holding the lock, so other threads aren't kept waiting
+        let highlights = compute();
+        let mut entries = entries();
+        if entries.len() < MAX_ENTRIES {
+            entries.entry(key).or_insert_with(|| Entry {
+                content: content.into(),
+                regions,
+                highlights: highlights.clone(),
+            });
+        }
+        highlights
+    }
+}
+
+fn hash(value: &(impl Hash + ?Sized)) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Runs `f` with `memo` selected for the current thread. `None` disables memoization.
+pub fn with_memo<T>(memo: Option<Arc<Memo>>, f: impl FnOnce() -> T) -> T {
+    let previous = MEMO.with(|current| current.replace(memo));
+    let result = f();
+    MEMO.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+/// The highlights of `content` with `changes`, from the memo selected for the current
+/// thread if it has them, or else from `compute`.
+pub fn highlights(
+    content: &str,
+    changes: &[Change],
+    compute: impl FnOnce() -> Highlights,
+) -> Highlights {
+    match MEMO.with(|memo| memo.borrow().clone()) {
+        Some(memo) => memo.get_or_compute(content, changes, compute),
+        None => compute(),
+    }
+}
+
+impl IntoLua for Stats {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("hits", self.hits)?;
+        table.set("misses", self.misses)?;
+        table.set("hit_rate", self.hit_rate())?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(start: u32, end: u32) -> Change {
+        Change {
+            start,
+            end,
+            content: String::new(),
+            highlight: "normal".to_string(),
+        }
+    }
+
+    #[test]
+    fn repeated_lines_hit_the_memo() {
+        let memo = Arc::new(Memo::default());
+        let computed = std::cell::Cell::new(0);
+        let compute = || {
+            computed.set(computed.get() + 1);
+            Highlights::new()
+        };
+        with_memo(Some(memo.clone()), || {
+            highlights("use foo;", &[change(4, 7)], compute);
+            highlights("use foo;", &[change(4, 7)], compute);
+            highlights("use foo;", &[change(0, 3)], compute);
+        });
+        // Without a memo, nothing is remembered
+        highlights("use foo;", &[change(4, 7)], compute);
+
+        assert_eq!(computed.get(), 3);
+        assert_eq!(memo.stats(), Stats { hits: 1, misses: 2 });
+        assert!((memo.stats().hit_rate() - 1.0 / 3.0).abs() < f64::EPSILON);
+    }
+}
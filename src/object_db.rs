@@ -0,0 +1,193 @@
+//! In-process access to git's object database through [gix](https://github.com/GitoxideLabs/gitoxide),
+//! behind the `gix` feature.
+//!
+//! Resolving revisions, reading blobs and counting changed lines of commit ranges otherwise
+//! takes a `git` subprocess each (`rev-parse`, `cat-file --batch`, `diff --numstat`). With the
+//! feature enabled, these read the repository directly and only fall back to `git` when gix
+//! can't answer, e.g. for revision syntax it doesn't support. Repositories behind a command
+//! wrapper (see [`crate::wrapper`]) aren't local, so they always go through `git`.
+//!
+//! Running difftastic itself still needs `git diff --ext-diff`.
+
+use crate::FileStats;
+use crate::repo;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Opens the repository selected for the current thread, unless commands run elsewhere.
+fn open() -> Option<gix::Repository> {
+    if crate::wrapper::current().is_some() {
+        return None;
+    }
+    gix::discover(repo::dir()?).ok()
+}
+
+/// Opens the git directory `git_dir`, or the current repository without one.
+fn open_at(git_dir: Option<&Path>) -> Option<gix::Repository> {
+    match git_dir {
+        Some(_) if crate::wrapper::current().is_some() => None,
+        Some(git_dir) => gix::open(git_dir).ok(),
+        None => open(),
+    }
+}
+
+/// Resolves `rev` to a full commit hash, or `None` if gix can't.
+pub fn rev_parse(rev: &str) -> Option<String> {
+    let repo = open()?;
+    let commit = repo
+        .rev_parse_single(rev)
+        .ok()?
+        .object()
+        .ok()?
+        .peel_to_commit()
+        .ok()?;
+    Some(commit.id.to_string())
+}
+
+/// Reads the blobs named by `<rev>:<path>` specs (`:<path>` for the index) from `git_dir`
+/// or the current repository, like `git cat-file --batch`. Entries are `None` for paths that
+/// don't name a blob. Returns `None` if the repository or any revision can't be read, so
+/// callers fall back to git.
+pub fn blobs(git_dir: Option<&Path>, specs: &[String]) -> Option<Vec<Option<Vec<u8>>>> {
+    let repo = open_at(git_dir)?;
+    let mut trees = HashMap::new();
+    let mut index = None;
+    specs
+        .iter()
+        .map(|spec| {
+            let (rev, path) = spec.split_once(':')?;
+            let id = if rev.is_empty() {
+                let index = match &mut index {
+                    Some(index) => index,
+                    None => index.insert(repo.index_or_empty().ok()?),
+                };
+                let path = gix::path::into_bstr(Path::new(path));
+                match index.entry_by_path(path.as_ref()) {
+                    Some(entry) => entry.id,
+                    None => return Some(None),
+                }
+            } else {
+                let tree = match trees.get(rev) {
+                    Some(tree) => tree,
+                    None => {
+                        let tree = repo
+                            .rev_parse_single(rev)
+                            .ok()?
+                            .object()
+                            .ok()?
+                            .peel_to_tree()
+                            .ok()?;
+                        trees.entry(rev).or_insert(tree)
+                    }
+                };
+                match tree.lookup_entry_by_path(path).ok()? {
+                    Some(entry) if entry.mode().is_blob() => entry.object_id(),
+                    _ => return Some(None),
+                }
+            };
+            let blob = repo.find_object(id).ok()?;
+            Some(Some(blob.data.clone()))
+        })
+        .collect()
+}
+
+/// Counts added and removed lines per file between the commits `old` and `new`, like
+/// `git diff --numstat`, restricted to `paths` unless empty. Binary files are left out.
+pub fn numstat(old: &str, new: &str, paths: &[PathBuf]) -> Option<FileStats> {
+    let repo = open()?;
+    let tree = |rev: &str| {
+        repo.rev_parse_single(rev)
+            .ok()?
+            .object()
+            .ok()?
+            .peel_to_tree()
+            .ok()
+    };
+    let (old, new) = (tree(old)?, tree(new)?);
+    let mut cache = repo
+        .diff_resource_cache(gix::diff::blob::pipeline::Mode::ToGit, Default::default())
+        .ok()?;
+    let mut stats = HashMap::new();
+    old.changes()
+        .ok()?
+        .options(|opts| {
+            // Like `git diff -M`: renamed files are counted under their new path
+            opts.track_path().track_rewrites(Some(Default::default()));
+        })
+        .for_each_to_obtain_tree(&new, |change| {
+            let path = gix::path::from_bstr(change.location()).into_owned();
+            let selected = paths.is_empty() || paths.iter().any(|p| path.starts_with(p));
+            if selected && change.entry_mode().is_blob() {
+                // `None` for binary files, which `git diff --numstat` counts as `-`
+                if let Some(counts) = change.diff(&mut cache)?.line_counts()? {
+                    stats.insert(path, (counts.insertions, counts.removals));
+                }
+            }
+            cache.clear_resource_cache_keep_allocation();
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(
+                gix::object::tree::diff::Action::Continue,
+            )
+        })
+        .ok()?;
+    Some(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=t", "-c", "user.email=t@t"])
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn reads_blobs_and_counts_lines_like_git() {
+        let dir = std::env::temp_dir().join(format!("difft-object-db-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        git(&dir, &["init", "-q"]);
+        std::fs::write(dir.join("src/a.txt"), "one\ntwo\n").unwrap();
+        git(&dir, &["add", "."]);
+        git(&dir, &["commit", "-qm", "first"]);
+        std::fs::write(dir.join("src/a.txt"), "one\n2\nthree\n").unwrap();
+        std::fs::write(dir.join("b.txt"), "new\n").unwrap();
+        git(&dir, &["add", "."]);
+        git(&dir, &["commit", "-qm", "second"]);
+
+        repo::in_repo(Some(&dir), || {
+            assert_eq!(
+                blobs(
+                    None,
+                    &[
+                        "HEAD~1:src/a.txt".to_string(),
+                        "HEAD~1:b.txt".to_string(),
+                        ":b.txt".to_string(),
+                    ]
+                ),
+                Some(vec![
+                    Some(b"one\ntwo\n".to_vec()),
+                    None,
+                    Some(b"new\n".to_vec())
+                ])
+            );
+            assert!(blobs(None, &["no-such-rev:a.txt".to_string()]).is_none());
+            assert!(blobs(Some(&dir.join(".git")), &["HEAD:b.txt".to_string()]).is_some());
+
+            let stats = numstat("HEAD~1", "HEAD", &[]).unwrap();
+            assert_eq!(stats.get(Path::new("src/a.txt")), Some(&(2, 1)));
+            assert_eq!(stats.get(Path::new("b.txt")), Some(&(1, 0)));
+            let stats = numstat("HEAD~1", "HEAD", &[PathBuf::from("src")]).unwrap();
+            assert_eq!(stats.len(), 1);
+
+            assert_eq!(rev_parse("HEAD~1").map(|id| id.len()), Some(40));
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
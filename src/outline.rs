@@ -0,0 +1,364 @@
+//! Lightweight, language-agnostic detection of definitions and imports in source lines.
+//!
+//! Difftastic tells us *which* tokens changed but not which function or block they belong
+//! to. This module recognizes common definition and import syntax across popular languages
+//! with simple prefix matching, which is enough to produce labels like `fn run_diff` or
+//! `imports` without a full parser.
+//!
+//! [`Outline`] indexes the function definitions of a whole file so the function enclosing
+//! any line can be found using indentation, similar to git's hunk-header `funcname` logic.
+
+/// Keywords that may precede a definition keyword without changing its meaning.
+const MODIFIERS: &[&str] = &[
+    "pub",
+    "pub(crate)",
+    "pub(super)",
+    "export",
+    "default",
+    "async",
+    "static",
+    "public",
+    "private",
+    "protected",
+    "internal",
+    "unsafe",
+    "extern",
+    "abstract",
+    "final",
+    "override",
+    "local",
+    "inline",
+    "virtual",
+    "open",
+    "sealed",
+    "data",
+];
+
+/// Keywords that introduce a definition.
+const DEFINITION_KEYWORDS: &[&str] = &[
+    "fn",
+    "def",
+    "function",
+    "func",
+    "fun",
+    "class",
+    "struct",
+    "enum",
+    "trait",
+    "impl",
+    "interface",
+    "mod",
+    "module",
+    "object",
+    "union",
+    "defn",
+    "defmacro",
+];
+
+/// Definition keywords that introduce a function or method.
+const FUNCTION_KEYWORDS: &[&str] = &["fn", "def", "function", "func", "fun", "defn", "defmacro"];
+
+/// Line prefixes that introduce an import or include.
+const IMPORT_PREFIXES: &[&str] = &[
+    "use ",
+    "pub use ",
+    "import ",
+    "from ",
+    "#include",
+    "require ",
+    "extern crate ",
+    "using ",
+    "(require ",
+    "(ns ",
+];
+
+/// A definition recognized on a single line, e.g. `fn run_diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    /// The definition keyword (`fn`, `class`, `struct`, ...).
+    pub keyword: &'static str,
+
+    /// The defined name.
+    pub name: String,
+}
+
+impl Definition {
+    /// Whether this definition is a function or method (as opposed to a type or module).
+    #[must_use]
+    pub fn is_function(&self) -> bool {
+        FUNCTION_KEYWORDS.contains(&self.keyword)
+    }
+}
+
+impl std::fmt::Display for Definition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.keyword, self.name)
+    }
+}
+
+/// Returns `true` for characters that can appear in a definition name.
+#[inline]
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '$' | '!' | '?' | '-' | '.' | ':')
+}
+
+/// Recognizes a definition at the start of a line.
+#[must_use]
+pub fn definition(line: &str) -> Option<Definition> {
+    let mut rest = line.trim_start().trim_start_matches('(');
+
+    // Skip modifiers such as `pub async` or `export default`
+    loop {
+        let word = rest.split_whitespace().next()?;
+        if !MODIFIERS.contains(&word) {
+            break;
+        }
+        rest = rest[word.len()..].trim_start();
+    }
+
+    // Generic parameters may directly follow the keyword, as in `impl<T>`
+    let word = rest.split(|c: char| c.is_whitespace() || c == '<').next()?;
+    let keyword = DEFINITION_KEYWORDS.iter().find(|&&k| k == word)?;
+    rest = rest[word.len()..].trim_start();
+
+    // Go methods: `func (r *Receiver) Name(...)`
+    if *keyword == "func" && rest.starts_with('(') {
+        rest = rest[rest.find(')')? + 1..].trim_start();
+    }
+
+    let name = if *keyword == "impl" {
+        // `impl<T> Trait for Type {` is more useful with the whole header
+        let header = rest.split('{').next().unwrap_or(rest).trim();
+        let header = header.strip_prefix('<').map_or(header, |h| {
+            h.split_once('>').map_or(h, |(_, tail)| tail.trim_start())
+        });
+        header.to_string()
+    } else {
+        rest.chars()
+            .take_while(|&c| is_name_char(c))
+            .collect::<String>()
+            .trim_end_matches(':')
+            .to_string()
+    };
+
+    (!name.is_empty()).then_some(Definition { keyword, name })
+}
+
+/// Returns the width of a line's leading whitespace, counting a tab as 4 columns.
+#[inline]
+fn indentation(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| c.is_whitespace())
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum()
+}
+
+/// Returns `true` for lines that only close a block (`}`, `end`, `)];`, ...).
+#[inline]
+fn is_block_close(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed == "end"
+        || trimmed.starts_with("end ")
+        || (!trimmed.is_empty() && trimmed.chars().all(|c| "}])>;,".contains(c)))
+}
+
+/// Index of the function definitions in a file, for enclosing-function lookups.
+#[derive(Debug, Default)]
+pub struct Outline {
+    /// `(line index, indentation, definition)` sorted by line index.
+    functions: Vec<(usize, usize, Definition)>,
+}
+
+impl Outline {
+    /// Scans all lines of a file for function definitions.
+    #[must_use]
+    pub fn new<S: AsRef<str>>(lines: &[S]) -> Self {
+        let functions = lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                let line = line.as_ref();
+                definition(line)
+                    .filter(Definition::is_function)
+                    .map(|def| (i, indentation(line), def))
+            })
+            .collect();
+        Self { functions }
+    }
+
+    /// All function definitions in file order.
+    pub fn functions(&self) -> impl Iterator<Item = &Definition> {
+        self.functions.iter().map(|(_, _, def)| def)
+    }
+
+    /// Finds the function whose body contains line `line` of `lines`.
+    ///
+    /// A function encloses a line if it is defined above it with strictly smaller
+    /// indentation. The definition line itself and block-closing lines at the definition's
+    /// indentation also count as part of the function. Blank lines have no enclosing function.
+    #[must_use]
+    pub fn enclosing<S: AsRef<str>>(&self, lines: &[S], line: usize) -> Option<&Definition> {
+        self.enclosing_entry(lines, line).map(|(_, _, def)| def)
+    }
+
+    /// Finds the line index of the function definition enclosing line `line` (see
+    /// [`Outline::enclosing`]), e.g. for a git-style hunk header.
+    #[must_use]
+    pub fn enclosing_line<S: AsRef<str>>(&self, lines: &[S], line: usize) -> Option<usize> {
+        self.enclosing_entry(lines, line).map(|(i, _, _)| *i)
+    }
+
+    fn enclosing_entry<S: AsRef<str>>(
+        &self,
+        lines: &[S],
+        line: usize,
+    ) -> Option<&(usize, usize, Definition)> {
+        let content = lines.get(line)?.as_ref();
+        if content.trim().is_empty() {
+            return None;
+        }
+        let indent = indentation(content);
+        let closes = is_block_close(content);
+        let candidates = self.functions.partition_point(|(i, _, _)| *i <= line);
+
+        self.functions[..candidates]
+            .iter()
+            .rev()
+            .find(|(i, def_indent, _)| {
+                *i == line || *def_indent < indent || (closes && *def_indent == indent)
+            })
+    }
+}
+
+/// Returns `true` if the line looks like an import, `use`, or `#include`.
+#[must_use]
+pub fn is_import(line: &str) -> bool {
+    let line = line.trim_start();
+    IMPORT_PREFIXES
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+        || (line.starts_with("local ") && line.contains("require("))
+        || (line.starts_with("const ") && line.contains("require("))
+}
+
+/// Produces a semantic label for a group of changed lines.
+///
+/// The first definition wins (e.g. `fn run_diff`); otherwise, if every non-blank line is an
+/// import, the label is `imports`.
+#[must_use]
+pub fn label<'a>(lines: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    let mut all_imports = true;
+    let mut any = false;
+
+    for line in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(def) = definition(line) {
+            return Some(def.to_string());
+        }
+        any = true;
+        all_imports &= is_import(line);
+    }
+
+    (any && all_imports).then(|| "imports".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rust_function_with_modifiers() {
+        let def = definition("    pub async fn run_diff(lua: &Lua) -> LuaResult<()> {").unwrap();
+        assert_eq!(def.to_string(), "fn run_diff");
+    }
+
+    #[test]
+    fn rust_impl_header() {
+        let def = definition("impl<T> IntoLua for Side<T> {").unwrap();
+        assert_eq!(def.to_string(), "impl IntoLua for Side<T>");
+    }
+
+    #[test]
+    fn go_method_skips_receiver() {
+        let def = definition("func (s *Server) Start(ctx context.Context) error {").unwrap();
+        assert_eq!(def.to_string(), "func Start");
+    }
+
+    #[test]
+    fn lua_and_python_definitions() {
+        assert_eq!(
+            definition("local function setup(opts)")
+                .unwrap()
+                .to_string(),
+            "function setup"
+        );
+        assert_eq!(
+            definition("def process(self):").unwrap().to_string(),
+            "def process"
+        );
+        assert_eq!(
+            definition("(defn parse-args [args]").unwrap().to_string(),
+            "defn parse-args"
+        );
+    }
+
+    #[test]
+    fn plain_statements_are_not_definitions() {
+        assert_eq!(definition("let x = fn_call();"), None);
+        assert_eq!(definition("return function"), None);
+        assert_eq!(definition(""), None);
+    }
+
+    #[test]
+    fn enclosing_function_by_indentation() {
+        let lines = [
+            "fn outer() {",
+            "    let a = 1;",
+            "    fn inner() {",
+            "        a + 1",
+            "    }",
+            "    a",
+            "}",
+            "",
+            "const X: u32 = 1;",
+        ];
+        let outline = Outline::new(&lines);
+        let name = |line| outline.enclosing(&lines, line).map(ToString::to_string);
+
+        assert_eq!(name(0).as_deref(), Some("fn outer"));
+        assert_eq!(name(1).as_deref(), Some("fn outer"));
+        assert_eq!(name(3).as_deref(), Some("fn inner"));
+        assert_eq!(name(4).as_deref(), Some("fn inner"));
+        assert_eq!(name(5).as_deref(), Some("fn outer"));
+        assert_eq!(name(6).as_deref(), Some("fn outer"));
+        assert_eq!(name(7), None);
+        assert_eq!(name(8), None);
+    }
+
+    #[test]
+    fn outline_ignores_type_definitions() {
+        let lines = ["struct Foo {", "    a: u32,", "}"];
+        let outline = Outline::new(&lines);
+        assert_eq!(outline.functions().count(), 0);
+        assert_eq!(outline.enclosing(&lines, 1), None);
+    }
+
+    #[test]
+    fn import_block_label() {
+        let lines = ["use std::path::Path;", "", "use mlua::prelude::*;"];
+        assert_eq!(label(lines), Some("imports".to_string()));
+    }
+
+    #[test]
+    fn definition_label_wins() {
+        let lines = ["    let x = 1;", "fn helper() {"];
+        assert_eq!(label(lines), Some("fn helper".to_string()));
+    }
+
+    #[test]
+    fn no_label_for_plain_code() {
+        assert_eq!(label(["x += 1;", "use_it(x);"]), None);
+    }
+}
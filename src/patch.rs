@@ -0,0 +1,182 @@
+//! Applying several hunks of one file at once.
+//!
+//! Staging or reverting hunks one patch at a time breaks as soon as an earlier hunk changes
+//! the number of lines: the line numbers of later hunks no longer match. Instead, every
+//! hunk is located in the original text first, by its recorded position if the lines still
+//! match there and by the nearest match of its old lines otherwise, and the text is then
+//! rebuilt in a single pass. Hunks therefore never see each other's offsets.
+
+use mlua::prelude::*;
+
+/// One hunk to apply: `old` lines (context and removals) replaced by `new` lines
+/// (context and additions).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchHunk {
+    /// 1-indexed line where `old` starts in the original text. For hunks without old lines,
+    /// the line after which `new` is inserted (`0` for the start of the file), as in
+    /// unified diffs.
+    pub old_start: u32,
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+}
+
+impl FromLua for PatchHunk {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(table) = value else {
+            return Err(LuaError::RuntimeError(format!(
+                "hunk must be a table, got {}",
+                value.type_name()
+            )));
+        };
+        Ok(Self {
+            old_start: table.get("old_start")?,
+            old: table.get::<Option<_>>("old")?.unwrap_or_default(),
+            new: table.get::<Option<_>>("new")?.unwrap_or_default(),
+        })
+    }
+}
+
+/// Applies `hunks` to `text`, in any order, and returns the new text.
+///
+/// Fails if a hunk's old lines can't be found or two hunks touch the same lines.
+pub fn apply_hunks(text: &str, hunks: &[PatchHunk]) -> Result<String, String> {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+
+    let mut located: Vec<(usize, &PatchHunk)> = hunks
+        .iter()
+        .map(|hunk| {
+            locate(&lines, hunk)
+                .map(|start| (start, hunk))
+                .ok_or_else(|| {
+                    format!(
+                        "Hunk at line {} does not apply: its lines were not found",
+                        hunk.old_start
+                    )
+                })
+        })
+        .collect::<Result<_, _>>()?;
+    located.sort_by_key(|&(start, _)| start);
+
+    let ending = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut output = String::with_capacity(text.len());
+    let mut pos = 0;
+    let mut ends_with_hunk = false;
+    for (start, hunk) in located {
+        if start < pos {
+            return Err(format!(
+                "Hunk at line {} overlaps the previous hunk",
+                hunk.old_start
+            ));
+        }
+        output.extend(lines[pos..start].iter().copied());
+        // Lines inserted after a last line without a newline start on a line of their own
+        if !hunk.new.is_empty() && !output.is_empty() && !output.ends_with('\n') {
+            output.push_str(ending);
+        }
+        for line in &hunk.new {
+            output.push_str(line);
+            output.push_str(ending);
+        }
+        pos = start + hunk.old.len();
+        ends_with_hunk = pos == lines.len() && !hunk.new.is_empty();
+    }
+    output.extend(lines[pos..].iter().copied());
+
+    // Keep a missing final newline missing when the last line came from a hunk, dropping
+    // only the ending appended after it: its new lines may end with blank ones
+    if !text.is_empty() && !text.ends_with('\n') && ends_with_hunk {
+        output.truncate(output.len() - ending.len());
+    }
+    Ok(output)
+}
+
+/// Finds where `hunk` applies in `lines`: at its recorded position if its old lines match
+/// there, otherwise at the closest position where they do.
+fn locate(lines: &[&str], hunk: &PatchHunk) -> Option<usize> {
+    let matches_at = |start: usize| {
+        lines
+            .get(start..start + hunk.old.len())
+            .is_some_and(|window| {
+                window
+                    .iter()
+                    .zip(&hunk.old)
+                    .all(|(line, old)| line_text(line) == old)
+            })
+    };
+    let expected = if hunk.old.is_empty() {
+        hunk.old_start as usize
+    } else {
+        (hunk.old_start as usize).saturating_sub(1)
+    };
+    let last = lines.len().checked_sub(hunk.old.len())?;
+    (0..=last.max(expected))
+        .flat_map(|distance| [expected.checked_sub(distance), Some(expected + distance)])
+        .flatten()
+        .filter(|&start| start <= last)
+        .find(|&start| matches_at(start))
+}
+
+/// A line without its line ending.
+fn line_text(line: &str) -> &str {
+    line.trim_end_matches('\n').trim_end_matches('\r')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hunk(old_start: u32, old: &[&str], new: &[&str]) -> PatchHunk {
+        PatchHunk {
+            old_start,
+            old: old.iter().map(|s| s.to_string()).collect(),
+            new: new.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn applies_hunks_regardless_of_order_and_offsets() {
+        let text = "a\nb\nc\nd\ne\n";
+        let hunks = [
+            hunk(4, &["d", "e"], &["d", "E"]),
+            hunk(1, &["a", "b"], &["a", "x", "y", "b"]),
+        ];
+        assert_eq!(apply_hunks(text, &hunks).unwrap(), "a\nx\ny\nb\nc\nd\nE\n");
+    }
+
+    #[test]
+    fn relocates_hunks_with_stale_line_numbers() {
+        let text = "new\na\nb\nc\n";
+        let hunks = [hunk(2, &["b", "c"], &["b", "C"])];
+        assert_eq!(apply_hunks(text, &hunks).unwrap(), "new\na\nb\nC\n");
+    }
+
+    #[test]
+    fn rejects_missing_and_overlapping_hunks() {
+        let text = "a\nb\nc\n";
+        assert!(apply_hunks(text, &[hunk(1, &["z"], &[])]).is_err());
+        let overlapping = [hunk(1, &["a", "b"], &["a"]), hunk(2, &["b", "c"], &["c"])];
+        assert!(apply_hunks(text, &overlapping).is_err());
+    }
+
+    #[test]
+    fn preserves_line_endings() {
+        assert_eq!(
+            apply_hunks("a\r\nb", &[hunk(2, &["b"], &["B"])]).unwrap(),
+            "a\r\nB"
+        );
+        assert_eq!(apply_hunks("", &[hunk(0, &[], &["a"])]).unwrap(), "a\n");
+    }
+
+    #[test]
+    fn keeps_lines_around_a_missing_final_newline() {
+        assert_eq!(
+            apply_hunks("a\nb", &[hunk(2, &["b"], &["B", ""])]).unwrap(),
+            "a\nB\n"
+        );
+        assert_eq!(apply_hunks("a", &[hunk(1, &[], &["b"])]).unwrap(), "a\nb");
+        assert_eq!(
+            apply_hunks("a\r\nb", &[hunk(2, &[], &["c"])]).unwrap(),
+            "a\r\nb\r\nc"
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! Background preparation of range diffs.
+//!
+//! Browsing a range commit by commit runs difftastic and fetches both sides of every file
+//! for each commit, which dominates the time to switch commits. `prefetch` does that work
+//! ahead of time on a background thread and keeps the results in a small in-memory cache
+//! that `run_diff` consults before spawning any subprocess.
+//!
+//! Only plain range diffs are cached: commits are immutable, so a prepared diff never goes
+//! stale, whereas working tree diffs would.
+
+use crate::{
+    Content, ContentSource, DiffMode, DiffOptions, DiffPlan, limits, plan_diff, provider, repo,
+};
+use rayon::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Maximum number of prepared diffs kept in memory.
+const CAPACITY: usize = 16;
+
+/// Prepared diffs shared between the background thread and `run_diff`.
+static CACHE: Mutex<DiffCache> = Mutex::new(DiffCache::new(CAPACITY));
+
+/// A diff plan along with the contents of both sides of its files.
+pub struct PreparedDiff {
+    pub plan: DiffPlan,
+
+    /// Old and new contents by new path. Files missing here are fetched on demand.
+    contents: HashMap<PathBuf, (Content, Content)>,
+}
+
+impl PreparedDiff {
+    /// Wraps a plan whose contents haven't been fetched yet.
+    pub fn new(plan: DiffPlan) -> Self {
+        Self {
+            plan,
+            contents: HashMap::new(),
+        }
+    }
+
+    /// Fetches the contents of `(old_path, new_path)` pairs that weren't prefetched in bulk,
+    /// for the sides that support it (see `ContentSource::fetch_batch`), or all of them
+    /// through a registered content provider (see [`crate::provider`]).
+    pub fn fetch_batch<'a>(
+        &self,
+        paths: impl IntoIterator<Item = (&'a Path, &'a Path)>,
+    ) -> Result<Batch, String> {
+        let (old_paths, new_paths): (Vec<&Path>, Vec<&Path>) = paths
+            .into_iter()
+            .filter(|(_, new_path)| !self.contents.contains_key(*new_path))
+            .unzip();
+        if new_paths.is_empty() {
+            return Ok(Batch::default());
+        }
+        let side = |source: &ContentSource, name, paths: &[&Path]| match provider::fetch(
+            source, name, paths,
+        ) {
+            Some(contents) => contents.map(Some),
+            None => Ok(source.fetch_batch(paths)),
+        };
+        Ok(Batch {
+            old: side(&self.plan.old, "old", &old_paths)?,
+            new: side(&self.plan.new, "new", &new_paths)?,
+        })
+    }
+
+    /// Old contents of `old_path` and new contents of `new_path`, which differ for renamed
+    /// files, from the prefetched contents or `batch` if available.
+    pub fn contents(&self, batch: &Batch, old_path: &Path, new_path: &Path) -> (Content, Content) {
+        if let Some(contents) = self.contents.get(new_path) {
+            return contents.clone();
+        }
+        let side =
+            |fetched: &Option<HashMap<PathBuf, Content>>, source: &ContentSource, path: &Path| {
+                match fetched.as_ref().and_then(|fetched| fetched.get(path)) {
+                    Some(content) => content.clone(),
+                    None => source.fetch(path),
+                }
+            };
+        (
+            side(&batch.old, &self.plan.old, old_path),
+            side(&batch.new, &self.plan.new, new_path),
+        )
+    }
+}
+
+/// Contents fetched in bulk by [`PreparedDiff::fetch_batch`], per side. A side is `None`
+/// when its source can't be batched, and its files are fetched one at a time.
+#[derive(Default)]
+pub struct Batch {
+    old: Option<HashMap<PathBuf, Content>>,
+    new: Option<HashMap<PathBuf, Content>>,
+}
+
+/// Cache key: `(repository, vcs, range)`.
+type Key = (Option<PathBuf>, String, String);
+
+/// A bounded cache evicting the oldest prepared diff once full.
+struct DiffCache {
+    capacity: usize,
+    entries: VecDeque<(Key, Arc<PreparedDiff>)>,
+}
+
+impl DiffCache {
+    const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, key: &Key) -> Option<Arc<PreparedDiff>> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, diff)| Arc::clone(diff))
+    }
+
+    fn insert(&mut self, key: Key, diff: Arc<PreparedDiff>) {
+        self.entries.retain(|(k, _)| *k != key);
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, diff));
+    }
+}
+
+/// Key of `range` in the repository selected for the current thread.
+fn key(vcs: &str, range: &str) -> Key {
+    (repo::dir(), vcs.to_string(), range.to_string())
+}
+
+/// Returns the prefetched diff for `mode`, if it was prepared and `opts` don't change
+/// what the plan contains.
+pub fn cached(mode: &DiffMode, vcs: &str, opts: &DiffOptions) -> Option<Arc<PreparedDiff>> {
+    let DiffMode::Range(range) = mode else {
+        return None;
+    };
+    if opts.parent.is_some() || !opts.paths.is_empty() {
+        return None;
+    }
+    CACHE.lock().ok()?.get(&key(vcs, range))
+}
+
+/// Runs difftastic for `range` and fetches the contents of every changed file.
+/// Holds the repository's lock while doing so (see [`repo::serialized`]).
+/// Without `contents`, only runs difftastic, leaving the contents to be fetched on demand.
+fn prepare(vcs: &str, range: &str, contents: bool) -> Result<PreparedDiff, String> {
+    repo::serialized(|| prepare_unlocked(vcs, range, contents))
+}
+
+fn prepare_unlocked(vcs: &str, range: &str, contents: bool) -> Result<PreparedDiff, String> {
+    let plan = plan_diff(
+        &DiffMode::Range(range.to_string()),
+        vcs,
+        &DiffOptions::default(),
+    )?;
+    let mut prepared = PreparedDiff::new(plan);
+    if !contents {
+        return Ok(prepared);
+    }
+    let files = prepared.plan.files()?;
+    let batch =
+        prepared.fetch_batch(files.iter().map(|file| (file.old_path(), file.new_path())))?;
+    let repo = repo::current();
+    let contents = limits::install(|| {
+        files
+            .into_par_iter()
+            .map(|file| {
+                let contents = repo::in_repo(repo.as_deref(), || {
+                    prepared.contents(&batch, file.old_path(), file.new_path())
+                });
+                (file.path, contents)
+            })
+            .collect()
+    });
+    prepared.contents = contents;
+    Ok(prepared)
+}
+
+/// Drops every prepared diff.
+pub fn clear() {
+    if let Ok(mut cache) = CACHE.lock() {
+        cache.entries.clear();
+    }
+}
+
+/// Prepares the diffs of `ranges` on a background thread, skipping ranges already cached.
+///
+/// Failures are dropped silently: running the same diff later reports the error.
+pub fn spawn(vcs: String, ranges: Vec<String>) {
+    let repo = repo::current();
+    // The content provider can't be called from the background thread, so contents are
+    // left to the diff that uses the plan
+    let contents = !provider::is_set();
+    std::thread::spawn(move || {
+        // One range at a time: each diff is parallel already and holds the repository's lock
+        repo::in_repo(repo.as_deref(), || {
+            for range in &ranges {
+                let key = key(&vcs, range);
+                if CACHE.lock().is_ok_and(|cache| cache.get(&key).is_some()) {
+                    continue;
+                }
+                if let Ok(diff) = prepare(&vcs, range, contents)
+                    && let Ok(mut cache) = CACHE.lock()
+                {
+                    cache.insert(key, Arc::new(diff));
+                }
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prepared(json: &str) -> PreparedDiff {
+        PreparedDiff::new(DiffPlan {
+            json: json.to_string(),
+            stats: HashMap::new(),
+            old: ContentSource::WorkingTree(None),
+            new: ContentSource::WorkingTree(None),
+            parents: Vec::new(),
+            renames: HashMap::new(),
+            modes: HashMap::new(),
+            submodules: HashMap::new(),
+        })
+    }
+
+    #[test]
+    fn cache_evicts_oldest_when_full() {
+        let mut cache = DiffCache::new(2);
+        cache.insert(key("git", "a"), Arc::new(prepared("a")));
+        cache.insert(key("git", "b"), Arc::new(prepared("b")));
+        cache.insert(key("git", "a"), Arc::new(prepared("a2")));
+        cache.insert(key("git", "c"), Arc::new(prepared("c")));
+
+        assert!(cache.get(&key("git", "b")).is_none());
+        assert_eq!(cache.get(&key("git", "a")).unwrap().plan.json, "a2");
+        assert_eq!(cache.get(&key("git", "c")).unwrap().plan.json, "c");
+        assert!(cache.get(&key("jj", "c")).is_none());
+    }
+
+    #[test]
+    fn prepared_contents_fall_back_to_fetching() {
+        let mut diff = prepared("");
+        diff.contents
+            .insert("a.txt".into(), (None, Some(b"new".to_vec())));
+
+        assert_eq!(
+            diff.contents(&Batch::default(), Path::new("a.txt"), Path::new("a.txt")),
+            (None, Some(b"new".to_vec()))
+        );
+        assert_eq!(
+            diff.contents(&Batch::default(), Path::new("b.txt"), Path::new("b.txt")),
+            (None, None)
+        );
+    }
+}
@@ -11,6 +11,9 @@
 //! 3. For changed files, the pre-computed `aligned_lines` from difftastic guides row alignment
 //! 4. Highlights are computed by analyzing the change regions and merging adjacent regions
 //!
+//! [`process_json`] wraps the same flow for a single file's raw JSON and contents, with no
+//! VCS involved, for reproducing processing bugs from a bug report.
+//!
 //! ## Highlight Strategy
 //!
 //! The highlight computation aims to provide useful visual feedback:
@@ -21,21 +24,74 @@
 //!   exactly which characters differ
 //! - Merged regions: Adjacent change regions separated only by whitespace are merged
 //!   for cleaner visual presentation
+//! - Split regions: With [`ProcessOptions::split_words`], partial regions are split back
+//!   into their identifiers, so dense expression edits like `foo.bar(x)` → `foo.baz(y)`
+//!   highlight `baz` and `y` rather than one long span
+//! - Word diff: With [`ProcessOptions::word_diff`], full-line highlights of rows showing
+//!   an old and a new line are narrowed down to the words that differ between the two,
+//!   like `git diff --word-diff`
+//! - Text diffs: difftastic doesn't mark the changes of lines it diffed as text, so their
+//!   differing characters are found here (see [`diff_chars`])
+//!
+//! ## Hunk Summaries
+//!
+//! Each hunk is summarized with the dominant syntax kind of its changes (taken from
+//! difftastic's per-token `highlight` field) and a semantic label such as `fn run_diff`
+//! or `imports`, detected by the [`crate::outline`] module. The same module determines
+//! which functions had their bodies touched, listed per file in `changed_functions`.
+//!
+//! ## Highlight Layers
+//!
+//! A row can be covered by several highlights at once: syntax colors, a whole-line change,
+//! refined changes within the line and invisible character labels. Each region is handed
+//! to Lua with the extmark priority of its [`Layer`], so they stack the same way on every
+//! row.
+//!
+//! ## Syntax Spans
+//!
+//! With [`ProcessOptions::syntax_spans`] enabled, every non-filler side also carries the
+//! syntax spans of its full line from the [`crate::syntax`] highlighter. The spans are
+//! computed over whole files, so multi-line comments and strings are colored correctly
+//! even when filler rows split them in the display.
+//!
+//! ## Context Folding
+//!
+//! With [`ProcessOptions::context`], runs of unchanged rows away from any change are folded
+//! into a single [`RowChange::Gap`] row, so long files with few changes stay short. Each
+//! gap's folded lines are listed in [`DisplayFile::folds`] for showing them on demand.
+//!
+//! ## Unified View
+//!
+//! With [`View::Unified`], the rows are also laid out as a single column in
+//! [`DisplayFile::unified`], each line marked as kept, removed or added and pointing back
+//! at its row, so renderers can offer both layouts from the same processed file.
+//!
+//! ## Conflicts
+//!
+//! [`process_conflict`] builds a three-column [`ConflictFile`] (base, ours, theirs) for a
+//! file with merge conflicts, by merging difftastic's base-to-ours and base-to-theirs
+//! alignments on the base lines.
 
-use crate::difftastic::{Change, Chunk, DifftFile, Status};
+use crate::difftastic::{self, Change, Chunk, DifftFile, Status};
+use crate::encoding::{self, Encodings, Remap};
+use crate::memo;
+use crate::outline;
+use crate::syntax::{self, SyntaxSpan};
 use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use unicode_width::UnicodeWidthChar;
 
 /// Most lines have 0-2 highlight regions; inline storage avoids heap allocation.
-type Highlights = SmallVec<[HighlightRegion; 2]>;
+pub(crate) type Highlights = SmallVec<[HighlightRegion; 2]>;
 
 /// A highlight region within a line, specified by column range.
 ///
 /// Represents a contiguous span of characters that should be highlighted
 /// in the diff viewer to indicate changes.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct HighlightRegion {
     /// Start column (0-indexed, inclusive).
     pub start: u32,
@@ -44,8 +100,45 @@ pub struct HighlightRegion {
     ///
     /// Using -1 as a sentinel value allows the Lua side to easily detect
     /// when the entire line should be highlighted without needing to know
-    /// the actual line length.
+    /// the actual line length. With [`ProcessOptions::line_length_ends`], full-line
+    /// highlights end at the line's length instead.
     pub end: i32,
+
+    /// Whether the region covers the whole line or part of it.
+    #[serde(default)]
+    pub kind: RegionKind,
+
+    /// The syntax kind difftastic gave the changes in the region (`"keyword"`, `"string"`,
+    /// ...), the one covering the most bytes if they differ. `None` if it gave none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub syntax_kind: Option<String>,
+
+    /// Display cells before `start`, when [`ProcessOptions::display_columns`] is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_start: Option<u32>,
+
+    /// Display cells before `end`, or -1 along with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_end: Option<i32>,
+}
+
+/// Kind of a [`HighlightRegion`], so renderers don't need to special-case `end`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionKind {
+    Full,
+    #[default]
+    Partial,
+}
+
+impl RegionKind {
+    #[must_use]
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Partial => "partial",
+        }
+    }
 }
 
 impl HighlightRegion {
@@ -57,7 +150,14 @@ impl HighlightRegion {
     #[inline]
     #[must_use]
     fn full_line() -> Self {
-        Self { start: 0, end: -1 }
+        Self {
+            start: 0,
+            end: -1,
+            kind: RegionKind::Full,
+            syntax_kind: None,
+            display_start: None,
+            display_end: None,
+        }
     }
 
     /// Creates a highlight region for a specific column range.
@@ -67,6 +167,58 @@ impl HighlightRegion {
         Self {
             start,
             end: i32::try_from(end).unwrap_or(i32::MAX),
+            kind: RegionKind::Partial,
+            syntax_kind: None,
+            display_start: None,
+            display_end: None,
+        }
+    }
+
+    /// Sets the region's syntax kind from `changes`, the ones it covers.
+    #[must_use]
+    fn with_kind_of<'a>(self, changes: impl Iterator<Item = &'a Change>) -> Self {
+        Self {
+            syntax_kind: dominant_kind(changes),
+            ..self
+        }
+    }
+
+    /// The layer the region is drawn on.
+    #[inline]
+    #[must_use]
+    pub fn layer(&self) -> Layer {
+        // Entries cached before regions had a kind only have the sentinel
+        if self.kind == RegionKind::Full || self.end == -1 {
+            Layer::FullLine
+        } else {
+            Layer::Inline
+        }
+    }
+}
+
+/// Highlight layers of a row, lowest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer {
+    /// Syntax spans, level with Neovim's treesitter highlights.
+    Syntax,
+    /// Changes covering a whole line.
+    FullLine,
+    /// Changes to part of a line, drawn over a whole-line change of the same row.
+    Inline,
+    /// Labels of invisible characters inside changes.
+    Invisible,
+}
+
+impl Layer {
+    /// Extmark priority of the layer. Change highlights sit above Neovim's semantic tokens
+    /// (125) so language servers can't hide them.
+    #[must_use]
+    pub const fn priority(self) -> u32 {
+        match self {
+            Self::Syntax => 100,
+            Self::FullLine => 130,
+            Self::Inline => 140,
+            Self::Invisible => 160,
         }
     }
 }
@@ -75,7 +227,7 @@ impl HighlightRegion {
 ///
 /// Contains the line content, whether it's a filler (placeholder) line,
 /// and the regions to highlight within the line.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Side {
     /// The text content of this line.
     ///
@@ -88,11 +240,84 @@ pub struct Side {
     /// has content but the other doesn't (e.g., for pure additions or deletions).
     pub is_filler: bool,
 
+    /// The line's number in its version of the file (1-indexed), for line-number gutters
+    /// and links to the line. `None` for fillers and synthetic rows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_number: Option<u32>,
+
     /// Regions within the line to highlight as changed.
     ///
     /// Empty for unchanged lines and filler lines. Uses SmallVec to avoid
     /// heap allocation for the common case of 0-2 highlights per line.
     pub highlights: Highlights,
+
+    /// Syntax spans covering the whole line, changed or not.
+    ///
+    /// Only populated when [`ProcessOptions::syntax_spans`] is enabled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub syntax: Vec<SyntaxSpan>,
+
+    /// Invisible characters (zero-width spaces, BOMs, bidi controls) inside highlighted
+    /// regions, so the UI can render them explicitly instead of as empty-looking changes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub invisibles: Vec<InvisibleChar>,
+}
+
+/// An invisible character found inside a changed region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InvisibleChar {
+    /// Byte offset of the character within the line.
+    pub column: u32,
+
+    /// Unicode code point, e.g. `0x200B` for a zero-width space.
+    pub codepoint: u32,
+}
+
+impl InvisibleChar {
+    /// Display label for the character, e.g. `<U+200B>`.
+    #[must_use]
+    pub fn label(&self) -> String {
+        format!("<U+{:04X}>", self.codepoint)
+    }
+}
+
+/// Returns `true` for characters that render as nothing but still differ byte-wise:
+/// soft hyphens, zero-width spaces and joiners, bidi marks, embeddings, overrides and
+/// isolates, invisible operators, and the byte order mark.
+#[inline]
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}'
+            | '\u{180E}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{FEFF}'
+    )
+}
+
+/// Finds invisible characters inside the highlighted regions of a line.
+fn find_invisibles(content: &str, highlights: &[HighlightRegion]) -> Vec<InvisibleChar> {
+    // Cheap pre-check: every invisible character is non-ASCII
+    if highlights.is_empty() || content.is_ascii() {
+        return Vec::new();
+    }
+    content
+        .char_indices()
+        .filter(|&(i, c)| {
+            is_invisible(c)
+                && highlights.iter().any(|h| {
+                    let i = i as u32;
+                    i >= h.start && (h.end == -1 || i64::from(i) < i64::from(h.end))
+                })
+        })
+        .map(|(i, c)| InvisibleChar {
+            column: i as u32,
+            codepoint: u32::from(c),
+        })
+        .collect()
 }
 
 impl Side {
@@ -100,9 +325,12 @@ impl Side {
     #[inline]
     fn new(content: String, is_filler: bool, highlights: Highlights) -> Self {
         Self {
+            invisibles: find_invisibles(&content, &highlights),
             content,
             is_filler,
+            line_number: None,
             highlights,
+            syntax: Vec::new(),
         }
     }
 
@@ -138,23 +366,227 @@ impl Side {
 /// - Left with content, right filler: A deleted line
 /// - Left filler, right with content: An added line
 /// - Both unchanged: Context line (no highlights)
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Row {
     /// The left side (old/before version) of this row.
     pub left: Side,
 
     /// The right side (new/after version) of this row.
     pub right: Side,
+
+    /// How this row changed, so the renderer can tone down structural-only changes.
+    pub change: RowChange,
+
+    /// The change in words (e.g. `"3 words changed"`), for screen readers.
+    ///
+    /// Only set with [`ProcessOptions::accessible`], and only for changed rows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Classification of a row's change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RowChange {
+    /// No changes on either side.
+    Unchanged,
+
+    /// The whole line was added, removed or replaced.
+    Line,
+
+    /// Only parts of the line changed.
+    Intraline,
+
+    /// Difftastic reports changes, but both sides have identical text. Happens when
+    /// syntax moved or was re-associated (e.g. a token matched to a different node).
+    Structural,
+
+    /// Synthetic row describing a side instead of showing its lines, e.g. `"Empty file"`.
+    /// Not part of any hunk.
+    Notice,
+
+    /// Only the line ending changed (`\r\n` to `\n` or back): both sides have identical
+    /// text once the `\r` is stripped. Flagged whether or not difftastic reported changes.
+    Eol,
+
+    /// Synthetic row standing in for unchanged rows folded away, e.g. `"· · · 45 unchanged
+    /// lines"` on both sides (see [`ProcessOptions::context`] and [`Fold`]).
+    Gap,
+}
+
+impl RowChange {
+    /// Classifies a row from its sides' filler state, content and highlights.
+    fn classify(left: &Side, right: &Side) -> Self {
+        let full_line = |side: &Side| side.highlights.iter().any(|h| h.layer() == Layer::FullLine);
+        if left.is_filler || right.is_filler {
+            Self::Line
+        } else if left.highlights.is_empty() && right.highlights.is_empty() {
+            Self::Unchanged
+        } else if left.content == right.content {
+            Self::Structural
+        } else if full_line(left) && full_line(right) {
+            Self::Line
+        } else {
+            Self::Intraline
+        }
+    }
+
+    #[must_use]
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Unchanged => "unchanged",
+            Self::Line => "line",
+            Self::Intraline => "intraline",
+            Self::Structural => "structural",
+            Self::Notice => "notice",
+            Self::Eol => "eol",
+            Self::Gap => "gap",
+        }
+    }
+}
+
+/// Semantic summary of a hunk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hunk {
+    /// Row index (0-indexed) where the hunk starts.
+    pub start: u32,
+
+    /// Row index (0-indexed) of the hunk's last changed row, e.g. for selecting or staging
+    /// the hunk.
+    #[serde(default)]
+    pub end: u32,
+
+    /// Lines the hunk adds and removes, counting a changed line as both, like `git diff`.
+    /// Rows whose text didn't change (see [`RowChange::Structural`]) don't count.
+    #[serde(default)]
+    pub additions: u32,
+    #[serde(default)]
+    pub deletions: u32,
+
+    /// The syntax kind covering most changed bytes in the hunk (`"keyword"`, `"string"`, ...).
+    ///
+    /// `None` if difftastic provided no highlight information.
+    pub kind: Option<String>,
+
+    /// Short human-readable label such as `"fn run_diff"`, `"imports"` or `"string literal"`.
+    pub label: Option<String>,
+
+    /// The definition line of the function enclosing the hunk's first change, trimmed, e.g.
+    /// `"pub fn run_diff(revset: &str) -> Result<Diff, String> {"`, for showing above the
+    /// hunk like the `@@ ... @@ fn run_diff` header of `git diff`. Prefers the new version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+
+    /// First and last changed line of the new version (1-indexed, inclusive), for linking
+    /// to the hunk from outside the viewer (e.g. a `#L10-L14` permalink).
+    ///
+    /// `None` for hunks that only remove lines.
+    pub new_start: Option<u32>,
+    pub new_end: Option<u32>,
+
+    /// Which old lines correspond to which new lines within the hunk, one entry per
+    /// difftastic chunk, e.g. for drawing connectors between the panes.
+    pub pairs: Vec<LinePairing>,
+
+    /// The identifier the hunk renames, when every change in it replaces the same old
+    /// identifier with the same new one. Only set with [`ProcessOptions::symbol_renames`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rename_of: Option<SymbolRename>,
+}
+
+/// An identifier renamed throughout a hunk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymbolRename {
+    pub old: String,
+    pub new: String,
+}
+
+/// Old and new line ranges (1-indexed, inclusive) that replace each other.
+///
+/// A side is `None` when only lines of the other side changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinePairing {
+    pub old_start: Option<u32>,
+    pub old_end: Option<u32>,
+    pub new_start: Option<u32>,
+    pub new_end: Option<u32>,
+}
+
+impl LinePairing {
+    /// Pairs the extents of the given 0-indexed old and new line numbers.
+    fn spanning(old: impl IntoIterator<Item = u32>, new: impl IntoIterator<Item = u32>) -> Self {
+        let (old_start, old_end) = line_extent(old);
+        let (new_start, new_end) = line_extent(new);
+        Self {
+            old_start,
+            old_end,
+            new_start,
+            new_end,
+        }
+    }
+}
+
+/// A file mode change reported by the VCS, e.g. `100644` to `100755` when a script
+/// becomes executable. Difftastic only compares contents, so it can't report these.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModeChange {
+    pub old: String,
+    pub new: String,
+}
+
+/// A submodule whose recorded commit changed. `None` commits are sides the submodule
+/// doesn't exist on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubmoduleChange {
+    pub old: Option<String>,
+    pub new: Option<String>,
+
+    /// Subjects of the commits between the two, prefixed with `>` when added and `<` when
+    /// removed (rewound), like `git diff --submodule=log`. Only filled in on request, and
+    /// when the submodule is checked out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub log: Vec<String>,
+}
+
+/// A difftastic chunk located in the rows.
+///
+/// Chunks group changes structurally, so a section may span several [`Hunk`]s (changed
+/// rows separated by unchanged ones) or share one hunk with other sections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Section {
+    /// First and last row (0-indexed, inclusive) showing a line of the chunk.
+    pub start: u32,
+    pub end: u32,
+
+    /// The chunk's old and new lines.
+    pub lines: LinePairing,
+}
+
+/// Unchanged rows folded into a [`RowChange::Gap`] row (see [`ProcessOptions::context`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fold {
+    /// Row index (0-indexed) of the gap row.
+    pub row: u32,
+
+    /// How many rows the gap row stands in for.
+    pub rows: u32,
+
+    /// The folded old and new lines, for showing them on demand.
+    pub lines: LinePairing,
 }
 
 /// A processed file ready for display in the diff viewer.
 ///
 /// Contains all the information needed to render a file's diff in Neovim:
 /// file metadata, the aligned rows for display, and navigation aids.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DisplayFile {
     pub path: PathBuf,
 
+    /// Path before a rename, if the file was renamed (see [`Status::Renamed`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<PathBuf>,
+
     /// The detected programming language.
     pub language: String,
 
@@ -174,6 +606,87 @@ pub struct DisplayFile {
     /// Used for navigation commands like "jump to next hunk".
     pub hunk_starts: Vec<u32>,
 
+    /// Semantic summary of each hunk, in the same order as `hunk_starts`.
+    pub hunks: Vec<Hunk>,
+
+    /// Difftastic's chunks in row order, for navigating by structural change groups
+    /// instead of by hunks.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sections: Vec<Section>,
+
+    /// Runs of unchanged rows replaced by a gap row, in row order. Only populated when
+    /// [`ProcessOptions::context`] is set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub folds: Vec<Fold>,
+
+    /// Functions and methods whose bodies contain changes (e.g. `"fn run_diff"`).
+    ///
+    /// Deduplicated and in order of first appearance in the diff.
+    pub changed_functions: Vec<String>,
+
+    /// Whether only metadata and stats were computed (see [`stats_only`]).
+    ///
+    /// Such entries have no rows or hunks; the caller loads them on demand.
+    pub stats_only: bool,
+
+    /// Whether processing failed unexpectedly, leaving a stats-only entry in place of the
+    /// rows (see [`process_or_degrade`]).
+    pub processing_failed: bool,
+
+    /// Whether the old version existed but was empty or whitespace-only.
+    pub was_empty: bool,
+
+    /// Whether the new version exists but is empty or whitespace-only.
+    ///
+    /// Either flag adds a [`RowChange::Notice`] row at the top, since such sides would
+    /// otherwise show nothing or blank lines with no cue.
+    pub now_empty: bool,
+
+    /// Whether difftastic reported the file as binary (see [`binary`]).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub binary: bool,
+
+    /// Sizes in bytes of the old and new versions of a binary file, `None` for a side the
+    /// file doesn't exist on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_size: Option<u64>,
+
+    /// The file's mode before and after, if it changed (see [`add_mode_change`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode_change: Option<ModeChange>,
+
+    /// The submodule's old and new commits, if the file is a submodule (see [`submodule`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submodule: Option<SubmoduleChange>,
+
+    /// Removed old lines grouped by the new line they were removed above.
+    ///
+    /// Only populated when [`ProcessOptions::virtual_deletions`] is enabled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub virtual_deletions: Vec<VirtualDeletion>,
+
+    /// The rows as a single column, like `git diff` shows them.
+    ///
+    /// Only populated with [`View::Unified`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unified: Vec<UnifiedLine>,
+
+    /// How the old and new versions' lines were terminated, if they exist and their
+    /// contents were processed (see [`process_contents`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_line_endings: Option<LineEndings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_line_endings: Option<LineEndings>,
+
+    /// Encodings the old and new versions were transcoded from, if not UTF-8 (e.g.
+    /// `"Shift_JIS"`, see [`crate::encoding`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_encoding: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_encoding: Option<String>,
+
     /// Original line number mapping: `(left_line, right_line)` for each display row.
     ///
     /// `None` means filler line. Line numbers are 0-indexed into the source file.
@@ -181,6 +694,247 @@ pub struct DisplayFile {
     pub aligned_lines: Vec<(Option<u32>, Option<u32>)>,
 }
 
+/// Old lines removed right above a kept line of the new version, for renderers that show
+/// the new version only and draw removals as virtual lines instead of buffer lines.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VirtualDeletion {
+    /// Row index (0-indexed) of the new line the removals are shown above, or `None` when
+    /// they come after the last line.
+    pub row: Option<u32>,
+
+    /// That line in the new version (1-indexed).
+    pub new_line: Option<u32>,
+
+    /// The removed old lines with their change highlights, in order.
+    pub lines: Vec<Side>,
+}
+
+/// A line of the [`View::Unified`] view.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnifiedLine {
+    /// Whether the line is kept, removed or added.
+    pub origin: Origin,
+
+    /// Row index (0-indexed) the line comes from, e.g. for mapping hunks and sections to
+    /// the unified lines.
+    pub row: u32,
+
+    /// The line itself, with its highlights and line number.
+    #[serde(flatten)]
+    pub side: Side,
+}
+
+/// Where a [`UnifiedLine`] comes from, the `' '`, `-` and `+` of `git diff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    /// A line of both versions.
+    Context,
+    Removed,
+    Added,
+
+    /// The text of a [`RowChange::Notice`] or [`RowChange::Gap`] row.
+    Notice,
+}
+
+impl Origin {
+    #[must_use]
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Context => "context",
+            Self::Removed => "removed",
+            Self::Added => "added",
+            Self::Notice => "notice",
+        }
+    }
+}
+
+/// One row of a three-way conflict view, aligned on the base version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConflictRow {
+    /// The common ancestor's line.
+    pub base: Side,
+
+    /// Our line (index stage 2, the branch being merged into).
+    pub ours: Side,
+
+    /// Their line (index stage 3, the branch being merged).
+    pub theirs: Side,
+
+    /// Whether the row is part of a region changed on both sides.
+    pub conflict: bool,
+}
+
+/// A file with merge conflicts, ready for a base/ours/theirs display.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConflictFile {
+    pub path: PathBuf,
+
+    /// The detected programming language.
+    pub language: String,
+
+    /// The aligned rows for three-column display.
+    pub rows: Vec<ConflictRow>,
+
+    /// Row indices (0-indexed) where changed regions start, on either side.
+    pub hunk_starts: Vec<u32>,
+
+    /// Row indices (0-indexed) where regions changed on both sides start.
+    pub conflict_starts: Vec<u32>,
+
+    /// `(base, ours, theirs)` line numbers for each row, 0-indexed. `None` means filler.
+    pub aligned_lines: Vec<(Option<u32>, Option<u32>, Option<u32>)>,
+}
+
+/// Optional processing steps, all disabled by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ProcessOptions {
+    /// Attach full-line syntax spans to each side (see [`Side::syntax`]).
+    pub syntax_spans: bool,
+
+    /// Simplify the output for screen-reader-oriented renderers: partial highlights become
+    /// full-line highlights and changed rows get a [`Row::description`].
+    pub accessible: bool,
+
+    /// Treat lines whose old and new text only differ in whitespace as unchanged, like
+    /// `git diff -w`.
+    pub ignore_whitespace: bool,
+
+    /// Collect removed lines as [`DisplayFile::virtual_deletions`].
+    pub virtual_deletions: bool,
+
+    /// Keep the `\r` of `\r\n` line breaks at the end of lines instead of stripping it.
+    pub keep_cr: bool,
+
+    /// Keep the empty line after a final line break, so joining the lines with `\n` gives
+    /// back the original content.
+    pub keep_final_empty_line: bool,
+
+    /// Split partial highlights at identifier/punctuation boundaries, highlighting only the
+    /// identifiers of regions that contain any (see [`split_words`]).
+    pub split_words: bool,
+
+    /// Diff the words of rows whose old or new line is highlighted in full, highlighting
+    /// only the ones that differ (see [`refine_words`]).
+    pub word_diff: bool,
+
+    /// Percentage of a line's non-whitespace characters that, once changed, turns its partial
+    /// highlights into a full-line highlight. `None` requires all of them.
+    pub full_line_threshold: Option<u8>,
+
+    /// Expand tabs to spaces up to the next multiple of this many columns, moving every
+    /// offset into the line along (see [`expand_tabs`]).
+    pub tab_width: Option<u32>,
+
+    /// Also give highlights their display cell columns, measured with the East Asian widths
+    /// of their characters (see [`HighlightRegion::display_start`]).
+    pub display_columns: bool,
+
+    /// End full-line highlights at their line's length in bytes instead of -1, leaving
+    /// [`HighlightRegion::kind`] to tell them apart.
+    pub line_length_ends: bool,
+
+    /// Detect hunks that only rename an identifier (see [`Hunk::rename_of`]).
+    pub symbol_renames: bool,
+
+    /// Also lay the rows out as a single column (see [`DisplayFile::unified`]).
+    pub view: View,
+
+    /// Fold unchanged rows further than this many rows from any change into gap rows, like
+    /// the context lines of a unified diff (see [`fold_context`]). `None` keeps every row.
+    pub context: Option<u32>,
+
+    /// Encodings of files that aren't UTF-8, by glob (see [`crate::encoding`]).
+    pub encodings: Encodings,
+}
+
+/// Layouts of a processed file. The side-by-side rows are always there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum View {
+    #[default]
+    SideBySide,
+
+    /// Also lay the rows out as a single column, each run of changed rows as its removed
+    /// lines followed by its added lines.
+    Unified,
+}
+
+/// Line break style of a file's content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EolStyle {
+    Lf,
+    Crlf,
+    /// Both `\n` and `\r\n` line breaks.
+    Mixed,
+    /// A single line without a line break.
+    None,
+}
+
+impl EolStyle {
+    #[must_use]
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Lf => "lf",
+            Self::Crlf => "crlf",
+            Self::Mixed => "mixed",
+            Self::None => "none",
+        }
+    }
+}
+
+/// How a file's lines were terminated, so its content can be written back unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineEndings {
+    pub style: EolStyle,
+
+    /// Whether the last line ends with a line break.
+    pub final_newline: bool,
+}
+
+impl LineEndings {
+    #[must_use]
+    pub fn detect(content: &str) -> Self {
+        let breaks = content.matches('\n').count();
+        let crlf = content.matches("\r\n").count();
+        let style = match (breaks, crlf) {
+            (0, _) => EolStyle::None,
+            (_, 0) => EolStyle::Lf,
+            (breaks, crlf) if breaks == crlf => EolStyle::Crlf,
+            _ => EolStyle::Mixed,
+        };
+        Self {
+            style,
+            final_newline: content.ends_with('\n'),
+        }
+    }
+}
+
+/// Splits `content` into lines, along with whether each line ended with `\r\n`. By
+/// default the lines match [`str::lines`]: `\r\n` line breaks are stripped like `\n` ones,
+/// and a final line break doesn't start a new line.
+#[must_use]
+pub fn split_lines(content: &str, options: &ProcessOptions) -> (Vec<String>, Vec<bool>) {
+    let mut lines: Vec<String> = content.split('\n').map(String::from).collect();
+    // Every line but the last one was followed by a `\n`
+    let terminated = lines.len() - 1;
+    let mut crlf: Vec<bool> = lines[..terminated]
+        .iter()
+        .map(|line| line.ends_with('\r'))
+        .collect();
+    crlf.push(false);
+    if !options.keep_cr {
+        for (line, _) in lines.iter_mut().zip(&crlf).filter(|(_, crlf)| **crlf) {
+            line.pop();
+        }
+    }
+    if !options.keep_final_empty_line && lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+        crlf.pop();
+    }
+    (lines, crlf)
+}
+
 /// Processes a difftastic file into display-ready format.
 ///
 /// Main entry point that dispatches to handlers based on file status:
@@ -188,299 +942,1942 @@ pub struct DisplayFile {
 /// - Deleted files: all `old_lines` become deletions (left side only)
 /// - Changed files: uses `aligned_lines` to pair up lines from both versions
 ///
+/// `old_crlf` and `new_crlf` tell which lines ended with `\r\n`, so rows whose line ending alone
+/// changed are flagged before folds and layouts are derived from the rows.
+///
 /// The `stats` parameter provides line-based diff stats from the VCS (additions, deletions).
-/// If `None`, stats are computed from the file content.
+/// If `None`, stats are computed from the file content. `options` enables optional
+/// processing steps such as syntax spans.
 #[must_use]
 pub fn process_file(
     file: DifftFile,
     old_lines: Vec<String>,
     new_lines: Vec<String>,
+    (old_crlf, new_crlf): (&[bool], &[bool]),
     stats: Option<(u32, u32)>,
+    options: &ProcessOptions,
 ) -> DisplayFile {
-    match file.status {
-        Status::Created => process_created(file, new_lines, stats),
-        Status::Deleted => process_deleted(file, old_lines, stats),
-        Status::Changed => process_changed(file, &old_lines, &new_lines, stats),
+    // Binary contents have no lines worth showing
+    if file.is_binary() {
+        return binary(file, None, None);
     }
-}
 
-/// Processes a newly created file.
-///
-/// All lines appear on the right side with full-line highlighting,
-/// with filler lines on the left side.
-fn process_created(
-    file: DifftFile,
-    new_lines: Vec<String>,
-    stats: Option<(u32, u32)>,
-) -> DisplayFile {
-    let num_lines = new_lines.len();
-    let rows: Vec<Row> = new_lines
-        .into_iter()
-        .map(|line| Row {
-            left: Side::filler(),
-            right: Side::with_full_highlight(line),
+    // Highlight whole files before the lines are moved into rows
+    let spans = options.syntax_spans.then(|| {
+        (
+            syntax::highlight_lines(&old_lines, &file.language),
+            syntax::highlight_lines(&new_lines, &file.language),
+        )
+    });
+
+    let chunk_pairs: Vec<LinePairing> = file
+        .chunks
+        .iter()
+        .map(|chunk| {
+            LinePairing::spanning(
+                chunk
+                    .iter()
+                    .filter_map(|line| Some(line.lhs.as_ref()?.line_number)),
+                chunk
+                    .iter()
+                    .filter_map(|line| Some(line.rhs.as_ref()?.line_number)),
+            )
         })
         .collect();
 
-    // For created files: left is always None, right maps 0..n
-    let aligned_lines: Vec<(Option<u32>, Option<u32>)> =
-        (0..num_lines).map(|i| (None, Some(i as u32))).collect();
+    let old_notice = (file.status != Status::Created)
+        .then(|| blank_notice(&old_lines))
+        .flatten();
+    let new_notice = (file.status != Status::Deleted)
+        .then(|| blank_notice(&new_lines))
+        .flatten();
 
-    let (additions, deletions) = stats.unwrap_or((rows.len() as u32, 0));
-    let hunk_starts = if rows.is_empty() { vec![] } else { vec![0] };
+    let mut display = match file.status {
+        Status::Created => process_created(file, new_lines, stats),
+        Status::Deleted => process_deleted(file, old_lines, stats),
+        Status::Changed | Status::Unchanged | Status::Renamed => {
+            process_changed(file, &old_lines, &new_lines, stats, options)
+        }
+    };
+    mark_eol_changes(&mut display, old_crlf, new_crlf);
 
-    DisplayFile {
-        path: file.path,
-        language: file.language,
-        status: file.status,
-        additions,
-        deletions,
-        rows,
-        hunk_starts,
-        aligned_lines,
+    if let Some((old_spans, new_spans)) = spans {
+        attach_syntax(&mut display, old_spans, new_spans);
+    }
+    set_hunk_ranges(&mut display);
+    set_sections(&mut display, &chunk_pairs);
+    set_hunk_pairs(&mut display, chunk_pairs);
+    if options.symbol_renames {
+        set_hunk_renames(&mut display);
+    }
+    if options.accessible {
+        simplify_for_accessibility(&mut display);
+    }
+    if old_notice.is_some() || new_notice.is_some() {
+        insert_notice(&mut display, old_notice, new_notice);
+    }
+    set_line_numbers(&mut display);
+    if let Some(width) = options.tab_width {
+        expand_tabs(&mut display, width);
+    }
+    if options.line_length_ends {
+        set_line_length_ends(&mut display);
+    }
+    if options.display_columns {
+        set_display_columns(&mut display);
+    }
+    if options.virtual_deletions {
+        display.virtual_deletions = virtual_deletions(&display);
     }
+    if let Some(context) = options.context {
+        fold_context(&mut display, context);
+    }
+    if options.view == View::Unified {
+        display.unified = unified_lines(&display);
+    }
+    display
 }
 
-/// Processes a deleted file.
-///
-/// All lines appear on the left side with full-line highlighting,
-/// with filler lines on the right side.
-fn process_deleted(
-    file: DifftFile,
-    old_lines: Vec<String>,
-    stats: Option<(u32, u32)>,
-) -> DisplayFile {
-    let num_lines = old_lines.len();
-    let rows: Vec<Row> = old_lines
-        .into_iter()
-        .map(|line| Row {
-            left: Side::with_full_highlight(line),
-            right: Side::filler(),
-        })
-        .collect();
-
-    // For deleted files: left maps 0..n, right is always None
-    let aligned_lines: Vec<(Option<u32>, Option<u32>)> =
-        (0..num_lines).map(|i| (Some(i as u32), None)).collect();
-
-    let (additions, deletions) = stats.unwrap_or((0, rows.len() as u32));
-    let hunk_starts = if rows.is_empty() { vec![] } else { vec![0] };
-
-    DisplayFile {
-        path: file.path,
-        language: file.language,
-        status: file.status,
-        additions,
-        deletions,
-        rows,
-        hunk_starts,
-        aligned_lines,
+/// Lays the rows of `display` out as a single column: unchanged rows once, from the new
+/// side, and each run of changed rows as all its old lines followed by all its new lines,
+/// like `git diff`. Rows whose text didn't change (see [`RowChange::Structural`]) count as
+/// unchanged.
+fn unified_lines(display: &DisplayFile) -> Vec<UnifiedLine> {
+    let line = |origin, row: usize, side: &Side| UnifiedLine {
+        origin,
+        row: row as u32,
+        side: side.clone(),
+    };
+    let mut lines = Vec::with_capacity(display.rows.len());
+    let mut added = Vec::new();
+    for (i, row) in display.rows.iter().enumerate() {
+        let changed = matches!(
+            row.change,
+            RowChange::Line | RowChange::Intraline | RowChange::Eol
+        );
+        if !changed {
+            lines.append(&mut added);
+        }
+        match row.change {
+            RowChange::Line | RowChange::Intraline | RowChange::Eol => {
+                if !row.left.is_filler {
+                    lines.push(line(Origin::Removed, i, &row.left));
+                }
+                if !row.right.is_filler {
+                    added.push(line(Origin::Added, i, &row.right));
+                }
+            }
+            RowChange::Notice | RowChange::Gap => {
+                // Gap rows show the same text on both sides
+                let right = (row.right.content != row.left.content).then_some(&row.right);
+                for side in std::iter::once(&row.left).chain(right) {
+                    if !side.content.is_empty() {
+                        lines.push(line(Origin::Notice, i, side));
+                    }
+                }
+            }
+            RowChange::Unchanged | RowChange::Structural => {
+                lines.push(line(Origin::Context, i, &row.right));
+            }
+        }
     }
+    lines.append(&mut added);
+    lines
 }
 
-/// Change info for a line: the changes slice for highlight computation.
-type ChangeInfo<'a> = &'a [Change];
+/// Numbers the sides of `display` with the lines they show.
+fn set_line_numbers(display: &mut DisplayFile) {
+    for (row, &(lhs_ln, rhs_ln)) in display.rows.iter_mut().zip(&display.aligned_lines) {
+        row.left.line_number = lhs_ln.map(|ln| ln + 1);
+        row.right.line_number = rhs_ln.map(|ln| ln + 1);
+    }
+}
 
-/// Extracts change information from chunks into lookup maps.
-///
-/// Returns `(lhs_changes, rhs_changes)` hashmaps keyed by line number
-/// for efficient lookup during row processing.
-#[allow(clippy::type_complexity)]
-fn extract_changes(
-    chunks: &[Chunk],
-) -> (HashMap<u32, ChangeInfo<'_>>, HashMap<u32, ChangeInfo<'_>>) {
-    // Pre-calculate capacity hint from total diff lines
-    let capacity: usize = chunks.iter().map(|c| c.len()).sum();
-    let mut lhs_changes: HashMap<u32, ChangeInfo<'_>> = HashMap::with_capacity(capacity);
-    let mut rhs_changes: HashMap<u32, ChangeInfo<'_>> = HashMap::with_capacity(capacity);
+/// Replaces the runs of unchanged rows further than `context` rows from any other row with
+/// a [`RowChange::Gap`] row each, recorded in [`DisplayFile::folds`], and moves every row
+/// index along. A run of one row is kept, as its gap row would take as much space.
+fn fold_context(display: &mut DisplayFile, context: u32) {
+    let context = context as usize;
+    let len = display.rows.len();
+    let mut shown = vec![false; len];
+    for (i, row) in display.rows.iter().enumerate() {
+        if row.change != RowChange::Unchanged {
+            shown[i.saturating_sub(context)..(i + context + 1).min(len)].fill(true);
+        }
+    }
 
-    for chunk in chunks {
-        for diff_line in chunk {
-            if let Some(side) = &diff_line.lhs {
-                lhs_changes.insert(side.line_number, &side.changes);
-            }
-            if let Some(side) = &diff_line.rhs {
-                rhs_changes.insert(side.line_number, &side.changes);
-            }
+    let mut rows = std::mem::take(&mut display.rows).into_iter();
+    let mut aligned_lines = std::mem::take(&mut display.aligned_lines).into_iter();
+    // The row each row ended up as, or the gap row standing in for it
+    let mut moved_to = Vec::with_capacity(len);
+    let mut start = 0;
+    while start < len {
+        let run = shown[start..]
+            .iter()
+            .take_while(|&&s| s == shown[start])
+            .count();
+        let row = display.rows.len() as u32;
+        if shown[start] || run == 1 {
+            moved_to.extend(row..row + run as u32);
+            display.rows.extend(rows.by_ref().take(run));
+            display
+                .aligned_lines
+                .extend(aligned_lines.by_ref().take(run));
+        } else {
+            moved_to.extend(std::iter::repeat_n(row, run));
+            rows.by_ref().take(run).for_each(drop);
+            let folded: Vec<_> = aligned_lines.by_ref().take(run).collect();
+            display.folds.push(Fold {
+                row,
+                rows: run as u32,
+                lines: LinePairing::spanning(
+                    folded.iter().filter_map(|&(lhs_ln, _)| lhs_ln),
+                    folded.iter().filter_map(|&(_, rhs_ln)| rhs_ln),
+                ),
+            });
+            display.rows.push(gap_row(run));
+            display.aligned_lines.push((None, None));
         }
+        start += run;
     }
 
-    (lhs_changes, rhs_changes)
+    let moved = |row: &mut u32| *row = moved_to[*row as usize];
+    display.hunk_starts.iter_mut().for_each(moved);
+    for hunk in &mut display.hunks {
+        moved(&mut hunk.start);
+        moved(&mut hunk.end);
+    }
+    for section in &mut display.sections {
+        moved(&mut section.start);
+        moved(&mut section.end);
+    }
+    for deletion in &mut display.virtual_deletions {
+        if let Some(row) = &mut deletion.row {
+            moved(row);
+        }
+    }
 }
 
-/// Processes a changed (modified) file.
-///
-/// Uses the pre-computed `aligned_lines` from difftastic to create
-/// properly aligned rows. Computes highlights based on the change
-/// information in the chunks.
-fn process_changed(
-    file: DifftFile,
-    old_lines: &[String],
-    new_lines: &[String],
-    stats: Option<(u32, u32)>,
-) -> DisplayFile {
-    let (lhs_changes, rhs_changes) = extract_changes(&file.chunks);
-    let num_rows = file.aligned_lines.len();
+/// A [`RowChange::Gap`] row standing in for `rows` unchanged rows.
+fn gap_row(rows: usize) -> Row {
+    let gap = || {
+        let label = format!("· · · {rows} unchanged lines");
+        Side::new(label, false, Highlights::new())
+    };
+    Row {
+        left: gap(),
+        right: gap(),
+        change: RowChange::Gap,
+        description: None,
+    }
+}
 
-    let mut rows = Vec::with_capacity(num_rows);
-    let mut hunk_starts = Vec::new();
-    let mut in_hunk = false;
+/// Expands the tabs in every line of `display` to spaces, for UIs that render tabs expanded
+/// and would otherwise misplace highlights and alignment. Tab stops are every `width`
+/// columns, counting wide characters as two (see [`set_display_columns`]). Highlights,
+/// syntax spans and invisibles are moved to the expanded line; highlights covering a tab
+/// cover all its spaces.
+fn expand_tabs(display: &mut DisplayFile, width: u32) {
+    let width = width.max(1);
+    for row in &mut display.rows {
+        for side in [&mut row.left, &mut row.right] {
+            expand_side_tabs(side, width);
+        }
+    }
+}
 
-    for (row_idx, (lhs_ln, rhs_ln)) in file.aligned_lines.iter().enumerate() {
-        // Get content for each side (using line number as 0-indexed into lines)
-        let left_content = lhs_ln
-            .and_then(|ln| old_lines.get(ln as usize))
-            .map_or_else(String::new, |s| s.clone());
-        let right_content = rhs_ln
-            .and_then(|ln| new_lines.get(ln as usize))
-            .map_or_else(String::new, |s| s.clone());
+/// Ends the full-line highlights of `display` at the length of their line.
+fn set_line_length_ends(display: &mut DisplayFile) {
+    for row in &mut display.rows {
+        for side in [&mut row.left, &mut row.right] {
+            let len = i32::try_from(side.content.len()).unwrap_or(i32::MAX);
+            for region in side.highlights.iter_mut().filter(|r| r.end == -1) {
+                region.end = len;
+            }
+        }
+    }
+}
 
-        // Get changes for each side
-        let left_changes = lhs_ln.and_then(|ln| lhs_changes.get(&ln).copied());
-        let right_changes = rhs_ln.and_then(|ln| rhs_changes.get(&ln).copied());
+/// Sets the display cell columns of every highlight in `display`. Wide characters (e.g.
+/// CJK) take two cells and combining ones none; tabs left unexpanded take one.
+fn set_display_columns(display: &mut DisplayFile) {
+    for row in &mut display.rows {
+        for side in [&mut row.left, &mut row.right] {
+            set_side_display_columns(side);
+        }
+    }
+}
 
-        // Compute highlights based on change information
-        let left_highlights = left_changes.map_or_else(Highlights::new, |changes| {
-            compute_highlights(&left_content, changes)
-        });
-        let right_highlights = right_changes.map_or_else(Highlights::new, |changes| {
-            compute_highlights(&right_content, changes)
+fn set_side_display_columns(side: &mut Side) {
+    let content = &side.content;
+    let cells = |byte: u32| {
+        content
+            .char_indices()
+            .take_while(|&(i, _)| i < byte as usize)
+            .map(|(_, c)| c.width().unwrap_or(1) as u32)
+            .sum::<u32>()
+    };
+    for region in &mut side.highlights {
+        region.display_start = Some(cells(region.start));
+        region.display_end = Some(match u32::try_from(region.end) {
+            Ok(end) => i32::try_from(cells(end)).unwrap_or(i32::MAX),
+            Err(_) => region.end,
         });
+    }
+}
 
-        // Determine if this row is part of a hunk (has changes or fillers)
-        let is_changed = lhs_ln.is_none()
-            || rhs_ln.is_none()
-            || !left_highlights.is_empty()
-            || !right_highlights.is_empty();
+fn expand_side_tabs(side: &mut Side, width: u32) {
+    if !side.content.contains('\t') {
+        return;
+    }
+    // Offset in the expanded line of each byte of the original, plus the end
+    let mut offsets = Vec::with_capacity(side.content.len() + 1);
+    let mut expanded = String::with_capacity(side.content.len());
+    let mut column = 0;
+    for c in side.content.chars() {
+        offsets.extend(std::iter::repeat_n(expanded.len() as u32, c.len_utf8()));
+        if c == '\t' {
+            let spaces = width - column % width;
+            expanded.extend(std::iter::repeat_n(' ', spaces as usize));
+            column += spaces;
+        } else {
+            expanded.push(c);
+            column += c.width().unwrap_or(1) as u32;
+        }
+    }
+    offsets.push(expanded.len() as u32);
+    let offset = |byte: u32| {
+        offsets
+            .get(byte as usize)
+            .copied()
+            .unwrap_or(expanded.len() as u32)
+    };
 
-        // Track hunk boundaries for navigation
-        if is_changed && !in_hunk {
-            hunk_starts.push(row_idx as u32);
-            in_hunk = true;
-        } else if !is_changed {
-            in_hunk = false;
+    for region in &mut side.highlights {
+        region.start = offset(region.start);
+        if let Ok(end) = u32::try_from(region.end) {
+            region.end = offset(end) as i32;
         }
+    }
+    for span in &mut side.syntax {
+        span.start = offset(span.start);
+        span.end = offset(span.end);
+    }
+    for invisible in &mut side.invisibles {
+        invisible.column = offset(invisible.column);
+    }
+    side.content = expanded;
+}
 
-        rows.push(Row {
-            left: Side::new(left_content, lhs_ln.is_none(), left_highlights),
-            right: Side::new(right_content, rhs_ln.is_none(), right_highlights),
-        });
+/// Splits both versions' contents into lines (see [`split_lines`]), processes `file` with
+/// them and records how their lines were terminated, flagging rows whose line ending alone
+/// changed. Binary files get the contents' lengths as sizes instead.
+#[must_use]
+pub fn process_contents(
+    mut file: DifftFile,
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+    stats: Option<(u32, u32)>,
+    options: &ProcessOptions,
+) -> DisplayFile {
+    if file.is_binary() {
+        let size = |content: Option<&[u8]>| content.map(|content| content.len() as u64);
+        return binary(file, size(old), size(new));
     }
+    let old_text = old.map(|bytes| encoding::decode(bytes, file.old_path(), &options.encodings));
+    let new_text = new.map(|bytes| encoding::decode(bytes, file.new_path(), &options.encodings));
+    let old_encoding = old_text.as_ref().and_then(|(_, encoding)| *encoding);
+    let new_encoding = new_text.as_ref().and_then(|(_, encoding)| *encoding);
+    remap_changes(
+        &mut file,
+        old.and_then(|bytes| Remap::new(bytes, old_encoding)),
+        new.and_then(|bytes| Remap::new(bytes, new_encoding)),
+    );
 
-    // Use VCS stats if available, otherwise default to 0
-    let (additions, deletions) = stats.unwrap_or((0, 0));
+    let lines =
+        |text: Option<&str>| text.map_or_else(Default::default, |text| split_lines(text, options));
+    let (old_text, new_text) = (
+        old_text.as_ref().map(|(text, _)| text.as_ref()),
+        new_text.as_ref().map(|(text, _)| text.as_ref()),
+    );
+    let ((old_lines, old_crlf), (new_lines, new_crlf)) = (lines(old_text), lines(new_text));
+    let mut display = process_file(
+        file,
+        old_lines,
+        new_lines,
+        (&old_crlf, &new_crlf),
+        stats,
+        options,
+    );
+    display.old_line_endings = old_text.map(LineEndings::detect);
+    display.new_line_endings = new_text.map(LineEndings::detect);
+    display.old_encoding = old_encoding.map(|encoding| encoding.name().to_string());
+    display.new_encoding = new_encoding.map(|encoding| encoding.name().to_string());
+    display
+}
 
-    DisplayFile {
-        path: file.path,
-        language: file.language,
-        status: file.status,
-        additions,
-        deletions,
-        rows,
-        hunk_starts,
-        aligned_lines: file.aligned_lines,
+/// Flags rows whose lines only differ in their line ending as [`RowChange::Eol`], given
+/// which old and new lines ended with `\r\n`.
+fn mark_eol_changes(display: &mut DisplayFile, old_crlf: &[bool], new_crlf: &[bool]) {
+    if !old_crlf.contains(&true) && !new_crlf.contains(&true) {
+        return;
+    }
+    // With `keep_cr`, the `\r` is still part of the content
+    fn without_cr(line: &str) -> &str {
+        line.strip_suffix('\r').unwrap_or(line)
+    }
+    let crlf = |lines: &[bool], ln: u32| lines.get(ln as usize).copied().unwrap_or_default();
+    for (row, &(lhs_ln, rhs_ln)) in display.rows.iter_mut().zip(&display.aligned_lines) {
+        let (Some(lhs_ln), Some(rhs_ln)) = (lhs_ln, rhs_ln) else {
+            continue;
+        };
+        let same_text = without_cr(&row.left.content) == without_cr(&row.right.content);
+        if crlf(old_crlf, lhs_ln) != crlf(new_crlf, rhs_ln) && same_text {
+            row.change = RowChange::Eol;
+        }
     }
 }
 
-/// Computes highlight regions for a line based on its changes.
+/// Moves the changes of transcoded sides from difftastic's offsets to the decoded lines.
+fn remap_changes(file: &mut DifftFile, old: Option<Remap>, new: Option<Remap>) {
+    if old.is_none() && new.is_none() {
+        return;
+    }
+    let remap_side = |side: Option<&mut difftastic::Side>, remap: Option<&Remap>| {
+        let (Some(side), Some(remap)) = (side, remap) else {
+            return;
+        };
+        let line = side.line_number as usize;
+        for change in &mut side.changes {
+            change.start = remap.offset(line, change.start);
+            change.end = remap.offset(line, change.end);
+        }
+    };
+    for line in file.chunks.iter_mut().flatten() {
+        remap_side(line.lhs.as_mut(), old.as_ref());
+        remap_side(line.rhs.as_mut(), new.as_ref());
+    }
+}
+
+/// Groups the removed old lines by the next row that keeps a new line.
 ///
-/// Implements several optimizations for cleaner visual presentation:
-/// - Single spanning change → full-line highlight
-/// - Adjacent regions separated by whitespace → merged
-/// - All non-whitespace covered → full-line highlight
-/// - No changes → empty (no highlighting)
-fn compute_highlights(content: &str, changes: &[Change]) -> Highlights {
-    if changes.is_empty() {
-        return Highlights::new();
+/// A modified row counts as removing its old line and keeping its new one, so the old
+/// line is shown above the new line that replaced it.
+fn virtual_deletions(display: &DisplayFile) -> Vec<VirtualDeletion> {
+    let mut deletions = Vec::new();
+    let mut pending = Vec::new();
+    for (i, (row, &(_, rhs_ln))) in display.rows.iter().zip(&display.aligned_lines).enumerate() {
+        let removed =
+            !row.left.is_filler && (row.right.is_filler || !row.left.highlights.is_empty());
+        if removed {
+            pending.push(row.left.clone());
+        }
+        if !row.right.is_filler && !pending.is_empty() {
+            deletions.push(VirtualDeletion {
+                row: Some(i as u32),
+                new_line: rhs_ln.map(|ln| ln + 1),
+                lines: std::mem::take(&mut pending),
+            });
+        }
+    }
+    if !pending.is_empty() {
+        deletions.push(VirtualDeletion {
+            row: None,
+            new_line: None,
+            lines: pending,
+        });
     }
+    deletions
+}
 
-    // If a single change covers the entire line, use full-line highlight
-    let len = content.len() as u32;
-    if changes.len() == 1 && changes[0].start == 0 && changes[0].end >= len {
-        return smallvec::smallvec![HighlightRegion::full_line()];
+/// Fills in each hunk's last row, line counts and changed line range on the new side from
+/// its changed rows.
+fn set_hunk_ranges(display: &mut DisplayFile) {
+    let ends: Vec<usize> = display
+        .hunks
+        .iter()
+        .skip(1)
+        .map(|hunk| hunk.start as usize)
+        .chain([display.rows.len()])
+        .collect();
+    for (hunk, end) in display.hunks.iter_mut().zip(ends) {
+        let start = hunk.start as usize;
+        let changed = || {
+            display.rows[start..end]
+                .iter()
+                .zip(&display.aligned_lines[start..end])
+                .enumerate()
+                .filter(|(_, (row, _))| row.change != RowChange::Unchanged)
+        };
+        hunk.end = changed()
+            .next_back()
+            .map_or(hunk.start, |(i, _)| (start + i) as u32);
+        let counted = || {
+            changed()
+                .map(|(_, (row, _))| row)
+                .filter(|row| row.change != RowChange::Structural)
+        };
+        hunk.additions = counted().filter(|row| !row.right.is_filler).count() as u32;
+        hunk.deletions = counted().filter(|row| !row.left.is_filler).count() as u32;
+        let lines = changed().filter_map(|(_, (_, &(_, rhs_ln)))| rhs_ln.map(|ln| ln + 1));
+        (hunk.new_start, hunk.new_end) = lines.fold((None, None), |(first, last), ln| {
+            (first.or(Some(ln)), last.max(Some(ln)))
+        });
     }
+}
 
-    // Sort and merge adjacent regions (merging across whitespace gaps)
-    let mut regions: SmallVec<[(u32, u32); 4]> = changes.iter().map(|c| (c.start, c.end)).collect();
-    regions.sort_unstable_by_key(|r| r.0);
-    let merged = merge_regions(&regions, content.as_bytes());
+/// Sets [`Hunk::rename_of`] on hunks whose changed rows are identical but for one old
+/// identifier replaced with one new identifier. Hunks adding or removing lines don't count.
+fn set_hunk_renames(display: &mut DisplayFile) {
+    let ends: Vec<usize> = display
+        .hunks
+        .iter()
+        .skip(1)
+        .map(|hunk| hunk.start as usize)
+        .chain([display.rows.len()])
+        .collect();
+    for (hunk, end) in display.hunks.iter_mut().zip(ends) {
+        let rows = display
+            .rows
+            .get(hunk.start as usize..end)
+            .unwrap_or_default();
+        hunk.rename_of = renamed_identifier(rows);
+    }
+}
 
-    // If merged regions cover all non-whitespace, use full-line highlight
-    if covers_all_non_whitespace(content, &merged) {
-        return smallvec::smallvec![HighlightRegion::full_line()];
+/// The identifier every changed row of `rows` renames, if that's their only change.
+fn renamed_identifier(rows: &[Row]) -> Option<SymbolRename> {
+    let is_identifier = |token: &str| {
+        token.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && token.chars().all(|c| c.is_alphanumeric() || c == '_')
+    };
+    let mut rename: Option<(&str, &str)> = None;
+    for row in rows.iter().filter(|row| row.change != RowChange::Unchanged) {
+        if row.left.is_filler || row.right.is_filler {
+            return None;
+        }
+        let (old, new) = (tokens(&row.left.content), tokens(&row.right.content));
+        if old.len() != new.len() {
+            return None;
+        }
+        for pair in old.into_iter().zip(new).filter(|(old, new)| old != new) {
+            if !is_identifier(pair.0) || !is_identifier(pair.1) {
+                return None;
+            }
+            match rename {
+                None => rename = Some(pair),
+                Some(renamed) if renamed == pair => {}
+                Some(_) => return None,
+            }
+        }
     }
+    rename.map(|(old, new)| SymbolRename {
+        old: old.to_string(),
+        new: new.to_string(),
+    })
+}
 
-    // Return the individual regions
-    merged
+/// Splits `line` into runs of identifier characters and single other characters.
+fn tokens(line: &str) -> Vec<&str> {
+    token_ranges(line)
         .into_iter()
-        .map(|(start, end)| HighlightRegion::columns(start, end))
+        .map(|(start, end)| &line[start as usize..end as usize])
         .collect()
 }
 
-/// Merges adjacent change regions, bridging gaps that contain only whitespace.
-///
-/// Creates cleaner visual output by combining regions like `[0-3], [4-7]`
-/// into `[0-7]` when the gap contains only whitespace.
-fn merge_regions(regions: &[(u32, u32)], bytes: &[u8]) -> SmallVec<[(u32, u32); 4]> {
-    let mut merged: SmallVec<[(u32, u32); 4]> = SmallVec::with_capacity(regions.len());
-
-    for &(start, end) in regions {
-        if let Some((_, last_end)) = merged.last_mut() {
-            let gap_start = *last_end as usize;
-            let gap_end = start as usize;
-
-            // Merge if regions overlap/touch or if the gap is only whitespace
-            if gap_start >= gap_end || is_whitespace_only(bytes, gap_start, gap_end) {
-                *last_end = (*last_end).max(end);
-                continue;
+/// The byte ranges of the [`tokens`] of `line`.
+fn token_ranges(line: &str) -> Vec<(u32, u32)> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut tokens = Vec::new();
+    let mut word_start = None;
+    for (i, c) in line.char_indices() {
+        let i = i as u32;
+        match (word_start, is_word(c)) {
+            (None, true) => word_start = Some(i),
+            (Some(_), true) => {}
+            (start, false) => {
+                if let Some(start) = start {
+                    tokens.push((start, i));
+                    word_start = None;
+                }
+                tokens.push((i, i + c.len_utf8() as u32));
             }
         }
-        merged.push((start, end));
     }
+    if let Some(start) = word_start {
+        tokens.push((start, line.len() as u32));
+    }
+    tokens
+}
 
-    merged
+/// Rows showing each old and each new line, by 1-indexed line number.
+fn line_rows(display: &DisplayFile) -> (HashMap<u32, usize>, HashMap<u32, usize>) {
+    let mut old_rows = HashMap::new();
+    let mut new_rows = HashMap::new();
+    for (row, &(lhs_ln, rhs_ln)) in display.aligned_lines.iter().enumerate() {
+        if let Some(ln) = lhs_ln {
+            old_rows.insert(ln + 1, row);
+        }
+        if let Some(ln) = rhs_ln {
+            new_rows.insert(ln + 1, row);
+        }
+    }
+    (old_rows, new_rows)
 }
 
-/// Checks if a byte range contains only ASCII whitespace.
-///
-/// Returns `true` if the range is empty or contains only spaces, tabs, etc.
-#[inline]
-fn is_whitespace_only(bytes: &[u8], start: usize, end: usize) -> bool {
-    bytes
-        .get(start..end)
-        .is_some_and(|slice| slice.iter().all(u8::is_ascii_whitespace))
+/// Locates each chunk's lines in the rows (see [`Section`]). Chunks whose lines aren't
+/// shown anywhere are left out.
+fn set_sections(display: &mut DisplayFile, chunk_pairs: &[LinePairing]) {
+    let (old_rows, new_rows) = line_rows(display);
+    let rows = |rows: &HashMap<u32, usize>, start: Option<u32>, end: Option<u32>| match (start, end)
+    {
+        (Some(start), Some(end)) => (start..=end)
+            .filter_map(|ln| rows.get(&ln).copied())
+            .collect(),
+        _ => Vec::new(),
+    };
+    let mut sections: Vec<Section> = chunk_pairs
+        .iter()
+        .filter_map(|&lines| {
+            let old = rows(&old_rows, lines.old_start, lines.old_end);
+            let new = rows(&new_rows, lines.new_start, lines.new_end);
+            let all = old.iter().chain(&new);
+            Some(Section {
+                start: *all.clone().min()? as u32,
+                end: *all.max()? as u32,
+                lines,
+            })
+        })
+        .collect();
+    sections.sort_by_key(|section| (section.start, section.end));
+    display.sections = sections;
 }
 
-/// Checks if the regions cover all non-whitespace characters in the line.
+/// Assigns each chunk's line pairing to the hunk containing its first changed row.
 ///
-/// Used to determine if we should use a full-line highlight instead of
-/// multiple partial regions. Avoids intermediate allocation by checking
-/// positions as we iterate.
-fn covers_all_non_whitespace(line: &str, regions: &[(u32, u32)]) -> bool {
-    let mut has_non_ws = false;
+/// Hunks no chunk falls into (e.g. files difftastic sent without chunks) get a single
+/// pairing spanning their changed rows.
+fn set_hunk_pairs(display: &mut DisplayFile, chunk_pairs: Vec<LinePairing>) {
+    let (old_rows, new_rows) = line_rows(display);
 
-    for (i, c) in line.char_indices() {
-        if !c.is_whitespace() {
-            has_non_ws = true;
-            let pos = i as u32;
-            // Check if this position is covered by any region
-            if !regions
+    for pair in chunk_pairs {
+        let row = [
+            pair.old_start.and_then(|ln| old_rows.get(&ln)),
+            pair.new_start.and_then(|ln| new_rows.get(&ln)),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let Some(&row) = row else { continue };
+        if display.rows[row].change == RowChange::Unchanged {
+            continue;
+        }
+        let hunk = display
+            .hunks
+            .partition_point(|hunk| hunk.start as usize <= row)
+            .checked_sub(1);
+        if let Some(hunk) = hunk.and_then(|i| display.hunks.get_mut(i)) {
+            hunk.pairs.push(pair);
+        }
+    }
+
+    let ends: Vec<usize> = display
+        .hunks
+        .iter()
+        .skip(1)
+        .map(|hunk| hunk.start as usize)
+        .chain([display.rows.len()])
+        .collect();
+    for (hunk, end) in display.hunks.iter_mut().zip(ends) {
+        if !hunk.pairs.is_empty() {
+            continue;
+        }
+        let start = hunk.start as usize;
+        let changed = || {
+            display.rows[start..end]
                 .iter()
-                .any(|(start, end)| pos >= *start && pos < *end)
-            {
-                return false;
+                .zip(&display.aligned_lines[start..end])
+                .filter(|(row, _)| row.change != RowChange::Unchanged)
+                .map(|(_, &lines)| lines)
+        };
+        hunk.pairs.push(LinePairing::spanning(
+            changed().filter_map(|(lhs_ln, _)| lhs_ln),
+            changed().filter_map(|(_, rhs_ln)| rhs_ln),
+        ));
+    }
+}
+
+/// Describes each changed row in words and collapses its highlights to full lines.
+fn simplify_for_accessibility(display: &mut DisplayFile) {
+    for row in &mut display.rows {
+        row.description = describe_row(row);
+        for side in [&mut row.left, &mut row.right] {
+            if !side.highlights.is_empty() {
+                side.highlights = smallvec::smallvec![HighlightRegion::full_line()];
             }
         }
     }
-
-    has_non_ws
 }
 
-impl IntoLua for HighlightRegion {
+/// Describes how a row changed, or `None` for unchanged rows.
+fn describe_row(row: &Row) -> Option<String> {
+    let description = match row.change {
+        RowChange::Unchanged => return None,
+        RowChange::Line if row.left.is_filler => "Line added".to_string(),
+        RowChange::Line if row.right.is_filler => "Line removed".to_string(),
+        RowChange::Line => "Line replaced".to_string(),
+        RowChange::Intraline => {
+            let words = changed_words(&row.left).max(changed_words(&row.right));
+            let unit = if words == 1 { "word" } else { "words" };
+            format!("{words} {unit} changed")
+        }
+        RowChange::Structural => "Moved or restructured, text unchanged".to_string(),
+        RowChange::Eol => "Line ending changed".to_string(),
+        RowChange::Gap => row.right.content.clone(),
+        RowChange::Notice => {
+            let before =
+                (!row.left.content.is_empty()).then(|| format!("Before: {}", row.left.content));
+            let after =
+                (!row.right.content.is_empty()).then(|| format!("After: {}", row.right.content));
+            before
+                .into_iter()
+                .chain(after)
+                .collect::<Vec<_>>()
+                .join(". ")
+        }
+    };
+    Some(description)
+}
+
+/// Counts the words of a side that overlap its highlight regions.
+fn changed_words(side: &Side) -> usize {
+    let base = side.content.as_ptr() as usize;
+    side.content
+        .split_whitespace()
+        .filter(|word| {
+            let start = (word.as_ptr() as usize - base) as i64;
+            let end = start + word.len() as i64;
+            side.highlights
+                .iter()
+                .any(|h| h.end == -1 || (i64::from(h.start) < end && i64::from(h.end) > start))
+        })
+        .count()
+}
+
+/// Describes a side with no visible content: `"Empty file"` or `"Whitespace only"`.
+/// Returns `None` if some line has non-whitespace text.
+fn blank_notice(lines: &[String]) -> Option<&'static str> {
+    if lines.is_empty() {
+        Some("Empty file")
+    } else if lines.iter().all(|line| line.trim().is_empty()) {
+        Some("Whitespace only")
+    } else {
+        None
+    }
+}
+
+/// Sets the empty flags and prepends a notice row describing the blank sides, shifting
+/// the hunks down by one row.
+fn insert_notice(display: &mut DisplayFile, old_notice: Option<&str>, new_notice: Option<&str>) {
+    display.was_empty = old_notice.is_some();
+    display.now_empty = new_notice.is_some();
+
+    prepend_notice(
+        display,
+        old_notice.unwrap_or_default(),
+        new_notice.unwrap_or_default(),
+    );
+}
+
+/// Prepends a notice row with `old` and `new` as its sides' text, shifting the hunks, folds
+/// and virtual deletions down by one row and adding it to the unified view.
+fn prepend_notice(display: &mut DisplayFile, old: &str, new: &str) {
+    display.rows.insert(0, notice_row(old, new));
+    display.aligned_lines.insert(0, (None, None));
+    for start in &mut display.hunk_starts {
+        *start += 1;
+    }
+    for fold in &mut display.folds {
+        fold.row += 1;
+    }
+    for deletion in &mut display.virtual_deletions {
+        if let Some(row) = &mut deletion.row {
+            *row += 1;
+        }
+    }
+    for hunk in &mut display.hunks {
+        hunk.start += 1;
+        hunk.end += 1;
+    }
+    for section in &mut display.sections {
+        section.start += 1;
+        section.end += 1;
+    }
+    // The unified view lists the notice too, so it's laid out again from the final rows
+    if !display.unified.is_empty() {
+        display.unified = unified_lines(display);
+    }
+}
+
+/// A [`RowChange::Notice`] row showing `old` and `new` on either side.
+fn notice_row(old: &str, new: &str) -> Row {
+    let notice = |text: &str| Side::new(text.to_string(), false, Highlights::new());
+    Row {
+        left: notice(old),
+        right: notice(new),
+        change: RowChange::Notice,
+        description: None,
+    }
+}
+
+/// Records a mode change with a notice row showing the old and new mode, like the
+/// `old mode`/`new mode` lines of `git diff`. Files whose contents are identical get no
+/// other rows (see [`mode_only`]).
+pub fn add_mode_change(display: &mut DisplayFile, change: ModeChange) {
+    if !display.stats_only {
+        prepend_notice(
+            display,
+            &format!("old mode {}", change.old),
+            &format!("new mode {}", change.new),
+        );
+    }
+    display.mode_change = Some(change);
+}
+
+/// Builds the entry of a file whose mode changed but whose contents didn't: just the
+/// mode change row, without fetching the contents.
+#[must_use]
+pub fn mode_only(file: DifftFile, change: ModeChange) -> DisplayFile {
+    let mut display = DisplayFile {
+        stats_only: false,
+        ..stats_only(file, Some((0, 0)))
+    };
+    add_mode_change(&mut display, change);
+    display
+}
+
+/// Builds the entry of a submodule whose recorded commit changed: a notice row with the old
+/// and new commit, like the `Subproject commit` lines of `git diff`, then a row per line of
+/// [`SubmoduleChange::log`], removed commits on the left and added ones on the right.
+#[must_use]
+pub fn submodule(file: DifftFile, change: SubmoduleChange) -> DisplayFile {
+    let status = match (&change.old, &change.new) {
+        _ if file.status == Status::Renamed => Status::Renamed,
+        (None, _) => Status::Created,
+        (_, None) => Status::Deleted,
+        _ => Status::Changed,
+    };
+    let mut display = DisplayFile {
+        language: "Submodule".to_string(),
+        status,
+        stats_only: false,
+        ..stats_only(file, Some((0, 0)))
+    };
+    let commit = |hash: &Option<String>| {
+        hash.as_ref()
+            .map_or_else(String::new, |hash| format!("Subproject commit {hash}"))
+    };
+    display
+        .rows
+        .push(notice_row(&commit(&change.old), &commit(&change.new)));
+    for line in &change.log {
+        let row = if line.starts_with('<') {
+            notice_row(line, "")
+        } else {
+            notice_row("", line)
+        };
+        display.rows.push(row);
+    }
+    display.aligned_lines = vec![(None, None); display.rows.len()];
+    display.submodule = Some(change);
+    display
+}
+
+/// Builds a placeholder entry with only metadata and line stats, skipping all row work.
+///
+/// Used for files left out of a preview. Without VCS stats, the counts come from
+/// difftastic's chunks.
+#[must_use]
+pub fn stats_only(file: DifftFile, stats: Option<(u32, u32)>) -> DisplayFile {
+    let (additions, deletions) = stats.unwrap_or_else(|| chunk_stats(&file.chunks));
+    let binary = file.is_binary();
+    DisplayFile {
+        path: file.path,
+        old_path: file.old_path,
+        language: file.language,
+        status: file.status,
+        additions,
+        deletions,
+        rows: Vec::new(),
+        hunk_starts: Vec::new(),
+        hunks: Vec::new(),
+        sections: Vec::new(),
+        folds: Vec::new(),
+        changed_functions: Vec::new(),
+        stats_only: true,
+        processing_failed: false,
+        was_empty: false,
+        now_empty: false,
+        binary,
+        old_size: None,
+        new_size: None,
+        mode_change: None,
+        submodule: None,
+        virtual_deletions: Vec::new(),
+        unified: Vec::new(),
+        old_line_endings: None,
+        new_line_endings: None,
+        old_encoding: None,
+        new_encoding: None,
+        aligned_lines: Vec::new(),
+    }
+}
+
+/// Builds the entry of a binary file: no rows, and the sizes of both versions in place of
+/// the line stats, which VCSs don't count for binary files.
+#[must_use]
+pub fn binary(file: DifftFile, old_size: Option<u64>, new_size: Option<u64>) -> DisplayFile {
+    DisplayFile {
+        stats_only: false,
+        old_size,
+        new_size,
+        ..stats_only(file, Some((0, 0)))
+    }
+}
+
+/// Runs `process` on `file`, degrading to a stats-only entry flagged `processing_failed`
+/// if it panics, e.g. on an internal invariant broken by unexpected difftastic output.
+/// One bad file then doesn't take the rest of the diff down with it.
+#[must_use]
+pub fn process_or_degrade(
+    file: DifftFile,
+    stats: Option<(u32, u32)>,
+    process: impl FnOnce(DifftFile) -> DisplayFile,
+) -> DisplayFile {
+    let placeholder = listing(&file, stats);
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| process(file))).unwrap_or_else(|_| {
+        DisplayFile {
+            processing_failed: true,
+            ..placeholder
+        }
+    })
+}
+
+/// Like [`stats_only`], but leaves `file` in place, e.g. to list files before they're
+/// processed.
+#[must_use]
+pub fn listing(file: &DifftFile, stats: Option<(u32, u32)>) -> DisplayFile {
+    let placeholder = DifftFile {
+        path: file.path.clone(),
+        old_path: file.old_path.clone(),
+        language: file.language.clone(),
+        status: file.status,
+        aligned_lines: Vec::new(),
+        chunks: Vec::new(),
+    };
+    stats_only(
+        placeholder,
+        Some(stats.unwrap_or_else(|| chunk_stats(&file.chunks))),
+    )
+}
+
+/// Approximates `(additions, deletions)` by counting the changed lines on each side.
+#[must_use]
+pub fn chunk_stats(chunks: &[Chunk]) -> (u32, u32) {
+    chunks
+        .iter()
+        .flatten()
+        .fold((0, 0), |(additions, deletions), line| {
+            (
+                additions + u32::from(line.rhs.is_some()),
+                deletions + u32::from(line.lhs.is_some()),
+            )
+        })
+}
+
+/// Processes difftastic's JSON for a single file together with the file's contents.
+///
+/// This is the pure core of the pipeline: no VCS commands are run, so the JSON and the two
+/// file versions attached to a bug report are enough to reproduce the processed output.
+/// `old` is `None` for created files and `new` is `None` for deleted files. The contents
+/// go through [`process_contents`], so they're transcoded and their line endings recorded
+/// like those of a repository's files.
+///
+/// # Errors
+///
+/// Fails if the JSON is invalid or doesn't describe exactly one file.
+pub fn process_json(
+    json: &str,
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+    options: &ProcessOptions,
+) -> Result<DisplayFile, String> {
+    let mut files =
+        difftastic::parse(json).map_err(|e| format!("Failed to parse difftastic JSON: {e}"))?;
+    if files.len() != 1 {
+        return Err(format!("Expected JSON for one file, got {}", files.len()));
+    }
+    Ok(process_contents(files.remove(0), old, new, None, options))
+}
+
+/// Moves each source line's syntax spans onto the row side that displays it.
+fn attach_syntax(
+    display: &mut DisplayFile,
+    mut old_spans: Vec<Vec<SyntaxSpan>>,
+    mut new_spans: Vec<Vec<SyntaxSpan>>,
+) {
+    let take = |spans: &mut Vec<Vec<SyntaxSpan>>, ln: Option<u32>| {
+        ln.and_then(|ln| spans.get_mut(ln as usize))
+            .map(std::mem::take)
+            .unwrap_or_default()
+    };
+    for (row, &(lhs_ln, rhs_ln)) in display.rows.iter_mut().zip(&display.aligned_lines) {
+        row.left.syntax = take(&mut old_spans, lhs_ln);
+        row.right.syntax = take(&mut new_spans, rhs_ln);
+    }
+}
+
+/// Processes a newly created file.
+///
+/// All lines appear on the right side with full-line highlighting,
+/// with filler lines on the left side.
+fn process_created(
+    file: DifftFile,
+    new_lines: Vec<String>,
+    stats: Option<(u32, u32)>,
+) -> DisplayFile {
+    let num_lines = new_lines.len();
+    let rows: Vec<Row> = new_lines
+        .into_iter()
+        .map(|line| Row {
+            left: Side::filler(),
+            right: Side::with_full_highlight(line),
+            change: RowChange::Line,
+            description: None,
+        })
+        .collect();
+
+    // For created files: left is always None, right maps 0..n
+    let aligned_lines: Vec<(Option<u32>, Option<u32>)> =
+        (0..num_lines).map(|i| (None, Some(i as u32))).collect();
+
+    let (additions, deletions) = stats.unwrap_or((rows.len() as u32, 0));
+    let hunk_starts = if rows.is_empty() { vec![] } else { vec![0] };
+    let hunks = whole_file_hunk(&file.chunks, rows.iter().map(|r| r.right.content.as_str()));
+    let changed_functions = all_functions(rows.iter().map(|r| &r.right.content));
+
+    DisplayFile {
+        path: file.path,
+        old_path: file.old_path,
+        language: file.language,
+        status: file.status,
+        additions,
+        deletions,
+        rows,
+        hunk_starts,
+        hunks,
+        sections: Vec::new(),
+        folds: Vec::new(),
+        changed_functions,
+        stats_only: false,
+        processing_failed: false,
+        was_empty: false,
+        now_empty: false,
+        binary: false,
+        old_size: None,
+        new_size: None,
+        mode_change: None,
+        submodule: None,
+        virtual_deletions: Vec::new(),
+        unified: Vec::new(),
+        old_line_endings: None,
+        new_line_endings: None,
+        old_encoding: None,
+        new_encoding: None,
+        aligned_lines,
+    }
+}
+
+/// Processes a deleted file.
+///
+/// All lines appear on the left side with full-line highlighting,
+/// with filler lines on the right side.
+fn process_deleted(
+    file: DifftFile,
+    old_lines: Vec<String>,
+    stats: Option<(u32, u32)>,
+) -> DisplayFile {
+    let num_lines = old_lines.len();
+    let rows: Vec<Row> = old_lines
+        .into_iter()
+        .map(|line| Row {
+            left: Side::with_full_highlight(line),
+            right: Side::filler(),
+            change: RowChange::Line,
+            description: None,
+        })
+        .collect();
+
+    // For deleted files: left maps 0..n, right is always None
+    let aligned_lines: Vec<(Option<u32>, Option<u32>)> =
+        (0..num_lines).map(|i| (Some(i as u32), None)).collect();
+
+    let (additions, deletions) = stats.unwrap_or((0, rows.len() as u32));
+    let hunk_starts = if rows.is_empty() { vec![] } else { vec![0] };
+    let hunks = whole_file_hunk(&file.chunks, rows.iter().map(|r| r.left.content.as_str()));
+    let changed_functions = all_functions(rows.iter().map(|r| &r.left.content));
+
+    DisplayFile {
+        path: file.path,
+        old_path: file.old_path,
+        language: file.language,
+        status: file.status,
+        additions,
+        deletions,
+        rows,
+        hunk_starts,
+        hunks,
+        sections: Vec::new(),
+        folds: Vec::new(),
+        changed_functions,
+        stats_only: false,
+        processing_failed: false,
+        was_empty: false,
+        now_empty: false,
+        binary: false,
+        old_size: None,
+        new_size: None,
+        mode_change: None,
+        submodule: None,
+        virtual_deletions: Vec::new(),
+        unified: Vec::new(),
+        old_line_endings: None,
+        new_line_endings: None,
+        old_encoding: None,
+        new_encoding: None,
+        aligned_lines,
+    }
+}
+
+/// Builds the single hunk of a created or deleted file, or nothing if the file is empty.
+fn whole_file_hunk<'a>(chunks: &'a [Chunk], lines: impl Iterator<Item = &'a str>) -> Vec<Hunk> {
+    let mut lines = lines.peekable();
+    if lines.peek().is_none() {
+        return Vec::new();
+    }
+    let changes = chunks
+        .iter()
+        .flatten()
+        .flat_map(|line| line.lhs.iter().chain(line.rhs.iter()))
+        .flat_map(|side| side.changes.iter());
+    vec![summarize_hunk(0, changes, lines)]
+}
+
+/// Lists every function defined in a created or deleted file.
+fn all_functions<'a>(lines: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let lines: Vec<&String> = lines.collect();
+    let mut names = Vec::new();
+    for def in outline::Outline::new(&lines).functions() {
+        push_unique(&mut names, def.to_string());
+    }
+    names
+}
+
+/// Appends `name` unless it is already present, preserving first-appearance order.
+#[inline]
+fn push_unique(names: &mut Vec<String>, name: String) {
+    if !names.contains(&name) {
+        names.push(name);
+    }
+}
+
+/// Summarizes a hunk from its changes and the text of its changed lines.
+fn summarize_hunk<'a>(
+    start: u32,
+    changes: impl Iterator<Item = &'a Change>,
+    lines: impl Iterator<Item = &'a str>,
+) -> Hunk {
+    let kind = dominant_kind(changes);
+    let label = outline::label(lines).or_else(|| match kind.as_deref() {
+        Some("string") => Some("string literal".to_string()),
+        Some("comment") => Some("comment".to_string()),
+        _ => None,
+    });
+    Hunk {
+        start,
+        end: start,
+        additions: 0,
+        deletions: 0,
+        kind,
+        label,
+        header: None,
+        new_start: None,
+        new_end: None,
+        pairs: Vec::new(),
+        rename_of: None,
+    }
+}
+
+/// Finds the highlight kind covering the most changed bytes.
+///
+/// Ties are broken by first appearance so the result is deterministic.
+fn dominant_kind<'a>(changes: impl Iterator<Item = &'a Change>) -> Option<String> {
+    let mut tally: SmallVec<[(&str, u32); 4]> = SmallVec::new();
+    for change in changes.filter(|c| !c.highlight.is_empty()) {
+        let weight = change.end.saturating_sub(change.start).max(1);
+        match tally.iter_mut().find(|(kind, _)| *kind == change.highlight) {
+            Some((_, total)) => *total += weight,
+            None => tally.push((&change.highlight, weight)),
+        }
+    }
+
+    let mut best: Option<(&str, u32)> = None;
+    for (kind, total) in tally {
+        if best.is_none_or(|(_, max)| total > max) {
+            best = Some((kind, total));
+        }
+    }
+    best.map(|(kind, _)| kind.to_string())
+}
+
+/// Change info for a line: the changes slice for highlight computation.
+type ChangeInfo<'a> = &'a [Change];
+
+/// Extracts change information from chunks into lookup maps.
+///
+/// Returns `(lhs_changes, rhs_changes)` hashmaps keyed by line number
+/// for efficient lookup during row processing.
+#[allow(clippy::type_complexity)]
+fn extract_changes(
+    chunks: &[Chunk],
+) -> (HashMap<u32, ChangeInfo<'_>>, HashMap<u32, ChangeInfo<'_>>) {
+    // Pre-calculate capacity hint from total diff lines
+    let capacity: usize = chunks.iter().map(|c| c.len()).sum();
+    let mut lhs_changes: HashMap<u32, ChangeInfo<'_>> = HashMap::with_capacity(capacity);
+    let mut rhs_changes: HashMap<u32, ChangeInfo<'_>> = HashMap::with_capacity(capacity);
+
+    for chunk in chunks {
+        for diff_line in chunk {
+            if let Some(side) = &diff_line.lhs {
+                lhs_changes.insert(side.line_number, &side.changes);
+            }
+            if let Some(side) = &diff_line.rhs {
+                rhs_changes.insert(side.line_number, &side.changes);
+            }
+        }
+    }
+
+    (lhs_changes, rhs_changes)
+}
+
+/// Processes a changed (modified) file.
+///
+/// Uses the pre-computed `aligned_lines` from difftastic to create
+/// properly aligned rows. Computes highlights based on the change
+/// information in the chunks.
+fn process_changed(
+    file: DifftFile,
+    old_lines: &[String],
+    new_lines: &[String],
+    stats: Option<(u32, u32)>,
+    options: &ProcessOptions,
+) -> DisplayFile {
+    let (lhs_changes, rhs_changes) = extract_changes(&file.chunks);
+    let num_rows = file.aligned_lines.len();
+    let textual = file.is_textual();
+
+    let mut rows = Vec::with_capacity(num_rows);
+    let mut hunk_starts = Vec::new();
+    let mut hunk_ends = Vec::new();
+    let mut in_hunk = false;
+
+    let old_outline = outline::Outline::new(old_lines);
+    let new_outline = outline::Outline::new(new_lines);
+    let mut changed_functions = Vec::new();
+
+    for (row_idx, (lhs_ln, rhs_ln)) in file.aligned_lines.iter().enumerate() {
+        // Get content for each side (using line number as 0-indexed into lines)
+        let left_content = lhs_ln
+            .and_then(|ln| old_lines.get(ln as usize))
+            .map_or_else(String::new, |s| s.clone());
+        let right_content = rhs_ln
+            .and_then(|ln| new_lines.get(ln as usize))
+            .map_or_else(String::new, |s| s.clone());
+
+        // Get changes for each side, dropping them if only whitespace changed
+        let whitespace_only = options.ignore_whitespace
+            && lhs_ln.is_some()
+            && rhs_ln.is_some()
+            && left_content
+                .split_whitespace()
+                .eq(right_content.split_whitespace());
+        let left_changes = lhs_ln
+            .filter(|_| !whitespace_only)
+            .and_then(|ln| lhs_changes.get(&ln).copied());
+        let right_changes = rhs_ln
+            .filter(|_| !whitespace_only)
+            .and_then(|ln| rhs_changes.get(&ln).copied());
+
+        // Compute highlights based on change information
+        let highlights = |content: &str, changes: Option<&[Change]>| {
+            let highlights = changes.map_or_else(Highlights::new, |changes| {
+                compute_highlights(content, changes, options.full_line_threshold)
+            });
+            if options.split_words {
+                split_words(content, highlights)
+            } else {
+                highlights
+            }
+        };
+        let mut left_highlights = highlights(&left_content, left_changes);
+        let mut right_highlights = highlights(&right_content, right_changes);
+        // difftastic leaves paired lines of text diffs unmarked even when they differ
+        if textual
+            && lhs_ln.is_some()
+            && rhs_ln.is_some()
+            && !whitespace_only
+            && left_changes.is_none_or(<[_]>::is_empty)
+            && right_changes.is_none_or(<[_]>::is_empty)
+            && left_content != right_content
+        {
+            (left_highlights, right_highlights) =
+                diff_chars(&left_content, &right_content, options.full_line_threshold);
+        }
+        if options.word_diff && lhs_ln.is_some() && rhs_ln.is_some() {
+            refine_words(
+                (&left_content, &mut left_highlights),
+                (&right_content, &mut right_highlights),
+            );
+        }
+
+        // Determine if this row is part of a hunk (has changes or fillers)
+        let is_changed = lhs_ln.is_none()
+            || rhs_ln.is_none()
+            || !left_highlights.is_empty()
+            || !right_highlights.is_empty();
+
+        // Track hunk boundaries for navigation
+        if is_changed && !in_hunk {
+            hunk_starts.push(row_idx as u32);
+            in_hunk = true;
+        } else if !is_changed && in_hunk {
+            hunk_ends.push(row_idx);
+            in_hunk = false;
+        }
+
+        // Attribute the change to the function enclosing it, preferring the new version
+        if is_changed {
+            let enclosing = rhs_ln
+                .and_then(|ln| new_outline.enclosing(new_lines, ln as usize))
+                .or_else(|| lhs_ln.and_then(|ln| old_outline.enclosing(old_lines, ln as usize)));
+            if let Some(def) = enclosing {
+                push_unique(&mut changed_functions, def.to_string());
+            }
+        }
+
+        let left = Side::new(left_content, lhs_ln.is_none(), left_highlights);
+        let right = Side::new(right_content, rhs_ln.is_none(), right_highlights);
+        let change = RowChange::classify(&left, &right);
+        rows.push(Row {
+            left,
+            right,
+            change,
+            description: None,
+        });
+    }
+
+    if in_hunk {
+        hunk_ends.push(num_rows);
+    }
+
+    // Summarize each hunk from the changes and content of its rows
+    let hunks = hunk_starts
+        .iter()
+        .zip(&hunk_ends)
+        .map(|(&start, &end)| {
+            let range = start as usize..end;
+            let changes = file.aligned_lines[range.clone()]
+                .iter()
+                .flat_map(|(lhs_ln, rhs_ln)| {
+                    let left = lhs_ln.and_then(|ln| lhs_changes.get(&ln).copied());
+                    let right = rhs_ln.and_then(|ln| rhs_changes.get(&ln).copied());
+                    left.into_iter().chain(right).flatten()
+                });
+            let enclosing = |outline: &outline::Outline, lines: &[String], ln: u32| {
+                let def = outline.enclosing_line(lines, ln as usize)?;
+                Some(lines[def].trim().to_string())
+            };
+            let header = file.aligned_lines[range.clone()]
+                .iter()
+                .find_map(|&(lhs_ln, rhs_ln)| {
+                    rhs_ln
+                        .and_then(|ln| enclosing(&new_outline, new_lines, ln))
+                        .or_else(|| lhs_ln.and_then(|ln| enclosing(&old_outline, old_lines, ln)))
+                });
+            let lines = rows[range].iter().map(|row| {
+                if row.right.is_filler {
+                    row.left.content.as_str()
+                } else {
+                    row.right.content.as_str()
+                }
+            });
+            Hunk {
+                header,
+                ..summarize_hunk(start, changes, lines)
+            }
+        })
+        .collect();
+
+    // Use VCS stats if available, otherwise default to 0
+    let (additions, deletions) = stats.unwrap_or((0, 0));
+
+    DisplayFile {
+        path: file.path,
+        old_path: file.old_path,
+        language: file.language,
+        status: file.status,
+        additions,
+        deletions,
+        rows,
+        hunk_starts,
+        hunks,
+        sections: Vec::new(),
+        folds: Vec::new(),
+        changed_functions,
+        stats_only: false,
+        processing_failed: false,
+        was_empty: false,
+        now_empty: false,
+        binary: false,
+        old_size: None,
+        new_size: None,
+        mode_change: None,
+        submodule: None,
+        virtual_deletions: Vec::new(),
+        unified: Vec::new(),
+        old_line_endings: None,
+        new_line_endings: None,
+        old_encoding: None,
+        new_encoding: None,
+        aligned_lines: file.aligned_lines,
+    }
+}
+
+/// Processes a conflicted file from difftastic's base-to-ours and base-to-theirs diffs.
+///
+/// The two diffs are merged on the base lines (see [`align_three_way`]). A changed region
+/// is a conflict when both sides changed something in it, which is also when git leaves
+/// conflict markers.
+#[must_use]
+pub fn process_conflict(
+    ours: &DifftFile,
+    theirs: &DifftFile,
+    base_lines: &[String],
+    ours_lines: &[String],
+    theirs_lines: &[String],
+) -> ConflictFile {
+    let ours_aligned = alignment(ours, base_lines.len(), ours_lines.len());
+    let theirs_aligned = alignment(theirs, base_lines.len(), theirs_lines.len());
+    let aligned_lines = align_three_way(&ours_aligned, &theirs_aligned);
+
+    let (ours_base_changes, ours_changes) = extract_changes(&ours.chunks);
+    let (theirs_base_changes, theirs_changes) = extract_changes(&theirs.chunks);
+
+    let side = |lines: &[String], ln: Option<u32>, changes: &[&[Change]]| {
+        let Some(ln) = ln else {
+            return Side::filler();
+        };
+        let content = lines.get(ln as usize).cloned().unwrap_or_default();
+        let changes: Vec<Change> = changes.iter().flat_map(|c| c.iter().cloned()).collect();
+        let highlights = compute_highlights(&content, &changes, None);
+        Side {
+            line_number: Some(ln + 1),
+            ..Side::new(content, false, highlights)
+        }
+    };
+    // Whether one side changed a row: an inserted or removed line, or a changed one
+    let changed = |base_ln: Option<u32>,
+                   ln: Option<u32>,
+                   base_changes: &HashMap<u32, ChangeInfo<'_>>,
+                   changes: &HashMap<u32, ChangeInfo<'_>>| match (base_ln, ln) {
+        (None, None) => false,
+        (Some(b), Some(l)) => base_changes.contains_key(&b) || changes.contains_key(&l),
+        _ => true,
+    };
+
+    let mut rows = Vec::with_capacity(aligned_lines.len());
+    let mut sides_changed = Vec::with_capacity(aligned_lines.len());
+    for &(base_ln, ours_ln, theirs_ln) in &aligned_lines {
+        rows.push(ConflictRow {
+            base: side(
+                base_lines,
+                base_ln,
+                &[
+                    line_changes(&ours_base_changes, base_ln),
+                    line_changes(&theirs_base_changes, base_ln),
+                ],
+            ),
+            ours: side(ours_lines, ours_ln, &[line_changes(&ours_changes, ours_ln)]),
+            theirs: side(
+                theirs_lines,
+                theirs_ln,
+                &[line_changes(&theirs_changes, theirs_ln)],
+            ),
+            conflict: false,
+        });
+        sides_changed.push((
+            changed(base_ln, ours_ln, &ours_base_changes, &ours_changes),
+            changed(base_ln, theirs_ln, &theirs_base_changes, &theirs_changes),
+        ));
+    }
+
+    // Group changed rows into regions and flag those changed on both sides
+    let mut hunk_starts = Vec::new();
+    let mut conflict_starts = Vec::new();
+    let mut i = 0;
+    while i < rows.len() {
+        if sides_changed[i] == (false, false) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < rows.len() && sides_changed[i] != (false, false) {
+            i += 1;
+        }
+        hunk_starts.push(start as u32);
+        let region = &sides_changed[start..i];
+        if region.iter().any(|c| c.0) && region.iter().any(|c| c.1) {
+            conflict_starts.push(start as u32);
+            for row in &mut rows[start..i] {
+                row.conflict = true;
+            }
+        }
+    }
+
+    ConflictFile {
+        path: ours.path.clone(),
+        language: if ours.language.is_empty() {
+            theirs.language.clone()
+        } else {
+            ours.language.clone()
+        },
+        rows,
+        hunk_starts,
+        conflict_starts,
+        aligned_lines,
+    }
+}
+
+/// The changes on line `ln`, or none for fillers and unchanged lines.
+fn line_changes<'a>(changes: &HashMap<u32, ChangeInfo<'a>>, ln: Option<u32>) -> ChangeInfo<'a> {
+    ln.and_then(|ln| changes.get(&ln).copied())
+        .unwrap_or_default()
+}
+
+/// A file's two-way alignment, synthesized when difftastic didn't report one (identical,
+/// created or deleted files): identical lines pair up, otherwise all old lines are
+/// followed by all new lines.
+fn alignment(file: &DifftFile, old_len: usize, new_len: usize) -> Vec<(Option<u32>, Option<u32>)> {
+    if !file.aligned_lines.is_empty() {
+        return file.aligned_lines.clone();
+    }
+    if file.chunks.is_empty() && old_len == new_len {
+        return (0..old_len as u32).map(|i| (Some(i), Some(i))).collect();
+    }
+    let old = (0..old_len as u32).map(|i| (Some(i), None));
+    let new = (0..new_len as u32).map(|i| (None, Some(i)));
+    old.chain(new).collect()
+}
+
+/// Merges base-to-ours and base-to-theirs alignments into `(base, ours, theirs)` rows.
+///
+/// Every base line gets one row with its counterpart on each side. Lines inserted before
+/// the same base line on both sides share rows, so concurrent insertions line up next to
+/// each other.
+fn align_three_way(
+    ours: &[(Option<u32>, Option<u32>)],
+    theirs: &[(Option<u32>, Option<u32>)],
+) -> Vec<(Option<u32>, Option<u32>, Option<u32>)> {
+    let mut rows = Vec::with_capacity(ours.len().max(theirs.len()));
+    let (mut i, mut j) = (0, 0);
+    loop {
+        // Lines each side inserted before the next base line
+        let (ours_start, theirs_start) = (i, j);
+        while ours.get(i).is_some_and(|(base, _)| base.is_none()) {
+            i += 1;
+        }
+        while theirs.get(j).is_some_and(|(base, _)| base.is_none()) {
+            j += 1;
+        }
+        let (ours_inserted, theirs_inserted) = (&ours[ours_start..i], &theirs[theirs_start..j]);
+        for k in 0..ours_inserted.len().max(theirs_inserted.len()) {
+            rows.push((
+                None,
+                ours_inserted.get(k).and_then(|&(_, ln)| ln),
+                theirs_inserted.get(k).and_then(|&(_, ln)| ln),
+            ));
+        }
+
+        match (ours.get(i), theirs.get(j)) {
+            (None, None) => break,
+            (Some(&(base, ours_ln)), Some(&(theirs_base, theirs_ln))) if base == theirs_base => {
+                rows.push((base, ours_ln, theirs_ln));
+                i += 1;
+                j += 1;
+            }
+            // The alignments disagree on a base line: emit the earlier one on its own
+            (Some(&(base, ours_ln)), Some(&(theirs_base, _))) if base < theirs_base => {
+                rows.push((base, ours_ln, None));
+                i += 1;
+            }
+            (Some(&(base, ours_ln)), None) => {
+                rows.push((base, ours_ln, None));
+                i += 1;
+            }
+            (_, Some(&(base, theirs_ln))) => {
+                rows.push((base, None, theirs_ln));
+                j += 1;
+            }
+        }
+    }
+    rows
+}
+
+/// Computes highlight regions for a line based on its changes.
+///
+/// Implements several optimizations for cleaner visual presentation:
+/// - Single spanning change → full-line highlight
+/// - Adjacent regions separated by whitespace → merged
+/// - All non-whitespace covered, or the `threshold` percentage of it → full-line highlight
+/// - No changes → empty (no highlighting)
+///
+/// Lines needing the merge are memoized when a [`memo::Memo`] is selected.
+fn compute_highlights(content: &str, changes: &[Change], threshold: Option<u8>) -> Highlights {
+    if changes.is_empty() {
+        return Highlights::new();
+    }
+
+    // If a single change covers the entire line, use full-line highlight
+    let len = content.len() as u32;
+    if changes.len() == 1 && changes[0].start == 0 && changes[0].end >= len {
+        return smallvec::smallvec![HighlightRegion::full_line().with_kind_of(changes.iter())];
+    }
+
+    memo::highlights(content, changes, || {
+        merged_highlights(content, changes, threshold)
+    })
+}
+
+/// The merging steps of [`compute_highlights`].
+fn merged_highlights(content: &str, changes: &[Change], threshold: Option<u8>) -> Highlights {
+    // Sort and merge adjacent regions (merging across whitespace gaps). Regions past the end
+    // of the line, such as a `\r` stripped from it, are cut off, and boundaries inside a
+    // multi-byte character widened to cover it, as extmarks can't start or end there.
+    let len = content.len() as u32;
+    let mut regions: SmallVec<[(u32, u32); 4]> = changes
+        .iter()
+        .filter(|c| c.start < len)
+        .map(|c| {
+            (
+                char_floor(content, c.start),
+                char_ceil(content, c.end.min(len)),
+            )
+        })
+        .collect();
+    regions.sort_unstable_by_key(|r| r.0);
+    let merged = merge_regions(&regions, content.as_bytes());
+
+    // If merged regions cover all non-whitespace, or enough of it, use full-line highlight
+    if covers_non_whitespace(content, &merged, threshold.unwrap_or(100)) {
+        return smallvec::smallvec![HighlightRegion::full_line().with_kind_of(changes.iter())];
+    }
+
+    // Return the individual regions
+    merged
+        .into_iter()
+        .map(|(start, end)| {
+            let covered = changes.iter().filter(|c| c.start < end && c.end > start);
+            HighlightRegion::columns(start, end).with_kind_of(covered)
+        })
+        .collect()
+}
+
+/// The closest character boundary of `line` at or before byte `offset`.
+fn char_floor(line: &str, mut offset: u32) -> u32 {
+    while !line.is_char_boundary(offset as usize) {
+        offset -= 1;
+    }
+    offset
+}
+
+/// The closest character boundary of `line` at or after byte `offset`.
+fn char_ceil(line: &str, mut offset: u32) -> u32 {
+    while !line.is_char_boundary(offset as usize) {
+        offset += 1;
+    }
+    offset
+}
+
+/// Splits partial highlight regions into runs of identifier characters, dropping the
+/// punctuation and whitespace between them.
+///
+/// Turns a region over `baz(y)` into `baz` and `y`, since the punctuation around changed
+/// identifiers is usually unchanged structure that got merged in. Regions without any
+/// identifier characters (e.g. a changed operator) and full-line highlights are kept.
+fn split_words(content: &str, highlights: Highlights) -> Highlights {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut split = Highlights::new();
+    for region in highlights {
+        let text = usize::try_from(region.end)
+            .ok()
+            .and_then(|end| content.get(region.start as usize..end));
+        let Some(text) = text.filter(|text| text.contains(is_word)) else {
+            split.push(region);
+            continue;
+        };
+        let mut run: Option<u32> = None;
+        for (i, c) in text.char_indices().chain([(text.len(), ' ')]) {
+            let column = region.start + i as u32;
+            match (run, is_word(c)) {
+                (None, true) => run = Some(column),
+                (Some(start), false) => {
+                    split.push(HighlightRegion {
+                        syntax_kind: region.syntax_kind.clone(),
+                        ..HighlightRegion::columns(start, column)
+                    });
+                    run = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    split
+}
+
+/// Start and end offsets of parts of a line, in bytes.
+type ByteRanges = Vec<(u32, u32)>;
+
+/// Units per line beyond which [`unmatched`] gives up, bounding its table of common
+/// subsequence lengths.
+const MAX_LCS_UNITS: usize = 1024;
+
+/// Narrows the full-line highlights of a row's old and new line down to the tokens that
+/// differ between them, like `git diff --word-diff`.
+///
+/// difftastic highlights a whole line when none of its syntax nodes survived, even if most
+/// of its words did, e.g. when a statement is rewrapped into a different expression. The
+/// words are matched by their longest common subsequence, ignoring whitespace, and the
+/// unmatched ones merged across whitespace like other regions. Rows without a full-line
+/// highlight are left as difftastic sees them, as are rows with no word in common, too
+/// many words, or only whitespace changes.
+fn refine_words(old: (&str, &mut Highlights), new: (&str, &mut Highlights)) {
+    let is_full = |highlights: &Highlights| {
+        highlights
+            .iter()
+            .any(|region| region.layer() == Layer::FullLine)
+    };
+    if !is_full(old.1) && !is_full(new.1) {
+        return;
+    }
+    let words = |line: &str| -> ByteRanges {
+        token_ranges(line)
+            .into_iter()
+            .filter(|&(start, end)| !line[start as usize..end as usize].trim().is_empty())
+            .collect()
+    };
+    let Some((old_changed, new_changed)) =
+        unmatched((old.0, &words(old.0)), (new.0, &words(new.0)))
+    else {
+        return;
+    };
+    if old_changed.is_empty() && new_changed.is_empty() {
+        return;
+    }
+
+    for ((line, highlights), changed) in [(old, old_changed), (new, new_changed)] {
+        let Some(full) = highlights
+            .iter()
+            .find(|region| region.layer() == Layer::FullLine)
+        else {
+            continue;
+        };
+        let syntax_kind = full.syntax_kind.clone();
+        *highlights = merge_regions(&changed, line.as_bytes())
+            .into_iter()
+            .map(|(start, end)| HighlightRegion {
+                syntax_kind: syntax_kind.clone(),
+                ..HighlightRegion::columns(start, end)
+            })
+            .collect();
+    }
+}
+
+/// Highlights the characters that differ between a row's old and new line, for rows
+/// difftastic reports no changes on although their text differs.
+///
+/// Happens with files difftastic diffed as text, e.g. when it fell back to a line-based diff
+/// past its limits. The characters are matched by their longest common subsequence and the
+/// unmatched ones merged across whitespace. Lines with nothing in common, too long to
+/// match, or covered as far as `threshold` (see [`compute_highlights`]) are highlighted in
+/// full.
+fn diff_chars(old: &str, new: &str, threshold: Option<u8>) -> (Highlights, Highlights) {
+    let chars = |line: &str| -> ByteRanges {
+        line.char_indices()
+            .map(|(i, c)| (i as u32, (i + c.len_utf8()) as u32))
+            .collect()
+    };
+    let (old_changed, new_changed) =
+        unmatched((old, &chars(old)), (new, &chars(new))).unwrap_or_default();
+    let highlights = |line: &str, changed: &[(u32, u32)]| -> Highlights {
+        let merged = merge_regions(changed, line.as_bytes());
+        if merged.is_empty() {
+            Highlights::new()
+        } else if covers_non_whitespace(line, &merged, threshold.unwrap_or(100)) {
+            smallvec::smallvec![HighlightRegion::full_line()]
+        } else {
+            merged
+                .into_iter()
+                .map(|(start, end)| HighlightRegion::columns(start, end))
+                .collect()
+        }
+    };
+    if old_changed.is_empty() && new_changed.is_empty() {
+        let full = |line: &str| highlights(line, &chars(line));
+        (full(old), full(new))
+    } else {
+        (highlights(old, &old_changed), highlights(new, &new_changed))
+    }
+}
+
+/// The units of an old and a new line, as byte ranges, left out of the longest common
+/// subsequence of their text. `None` if the lines have no unit in common or more than
+/// [`MAX_LCS_UNITS`] of them.
+fn unmatched(
+    (old, old_units): (&str, &[(u32, u32)]),
+    (new, new_units): (&str, &[(u32, u32)]),
+) -> Option<(ByteRanges, ByteRanges)> {
+    if old_units.len() > MAX_LCS_UNITS || new_units.len() > MAX_LCS_UNITS {
+        return None;
+    }
+    let same = |i: usize, j: usize| {
+        let ((old_start, old_end), (new_start, new_end)) = (old_units[i], new_units[j]);
+        old[old_start as usize..old_end as usize] == new[new_start as usize..new_end as usize]
+    };
+
+    // Lengths of the longest common subsequence of the units after each pair of positions
+    let width = new_units.len() + 1;
+    let mut lengths = vec![0u16; (old_units.len() + 1) * width];
+    for i in (0..old_units.len()).rev() {
+        for j in (0..new_units.len()).rev() {
+            lengths[i * width + j] = if same(i, j) {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+    if lengths[0] == 0 {
+        return None;
+    }
+
+    let (mut old_changed, mut new_changed) = (Vec::new(), Vec::new());
+    let (mut i, mut j) = (0, 0);
+    while i < old_units.len() || j < new_units.len() {
+        if i == old_units.len() {
+            new_changed.push(new_units[j]);
+            j += 1;
+        } else if j == new_units.len() {
+            old_changed.push(old_units[i]);
+            i += 1;
+        } else if same(i, j) {
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            old_changed.push(old_units[i]);
+            i += 1;
+        } else {
+            new_changed.push(new_units[j]);
+            j += 1;
+        }
+    }
+    Some((old_changed, new_changed))
+}
+
+/// Merges adjacent change regions, bridging gaps that contain only whitespace.
+///
+/// Creates cleaner visual output by combining regions like `[0-3], [4-7]`
+/// into `[0-7]` when the gap contains only whitespace.
+fn merge_regions(regions: &[(u32, u32)], bytes: &[u8]) -> SmallVec<[(u32, u32); 4]> {
+    let mut merged: SmallVec<[(u32, u32); 4]> = SmallVec::with_capacity(regions.len());
+
+    for &(start, end) in regions {
+        if let Some((_, last_end)) = merged.last_mut() {
+            let gap_start = *last_end as usize;
+            let gap_end = start as usize;
+
+            // Merge if regions overlap/touch or if the gap is only whitespace
+            if gap_start >= gap_end || is_whitespace_only(bytes, gap_start, gap_end) {
+                *last_end = (*last_end).max(end);
+                continue;
+            }
+        }
+        merged.push((start, end));
+    }
+
+    merged
+}
+
+/// Checks if a byte range contains only ASCII whitespace.
+///
+/// Returns `true` if the range is empty or contains only spaces, tabs, etc.
+#[inline]
+fn is_whitespace_only(bytes: &[u8], start: usize, end: usize) -> bool {
+    bytes
+        .get(start..end)
+        .is_some_and(|slice| slice.iter().all(u8::is_ascii_whitespace))
+}
+
+/// Checks if the regions cover at least `percent` of the non-whitespace characters in the
+/// line, all of them at 100.
+///
+/// Used to determine if we should use a full-line highlight instead of
+/// multiple partial regions. Avoids intermediate allocation by counting
+/// positions as we iterate.
+fn covers_non_whitespace(line: &str, regions: &[(u32, u32)], percent: u8) -> bool {
+    let (mut total, mut covered) = (0u64, 0u64);
+
+    for (i, c) in line.char_indices() {
+        if !c.is_whitespace() {
+            total += 1;
+            let pos = i as u32;
+            // Check if this position is covered by any region
+            if regions
+                .iter()
+                .any(|(start, end)| pos >= *start && pos < *end)
+            {
+                covered += 1;
+            }
+        }
+    }
+
+    total > 0 && covered * 100 >= total * u64::from(percent)
+}
+
+impl IntoLua for HighlightRegion {
     fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
         let table = lua.create_table()?;
         table.set("start", self.start)?;
         table.set("end", self.end)?;
+        let layer = self.layer();
+        let kind = match layer {
+            Layer::FullLine => RegionKind::Full,
+            _ => RegionKind::Partial,
+        };
+        table.set("kind", kind.as_str())?;
+        table.set("syntax_kind", self.syntax_kind)?;
+        table.set("display_start", self.display_start)?;
+        table.set("display_end", self.display_end)?;
+        table.set("priority", layer.priority())?;
         Ok(LuaValue::Table(table))
     }
 }
@@ -490,6 +2887,7 @@ impl IntoLua for Side {
         let table = lua.create_table()?;
         table.set("content", self.content)?;
         table.set("is_filler", self.is_filler)?;
+        table.set("line_number", self.line_number)?;
 
         let highlights: Vec<LuaValue> = self
             .highlights
@@ -498,6 +2896,132 @@ impl IntoLua for Side {
             .collect::<LuaResult<_>>()?;
         table.set("highlights", lua.create_sequence_from(highlights)?)?;
 
+        if !self.syntax.is_empty() {
+            let syntax: Vec<LuaValue> = self
+                .syntax
+                .into_iter()
+                .map(|span| span.into_lua(lua))
+                .collect::<LuaResult<_>>()?;
+            table.set("syntax", lua.create_sequence_from(syntax)?)?;
+        }
+
+        if !self.invisibles.is_empty() {
+            let invisibles: Vec<LuaValue> = self
+                .invisibles
+                .into_iter()
+                .map(|c| c.into_lua(lua))
+                .collect::<LuaResult<_>>()?;
+            table.set("invisibles", lua.create_sequence_from(invisibles)?)?;
+        }
+
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for InvisibleChar {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("column", self.column)?;
+        table.set("codepoint", self.codepoint)?;
+        table.set("label", self.label())?;
+        table.set("priority", Layer::Invisible.priority())?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for SyntaxSpan {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("start", self.start)?;
+        table.set("end", self.end)?;
+        table.set("kind", self.kind)?;
+        table.set("priority", Layer::Syntax.priority())?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for Hunk {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("start", self.start)?;
+        table.set("end", self.end)?;
+        table.set("additions", self.additions)?;
+        table.set("deletions", self.deletions)?;
+        table.set("kind", self.kind)?;
+        table.set("label", self.label)?;
+        table.set("header", self.header)?;
+        table.set("new_start", self.new_start)?;
+        table.set("new_end", self.new_end)?;
+        table.set("pairs", lua.create_sequence_from(self.pairs)?)?;
+        if let Some(rename) = self.rename_of {
+            let rename_of = lua.create_table()?;
+            rename_of.set("old", rename.old)?;
+            rename_of.set("new", rename.new)?;
+            table.set("rename_of", rename_of)?;
+        }
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// First and last of the given 0-indexed line numbers, 1-indexed.
+fn line_extent(lines: impl IntoIterator<Item = u32>) -> (Option<u32>, Option<u32>) {
+    let mut lines = lines.into_iter().map(|ln| ln + 1);
+    let first = lines.next();
+    lines.fold((first, first), |(min, max), ln| {
+        (min.min(Some(ln)), max.max(Some(ln)))
+    })
+}
+
+impl IntoLua for VirtualDeletion {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("row", self.row)?;
+        table.set("new_line", self.new_line)?;
+        table.set("lines", lua.create_sequence_from(self.lines)?)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for UnifiedLine {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let LuaValue::Table(table) = self.side.into_lua(lua)? else {
+            unreachable!("Side converts to a table");
+        };
+        table.set("origin", self.origin.as_str())?;
+        table.set("row", self.row)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for Section {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let LuaValue::Table(table) = self.lines.into_lua(lua)? else {
+            unreachable!("LinePairing converts to a table");
+        };
+        table.set("start", self.start)?;
+        table.set("end", self.end)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for Fold {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let LuaValue::Table(table) = self.lines.into_lua(lua)? else {
+            unreachable!("LinePairing converts to a table");
+        };
+        table.set("row", self.row)?;
+        table.set("rows", self.rows)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for LinePairing {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("old_start", self.old_start)?;
+        table.set("old_end", self.old_end)?;
+        table.set("new_start", self.new_start)?;
+        table.set("new_end", self.new_end)?;
         Ok(LuaValue::Table(table))
     }
 }
@@ -505,8 +3029,12 @@ impl IntoLua for Side {
 impl IntoLua for Row {
     fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
         let table = lua.create_table()?;
+        table.set("lhs_line_number", self.left.line_number)?;
+        table.set("rhs_line_number", self.right.line_number)?;
         table.set("left", self.left.into_lua(lua)?)?;
         table.set("right", self.right.into_lua(lua)?)?;
+        table.set("change", self.change.as_str())?;
+        table.set("description", self.description)?;
         Ok(LuaValue::Table(table))
     }
 }
@@ -514,6 +3042,9 @@ impl IntoLua for Row {
 impl IntoLua for DisplayFile {
     fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
         let table = lua.create_table()?;
+        let old_path = self.old_path.as_ref().unwrap_or(&self.path);
+        table.set("old_path", old_path.to_string_lossy().as_ref())?;
+        table.set("new_path", self.path.to_string_lossy().as_ref())?;
         table.set("path", self.path.to_string_lossy().as_ref())?;
         table.set("language", self.language)?;
         table.set(
@@ -522,104 +3053,733 @@ impl IntoLua for DisplayFile {
                 Status::Created => "created",
                 Status::Deleted => "deleted",
                 Status::Changed => "changed",
+                Status::Unchanged => "unchanged",
+                Status::Renamed => "renamed",
             },
         )?;
         table.set("additions", self.additions)?;
         table.set("deletions", self.deletions)?;
+        table.set("stats_only", self.stats_only)?;
+        table.set("processing_failed", self.processing_failed)?;
+        table.set("was_empty", self.was_empty)?;
+        table.set("now_empty", self.now_empty)?;
+        table.set("binary", self.binary)?;
+        if self.binary {
+            table.set("old_size", self.old_size)?;
+            table.set("new_size", self.new_size)?;
+            let size = |size: Option<u64>| size.map_or(0, |size| size as i64);
+            table.set("size_delta", size(self.new_size) - size(self.old_size))?;
+        }
+        if let Some(change) = self.mode_change {
+            let mode_change = lua.create_table()?;
+            mode_change.set("old", change.old)?;
+            mode_change.set("new", change.new)?;
+            table.set("mode_change", mode_change)?;
+        }
+        if let Some(change) = self.submodule {
+            let submodule = lua.create_table()?;
+            submodule.set("old", change.old)?;
+            submodule.set("new", change.new)?;
+            submodule.set("log", change.log)?;
+            table.set("submodule", submodule)?;
+        }
+        let line_endings = |endings: Option<LineEndings>| {
+            endings
+                .map(|endings| {
+                    let table = lua.create_table()?;
+                    table.set("style", endings.style.as_str())?;
+                    table.set("final_newline", endings.final_newline)?;
+                    Ok::<_, LuaError>(table)
+                })
+                .transpose()
+        };
+        table.set("old_line_endings", line_endings(self.old_line_endings)?)?;
+        table.set("new_line_endings", line_endings(self.new_line_endings)?)?;
+        table.set("old_encoding", self.old_encoding)?;
+        table.set("new_encoding", self.new_encoding)?;
+        if !self.unified.is_empty() {
+            table.set("unified", lua.create_sequence_from(self.unified)?)?;
+        }
+        if !self.virtual_deletions.is_empty() {
+            table.set(
+                "virtual_deletions",
+                lua.create_sequence_from(self.virtual_deletions)?,
+            )?;
+        }
+
+        let rows: Vec<LuaValue> = self
+            .rows
+            .into_iter()
+            .map(|r| r.into_lua(lua))
+            .collect::<LuaResult<_>>()?;
+        table.set("rows", lua.create_sequence_from(rows)?)?;
+
+        table.set("hunk_starts", lua.create_sequence_from(self.hunk_starts)?)?;
+        table.set("hunks", lua.create_sequence_from(self.hunks)?)?;
+        table.set("sections", lua.create_sequence_from(self.sections)?)?;
+        table.set("folds", lua.create_sequence_from(self.folds)?)?;
+        table.set(
+            "changed_functions",
+            lua.create_sequence_from(self.changed_functions)?,
+        )?;
+
+        // Serialize aligned_lines as array of [left, right] pairs (nil for None)
+        let aligned: Vec<LuaValue> = self
+            .aligned_lines
+            .into_iter()
+            .map(|(left, right)| {
+                let pair = lua.create_table()?;
+                pair.set(1, left)?;
+                pair.set(2, right)?;
+                Ok(LuaValue::Table(pair))
+            })
+            .collect::<LuaResult<_>>()?;
+        table.set("aligned_lines", lua.create_sequence_from(aligned)?)?;
+
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for ConflictRow {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("base", self.base.into_lua(lua)?)?;
+        table.set("ours", self.ours.into_lua(lua)?)?;
+        table.set("theirs", self.theirs.into_lua(lua)?)?;
+        table.set("conflict", self.conflict)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for ConflictFile {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("path", self.path.to_string_lossy().as_ref())?;
+        table.set("language", self.language)?;
+        table.set("rows", lua.create_sequence_from(self.rows)?)?;
+        table.set("hunk_starts", lua.create_sequence_from(self.hunk_starts)?)?;
+        table.set(
+            "conflict_starts",
+            lua.create_sequence_from(self.conflict_starts)?,
+        )?;
+
+        // Serialize aligned_lines as array of [base, ours, theirs] triples (nil for None)
+        let aligned: Vec<LuaValue> = self
+            .aligned_lines
+            .into_iter()
+            .map(|(base, ours, theirs)| {
+                let triple = lua.create_table()?;
+                triple.set(1, base)?;
+                triple.set(2, ours)?;
+                triple.set(3, theirs)?;
+                Ok(LuaValue::Table(triple))
+            })
+            .collect::<LuaResult<_>>()?;
+        table.set("aligned_lines", lua.create_sequence_from(aligned)?)?;
+
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difftastic::{DiffLine, Side as DiffSide};
+
+    /// [`super::process_file`] for lines that all ended with `\n`.
+    fn process_file(
+        file: DifftFile,
+        old_lines: Vec<String>,
+        new_lines: Vec<String>,
+        stats: Option<(u32, u32)>,
+        options: &ProcessOptions,
+    ) -> DisplayFile {
+        super::process_file(file, old_lines, new_lines, (&[], &[]), stats, options)
+    }
+
+    /// Helper to create a Change with only start/end (content and highlight empty).
+    fn change(start: u32, end: u32) -> Change {
+        Change {
+            start,
+            end,
+            content: String::new(),
+            highlight: String::new(),
+        }
+    }
+
+    /// Helper to create a Change with a syntax highlight kind.
+    fn kind_change(start: u32, end: u32, highlight: &str) -> Change {
+        Change {
+            highlight: highlight.into(),
+            ..change(start, end)
+        }
+    }
+
+    /// Helper to create a DiffSide with given line number and changes.
+    fn diff_side(line: u32, changes: Vec<Change>) -> DiffSide {
+        DiffSide {
+            line_number: line,
+            changes,
+        }
+    }
+
+    #[test]
+    fn created_file_all_additions() {
+        let file = DifftFile {
+            path: "new.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Created,
+            aligned_lines: vec![],
+            chunks: vec![],
+        };
+        let result = process_file(
+            file,
+            vec![],
+            vec!["a".into(), "b".into()],
+            Some((2, 0)),
+            &ProcessOptions::default(),
+        );
+
+        assert_eq!(result.rows.len(), 2);
+        assert!(result.rows[0].left.is_filler);
+        assert_eq!(result.rows[0].right.content, "a");
+        assert!(!result.rows[0].right.is_filler);
+        assert_eq!(result.rows[0].right.highlights.len(), 1);
+        assert_eq!(result.rows[0].right.highlights[0].end, -1); // full line
+        assert_eq!(result.additions, 2);
+        assert_eq!(result.deletions, 0);
+    }
+
+    #[test]
+    fn line_length_ends_replace_the_full_line_sentinel() {
+        let file = DifftFile {
+            path: "new.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Created,
+            aligned_lines: vec![],
+            chunks: vec![],
+        };
+        let options = ProcessOptions {
+            line_length_ends: true,
+            ..ProcessOptions::default()
+        };
+        let result = process_file(
+            file,
+            vec![],
+            vec!["héllo".into(), "".into()],
+            None,
+            &options,
+        );
+
+        let region = &result.rows[0].right.highlights[0];
+        assert_eq!(
+            (region.start, region.end, region.kind),
+            (0, 6, RegionKind::Full)
+        );
+        assert_eq!(region.layer(), Layer::FullLine);
+        assert_eq!(result.rows[1].right.highlights[0].end, 0);
+        assert_eq!(result.rows[0].change, RowChange::Line);
+    }
+
+    #[test]
+    fn deleted_file_all_deletions() {
+        let file = DifftFile {
+            path: "old.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Deleted,
+            aligned_lines: vec![],
+            chunks: vec![],
+        };
+        let result = process_file(
+            file,
+            vec!["x".into(), "y".into()],
+            vec![],
+            Some((0, 2)),
+            &ProcessOptions::default(),
+        );
+
+        assert_eq!(result.rows.len(), 2);
+        assert_eq!(result.rows[0].left.content, "x");
+        assert!(!result.rows[0].left.is_filler);
+        assert!(result.rows[0].right.is_filler);
+        assert_eq!(result.additions, 0);
+        assert_eq!(result.deletions, 2);
+    }
+
+    #[test]
+    fn three_way_alignment_pairs_concurrent_insertions() {
+        // ours: base line 1 replaced by two lines; theirs: a line inserted before base line 1
+        let ours = [
+            (Some(0), Some(0)),
+            (Some(1), None),
+            (None, Some(1)),
+            (None, Some(2)),
+        ];
+        let theirs = [(Some(0), Some(0)), (None, Some(1)), (Some(1), Some(2))];
+        assert_eq!(
+            align_three_way(&ours, &theirs),
+            vec![
+                (Some(0), Some(0), Some(0)),
+                (None, None, Some(1)),
+                (Some(1), None, Some(2)),
+                (None, Some(1), None),
+                (None, Some(2), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn conflict_regions_need_changes_on_both_sides() {
+        let lines = |text: &[&str]| -> Vec<String> { text.iter().map(|s| s.to_string()).collect() };
+        let file = |aligned_lines, chunks| DifftFile {
+            path: "a.txt".into(),
+            old_path: None,
+            language: "Text".into(),
+            status: Status::Changed,
+            aligned_lines,
+            chunks,
+        };
+        let edit = |ln: u32| {
+            vec![vec![DiffLine {
+                lhs: Some(diff_side(ln, vec![change(0, 1)])),
+                rhs: Some(diff_side(ln, vec![change(0, 1)])),
+            }]]
+        };
+        let identity = vec![(Some(0), Some(0)), (Some(1), Some(1)), (Some(2), Some(2))];
+
+        // Ours edits line 0 only; both edit line 2
+        let ours = file(identity.clone(), [edit(0), edit(2)].concat());
+        let theirs = file(identity, edit(2));
+        let result = process_conflict(
+            &ours,
+            &theirs,
+            &lines(&["a", "b", "c"]),
+            &lines(&["A", "b", "C"]),
+            &lines(&["a", "b", "X"]),
+        );
+
+        assert_eq!(result.hunk_starts, vec![0, 2]);
+        assert_eq!(result.conflict_starts, vec![2]);
+        assert!(!result.rows[0].conflict);
+        assert!(result.rows[2].conflict);
+        assert_eq!(result.rows[2].theirs.content, "X");
+        assert_eq!(result.rows[2].base.highlights.len(), 1);
+    }
+
+    #[test]
+    fn hunks_carry_new_line_range() {
+        let file = DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![
+                (Some(0), Some(0)),
+                (Some(1), Some(1)),
+                (None, Some(2)),
+                (Some(2), Some(3)),
+                (Some(3), None),
+            ],
+            chunks: vec![
+                vec![
+                    DiffLine {
+                        lhs: Some(diff_side(1, vec![change(0, 1)])),
+                        rhs: Some(diff_side(1, vec![change(0, 1)])),
+                    },
+                    DiffLine {
+                        lhs: None,
+                        rhs: Some(diff_side(2, vec![change(0, 1)])),
+                    },
+                ],
+                vec![DiffLine {
+                    lhs: Some(diff_side(3, vec![change(0, 1)])),
+                    rhs: None,
+                }],
+            ],
+        };
+        let lines = |text: &[&str]| text.iter().map(|s| s.to_string()).collect();
+        let result = process_file(
+            file,
+            lines(&["a", "b", "c", "d"]),
+            lines(&["a", "B", "x", "c"]),
+            None,
+            &ProcessOptions::default(),
+        );
+
+        assert_eq!(result.hunk_starts, vec![1, 4]);
+        assert_eq!(
+            (result.hunks[0].new_start, result.hunks[0].new_end),
+            (Some(2), Some(3))
+        );
+        assert_eq!(
+            (result.hunks[1].new_start, result.hunks[1].new_end),
+            (None, None)
+        );
+        let ranges: Vec<_> = result
+            .hunks
+            .iter()
+            .map(|hunk| (hunk.start, hunk.end, hunk.additions, hunk.deletions))
+            .collect();
+        assert_eq!(ranges, [(1, 2, 2, 1), (4, 4, 0, 1)]);
+    }
+
+    #[test]
+    fn hunks_pair_old_and_new_lines_by_chunk() {
+        let file = DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0)), (None, Some(1)), (Some(1), Some(2))],
+            chunks: vec![
+                vec![DiffLine {
+                    lhs: Some(diff_side(0, vec![change(0, 1)])),
+                    rhs: Some(diff_side(0, vec![change(0, 1)])),
+                }],
+                vec![
+                    DiffLine {
+                        lhs: None,
+                        rhs: Some(diff_side(1, vec![change(0, 1)])),
+                    },
+                    DiffLine {
+                        lhs: Some(diff_side(1, vec![change(0, 1)])),
+                        rhs: Some(diff_side(2, vec![change(0, 1)])),
+                    },
+                ],
+            ],
+        };
+        let lines = |text: &[&str]| text.iter().map(|s| s.to_string()).collect();
+        let result = process_file(
+            file,
+            lines(&["a", "b"]),
+            lines(&["A", "x", "B"]),
+            None,
+            &ProcessOptions::default(),
+        );
+
+        assert_eq!(result.hunks.len(), 1);
+        assert_eq!(
+            result.hunks[0].pairs,
+            [
+                LinePairing {
+                    old_start: Some(1),
+                    old_end: Some(1),
+                    new_start: Some(1),
+                    new_end: Some(1),
+                },
+                LinePairing {
+                    old_start: Some(2),
+                    old_end: Some(2),
+                    new_start: Some(2),
+                    new_end: Some(3),
+                },
+            ]
+        );
+        // One hunk, but a section per chunk
+        let bounds: Vec<_> = result.sections.iter().map(|s| (s.start, s.end)).collect();
+        assert_eq!(bounds, [(0, 0), (1, 2)]);
+        assert_eq!(result.sections[1].lines, result.hunks[0].pairs[1]);
+    }
+
+    #[test]
+    fn virtual_deletions_attach_to_next_kept_line() {
+        let file = DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![
+                (Some(0), Some(0)),
+                (Some(1), None),
+                (Some(2), Some(1)),
+                (Some(3), None),
+            ],
+            chunks: vec![vec![
+                DiffLine {
+                    lhs: Some(diff_side(1, vec![change(0, 1)])),
+                    rhs: None,
+                },
+                DiffLine {
+                    lhs: Some(diff_side(2, vec![change(0, 1)])),
+                    rhs: Some(diff_side(1, vec![change(0, 1)])),
+                },
+                DiffLine {
+                    lhs: Some(diff_side(3, vec![change(0, 1)])),
+                    rhs: None,
+                },
+            ]],
+        };
+        let lines = |text: &[&str]| text.iter().map(|s| s.to_string()).collect();
+        let options = ProcessOptions {
+            virtual_deletions: true,
+            ..ProcessOptions::default()
+        };
+        let mut result = process_file(
+            file,
+            lines(&["a", "b", "c", "d"]),
+            lines(&["a", "C"]),
+            None,
+            &options,
+        );
+
+        let deletions = &result.virtual_deletions;
+        assert_eq!(deletions.len(), 2);
+        assert_eq!(
+            (deletions[0].row, deletions[0].new_line),
+            (Some(2), Some(2))
+        );
+        let removed: Vec<&str> = deletions[0]
+            .lines
+            .iter()
+            .map(|side| side.content.as_str())
+            .collect();
+        assert_eq!(removed, ["b", "c"]);
+        assert_eq!((deletions[1].row, deletions[1].new_line), (None, None));
+        assert_eq!(deletions[1].lines[0].content, "d");
+
+        add_mode_change(
+            &mut result,
+            ModeChange {
+                old: "100644".into(),
+                new: "100755".into(),
+            },
+        );
+        let rows: Vec<_> = result.virtual_deletions.iter().map(|d| d.row).collect();
+        assert_eq!(rows, [Some(3), None]);
+        assert_eq!(result.rows[3].right.content, "C");
+    }
+
+    #[test]
+    fn eol_only_changes_are_flagged_and_cr_regions_cut_off() {
+        // difftastic's lines keep their `\r`
+        let file = DifftFile {
+            path: "notes.txt".into(),
+            old_path: None,
+            language: "Text".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0)), (Some(1), Some(1))],
+            chunks: vec![vec![
+                DiffLine {
+                    lhs: Some(diff_side(0, vec![change(0, 4)])),
+                    rhs: Some(diff_side(0, vec![change(0, 3)])),
+                },
+                DiffLine {
+                    lhs: Some(diff_side(1, vec![change(4, 6)])),
+                    rhs: Some(diff_side(1, vec![change(4, 6)])),
+                },
+            ]],
+        };
+        let result = process_contents(
+            file,
+            Some(b"one\r\ntwo x\r\n"),
+            Some(b"one\ntwo y\r\n"),
+            None,
+            &ProcessOptions::default(),
+        );
 
-        let rows: Vec<LuaValue> = self
-            .rows
-            .into_iter()
-            .map(|r| r.into_lua(lua))
-            .collect::<LuaResult<_>>()?;
-        table.set("rows", lua.create_sequence_from(rows)?)?;
+        assert_eq!(result.rows[0].change, RowChange::Eol);
+        assert_eq!(result.rows[0].left.content, "one");
+        assert_eq!(result.rows[1].change, RowChange::Intraline);
+        let highlight = &result.rows[1].right.highlights[0];
+        assert_eq!((highlight.start, highlight.end), (4, 5));
+        assert_eq!(result.hunks.len(), 1);
 
-        table.set("hunk_starts", lua.create_sequence_from(self.hunk_starts)?)?;
+        let (lines, crlf) = split_lines("a\r\nb\nc\r", &ProcessOptions::default());
+        assert_eq!(lines, ["a", "b", "c\r"]);
+        assert_eq!(crlf, [true, false, false]);
+    }
 
-        // Serialize aligned_lines as array of [left, right] pairs (nil for None)
-        let aligned: Vec<LuaValue> = self
-            .aligned_lines
-            .into_iter()
-            .map(|(left, right)| {
-                let pair = lua.create_table()?;
-                pair.set(1, left)?;
-                pair.set(2, right)?;
-                Ok(LuaValue::Table(pair))
-            })
-            .collect::<LuaResult<_>>()?;
-        table.set("aligned_lines", lua.create_sequence_from(aligned)?)?;
+    #[test]
+    fn split_lines_matches_str_lines_by_default() {
+        let options = ProcessOptions::default();
+        for content in ["", "a", "a\n", "a\r\nb\r\n", "a\n\n", "a\r"] {
+            let expected: Vec<String> = content.lines().map(String::from).collect();
+            assert_eq!(split_lines(content, &options).0, expected, "{content:?}");
+        }
 
-        Ok(LuaValue::Table(table))
+        let options = ProcessOptions {
+            keep_cr: true,
+            keep_final_empty_line: true,
+            ..ProcessOptions::default()
+        };
+        let content = "a\r\nb\n";
+        assert_eq!(split_lines(content, &options).0, ["a\r", "b", ""]);
+        assert_eq!(split_lines(content, &options).0.join("\n"), content);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::difftastic::{DiffLine, Side as DiffSide};
+    #[test]
+    fn line_endings_detected() {
+        let detect = |content| {
+            let endings = LineEndings::detect(content);
+            (endings.style, endings.final_newline)
+        };
+        assert_eq!(detect("a\nb\n"), (EolStyle::Lf, true));
+        assert_eq!(detect("a\r\nb"), (EolStyle::Crlf, false));
+        assert_eq!(detect("a\r\nb\n"), (EolStyle::Mixed, true));
+        assert_eq!(detect("a"), (EolStyle::None, false));
+    }
 
-    /// Helper to create a Change with only start/end (content and highlight empty).
-    fn change(start: u32, end: u32) -> Change {
-        Change {
-            start,
-            end,
-            content: String::new(),
-            highlight: String::new(),
-        }
+    #[test]
+    fn accessible_output_describes_rows() {
+        let file = DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0)), (None, Some(1))],
+            chunks: vec![vec![
+                DiffLine {
+                    lhs: Some(diff_side(0, vec![change(4, 5), change(8, 9)])),
+                    rhs: Some(diff_side(0, vec![change(4, 5), change(8, 9)])),
+                },
+                DiffLine {
+                    lhs: None,
+                    rhs: Some(diff_side(1, vec![change(0, 4)])),
+                },
+            ]],
+        };
+        let options = ProcessOptions {
+            accessible: true,
+            ..ProcessOptions::default()
+        };
+        let result = process_file(
+            file,
+            vec!["let a = 1;".into()],
+            vec!["let b = 2;".into(), "done".into()],
+            None,
+            &options,
+        );
+
+        assert_eq!(result.rows[0].change, RowChange::Intraline);
+        assert_eq!(
+            result.rows[0].description.as_deref(),
+            Some("2 words changed")
+        );
+        assert_eq!(
+            result.rows[0].left.highlights[..],
+            [HighlightRegion::full_line()]
+        );
+        assert_eq!(result.rows[1].description.as_deref(), Some("Line added"));
     }
 
-    /// Helper to create a DiffSide with given line number and changes.
-    fn diff_side(line: u32, changes: Vec<Change>) -> DiffSide {
-        DiffSide {
-            line_number: line,
-            changes,
-        }
+    #[test]
+    fn ignore_whitespace_leaves_reindented_lines_unchanged() {
+        let file = || DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0)), (Some(1), Some(1))],
+            chunks: vec![vec![
+                DiffLine {
+                    lhs: Some(diff_side(0, vec![change(0, 3)])),
+                    rhs: Some(diff_side(0, vec![change(4, 7)])),
+                },
+                DiffLine {
+                    lhs: Some(diff_side(1, vec![change(0, 1)])),
+                    rhs: Some(diff_side(1, vec![change(0, 1)])),
+                },
+            ]],
+        };
+        let old = || vec!["foo(a, b);".to_string(), "x".to_string()];
+        let new = || vec!["    foo(a,  b);".to_string(), "y".to_string()];
+        let options = ProcessOptions {
+            ignore_whitespace: true,
+            ..ProcessOptions::default()
+        };
+
+        let result = process_file(file(), old(), new(), None, &options);
+        assert_eq!(result.rows[0].change, RowChange::Unchanged);
+        assert_eq!(result.rows[1].change, RowChange::Line);
+        assert_eq!(result.hunk_starts, vec![1]);
+
+        let result = process_file(file(), old(), new(), None, &ProcessOptions::default());
+        assert_eq!(result.hunk_starts, vec![0]);
     }
 
     #[test]
-    fn created_file_all_additions() {
+    fn panicking_file_degrades_to_stats_only() {
         let file = DifftFile {
-            path: "new.rs".into(),
+            path: "bad.rs".into(),
+            old_path: None,
             language: "Rust".into(),
-            status: Status::Created,
-            aligned_lines: vec![],
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0))],
             chunks: vec![],
         };
-        let result = process_file(file, vec![], vec!["a".into(), "b".into()], Some((2, 0)));
+        let result = process_or_degrade(file, Some((3, 1)), |_| panic!("invariant broken"));
+
+        assert!(result.processing_failed);
+        assert!(result.stats_only);
+        assert_eq!(result.path, PathBuf::from("bad.rs"));
+        assert_eq!((result.additions, result.deletions), (3, 1));
+    }
+
+    #[test]
+    fn emptied_file_gets_notice_row() {
+        let file = DifftFile {
+            path: "notes.txt".into(),
+            old_path: None,
+            language: "Text".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), None)],
+            chunks: vec![vec![DiffLine {
+                lhs: Some(diff_side(0, vec![change(0, 4)])),
+                rhs: None,
+            }]],
+        };
+        let result = process_file(
+            file,
+            vec!["todo".into()],
+            vec![],
+            None,
+            &ProcessOptions::default(),
+        );
 
+        assert!(!result.was_empty);
+        assert!(result.now_empty);
         assert_eq!(result.rows.len(), 2);
-        assert!(result.rows[0].left.is_filler);
-        assert_eq!(result.rows[0].right.content, "a");
-        assert!(!result.rows[0].right.is_filler);
-        assert_eq!(result.rows[0].right.highlights.len(), 1);
-        assert_eq!(result.rows[0].right.highlights[0].end, -1); // full line
-        assert_eq!(result.additions, 2);
-        assert_eq!(result.deletions, 0);
+        assert_eq!(result.rows[0].change, RowChange::Notice);
+        assert_eq!(result.rows[0].left.content, "");
+        assert_eq!(result.rows[0].right.content, "Empty file");
+        assert_eq!(result.rows[1].left.content, "todo");
+        assert_eq!(result.aligned_lines[0], (None, None));
+        assert_eq!(result.hunk_starts, vec![1]);
+        assert_eq!(result.hunks[0].start, 1);
     }
 
     #[test]
-    fn deleted_file_all_deletions() {
+    fn created_whitespace_only_file_gets_notice_row() {
         let file = DifftFile {
-            path: "old.rs".into(),
-            language: "Rust".into(),
-            status: Status::Deleted,
+            path: "blank.txt".into(),
+            old_path: None,
+            language: "Text".into(),
+            status: Status::Created,
             aligned_lines: vec![],
             chunks: vec![],
         };
-        let result = process_file(file, vec!["x".into(), "y".into()], vec![], Some((0, 2)));
+        let result = process_file(
+            file,
+            vec![],
+            vec!["  ".into(), "".into()],
+            None,
+            &ProcessOptions::default(),
+        );
 
-        assert_eq!(result.rows.len(), 2);
-        assert_eq!(result.rows[0].left.content, "x");
-        assert!(!result.rows[0].left.is_filler);
-        assert!(result.rows[0].right.is_filler);
-        assert_eq!(result.additions, 0);
-        assert_eq!(result.deletions, 2);
+        assert!(!result.was_empty);
+        assert!(result.now_empty);
+        assert_eq!(result.rows[0].right.content, "Whitespace only");
+        assert_eq!(result.rows.len(), 3);
     }
 
     #[test]
     fn modification_with_aligned_lines() {
         let file = DifftFile {
             path: "mod.rs".into(),
+            old_path: None,
             language: "Rust".into(),
             status: Status::Changed,
             aligned_lines: vec![(Some(0), Some(0)), (Some(1), Some(1)), (Some(2), Some(2))],
@@ -633,6 +3793,7 @@ mod tests {
             vec!["line1".into(), "foo".into(), "line3".into()],
             vec!["line1".into(), "foobar".into(), "line3".into()],
             Some((1, 1)),
+            &ProcessOptions::default(),
         );
 
         assert_eq!(result.rows.len(), 3);
@@ -642,10 +3803,239 @@ mod tests {
         assert!(!result.rows[1].right.highlights.is_empty());
     }
 
+    #[test]
+    fn text_diffs_without_changes_get_character_highlights() {
+        let file = DifftFile {
+            path: "notes.txt".into(),
+            old_path: None,
+            language: "Text".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0)), (Some(1), Some(1)), (Some(2), Some(2))],
+            chunks: Vec::new(),
+        };
+        let result = process_file(
+            file,
+            vec!["same".into(), "color = red".into(), "abc".into()],
+            vec!["same".into(), "colour = blue".into(), "xyz".into()],
+            Some((2, 2)),
+            &ProcessOptions::default(),
+        );
+
+        let regions = |side: &Side| -> Vec<(u32, i32)> {
+            side.highlights.iter().map(|r| (r.start, r.end)).collect()
+        };
+        assert_eq!(result.hunk_starts, [1]);
+        assert_eq!(result.rows[0].change, RowChange::Unchanged);
+        assert_eq!(regions(&result.rows[1].left), [(8, 9), (10, 11)]);
+        assert_eq!(regions(&result.rows[1].right), [(4, 5), (9, 12)]);
+        assert_eq!(result.rows[1].change, RowChange::Intraline);
+        // Lines with no character in common are replaced entirely
+        assert_eq!(result.rows[2].change, RowChange::Line);
+    }
+
+    #[test]
+    fn unified_view_lists_removed_lines_before_added_ones() {
+        let file = DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![
+                (Some(0), Some(0)),
+                (Some(1), Some(1)),
+                (None, Some(2)),
+                (Some(2), None),
+                (Some(3), Some(3)),
+            ],
+            chunks: vec![vec![
+                DiffLine {
+                    lhs: Some(diff_side(1, vec![change(0, 1)])),
+                    rhs: Some(diff_side(1, vec![change(0, 1)])),
+                },
+                DiffLine {
+                    lhs: None,
+                    rhs: Some(diff_side(2, vec![change(0, 1)])),
+                },
+                DiffLine {
+                    lhs: Some(diff_side(2, vec![change(0, 1)])),
+                    rhs: None,
+                },
+            ]],
+        };
+        let lines = |text: &[&str]| text.iter().map(|s| s.to_string()).collect();
+        let options = ProcessOptions {
+            view: View::Unified,
+            ..ProcessOptions::default()
+        };
+        let result = process_file(
+            file,
+            lines(&["a", "b c", "d", "e"]),
+            lines(&["a", "B c", "x", "e"]),
+            None,
+            &options,
+        );
+
+        let unified: Vec<_> = result
+            .unified
+            .iter()
+            .map(|line| (line.origin, line.row, line.side.content.as_str()))
+            .collect();
+        assert_eq!(
+            unified,
+            [
+                (Origin::Context, 0, "a"),
+                (Origin::Removed, 1, "b c"),
+                (Origin::Removed, 3, "d"),
+                (Origin::Added, 1, "B c"),
+                (Origin::Added, 2, "x"),
+                (Origin::Context, 4, "e"),
+            ]
+        );
+        assert_eq!(result.unified[3].side.highlights.len(), 1);
+        assert_eq!(result.unified[4].side.line_number, Some(3));
+    }
+
+    #[test]
+    fn unified_view_follows_eol_and_mode_change_rows() {
+        let file = DifftFile {
+            path: "notes.txt".into(),
+            old_path: None,
+            language: "Text".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0)), (Some(1), Some(1))],
+            chunks: Vec::new(),
+        };
+        let options = ProcessOptions {
+            view: View::Unified,
+            ..ProcessOptions::default()
+        };
+        let mut result = process_contents(file, Some(b"a\nb\n"), Some(b"a\r\nb\n"), None, &options);
+        add_mode_change(
+            &mut result,
+            ModeChange {
+                old: "100644".into(),
+                new: "100755".into(),
+            },
+        );
+
+        let unified: Vec<_> = result
+            .unified
+            .iter()
+            .map(|line| (line.origin, line.row, line.side.content.as_str()))
+            .collect();
+        assert_eq!(
+            unified,
+            [
+                (Origin::Notice, 0, "old mode 100644"),
+                (Origin::Notice, 0, "new mode 100755"),
+                (Origin::Removed, 1, "a"),
+                (Origin::Added, 1, "a"),
+                (Origin::Context, 2, "b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn context_folds_distant_unchanged_rows_into_gaps() {
+        let lines: Vec<String> = (1..=12).map(|i| format!("line{i}")).collect();
+        let mut new_lines = lines.clone();
+        new_lines[6] = "changed".into();
+        let file = DifftFile {
+            path: "long.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: (0..12).map(|i| (Some(i), Some(i))).collect(),
+            chunks: vec![vec![DiffLine {
+                lhs: Some(diff_side(6, vec![change(0, 5)])),
+                rhs: Some(diff_side(6, vec![change(0, 7)])),
+            }]],
+        };
+        let options = ProcessOptions {
+            context: Some(2),
+            ..ProcessOptions::default()
+        };
+        let result = process_file(file, lines, new_lines, Some((1, 1)), &options);
+
+        let changes: Vec<_> = result.rows.iter().map(|row| row.change).collect();
+        assert_eq!(changes[0], RowChange::Gap);
+        assert_eq!(changes[3], RowChange::Line);
+        assert_eq!(changes[6], RowChange::Gap);
+        assert_eq!(result.rows.len(), 7);
+        assert_eq!(result.rows[0].left.content, "· · · 4 unchanged lines");
+        assert_eq!(result.hunk_starts, [3]);
+        assert_eq!(result.aligned_lines[1], (Some(4), Some(4)));
+        assert_eq!(
+            result.folds,
+            [
+                Fold {
+                    row: 0,
+                    rows: 4,
+                    lines: LinePairing::spanning(0..4, 0..4),
+                },
+                Fold {
+                    row: 6,
+                    rows: 3,
+                    lines: LinePairing::spanning(9..12, 9..12),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn context_keeps_eol_rows_and_mode_rows_shift_folds() {
+        let file = DifftFile {
+            path: "long.txt".into(),
+            old_path: None,
+            language: "Text".into(),
+            status: Status::Changed,
+            aligned_lines: (0..8).map(|i| (Some(i), Some(i))).collect(),
+            chunks: vec![vec![DiffLine {
+                lhs: Some(diff_side(7, vec![change(0, 5)])),
+                rhs: Some(diff_side(7, vec![change(0, 7)])),
+            }]],
+        };
+        let options = ProcessOptions {
+            context: Some(1),
+            ..ProcessOptions::default()
+        };
+        let mut result = process_contents(
+            file,
+            Some(b"line1\nline2\nline3\nline4\nline5\nline6\nline7\nline8\n"),
+            Some(b"line1\r\nline2\nline3\nline4\nline5\nline6\nline7\nchanged\n"),
+            None,
+            &options,
+        );
+
+        let changes: Vec<_> = result.rows.iter().map(|row| row.change).collect();
+        assert_eq!(
+            changes,
+            [
+                RowChange::Eol,
+                RowChange::Unchanged,
+                RowChange::Gap,
+                RowChange::Unchanged,
+                RowChange::Line,
+            ]
+        );
+        assert_eq!((result.folds[0].row, result.folds[0].rows), (2, 4));
+
+        add_mode_change(
+            &mut result,
+            ModeChange {
+                old: "100644".into(),
+                new: "100755".into(),
+            },
+        );
+        assert_eq!(result.folds[0].row, 3);
+        assert_eq!(result.rows[3].change, RowChange::Gap);
+    }
+
     #[test]
     fn addition_with_filler_line() {
         let file = DifftFile {
             path: "add.rs".into(),
+            old_path: None,
             language: "Rust".into(),
             status: Status::Changed,
             aligned_lines: vec![(Some(0), Some(0)), (None, Some(1)), (Some(1), Some(2))],
@@ -659,6 +4049,7 @@ mod tests {
             vec!["line 1".into(), "line 3".into()],
             vec!["line 1".into(), "new line".into(), "line 3".into()],
             Some((1, 0)),
+            &ProcessOptions::default(),
         );
 
         assert_eq!(result.rows.len(), 3);
@@ -672,6 +4063,7 @@ mod tests {
     fn deletion_with_filler_line() {
         let file = DifftFile {
             path: "del.rs".into(),
+            old_path: None,
             language: "Rust".into(),
             status: Status::Changed,
             aligned_lines: vec![(Some(0), Some(0)), (Some(1), None), (Some(2), Some(1))],
@@ -685,43 +4077,209 @@ mod tests {
             vec!["line 1".into(), "deleted".into(), "line 3".into()],
             vec!["line 1".into(), "line 3".into()],
             Some((0, 1)),
+            &ProcessOptions::default(),
+        );
+
+        assert_eq!(result.rows.len(), 3);
+        assert_eq!(result.rows[1].left.content, "deleted");
+        assert!(!result.rows[1].left.is_filler);
+        assert!(result.rows[1].right.is_filler);
+        let numbers: Vec<_> = result
+            .rows
+            .iter()
+            .map(|row| (row.left.line_number, row.right.line_number))
+            .collect();
+        assert_eq!(
+            numbers,
+            [(Some(1), Some(1)), (Some(2), None), (Some(3), Some(2))]
+        );
+    }
+
+    #[test]
+    fn highlight_empty_changes_is_empty() {
+        let highlights = compute_highlights("content", &[], None);
+        assert!(highlights.is_empty());
+    }
+
+    #[test]
+    fn highlight_full_coverage_is_full_line() {
+        let highlights = compute_highlights("hello", &[change(0, 5)], None);
+        assert_eq!(highlights[0].end, -1);
+    }
+
+    #[test]
+    fn highlight_partial_coverage() {
+        let highlights = compute_highlights("hello world", &[change(0, 5)], None);
+        assert_eq!(highlights[0].start, 0);
+        assert_eq!(highlights[0].end, 5);
+    }
+
+    #[test]
+    fn highlight_merges_across_whitespace() {
+        let highlights = compute_highlights("foo bar", &[change(0, 3), change(4, 7)], None);
+        assert_eq!(highlights.len(), 1);
+        assert_eq!(highlights[0].end, -1); // merged to full line
+    }
+
+    #[test]
+    fn split_words_highlights_identifiers_only() {
+        let regions = |highlights: Highlights| -> Vec<(u32, i32)> {
+            highlights.iter().map(|r| (r.start, r.end)).collect()
+        };
+        let content = "foo.baz(y) + 1";
+        let highlights = compute_highlights(content, &[change(4, 10)], None);
+        assert_eq!(regions(highlights.clone()), [(4, 10)]);
+        assert_eq!(regions(split_words(content, highlights)), [(4, 7), (8, 9)]);
+
+        // Operators and full lines stay as they are
+        let operator = compute_highlights(content, &[change(11, 12)], None);
+        assert_eq!(regions(split_words(content, operator)), [(11, 12)]);
+        let full = compute_highlights(content, &[change(0, 14)], None);
+        assert_eq!(regions(split_words(content, full)), [(0, -1)]);
+    }
+
+    #[test]
+    fn word_diff_narrows_replaced_lines_to_changed_words() {
+        let regions = |highlights: &Highlights| -> Vec<(u32, i32)> {
+            highlights.iter().map(|r| (r.start, r.end)).collect()
+        };
+        let refined = |old: &str, new: &str| {
+            let mut old_highlights: Highlights = smallvec::smallvec![HighlightRegion::full_line()];
+            let mut new_highlights: Highlights = smallvec::smallvec![HighlightRegion::full_line()];
+            refine_words((old, &mut old_highlights), (new, &mut new_highlights));
+            (regions(&old_highlights), regions(&new_highlights))
+        };
+
+        assert_eq!(
+            refined("let total = sum(xs) + 1;", "let amount = sum(ys) + 1;"),
+            (vec![(4, 9), (16, 18)], vec![(4, 10), (17, 19)])
         );
+        // Adjacent changed words are one region, and a side losing no word has none
+        assert_eq!(refined("call(a)", "call(a, b c)"), (vec![], vec![(6, 11)]));
+        // Lines with nothing in common, or only differing in whitespace, stay as they are
+        assert_eq!(refined("foo", "bar"), (vec![(0, -1)], vec![(0, -1)]));
+        assert_eq!(refined("a  b", "a b"), (vec![(0, -1)], vec![(0, -1)]));
+    }
 
-        assert_eq!(result.rows.len(), 3);
-        assert_eq!(result.rows[1].left.content, "deleted");
-        assert!(!result.rows[1].left.is_filler);
-        assert!(result.rows[1].right.is_filler);
+    #[test]
+    fn highlights_carry_the_syntax_kind_of_their_changes() {
+        let content = r#"x = f("a b");"#;
+        // The region over `"a b")` is mostly string
+        let changes = [
+            kind_change(0, 1, "keyword"),
+            change(4, 5),
+            kind_change(6, 9, "string"),
+            kind_change(9, 11, "delimiter"),
+        ];
+        let highlights = compute_highlights(content, &changes, None);
+        let kinds: Vec<_> = highlights
+            .iter()
+            .map(|r| (r.start, r.syntax_kind.as_deref()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [(0, Some("keyword")), (4, None), (6, Some("string"))]
+        );
+
+        let full = compute_highlights("x = 1", &[kind_change(0, 5, "normal")], None);
+        assert_eq!(full[0].syntax_kind.as_deref(), Some("normal"));
     }
 
     #[test]
-    fn highlight_empty_changes_is_empty() {
-        let highlights = compute_highlights("content", &[]);
-        assert!(highlights.is_empty());
+    fn highlight_threshold_collapses_mostly_changed_lines() {
+        // `value` and `10` are 7 of the 12 non-whitespace characters (58%)
+        let content = "let value = 10;";
+        let changes = [change(4, 9), change(12, 14)];
+        assert_eq!(compute_highlights(content, &changes, None).len(), 2);
+        assert_eq!(compute_highlights(content, &changes, Some(60)).len(), 2);
+        let collapsed = compute_highlights(content, &changes, Some(55));
+        assert_eq!((collapsed.len(), collapsed[0].end), (1, -1));
     }
 
     #[test]
-    fn highlight_full_coverage_is_full_line() {
-        let highlights = compute_highlights("hello", &[change(0, 5)]);
-        assert_eq!(highlights[0].end, -1);
+    fn expanded_tabs_move_offsets_along() {
+        let mut side = Side::new(
+            "\tx\ty = 1".to_string(),
+            false,
+            smallvec::smallvec![HighlightRegion::columns(2, 6), HighlightRegion::full_line()],
+        );
+        expand_side_tabs(&mut side, 4);
+        assert_eq!(side.content, "    x   y = 1");
+        let regions: Vec<_> = side.highlights.iter().map(|r| (r.start, r.end)).collect();
+        // The region over `\ty =` starts at the tab's first space
+        assert_eq!(regions, [(5, 11), (0, -1)]);
     }
 
     #[test]
-    fn highlight_partial_coverage() {
-        let highlights = compute_highlights("hello world", &[change(0, 5)]);
-        assert_eq!(highlights[0].start, 0);
-        assert_eq!(highlights[0].end, 5);
+    fn display_columns_count_wide_characters_twice() {
+        let mut side = Side::new(
+            "名前 = \"é\"".to_string(),
+            false,
+            smallvec::smallvec![
+                HighlightRegion::columns(0, 6),
+                HighlightRegion::columns(10, 13),
+                HighlightRegion::full_line()
+            ],
+        );
+        set_side_display_columns(&mut side);
+        let regions: Vec<_> = side
+            .highlights
+            .iter()
+            .map(|r| (r.display_start, r.display_end))
+            .collect();
+        assert_eq!(
+            regions,
+            [(Some(0), Some(4)), (Some(8), Some(10)), (Some(0), Some(-1))]
+        );
     }
 
     #[test]
-    fn highlight_merges_across_whitespace() {
-        let highlights = compute_highlights("foo bar", &[change(0, 3), change(4, 7)]);
-        assert_eq!(highlights.len(), 1);
-        assert_eq!(highlights[0].end, -1); // merged to full line
+    fn highlight_boundaries_inside_characters_widen_to_them() {
+        // `é` is bytes 4..6 and `→` 7..10
+        let content = "let é → x";
+        let highlights = compute_highlights(content, &[change(5, 8)], None);
+        assert_eq!((highlights[0].start, highlights[0].end), (4, 10));
+        for region in &highlights {
+            assert!(content.is_char_boundary(region.start as usize));
+            assert!(content.is_char_boundary(region.end as usize));
+        }
+    }
+
+    #[test]
+    fn hunks_renaming_one_identifier_are_labeled() {
+        let row = |old: &str, new: &str| Row {
+            left: Side::new(old.to_string(), false, Highlights::new()),
+            right: Side::new(new.to_string(), false, Highlights::new()),
+            change: RowChange::Intraline,
+            description: None,
+        };
+        let renamed = [
+            row("let total = sum(xs);", "let amount = sum(xs);"),
+            row("print(total + 1)", "print(amount + 1)"),
+        ];
+        assert_eq!(
+            renamed_identifier(&renamed),
+            Some(SymbolRename {
+                old: "total".into(),
+                new: "amount".into()
+            })
+        );
+        // A second rename, or any other change, isn't a rename of one identifier
+        let two = [renamed[0].clone(), row("f(xs)", "g(ys)")];
+        assert_eq!(renamed_identifier(&two), None);
+        assert_eq!(renamed_identifier(&[row("a + 1", "a + 2")]), None);
+        assert_eq!(
+            renamed_identifier(&[row("total", "total_x")]),
+            Some(SymbolRename {
+                old: "total".into(),
+                new: "total_x".into()
+            })
+        );
     }
 
     #[test]
     fn highlight_no_merge_across_non_whitespace() {
-        let highlights = compute_highlights("foo.bar", &[change(0, 3), change(4, 7)]);
+        let highlights = compute_highlights("foo.bar", &[change(0, 3), change(4, 7)], None);
         assert_eq!(highlights.len(), 2);
     }
 
@@ -729,6 +4287,7 @@ mod tests {
     fn expansion_multiline_to_single() {
         let file = DifftFile {
             path: "expand.rs".into(),
+            old_path: None,
             language: "Rust".into(),
             status: Status::Changed,
             aligned_lines: vec![
@@ -771,7 +4330,7 @@ mod tests {
             "}".into(),
         ];
 
-        let result = process_file(file, old_lines, new_lines, None);
+        let result = process_file(file, old_lines, new_lines, None, &ProcessOptions::default());
 
         assert_eq!(result.rows.len(), 5);
         assert_eq!(result.rows[0].left.content, "Self { a, b, c }");
@@ -784,6 +4343,7 @@ mod tests {
     fn contraction_single_to_multiline() {
         let file = DifftFile {
             path: "contract.rs".into(),
+            old_path: None,
             language: "Rust".into(),
             status: Status::Changed,
             aligned_lines: vec![
@@ -826,7 +4386,7 @@ mod tests {
         ];
         let new_lines = vec!["Self { a, b, c }".into()];
 
-        let result = process_file(file, old_lines, new_lines, None);
+        let result = process_file(file, old_lines, new_lines, None, &ProcessOptions::default());
 
         assert_eq!(result.rows.len(), 5);
         assert_eq!(result.rows[0].left.content, "Self {");
@@ -839,6 +4399,7 @@ mod tests {
     fn hunk_starts_detected_correctly() {
         let file = DifftFile {
             path: "hunks.rs".into(),
+            old_path: None,
             language: "Rust".into(),
             status: Status::Changed,
             aligned_lines: vec![
@@ -883,7 +4444,7 @@ mod tests {
             "fff".into(),
         ];
 
-        let result = process_file(file, old_lines, new_lines, None);
+        let result = process_file(file, old_lines, new_lines, None, &ProcessOptions::default());
 
         // Should have two hunks: one starting at row 1, one at row 5
         assert_eq!(result.hunk_starts.len(), 2);
@@ -891,16 +4452,498 @@ mod tests {
         assert_eq!(result.hunk_starts[1], 5);
     }
 
+    #[test]
+    fn hunk_summary_labels_function_and_kind() {
+        let file = DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0)), (Some(1), Some(1)), (Some(2), Some(2))],
+            chunks: vec![vec![
+                DiffLine {
+                    lhs: Some(diff_side(1, vec![kind_change(3, 6, "normal")])),
+                    rhs: Some(diff_side(1, vec![kind_change(3, 9, "normal")])),
+                },
+                DiffLine {
+                    lhs: Some(diff_side(2, vec![kind_change(4, 9, "string")])),
+                    rhs: Some(diff_side(2, vec![kind_change(4, 20, "string")])),
+                },
+            ]],
+        };
+        let result = process_file(
+            file,
+            vec!["// x".into(), "fn old() {".into(), "    \"old\"".into()],
+            vec![
+                "// x".into(),
+                "fn renamed() {".into(),
+                "    \"a longer string\"".into(),
+            ],
+            None,
+            &ProcessOptions::default(),
+        );
+
+        assert_eq!(result.hunks.len(), 1);
+        assert_eq!(result.hunks[0].start, 1);
+        assert_eq!(result.hunks[0].kind.as_deref(), Some("string"));
+        assert_eq!(result.hunks[0].label.as_deref(), Some("fn renamed"));
+    }
+
+    #[test]
+    fn hunk_summary_falls_back_to_kind_label() {
+        let file = DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0))],
+            chunks: vec![vec![DiffLine {
+                lhs: Some(diff_side(0, vec![kind_change(6, 9, "comment")])),
+                rhs: Some(diff_side(0, vec![kind_change(6, 9, "comment")])),
+            }]],
+        };
+        let result = process_file(
+            file,
+            vec!["x; // old".into()],
+            vec!["x; // new".into()],
+            None,
+            &ProcessOptions::default(),
+        );
+
+        assert_eq!(result.hunks[0].kind.as_deref(), Some("comment"));
+        assert_eq!(result.hunks[0].label.as_deref(), Some("comment"));
+    }
+
+    #[test]
+    fn changed_functions_deduplicated_in_order() {
+        let old_lines: Vec<String> = vec![
+            "fn a() {".into(),
+            "    1".into(),
+            "    2".into(),
+            "}".into(),
+            "fn b() {".into(),
+            "    3".into(),
+            "}".into(),
+        ];
+        let mut new_lines = old_lines.clone();
+        new_lines[1] = "    10".into();
+        new_lines[2] = "    20".into();
+        new_lines[5] = "    30".into();
+
+        let changed = |ln: u32| DiffLine {
+            lhs: Some(diff_side(ln, vec![change(4, 5)])),
+            rhs: Some(diff_side(ln, vec![change(4, 6)])),
+        };
+        let file = DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: (0..7).map(|i| (Some(i), Some(i))).collect(),
+            chunks: vec![vec![changed(1), changed(2)], vec![changed(5)]],
+        };
+        let result = process_file(file, old_lines, new_lines, None, &ProcessOptions::default());
+
+        assert_eq!(result.changed_functions, vec!["fn a", "fn b"]);
+        let headers: Vec<_> = result.hunks.iter().map(|h| h.header.as_deref()).collect();
+        assert_eq!(headers, [Some("fn a() {"), Some("fn b() {")]);
+    }
+
+    #[test]
+    fn created_file_single_import_hunk() {
+        let file = DifftFile {
+            path: "new.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Created,
+            aligned_lines: vec![],
+            chunks: vec![],
+        };
+        let result = process_file(
+            file,
+            vec![],
+            vec!["use std::fs;".into()],
+            None,
+            &ProcessOptions::default(),
+        );
+
+        assert_eq!(result.hunks.len(), 1);
+        assert_eq!(result.hunks[0].kind, None);
+        assert_eq!(result.hunks[0].label.as_deref(), Some("imports"));
+    }
+
+    #[test]
+    fn syntax_spans_follow_aligned_lines() {
+        let file = DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0)), (None, Some(1))],
+            chunks: vec![vec![DiffLine {
+                lhs: None,
+                rhs: Some(diff_side(1, vec![change(0, 6)])),
+            }]],
+        };
+        let options = ProcessOptions {
+            syntax_spans: true,
+            ..ProcessOptions::default()
+        };
+        let result = process_file(
+            file,
+            vec!["/* a".into()],
+            vec!["/* a".into(), "b */ 1".into()],
+            None,
+            &options,
+        );
+
+        assert!(result.rows[1].left.syntax.is_empty());
+        let right = &result.rows[1].right.syntax;
+        assert_eq!(right[0].kind, "comment");
+        assert_eq!(right[1].kind, "number");
+    }
+
+    #[test]
+    fn syntax_spans_disabled_by_default() {
+        let file = DifftFile {
+            path: "new.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Created,
+            aligned_lines: vec![],
+            chunks: vec![],
+        };
+        let result = process_file(
+            file,
+            vec![],
+            vec!["let x = 1;".into()],
+            None,
+            &ProcessOptions::default(),
+        );
+        assert!(result.rows[0].right.syntax.is_empty());
+    }
+
+    #[test]
+    fn process_json_snapshot() {
+        let json = r#"{
+            "path": "greet.rs",
+            "language": "Rust",
+            "status": "changed",
+            "aligned_lines": [[0, 0], [1, 1]],
+            "chunks": [[{
+                "lhs": {"line_number": 1, "changes": [{"start": 4, "end": 11, "content": "\"hello\"", "highlight": "string"}]},
+                "rhs": {"line_number": 1, "changes": [{"start": 4, "end": 11, "content": "\"howdy\"", "highlight": "string"}]}
+            }]]
+        }"#;
+        let result = process_json(
+            json,
+            Some(b"fn greet() {\n    \"hello\"\n"),
+            Some(b"fn greet() {\n    \"howdy\"\n"),
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(result.rows.len(), 2);
+        assert_eq!(result.rows[1].right.content, "    \"howdy\"");
+        assert_eq!(
+            result.rows[1].right.highlights.as_slice(),
+            &[HighlightRegion {
+                syntax_kind: Some("string".to_string()),
+                ..HighlightRegion::full_line()
+            }]
+        );
+        assert_eq!(result.hunks[0].label.as_deref(), Some("string literal"));
+        assert_eq!(result.changed_functions, vec!["fn greet"]);
+    }
+
+    #[test]
+    fn process_json_transcodes_contents() {
+        let json = r#"{"path":"menu.txt","language":"Text","status":"changed",
+            "aligned_lines":[[0,0]],
+            "chunks":[[{"lhs":{"line_number":0,"changes":[{"start":0,"end":5,"content":"café","highlight":"normal"}]},
+                        "rhs":{"line_number":0,"changes":[{"start":0,"end":3,"content":"tea","highlight":"normal"}]}}]]}"#;
+        let options = ProcessOptions {
+            encodings: vec![("*.txt".to_string(), encoding_rs::WINDOWS_1252)],
+            ..Default::default()
+        };
+        let result = process_json(json, Some(b"caf\xe9\n"), Some(b"tea\n"), &options).unwrap();
+        assert_eq!(result.rows[0].left.content, "caf\u{e9}");
+        assert_eq!(result.rows[0].right.content, "tea");
+    }
+
+    #[test]
+    fn process_json_requires_single_file() {
+        let err = process_json("[]", None, None, &ProcessOptions::default()).unwrap_err();
+        assert_eq!(err, "Expected JSON for one file, got 0");
+    }
+
+    #[test]
+    fn binary_files_get_sizes_instead_of_rows() {
+        let file = DifftFile {
+            path: "logo.png".into(),
+            old_path: None,
+            language: "Binary".into(),
+            status: Status::Changed,
+            aligned_lines: Vec::new(),
+            chunks: Vec::new(),
+        };
+        let result = process_contents(
+            file,
+            Some("\u{89}PNG\0\0\n\0".as_bytes()),
+            Some("\u{89}PNG\0".as_bytes()),
+            None,
+            &ProcessOptions::default(),
+        );
+
+        assert!(result.binary);
+        assert!(!result.stats_only);
+        assert!(result.rows.is_empty() && result.hunks.is_empty());
+        assert_eq!((result.old_size, result.new_size), (Some(9), Some(6)));
+        assert_eq!((result.additions, result.deletions), (0, 0));
+    }
+
+    #[test]
+    fn transcoded_contents_keep_changes_on_their_tokens() {
+        // difftastic sees "caf\u{FFFD} = 1", with `1` at 9..10
+        let file = DifftFile {
+            path: "menu.txt".into(),
+            old_path: None,
+            language: "Text".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0))],
+            chunks: vec![vec![DiffLine {
+                lhs: Some(diff_side(0, vec![change(9, 10)])),
+                rhs: Some(diff_side(0, vec![change(9, 10)])),
+            }]],
+        };
+        let options = ProcessOptions {
+            encodings: vec![("*.txt".into(), encoding_rs::WINDOWS_1252)],
+            ..ProcessOptions::default()
+        };
+        let result = process_contents(
+            file,
+            Some(b"caf\xe9 = 1\n"),
+            Some(b"caf\xe9 = 2\n"),
+            None,
+            &options,
+        );
+
+        let right = &result.rows[0].right;
+        assert_eq!(right.content, "café = 2");
+        assert_eq!((right.highlights[0].start, right.highlights[0].end), (8, 9));
+        assert_eq!(result.old_encoding.as_deref(), Some("windows-1252"));
+        assert_eq!(result.new_encoding.as_deref(), Some("windows-1252"));
+    }
+
+    #[test]
+    fn mode_only_file_gets_mode_change_row() {
+        let file = DifftFile {
+            path: "run.sh".into(),
+            old_path: None,
+            language: String::new(),
+            status: Status::Unchanged,
+            aligned_lines: Vec::new(),
+            chunks: Vec::new(),
+        };
+        let change = ModeChange {
+            old: "100644".into(),
+            new: "100755".into(),
+        };
+        let result = mode_only(file, change.clone());
+
+        assert!(!result.stats_only);
+        assert_eq!(result.mode_change, Some(change));
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0].change, RowChange::Notice);
+        assert_eq!(result.rows[0].left.content, "old mode 100644");
+        assert_eq!(result.rows[0].right.content, "new mode 100755");
+        assert_eq!((result.additions, result.deletions), (0, 0));
+    }
+
+    #[test]
+    fn submodule_shows_commits_and_log() {
+        let file = DifftFile {
+            path: "vendor/lib".into(),
+            old_path: None,
+            language: "Text".into(),
+            status: Status::Changed,
+            aligned_lines: Vec::new(),
+            chunks: Vec::new(),
+        };
+        let change = SubmoduleChange {
+            old: Some("aaa".into()),
+            new: Some("bbb".into()),
+            log: vec!["> Add feature".into(), "< Revert fix".into()],
+        };
+        let result = submodule(file.clone(), change.clone());
+
+        assert_eq!(result.status, Status::Changed);
+        assert_eq!(result.language, "Submodule");
+        assert_eq!(result.rows.len(), 3);
+        assert_eq!(result.aligned_lines.len(), 3);
+        assert!(
+            result
+                .rows
+                .iter()
+                .all(|row| row.change == RowChange::Notice)
+        );
+        assert_eq!(result.rows[0].left.content, "Subproject commit aaa");
+        assert_eq!(result.rows[0].right.content, "Subproject commit bbb");
+        assert_eq!(result.rows[1].right.content, "> Add feature");
+        assert_eq!(result.rows[2].left.content, "< Revert fix");
+        assert_eq!(result.submodule, Some(change));
+
+        let added = SubmoduleChange {
+            old: None,
+            new: Some("bbb".into()),
+            log: Vec::new(),
+        };
+        let result = submodule(file, added);
+        assert_eq!(result.status, Status::Created);
+        assert_eq!(result.rows[0].left.content, "");
+    }
+
+    #[test]
+    fn stats_only_counts_chunk_lines_without_rows() {
+        let file = DifftFile {
+            path: "big.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![(Some(0), Some(0)), (None, Some(1))],
+            chunks: vec![vec![
+                DiffLine {
+                    lhs: Some(diff_side(0, vec![change(0, 1)])),
+                    rhs: Some(diff_side(0, vec![change(0, 1)])),
+                },
+                DiffLine {
+                    lhs: None,
+                    rhs: Some(diff_side(1, vec![change(0, 1)])),
+                },
+            ]],
+        };
+        let result = stats_only(file, None);
+
+        assert!(result.stats_only);
+        assert!(result.rows.is_empty());
+        assert_eq!((result.additions, result.deletions), (2, 1));
+    }
+
+    #[test]
+    fn layers_stack_changes_over_syntax() {
+        let priorities = [
+            Layer::Syntax,
+            HighlightRegion::full_line().layer(),
+            HighlightRegion::columns(2, 5).layer(),
+            Layer::Invisible,
+        ]
+        .map(Layer::priority);
+        assert!(priorities.is_sorted_by(|a, b| a < b), "{priorities:?}");
+    }
+
+    #[test]
+    fn invisible_characters_in_changed_regions() {
+        let content = "a\u{200B}b \u{FEFF}c";
+        let highlights = [HighlightRegion::columns(0, 5)];
+        let found = find_invisibles(content, &highlights);
+
+        // Only the zero-width space falls inside the highlight; the BOM is outside it
+        assert_eq!(
+            found,
+            vec![InvisibleChar {
+                column: 1,
+                codepoint: 0x200B
+            }]
+        );
+        assert_eq!(found[0].label(), "<U+200B>");
+        assert_eq!(
+            find_invisibles(content, &[HighlightRegion::full_line()]).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn invisible_characters_ignored_on_unchanged_lines() {
+        assert!(find_invisibles("a\u{200B}b", &[]).is_empty());
+    }
+
+    #[test]
+    fn row_change_classification() {
+        let file = DifftFile {
+            path: "lib.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: Status::Changed,
+            aligned_lines: vec![
+                (Some(0), Some(0)),
+                (Some(1), Some(1)),
+                (Some(2), Some(2)),
+                (Some(3), Some(3)),
+                (None, Some(4)),
+            ],
+            chunks: vec![vec![
+                DiffLine {
+                    lhs: Some(diff_side(1, vec![change(4, 5)])),
+                    rhs: Some(diff_side(1, vec![change(4, 5)])),
+                },
+                DiffLine {
+                    lhs: Some(diff_side(2, vec![change(0, 3)])),
+                    rhs: Some(diff_side(2, vec![change(0, 3)])),
+                },
+                DiffLine {
+                    lhs: Some(diff_side(3, vec![change(0, 3)])),
+                    rhs: Some(diff_side(3, vec![change(0, 3)])),
+                },
+                DiffLine {
+                    lhs: None,
+                    rhs: Some(diff_side(4, vec![change(0, 3)])),
+                },
+            ]],
+        };
+        let result = process_file(
+            file,
+            vec!["a".into(), "let x;".into(), "foo".into(), "abc".into()],
+            vec![
+                "a".into(),
+                "let y;".into(),
+                "foo".into(),
+                "xyz".into(),
+                "new".into(),
+            ],
+            None,
+            &ProcessOptions::default(),
+        );
+
+        let changes: Vec<RowChange> = result.rows.iter().map(|r| r.change).collect();
+        assert_eq!(
+            changes,
+            vec![
+                RowChange::Unchanged,
+                RowChange::Intraline,
+                RowChange::Structural,
+                RowChange::Line,
+                RowChange::Line,
+            ]
+        );
+    }
+
     #[test]
     fn aligned_lines_created_file() {
         let file = DifftFile {
             path: "new.rs".into(),
+            old_path: None,
             language: "Rust".into(),
             status: Status::Created,
             aligned_lines: vec![],
             chunks: vec![],
         };
-        let result = process_file(file, vec![], vec!["a".into(), "b".into(), "c".into()], None);
+        let result = process_file(
+            file,
+            vec![],
+            vec!["a".into(), "b".into(), "c".into()],
+            None,
+            &ProcessOptions::default(),
+        );
 
         // Created files: left is always None, right maps 0..n
         assert_eq!(result.aligned_lines.len(), 3);
@@ -913,12 +4956,19 @@ mod tests {
     fn aligned_lines_deleted_file() {
         let file = DifftFile {
             path: "old.rs".into(),
+            old_path: None,
             language: "Rust".into(),
             status: Status::Deleted,
             aligned_lines: vec![],
             chunks: vec![],
         };
-        let result = process_file(file, vec!["x".into(), "y".into()], vec![], None);
+        let result = process_file(
+            file,
+            vec!["x".into(), "y".into()],
+            vec![],
+            None,
+            &ProcessOptions::default(),
+        );
 
         // Deleted files: left maps 0..n, right is always None
         assert_eq!(result.aligned_lines.len(), 2);
@@ -936,6 +4986,7 @@ mod tests {
         ];
         let file = DifftFile {
             path: "mod.rs".into(),
+            old_path: None,
             language: "Rust".into(),
             status: Status::Changed,
             aligned_lines: aligned.clone(),
@@ -946,6 +4997,7 @@ mod tests {
             vec!["a".into(), "b".into(), "c".into()],
             vec!["a".into(), "b".into(), "new".into(), "c".into()],
             None,
+            &ProcessOptions::default(),
         );
 
         // Changed files: aligned_lines should be passed through from difftastic
@@ -961,6 +5013,7 @@ mod tests {
         ];
         let file = DifftFile {
             path: "del.rs".into(),
+            old_path: None,
             language: "Rust".into(),
             status: Status::Changed,
             aligned_lines: aligned.clone(),
@@ -971,6 +5024,7 @@ mod tests {
             vec!["a".into(), "deleted".into(), "b".into()],
             vec!["a".into(), "b".into()],
             None,
+            &ProcessOptions::default(),
         );
 
         assert_eq!(result.aligned_lines, aligned);
@@ -0,0 +1,189 @@
+//! Progress of in-flight diffs, for status messages in the UI.
+//!
+//! A [`Tracker`] is selected for the current thread with [`with_tracker`], like
+//! cancellation tokens in [`crate::cancel`], and the diff pipeline reports its phases to
+//! it. Without a tracker, reporting does nothing.
+//!
+//! A [streaming](Tracker::streaming) tracker also collects the files of the diff as they
+//! become ready, so the UI can show them before the whole diff is done.
+
+use crate::processor::DisplayFile;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, PoisonError};
+
+thread_local! {
+    static TRACKER: RefCell<Option<Tracker>> = const { RefCell::new(None) };
+}
+
+/// A step of computing a diff.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Phase {
+    #[default]
+    Starting,
+    /// Running the VCS and difftastic.
+    Difftastic,
+    /// Fetching file contents and processing them into rows, counted in files.
+    Files,
+}
+
+impl Phase {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Starting => "starting",
+            Self::Difftastic => "running difftastic",
+            Self::Files => "processing files",
+        }
+    }
+}
+
+/// How far a diff got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Status {
+    pub phase: Phase,
+    /// Work items finished in the current phase.
+    pub done: usize,
+    /// Work items in the current phase, 0 if it isn't counted.
+    pub total: usize,
+}
+
+/// A file of a streamed diff: first a stats-only entry as soon as the file is known, then
+/// the processed file.
+#[derive(Debug)]
+pub struct FileUpdate {
+    /// Position of the file in the diff (0-indexed).
+    pub index: usize,
+    pub file: DisplayFile,
+}
+
+/// Shared progress of one diff, updated by the diff and read by the UI.
+#[derive(Debug, Clone, Default)]
+pub struct Tracker(Arc<Shared>);
+
+#[derive(Debug, Default)]
+struct Shared {
+    status: Mutex<Status>,
+    /// Files not taken by the UI yet, for streaming trackers.
+    files: Option<Mutex<Vec<FileUpdate>>>,
+}
+
+impl Tracker {
+    /// A tracker that also collects the diff's files as they become ready.
+    pub fn streaming() -> Self {
+        Self(Arc::new(Shared {
+            status: Mutex::default(),
+            files: Some(Mutex::default()),
+        }))
+    }
+
+    pub fn status(&self) -> Status {
+        *self.0.status.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts `phase` with `total` work items.
+    pub fn start(&self, phase: Phase, total: usize) {
+        *self.0.status.lock().unwrap_or_else(PoisonError::into_inner) = Status {
+            phase,
+            done: 0,
+            total,
+        };
+    }
+
+    /// Marks one more work item of the current phase as finished.
+    pub fn advance(&self) {
+        self.0
+            .status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .done += 1;
+    }
+
+    /// Whether files are handed over through [`Tracker::deliver`].
+    pub fn streams(&self) -> bool {
+        self.0.files.is_some()
+    }
+
+    /// Queues the file at `index` for the UI. Does nothing unless the tracker streams.
+    pub fn deliver(&self, index: usize, file: DisplayFile) {
+        if let Some(files) = &self.0.files {
+            files
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(FileUpdate { index, file });
+        }
+    }
+
+    /// Takes the files delivered since the last call, in delivery order.
+    pub fn take_files(&self) -> Vec<FileUpdate> {
+        self.0
+            .files
+            .as_ref()
+            .map(|files| std::mem::take(&mut *files.lock().unwrap_or_else(PoisonError::into_inner)))
+            .unwrap_or_default()
+    }
+}
+
+/// The tracker selected for the current thread, if any.
+pub fn current() -> Option<Tracker> {
+    TRACKER.with(|tracker| tracker.borrow().clone())
+}
+
+/// Starts `phase` on the tracker selected for the current thread, if any.
+pub fn start(phase: Phase, total: usize) {
+    if let Some(tracker) = current() {
+        tracker.start(phase, total);
+    }
+}
+
+/// Runs `f` with `tracker` selected for the current thread.
+pub fn with_tracker<T>(tracker: Option<Tracker>, f: impl FnOnce() -> T) -> T {
+    let previous = TRACKER.with(|current| current.replace(tracker));
+    let result = f();
+    TRACKER.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_are_reported_to_the_selected_tracker() {
+        start(Phase::Difftastic, 0);
+
+        let tracker = Tracker::default();
+        with_tracker(Some(tracker.clone()), || {
+            start(Phase::Files, 3);
+            current().unwrap().advance();
+        });
+        assert_eq!(
+            tracker.status(),
+            Status {
+                phase: Phase::Files,
+                done: 1,
+                total: 3,
+            }
+        );
+        assert!(current().is_none());
+        assert!(!tracker.streams());
+    }
+
+    #[test]
+    fn streaming_tracker_hands_over_files_once() {
+        let file = crate::difftastic::DifftFile {
+            path: "a.rs".into(),
+            old_path: None,
+            language: "Rust".into(),
+            status: crate::difftastic::Status::Created,
+            aligned_lines: Vec::new(),
+            chunks: Vec::new(),
+        };
+        let tracker = Tracker::streaming();
+        tracker.deliver(3, crate::processor::stats_only(file, Some((1, 0))));
+
+        let files = tracker.take_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].index, 3);
+        assert!(tracker.take_files().is_empty());
+    }
+}
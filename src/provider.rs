@@ -0,0 +1,72 @@
+//! File contents fetched through a Lua callback instead of local commands.
+//!
+//! For repositories only reachable from Lua (e.g. through a remote editing plugin talking
+//! to another Neovim over RPC), `set_content_provider` registers a function that gets
+//! called as `provider(path, rev, side, source)` for every file and side of a diff, and
+//! returns the file's text, or `nil` if it doesn't exist on that side:
+//!
+//! - `path` - path relative to the repository root
+//! - `rev` - the revision as given to the VCS (e.g. `"HEAD^1"`, `"@-"`), `nil` for the git
+//!   index, the working tree and user-defined VCS commands
+//! - `side` - `"old"` or `"new"`
+//! - `source` - `"git_commit"`, `"git_index"`, `"git_stash"`, `"jj"`, `"hg"`, `"sl"`,
+//!   `"working_tree"` or `"command"`
+//!
+//! Lua functions can only be called from the thread running Lua, so the provider only
+//! serves diffs computed there (`run_diff` and friends); background diffs keep reading
+//! contents locally.
+
+use crate::{Content, ContentSource};
+use mlua::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+thread_local! {
+    static PROVIDER: RefCell<Option<LuaFunction>> = const { RefCell::new(None) };
+}
+
+/// Sets the provider for diffs computed on this thread, or removes it with `None`.
+pub fn set(provider: Option<LuaFunction>) {
+    PROVIDER.with(|current| *current.borrow_mut() = provider);
+}
+
+/// Whether a provider serves diffs computed on this thread.
+pub fn is_set() -> bool {
+    PROVIDER.with(|current| current.borrow().is_some())
+}
+
+/// The `source` and `rev` arguments describing `source` to the provider.
+fn describe(source: &ContentSource) -> (&'static str, Option<&str>) {
+    match source {
+        ContentSource::GitCommit(rev) => ("git_commit", Some(rev)),
+        ContentSource::GitIndex => ("git_index", None),
+        ContentSource::GitStash(rev) => ("git_stash", Some(rev)),
+        ContentSource::Jj(rev, _) => ("jj", Some(rev)),
+        ContentSource::Hg(program, rev) => (*program, Some(rev)),
+        ContentSource::WorkingTree(_) => ("working_tree", None),
+        ContentSource::Command(_) => ("command", None),
+    }
+}
+
+/// Fetches `paths` from `source` through the provider, or `None` without one.
+pub fn fetch(
+    source: &ContentSource,
+    side: &str,
+    paths: &[&Path],
+) -> Option<Result<HashMap<PathBuf, Content>, String>> {
+    let provider = PROVIDER.with(|current| current.borrow().clone())?;
+    let (name, rev) = describe(source);
+    let contents = paths
+        .iter()
+        .map(|path| {
+            let path_str = path.to_string_lossy();
+            let content = provider
+                .call::<Option<LuaString>>((path_str.as_ref(), rev, side, name))
+                .map_err(|e| format!("Content provider failed for {path_str}: {e}"))?
+                .map(|content| content.as_bytes().to_vec());
+            Ok((path.to_path_buf(), content))
+        })
+        .collect();
+    Some(contents)
+}
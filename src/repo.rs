@@ -0,0 +1,114 @@
+//! The repository VCS commands run in.
+//!
+//! Commands run in Neovim's working directory unless a repository is selected for the
+//! current thread with [`in_repo`], which lets several repositories be diffed in parallel.
+//! Work spread over other threads must carry the selection along, by capturing
+//! [`current`] and calling [`in_repo`] again on each thread.
+//!
+//! Diffs of the same repository are serialized with [`serialized`]: VCS commands can update
+//! repository state as a side effect (jj snapshots the working copy on every command), so
+//! two diffs triggered at once from the UI could otherwise race and see different states.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+
+thread_local! {
+    static REPO: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// The repository selected for the current thread, if any.
+pub fn current() -> Option<PathBuf> {
+    REPO.with(|repo| repo.borrow().clone())
+}
+
+/// The directory commands run in: the selected repository or the working directory.
+pub fn dir() -> Option<PathBuf> {
+    current().or_else(|| std::env::current_dir().ok())
+}
+
+/// Runs `f` with `dir` selected as the repository for the current thread.
+/// `None` keeps running commands in the working directory.
+pub fn in_repo<T>(dir: Option<&Path>, f: impl FnOnce() -> T) -> T {
+    let previous = REPO.with(|repo| repo.replace(dir.map(Path::to_path_buf)));
+    let result = f();
+    REPO.with(|repo| *repo.borrow_mut() = previous);
+    result
+}
+
+/// Creates a command for `program` that runs in the selected repository.
+pub fn command(program: impl AsRef<OsStr>) -> Command {
+    let mut command = Command::new(program);
+    if let Some(dir) = current() {
+        command.current_dir(dir);
+    }
+    command
+}
+
+/// Runs `f` while holding the lock of the selected repository, so only one diff of a
+/// repository runs at a time.
+///
+/// The lock isn't reentrant: don't nest calls, and don't call this from rayon jobs, which
+/// may run on a thread already holding the lock while it waits for other jobs.
+pub fn serialized<T>(f: impl FnOnce() -> T) -> T {
+    static LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+        LazyLock::new(Default::default);
+
+    let dir = dir()
+        .map(|dir| dir.canonicalize().unwrap_or(dir))
+        .unwrap_or_default();
+    let lock = Arc::clone(
+        LOCKS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(dir)
+            .or_default(),
+    );
+    let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_run_in_selected_repo() {
+        assert_eq!(command("git").get_current_dir(), None);
+        in_repo(Some(Path::new("/repos/a")), || {
+            assert_eq!(
+                command("git").get_current_dir(),
+                Some(Path::new("/repos/a"))
+            );
+            in_repo(Some(Path::new("/repos/b")), || {
+                assert_eq!(dir(), Some(PathBuf::from("/repos/b")));
+            });
+            assert_eq!(current(), Some(PathBuf::from("/repos/a")));
+        });
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn diffs_of_the_same_repo_never_overlap() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        static RUNNING: AtomicBool = AtomicBool::new(false);
+
+        let repo = std::env::temp_dir();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    in_repo(Some(&repo), || {
+                        serialized(|| {
+                            assert!(!RUNNING.swap(true, Ordering::SeqCst));
+                            std::thread::sleep(std::time::Duration::from_millis(5));
+                            RUNNING.store(false, Ordering::SeqCst);
+                        })
+                    })
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,271 @@
+//! Detection of in-progress repository operations.
+//!
+//! While a rebase, merge, cherry-pick or revert is stopped on conflicts, git records the
+//! commit being applied in a pseudo-ref (`REBASE_HEAD`, `MERGE_HEAD`, ...) inside the git
+//! directory. This module inspects those markers so the viewer can show what is going on
+//! and offer a diff of the current resolution against the commit being applied.
+//!
+//! jj never stops mid-operation; conflicts are recorded in commits instead. For jj the
+//! state reports the working-copy commit and its conflicted files.
+//!
+//! Mercurial keeps similar state files in `.hg` (`rebasestate`, `graftstate`, ...), and an
+//! uncommitted merge shows up as a second working-directory parent. Sapling keeps the same
+//! files in `.sl`.
+
+use crate::cancel::CommandExt;
+use mlua::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// An operation that is stopped and waiting for the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Rebase,
+    Merge,
+    CherryPick,
+    Revert,
+}
+
+impl Operation {
+    /// The pseudo-ref git uses for the commit being applied by this operation.
+    #[must_use]
+    pub fn head_ref(self) -> &'static str {
+        match self {
+            Self::Rebase => "REBASE_HEAD",
+            Self::Merge => "MERGE_HEAD",
+            Self::CherryPick => "CHERRY_PICK_HEAD",
+            Self::Revert => "REVERT_HEAD",
+        }
+    }
+
+    #[must_use]
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Rebase => "rebase",
+            Self::Merge => "merge",
+            Self::CherryPick => "cherry-pick",
+            Self::Revert => "revert",
+        }
+    }
+}
+
+/// Snapshot of the repository's HEAD and any in-progress operation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepoState {
+    /// Commit hash of HEAD (git) or the working-copy commit (jj).
+    pub head: Option<String>,
+
+    /// Checked-out branch name. `None` when HEAD is detached or for jj.
+    pub branch: Option<String>,
+
+    /// Whether git's HEAD is detached (always `false` for jj).
+    pub detached: bool,
+
+    /// The operation currently stopped, if any.
+    pub operation: Option<Operation>,
+
+    /// Resolved commit hash of the operation's pseudo-ref (e.g. `REBASE_HEAD`).
+    pub operation_head: Option<String>,
+
+    /// Files with unresolved conflicts.
+    pub conflicted: Vec<PathBuf>,
+}
+
+/// Runs a command and returns its trimmed stdout if it succeeded.
+pub(crate) fn command_stdout(program: &str, args: &[&str]) -> Option<String> {
+    crate::repo::command(program)
+        .args(args)
+        .output_or_cancel()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+}
+
+/// Determines the stopped operation from the marker files in a git directory.
+///
+/// `rebase-merge`/`rebase-apply` are checked first because a rebase that stops on a
+/// conflicting cherry-pick also leaves `CHERRY_PICK_HEAD` behind.
+#[must_use]
+pub fn detect_git_operation(git_dir: &Path) -> Option<Operation> {
+    if git_dir.join("rebase-merge").is_dir()
+        || git_dir.join("rebase-apply").is_dir()
+        || git_dir.join("REBASE_HEAD").is_file()
+    {
+        Some(Operation::Rebase)
+    } else if git_dir.join("MERGE_HEAD").is_file() {
+        Some(Operation::Merge)
+    } else if git_dir.join("CHERRY_PICK_HEAD").is_file() {
+        Some(Operation::CherryPick)
+    } else if git_dir.join("REVERT_HEAD").is_file() {
+        Some(Operation::Revert)
+    } else {
+        None
+    }
+}
+
+/// Inspects a git repository's HEAD, branch and in-progress operation.
+fn git_state() -> RepoState {
+    let operation = command_stdout("git", &["rev-parse", "--absolute-git-dir"])
+        .and_then(|dir| detect_git_operation(&crate::wrapper::to_local(PathBuf::from(dir))));
+    let operation_head = operation
+        .and_then(|op| command_stdout("git", &["rev-parse", "--verify", "-q", op.head_ref()]));
+    let branch = command_stdout("git", &["symbolic-ref", "-q", "--short", "HEAD"]);
+    let conflicted = command_stdout("git", &["diff", "--name-only", "--diff-filter=U"])
+        .map(|out| out.lines().map(PathBuf::from).collect())
+        .unwrap_or_default();
+
+    RepoState {
+        head: command_stdout("git", &["rev-parse", "--verify", "-q", "HEAD"]),
+        detached: branch.is_none(),
+        branch,
+        operation,
+        operation_head,
+        conflicted,
+    }
+}
+
+/// Determines the stopped operation from the state files in a Mercurial `.hg` directory.
+#[must_use]
+pub fn detect_hg_operation(hg_dir: &Path) -> Option<Operation> {
+    if hg_dir.join("rebasestate").is_file() {
+        Some(Operation::Rebase)
+    } else if hg_dir.join("graftstate").is_file() {
+        Some(Operation::CherryPick)
+    } else if hg_dir.join("merge").join("state2").is_file()
+        || hg_dir.join("merge").join("state").is_file()
+    {
+        Some(Operation::Merge)
+    } else {
+        None
+    }
+}
+
+/// Inspects a Mercurial repository's working-directory parent, branch and operation, with
+/// `program` being `hg`, or `sl` for Sapling.
+fn hg_state(program: &str) -> RepoState {
+    let dot_dir = if program == "sl" { ".sl" } else { ".hg" };
+    let operation = command_stdout(program, &["root"]).and_then(|root| {
+        detect_hg_operation(&crate::wrapper::to_local(PathBuf::from(root)).join(dot_dir))
+    });
+    // The revision being merged in is the second working-directory parent
+    let operation_head = operation
+        .and_then(|_| command_stdout(program, &["log", "-r", "p2()", "-T", "{node}"]))
+        .filter(|node| !node.is_empty());
+    let conflicted = command_stdout(program, &["resolve", "--list"])
+        .map(|out| {
+            out.lines()
+                .filter_map(|line| line.strip_prefix("U "))
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    RepoState {
+        head: command_stdout(program, &["log", "-r", ".", "-T", "{node}"]),
+        branch: command_stdout(program, &["branch"]),
+        operation,
+        operation_head,
+        conflicted,
+        ..RepoState::default()
+    }
+}
+
+/// Inspects a jj repository's working-copy commit and its conflicts.
+fn jj_state() -> RepoState {
+    // `jj resolve --list` exits non-zero when there are no conflicts
+    let conflicted = command_stdout("jj", &["resolve", "--list", "-r", "@"])
+        .map(|out| {
+            out.lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    RepoState {
+        head: command_stdout("jj", &["log", "-r", "@", "--no-graph", "-T", "commit_id"]),
+        conflicted,
+        ..RepoState::default()
+    }
+}
+
+/// Detects the repository state for the given VCS. VCSs registered from Lua have no state
+/// to report.
+#[must_use]
+pub fn detect(vcs: &str) -> RepoState {
+    match vcs {
+        "git" => git_state(),
+        "hg" | "sl" => hg_state(vcs),
+        "jj" => jj_state(),
+        _ => RepoState::default(),
+    }
+}
+
+impl IntoLua for RepoState {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("head", self.head)?;
+        table.set("branch", self.branch)?;
+        table.set("detached", self.detached)?;
+        table.set("operation", self.operation.map(Operation::as_str))?;
+        table.set("operation_head", self.operation_head)?;
+        let conflicted: Vec<String> = self
+            .conflicted
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        table.set("conflicted", lua.create_sequence_from(conflicted)?)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty scratch directory standing in for `.git`.
+    fn scratch_git_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("difft-nvim-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn no_operation_in_clean_git_dir() {
+        let dir = scratch_git_dir("clean");
+        assert_eq!(detect_git_operation(&dir), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rebase_takes_precedence_over_cherry_pick() {
+        let dir = scratch_git_dir("rebase");
+        std::fs::create_dir(dir.join("rebase-merge")).unwrap();
+        std::fs::write(dir.join("CHERRY_PICK_HEAD"), "abc\n").unwrap();
+        assert_eq!(detect_git_operation(&dir), Some(Operation::Rebase));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hg_rebase_state_detected() {
+        let dir = scratch_git_dir("hg-rebase");
+        assert_eq!(detect_hg_operation(&dir), None);
+        std::fs::write(dir.join("rebasestate"), "").unwrap();
+        assert_eq!(detect_hg_operation(&dir), Some(Operation::Rebase));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_head_detected() {
+        let dir = scratch_git_dir("merge");
+        std::fs::write(dir.join("MERGE_HEAD"), "abc\n").unwrap();
+        assert_eq!(detect_git_operation(&dir), Some(Operation::Merge));
+        assert_eq!(Operation::Merge.head_ref(), "MERGE_HEAD");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn registered_vcs_has_no_state() {
+        assert_eq!(detect("fossil"), RepoState::default());
+    }
+}
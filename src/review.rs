@@ -0,0 +1,190 @@
+//! Reviewing a branch commit by commit, like the commits tab of a pull request.
+//!
+//! [`list`] reads the first-parent commits of a range and the line stats of each with a
+//! single `git log`, so a review UI can show every commit's size right away. Each commit's
+//! diff is only computed when loaded through the returned [`BranchReview`], against its
+//! first parent, going through the same caches as `run_diff`, and the next commit is
+//! prefetched meanwhile so stepping through the branch stays instant.
+
+use crate::cancel::CommandExt;
+use crate::{DiffMode, DiffOptions, guard, prefetch, repo};
+use mlua::prelude::*;
+use std::path::PathBuf;
+
+/// One commit of the reviewed branch, with its line stats against its first parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    /// Full commit hash.
+    pub commit: String,
+    /// First line of the commit message.
+    pub subject: String,
+    /// Committer timestamp in Unix seconds.
+    pub timestamp: Option<i64>,
+    /// `timestamp` formatted relative to now (e.g. `"3 days ago"`).
+    pub relative_time: Option<String>,
+    /// Changed files, binary ones included.
+    pub files: u32,
+    pub additions: u32,
+    pub deletions: u32,
+}
+
+/// Parses the output of `git log --numstat --format=%x00%H%x09%ct%x09%s`: a NUL byte and a
+/// header line per commit, followed by its `--numstat` lines. Binary files, counted with
+/// `-`, add to the files but not to the lines.
+fn parse_log(output: &str) -> Vec<Commit> {
+    output
+        .split('\0')
+        .filter_map(|block| {
+            let (header, numstat) = block.split_once('\n').unwrap_or((block, ""));
+            let mut fields = header.splitn(3, '\t');
+            let commit = fields.next().filter(|commit| !commit.is_empty())?;
+            let mut entry = Commit {
+                commit: commit.to_string(),
+                timestamp: fields.next().and_then(|t| t.parse().ok()),
+                subject: fields.next().unwrap_or_default().to_string(),
+                relative_time: None,
+                files: 0,
+                additions: 0,
+                deletions: 0,
+            };
+            for line in numstat.lines() {
+                let mut parts = line.splitn(3, '\t');
+                let (Some(added), Some(deleted), Some(_)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    continue;
+                };
+                entry.files += 1;
+                entry.additions += added.parse::<u32>().unwrap_or(0);
+                entry.deletions += deleted.parse::<u32>().unwrap_or(0);
+            }
+            Some(entry)
+        })
+        .collect()
+}
+
+/// Lists the first-parent commits of `range`, oldest first, with their line stats in
+/// `paths` (all files if empty). Merge commits are counted against their first parent, like
+/// the changes they bring in.
+pub fn list(range: &str, paths: &[PathBuf]) -> Result<Vec<Commit>, String> {
+    let pathspec = match crate::pathspec_args("git", paths) {
+        pathspec if pathspec.is_empty() => vec!["--".to_string()],
+        pathspec => pathspec,
+    };
+    let output = repo::command("git")
+        .args([
+            "log",
+            "--first-parent",
+            "--reverse",
+            "--diff-merges=first-parent",
+            "--numstat",
+            "-M",
+            "--format=%x00%H%x09%ct%x09%s",
+            range,
+        ])
+        .args(pathspec)
+        .output_or_cancel()
+        .map_err(|e| format!("Failed to run git: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("git command failed: {stderr}"));
+    }
+    Ok(parse_log(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// The commits of a branch under review, returned by `branch_review`.
+pub struct BranchReview {
+    pub commits: Vec<Commit>,
+    /// Repository the review was opened in, as Neovim's working directory may change.
+    pub dir: Option<PathBuf>,
+    pub opts: DiffOptions,
+}
+
+impl BranchReview {
+    fn commit(&self, index: usize) -> LuaResult<&Commit> {
+        index
+            .checked_sub(1)
+            .and_then(|i| self.commits.get(i))
+            .ok_or_else(|| {
+                LuaError::RuntimeError(format!(
+                    "No commit {index}: the branch has {} commit(s)",
+                    self.commits.len()
+                ))
+            })
+    }
+}
+
+impl LuaUserData for BranchReview {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // `{ { commit, subject, timestamp, relative_time, files, additions, deletions }, ... }`
+        methods.add_method("commits", |lua, review, ()| {
+            lua.create_sequence_from(review.commits.iter().cloned())
+        });
+        // The usual `run_diff` result for the 1-indexed commit, plus `index`, `commit` and
+        // `subject`
+        methods.add_method("load_commit_diff", |lua, review, index: usize| {
+            guard::call("load_commit_diff", || {
+                let commit = review.commit(index)?;
+                let mode = DiffMode::Range(commit.commit.clone());
+                let diff = repo::in_repo(review.dir.as_deref(), || {
+                    let diff = crate::compute_diff(&mode, "git", &review.opts);
+                    if let Some(next) = review.commits.get(index) {
+                        prefetch::spawn("git".to_string(), vec![next.commit.clone()]);
+                    }
+                    diff
+                })
+                .map_err(LuaError::RuntimeError)?;
+                let table = crate::diff_result_table(lua, diff)?;
+                table.set("index", index)?;
+                table.set("commit", commit.commit.as_str())?;
+                table.set("subject", commit.subject.as_str())?;
+                Ok(table)
+            })
+        });
+    }
+}
+
+impl IntoLua for Commit {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("commit", self.commit)?;
+        table.set("subject", self.subject)?;
+        table.set("timestamp", self.timestamp)?;
+        table.set("relative_time", self.relative_time)?;
+        table.set("files", self.files)?;
+        table.set("additions", self.additions)?;
+        table.set("deletions", self.deletions)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_log_reads_commits_and_their_stats() {
+        let output = concat!(
+            "\0aaa\t1700000000\tAdd parser\n\n",
+            "10\t2\tsrc/parser.rs\n",
+            "-\t-\tlogo.png\n",
+            "3\t0\tsrc/{old => new}/mod.rs\n",
+            "\0bbb\t1700000100\tMerge branch 'fix'\tinto feature\n\n",
+            "1\t1\tREADME.md\n",
+            "\0ccc\t1700000200\tEmpty commit\n",
+        );
+        let commits = parse_log(output);
+        assert_eq!(commits.len(), 3);
+        assert_eq!(commits[0].commit, "aaa");
+        assert_eq!(commits[0].timestamp, Some(1_700_000_000));
+        assert_eq!(commits[0].subject, "Add parser");
+        assert_eq!(
+            (commits[0].files, commits[0].additions, commits[0].deletions),
+            (3, 13, 2)
+        );
+        assert_eq!(commits[1].subject, "Merge branch 'fix'\tinto feature");
+        assert_eq!((commits[1].files, commits[1].additions), (1, 1));
+        assert_eq!((commits[2].files, commits[2].additions), (0, 0));
+        assert!(parse_log("").is_empty());
+    }
+}
@@ -0,0 +1,402 @@
+//! Reviewing a patch series patch by patch, like a mailing list thread.
+//!
+//! [`read`] takes a directory of `git format-patch` output or an mbox file (e.g. a thread
+//! downloaded from a mailing list archive), splits it into messages and lists every patch
+//! with its subject, author and line stats. A cover letter (`[PATCH 0/N]`) is kept aside,
+//! and other messages without a diff, such as replies, are left out.
+//!
+//! A patch's diff is only computed when loaded through the returned [`PatchSeries`], the
+//! way `import_patch` diffs a single patch. Later patches of a series apply on top of the
+//! earlier ones rather than to the repository, so the earlier patches are applied first to
+//! get the versions they change.
+
+use crate::unified_diff::{self, FilePatch};
+use crate::{DiffOptions, guard, repo};
+use mlua::prelude::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Extensions of the files read from a series directory.
+const EXTENSIONS: [&str; 4] = ["patch", "diff", "eml", "mbox"];
+
+/// One message of a series and the patch it carries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Patch {
+    /// Subject without its bracketed tags.
+    pub subject: String,
+    /// The subject's bracketed tags, e.g. `"PATCH v2 3/7"`.
+    pub tag: Option<String>,
+    /// `From` header, e.g. `"Jane Doe <jane@example.com>"`.
+    pub author: Option<String>,
+    /// `Date` header as sent.
+    pub date: Option<String>,
+    /// Message body above the `---` line preceding the diff.
+    pub message: String,
+    /// The files the patch changes.
+    pub files: Vec<FilePatch>,
+}
+
+impl Patch {
+    /// Whether the patch is the `0/N` message introducing a series.
+    fn is_cover_letter(&self) -> bool {
+        self.tag.as_deref().is_some_and(|tag| {
+            tag.split_whitespace()
+                .filter_map(|word| word.split_once('/'))
+                .any(|(number, _)| number.parse() == Ok(0))
+        })
+    }
+
+    /// `{ subject, tag, author, date, message, files, additions, deletions }`
+    fn to_lua(&self, lua: &Lua) -> LuaResult<LuaTable> {
+        let table = lua.create_table()?;
+        table.set("subject", self.subject.as_str())?;
+        table.set("tag", self.tag.as_deref())?;
+        table.set("author", self.author.as_deref())?;
+        table.set("date", self.date.as_deref())?;
+        table.set("message", self.message.as_str())?;
+        table.set("files", self.files.len())?;
+        table.set(
+            "additions",
+            self.files.iter().map(|f| f.additions).sum::<u32>(),
+        )?;
+        table.set(
+            "deletions",
+            self.files.iter().map(|f| f.deletions).sum::<u32>(),
+        )?;
+        Ok(table)
+    }
+}
+
+/// Splits the bracketed tags (`[PATCH v2 3/7]`, `[RFC]`) off the front of a subject.
+fn split_subject(subject: &str) -> (Option<String>, String) {
+    let mut rest = subject.trim();
+    let mut tags = Vec::new();
+    while let Some(tagged) = rest.strip_prefix('[')
+        && let Some((tag, after)) = tagged.split_once(']')
+    {
+        tags.push(tag.trim());
+        rest = after.trim_start();
+    }
+    ((!tags.is_empty()).then(|| tags.join(" ")), rest.to_string())
+}
+
+/// Splits mbox `text` into its messages, each starting with a `From ` line after a blank
+/// line. Text that doesn't start with one is a single message, e.g. a mail saved from a
+/// client.
+fn split_mbox(text: &str) -> Vec<&str> {
+    if !text.starts_with("From ") {
+        return vec![text];
+    }
+    let mut starts = vec![0];
+    let (mut offset, mut blank) = (0, false);
+    for line in text.split_inclusive('\n') {
+        if offset > 0 && blank && line.starts_with("From ") {
+            starts.push(offset);
+        }
+        blank = line.trim_end().is_empty();
+        offset += line.len();
+    }
+    starts.push(text.len());
+    starts.windows(2).map(|w| &text[w[0]..w[1]]).collect()
+}
+
+/// Parses a message: its headers, folded over several lines or not, up to the first blank
+/// line, then the body holding the commit message and the diff. Text without headers is
+/// all body, and gets `name` as its subject.
+fn parse_message(text: &str, name: &str) -> Result<Patch, String> {
+    let text = match text.strip_prefix("From ") {
+        Some(rest) => rest.split_once('\n').map_or("", |(_, rest)| rest),
+        None => text,
+    };
+    let is_header = |line: &str| {
+        line.split_once(':')
+            .is_some_and(|(name, _)| !name.is_empty() && !name.contains(char::is_whitespace))
+    };
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut body = text;
+    if text.lines().next().is_some_and(is_header) {
+        let mut offset = 0;
+        for line in text.split_inclusive('\n') {
+            offset += line.len();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            match headers.last_mut() {
+                Some((_, value)) if line.starts_with([' ', '\t']) => {
+                    value.push(' ');
+                    value.push_str(line.trim_start());
+                }
+                _ => {
+                    let (name, value) = line.split_once(':').unwrap_or((line, ""));
+                    headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+                }
+            }
+        }
+        body = &text[offset..];
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.clone())
+    };
+    let (tag, subject) = split_subject(&header("subject").unwrap_or_else(|| name.to_string()));
+    let message = body
+        .lines()
+        .take_while(|line| line.trim_end() != "---" && !line.starts_with("diff --git "))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let files = unified_diff::parse(body).map_err(|e| format!("{subject}: {e}"))?;
+    Ok(Patch {
+        subject,
+        tag,
+        author: header("from"),
+        date: header("date"),
+        message: message.trim().to_string(),
+        files,
+    })
+}
+
+/// Reads a series from a directory of patch files, in file name order, or from a single
+/// mbox or patch file. Returns the patches and the cover letter, if any.
+pub fn read(path: &Path) -> Result<(Vec<Patch>, Option<Patch>), String> {
+    let files = if path.is_dir() {
+        let entries = std::fs::read_dir(path)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|file| {
+                file.extension()
+                    .and_then(|extension| extension.to_str())
+                    .is_some_and(|extension| EXTENSIONS.contains(&extension))
+            })
+            .collect();
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let mut patches = Vec::new();
+    let mut cover_letter = None;
+    for file in &files {
+        let bytes =
+            std::fs::read(file).map_err(|e| format!("Failed to read {}: {e}", file.display()))?;
+        let text = String::from_utf8_lossy(&bytes);
+        let name = file.file_stem().unwrap_or_default().to_string_lossy();
+        for message in split_mbox(&text) {
+            let patch = parse_message(message, &name)?;
+            if !patch.files.is_empty() {
+                patches.push(patch);
+            } else if cover_letter.is_none() && patch.is_cover_letter() {
+                cover_letter = Some(patch);
+            }
+        }
+    }
+    if patches.is_empty() {
+        return Err(format!("No patches found in {}", path.display()));
+    }
+    Ok((patches, cover_letter))
+}
+
+/// The patches of a series under review, returned by `patch_series`.
+pub struct PatchSeries {
+    pub patches: Vec<Patch>,
+    pub cover_letter: Option<Patch>,
+    /// Repository the series was opened in, as Neovim's working directory may change.
+    pub dir: Option<PathBuf>,
+    pub opts: DiffOptions,
+}
+
+impl PatchSeries {
+    fn patch(&self, index: usize) -> LuaResult<&Patch> {
+        index
+            .checked_sub(1)
+            .and_then(|i| self.patches.get(i))
+            .ok_or_else(|| {
+                LuaError::RuntimeError(format!(
+                    "No patch {index}: the series has {} patch(es)",
+                    self.patches.len()
+                ))
+            })
+    }
+
+    /// The versions of the files changed by the first `count` patches, after applying them.
+    /// Files a patch couldn't be applied to are left to the repository.
+    fn versions_after(&self, count: usize) -> HashMap<PathBuf, String> {
+        let root = crate::git_root().or_else(repo::dir);
+        let mut versions = HashMap::new();
+        let files = self.patches[..count].iter().flat_map(|patch| &patch.files);
+        for file in files.filter(|file| !file.binary) {
+            let base = crate::patch_base(file, &versions, root.as_deref(), &self.opts);
+            let (_, new, complete) = file.rebuild(base.as_deref());
+            if let Some(old_path) = &file.old_path {
+                versions.remove(old_path);
+            }
+            if let (Some(path), Some(new), true) = (&file.new_path, new, complete) {
+                versions.insert(path.clone(), new);
+            }
+        }
+        versions
+    }
+}
+
+impl LuaUserData for PatchSeries {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // `{ { subject, tag, author, date, message, files, additions, deletions }, ... }`
+        methods.add_method("patches", |lua, series, ()| {
+            let patches = series
+                .patches
+                .iter()
+                .map(|patch| patch.to_lua(lua))
+                .collect::<LuaResult<Vec<_>>>()?;
+            lua.create_sequence_from(patches)
+        });
+        // The `0/N` message as a patch without stats, or `nil`
+        methods.add_method("cover_letter", |lua, series, ()| {
+            series
+                .cover_letter
+                .as_ref()
+                .map(|patch| patch.to_lua(lua))
+                .transpose()
+        });
+        // The `import_patch` result for the 1-indexed patch, plus `index` and `subject`
+        methods.add_method("load_patch_diff", |lua, series, index: usize| {
+            guard::call("load_patch_diff", || {
+                let patch = series.patch(index)?;
+                let diff = repo::in_repo(series.dir.as_deref(), || {
+                    repo::serialized(|| {
+                        let earlier = series.versions_after(index - 1);
+                        crate::patch_diff(&patch.files, &earlier, &series.opts)
+                    })
+                })
+                .map_err(LuaError::RuntimeError)?;
+                let table = crate::patch_result_table(lua, diff)?;
+                table.set("index", index)?;
+                table.set("subject", patch.subject.as_str())?;
+                Ok(table)
+            })
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERIES: &str = "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: Jane Doe <jane@example.com>
+Date: Tue, 3 Mar 2026 10:00:00 +0100
+Subject: [PATCH v2 0/2] Greet
+ by name
+
+Two patches.
+
+From 1111111111111111111111111111111111111111 Mon Sep 17 00:00:00 2001
+From: Jane Doe <jane@example.com>
+Subject: [PATCH v2 1/2] Add greeting
+
+Adds a file.
+---
+ greet.txt | 1 +
+
+diff --git a/greet.txt b/greet.txt
+new file mode 100644
+--- /dev/null
++++ b/greet.txt
+@@ -0,0 +1 @@
++hi
+--
+2.43.0
+
+From 2222222222222222222222222222222222222222 Mon Sep 17 00:00:00 2001
+From: Jane Doe <jane@example.com>
+Subject: Re: [PATCH v2 1/2] Add greeting
+
+> +hi
+
+Looks good.
+
+From 3333333333333333333333333333333333333333 Mon Sep 17 00:00:00 2001
+From: Jane Doe <jane@example.com>
+Subject: [PATCH v2 2/2] Greet by name
+
+---
+diff --git a/greet.txt b/greet.txt
+--- a/greet.txt
++++ b/greet.txt
+@@ -1 +1 @@
+-hi
++hi Jane
+--
+2.43.0
+";
+
+    #[test]
+    fn reads_an_mbox_series() {
+        let mbox = std::env::temp_dir().join(format!("difft-series-{}.mbox", std::process::id()));
+        std::fs::write(&mbox, SERIES).unwrap();
+        let (patches, cover_letter) = read(&mbox).unwrap();
+        std::fs::remove_file(&mbox).unwrap();
+
+        let cover_letter = cover_letter.unwrap();
+        assert_eq!(cover_letter.subject, "Greet by name");
+        assert_eq!(cover_letter.message, "Two patches.");
+
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].subject, "Add greeting");
+        assert_eq!(patches[0].tag.as_deref(), Some("PATCH v2 1/2"));
+        assert_eq!(
+            patches[0].author.as_deref(),
+            Some("Jane Doe <jane@example.com>")
+        );
+        assert_eq!(patches[0].message, "Adds a file.");
+        assert_eq!(patches[1].subject, "Greet by name");
+        assert_eq!(patches[1].message, "");
+        assert_eq!(
+            (patches[1].files[0].additions, patches[1].files[0].deletions),
+            (1, 1)
+        );
+    }
+
+    #[test]
+    fn later_patches_apply_on_top_of_earlier_ones() {
+        let patches = split_mbox(SERIES)
+            .into_iter()
+            .map(|message| parse_message(message, "series").unwrap())
+            .filter(|patch| !patch.files.is_empty())
+            .collect();
+        let series = PatchSeries {
+            patches,
+            cover_letter: None,
+            dir: None,
+            opts: DiffOptions::default(),
+        };
+        let earlier = series.versions_after(1);
+        assert_eq!(
+            earlier.get(Path::new("greet.txt")).map(String::as_str),
+            Some("hi\n")
+        );
+
+        let file = &series.patches[1].files[0];
+        let base = crate::patch_base(file, &earlier, None, &series.opts);
+        let (old, new, complete) = file.rebuild(base.as_deref());
+        assert_eq!(
+            (old.as_deref(), new.as_deref(), complete),
+            (Some("hi\n"), Some("hi Jane\n"), true)
+        );
+    }
+
+    #[test]
+    fn plain_diffs_are_single_messages() {
+        let patch = parse_message("--- a/x\n+++ b/x\n@@ -1 +1 @@\n-a\n+b\n", "fix").unwrap();
+        assert_eq!(patch.subject, "fix");
+        assert_eq!(patch.tag, None);
+        assert_eq!(patch.files.len(), 1);
+        assert_eq!(
+            split_subject("[RFC] [PATCH 3/7] x"),
+            (Some("RFC PATCH 3/7".into()), "x".into())
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! Per-change stats of a stack of jj changes, and the files several of them touch.
+//!
+//! Reviewing or reshaping a stack (`trunk()..@` by default) starts with knowing how big
+//! each change is and where the changes overlap: two changes editing the same file are
+//! candidates for squashing, or for a split that keeps that file in one of them.
+
+use crate::cancel::CommandExt;
+use crate::{limits, repo};
+use mlua::prelude::*;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+
+/// Revset of the stack when none is given.
+pub const DEFAULT_REVSET: &str = "trunk()..@";
+
+/// One change of the stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub change_id: String,
+    pub commit: String,
+    pub subject: String,
+    pub additions: u32,
+    pub deletions: u32,
+    /// Paths the change touches, sorted.
+    pub paths: Vec<String>,
+}
+
+/// A file touched by several changes of the stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    pub path: String,
+    /// Change IDs touching the file, in stack order.
+    pub changes: Vec<String>,
+}
+
+/// Changes of a stack from the bottom up, and the files they share.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stack {
+    pub changes: Vec<Change>,
+    /// Sorted by path.
+    pub overlaps: Vec<Overlap>,
+}
+
+/// Files touched by more than one of `changes`.
+fn overlaps(changes: &[Change]) -> Vec<Overlap> {
+    let mut touched: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for change in changes {
+        for path in &change.paths {
+            touched
+                .entry(path)
+                .or_default()
+                .push(change.change_id.clone());
+        }
+    }
+    touched
+        .into_iter()
+        .filter(|(_, changes)| changes.len() > 1)
+        .map(|(path, changes)| Overlap {
+            path: path.to_string(),
+            changes,
+        })
+        .collect()
+}
+
+/// Lists the changes of `revset` from the bottom of the stack up, without their stats.
+fn list(revset: &str, ignore_working_copy: bool) -> Result<Vec<Change>, String> {
+    let template =
+        r#"change_id.short() ++ "\t" ++ commit_id ++ "\t" ++ description.first_line() ++ "\n""#;
+    let output = crate::jj_command(ignore_working_copy)
+        .args([
+            "log",
+            "--no-graph",
+            "--reversed",
+            "-r",
+            revset,
+            "-T",
+            template,
+        ])
+        .output_or_cancel()
+        .map_err(|e| format!("Failed to run jj: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("jj command failed: {stderr}"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            Some(Change {
+                change_id: fields.next()?.to_string(),
+                commit: fields.next()?.to_string(),
+                subject: fields.next().unwrap_or_default().to_string(),
+                additions: 0,
+                deletions: 0,
+                paths: Vec::new(),
+            })
+        })
+        .collect())
+}
+
+/// Collects the stats of every change in `revset` and the files they share. Stats are
+/// read like the stats of any jj diff (see [`crate::jj_diff_stats`]), one change per
+/// thread.
+pub fn collect(revset: &str, ignore_working_copy: bool) -> Result<Stack, String> {
+    let mut changes = list(revset, ignore_working_copy)?;
+    let repo = repo::current();
+    limits::install(|| {
+        changes.par_iter_mut().for_each(|change| {
+            let stats = repo::in_repo(repo.as_deref(), || {
+                crate::jj_diff_stats(&change.commit, ignore_working_copy)
+            });
+            change.additions = stats.values().map(|(added, _)| added).sum();
+            change.deletions = stats.values().map(|(_, deleted)| deleted).sum();
+            change.paths = stats
+                .keys()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect();
+            change.paths.sort();
+        });
+    });
+    let overlaps = overlaps(&changes);
+    Ok(Stack { changes, overlaps })
+}
+
+impl IntoLua for Change {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("change_id", self.change_id)?;
+        table.set("commit", self.commit)?;
+        table.set("subject", self.subject)?;
+        table.set("files", self.paths.len())?;
+        table.set("additions", self.additions)?;
+        table.set("deletions", self.deletions)?;
+        table.set("paths", self.paths)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for Overlap {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("path", self.path)?;
+        table.set("changes", self.changes)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl IntoLua for Stack {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("changes", lua.create_sequence_from(self.changes)?)?;
+        table.set("overlaps", lua.create_sequence_from(self.overlaps)?)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(change_id: &str, paths: &[&str]) -> Change {
+        Change {
+            change_id: change_id.to_string(),
+            commit: String::new(),
+            subject: String::new(),
+            additions: 0,
+            deletions: 0,
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn overlaps_list_files_of_several_changes() {
+        let changes = [
+            change("a", &["src/lib.rs", "README.md"]),
+            change("b", &["src/main.rs"]),
+            change("c", &["src/lib.rs", "src/main.rs"]),
+            change("d", &["src/lib.rs"]),
+        ];
+        assert_eq!(
+            overlaps(&changes),
+            [
+                Overlap {
+                    path: "src/lib.rs".to_string(),
+                    changes: vec!["a".to_string(), "c".to_string(), "d".to_string()],
+                },
+                Overlap {
+                    path: "src/main.rs".to_string(),
+                    changes: vec!["b".to_string(), "c".to_string()],
+                },
+            ]
+        );
+        assert!(overlaps(&changes[..2]).is_empty());
+    }
+}
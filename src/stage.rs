@@ -0,0 +1,200 @@
+//! Staging and restoring changes from the viewer.
+//!
+//! These report how they went instead of raising an error: a hunk that no longer applies,
+//! e.g. because the file changed since it was diffed, is expected, and the viewer should
+//! say why and carry on. Whole files also report their stats afterwards, so the file list
+//! can refresh without diffing again.
+
+use crate::cancel::CommandExt;
+use crate::repo;
+use mlua::prelude::*;
+use std::process::Command;
+
+/// How staging a hunk went.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Staged {
+    pub path: String,
+    /// The 1-indexed hunk that was staged.
+    pub hunk: usize,
+    /// The patch fed to `git apply`, once it could be built.
+    pub patch: Option<String>,
+    /// Why staging failed, `None` if it succeeded.
+    pub error: Option<String>,
+}
+
+impl IntoLua for Staged {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let table = lua.create_table()?;
+        table.set("ok", self.error.is_none())?;
+        table.set("path", self.path)?;
+        table.set("hunk", self.hunk)?;
+        table.set("patch", self.patch)?;
+        table.set("error", self.error)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// How staging or restoring a whole file went.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileUpdate {
+    pub path: String,
+    /// Lines the file still adds and removes in the working copy afterwards: against the
+    /// index for git, against the parent of the working-copy commit for jj.
+    pub unstaged: Option<(u32, u32)>,
+    /// Lines the file adds and removes in the index afterwards, for git.
+    pub staged: Option<(u32, u32)>,
+    /// Why the command failed, `None` if it succeeded.
+    pub error: Option<String>,
+}
+
+impl IntoLua for FileUpdate {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        let stats = |stats: Option<(u32, u32)>| {
+            stats
+                .map(|(additions, deletions)| {
+                    let table = lua.create_table()?;
+                    table.set("additions", additions)?;
+                    table.set("deletions", deletions)?;
+                    Ok::<_, LuaError>(table)
+                })
+                .transpose()
+        };
+        let table = lua.create_table()?;
+        table.set("ok", self.error.is_none())?;
+        table.set("path", self.path)?;
+        table.set("unstaged", stats(self.unstaged)?)?;
+        table.set("staged", stats(self.staged)?)?;
+        table.set("error", self.error)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Stages the files `pathspec` selects with `git add`.
+pub fn add(pathspec: &[String]) -> Result<(), String> {
+    run(repo::command("git").arg("add").args(pathspec))
+}
+
+/// Restores the files `pathspec` selects in the working copy to their version in `rev`:
+/// with `git restore --source`, leaving the index alone, or `jj restore --from`. Without
+/// `rev`, git restores the staged version and jj the version in the parent of the
+/// working-copy commit.
+pub fn restore(vcs: &str, pathspec: &[String], rev: Option<&str>) -> Result<(), String> {
+    let mut command = match vcs {
+        "git" => {
+            let mut command = repo::command("git");
+            command.arg("restore");
+            if let Some(rev) = rev {
+                command.arg(format!("--source={rev}"));
+            }
+            command.arg("--worktree");
+            command
+        }
+        "jj" => {
+            let mut command = repo::command("jj");
+            command.arg("restore");
+            if let Some(rev) = rev {
+                command.args(["--from", rev]);
+            }
+            command
+        }
+        _ => return Err(format!("Restoring files needs git or jj, got '{vcs}'")),
+    };
+    run(command.args(pathspec))
+}
+
+/// Applies `patch` to the index of the selected repository with
+/// `git apply --cached --unidiff-zero`, leaving the working tree alone. Patch paths are
+/// relative to the repository root, so the repository root has to be selected.
+///
+/// `--unidiff-zero` lets hunks whose context was cut short by a fold or a notice apply
+/// away from the start and end of the file.
+pub fn apply_to_index(patch: &str) -> Result<(), String> {
+    let output = repo::command("git")
+        .args(["apply", "--cached", "--unidiff-zero", "-"])
+        .output_with_input(patch.as_bytes().to_vec())
+        .map_err(|e| format!("Failed to run git apply: {e}"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// Runs `command`, failing with its stderr.
+fn run(command: &mut Command) -> Result<(), String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output_or_cancel()
+        .map_err(|e| format!("Failed to run {program}: {e}"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn pathspec(path: &str) -> Vec<String> {
+        vec!["--".to_string(), path.to_string()]
+    }
+
+    #[test]
+    fn applies_patches_to_the_index_only() {
+        let dir = std::env::temp_dir().join(format!("difft-stage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        git(&dir, &["init", "-q"]);
+        std::fs::write(dir.join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        git(&dir, &["add", "."]);
+        std::fs::write(dir.join("a.txt"), "one\n2\nthree\nfour\n").unwrap();
+
+        repo::in_repo(Some(&dir), || {
+            let patch = "--- a/a.txt\n+++ b/a.txt\n@@ -2,1 +2,1 @@\n-two\n+2\n";
+            apply_to_index(patch).unwrap();
+            // The hunk no longer applies once staged
+            assert!(apply_to_index(patch).unwrap_err().contains("a.txt"));
+        });
+        assert_eq!(git(&dir, &["show", ":a.txt"]), "one\n2\nthree\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("a.txt")).unwrap(),
+            "one\n2\nthree\nfour\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stages_and_restores_whole_files() {
+        let dir = std::env::temp_dir().join(format!("difft-restore-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        git(&dir, &["init", "-q"]);
+        std::fs::write(dir.join("a.txt"), "one\n").unwrap();
+        git(&dir, &["add", "."]);
+        std::fs::write(dir.join("a.txt"), "two\n").unwrap();
+
+        repo::in_repo(Some(&dir), || {
+            add(&pathspec("a.txt")).unwrap();
+            std::fs::write(dir.join("a.txt"), "three\n").unwrap();
+            restore("git", &pathspec("a.txt"), None).unwrap();
+            assert!(restore("git", &pathspec("a.txt"), Some("no-such-rev")).is_err());
+            assert!(restore("hg", &pathspec("a.txt"), None).is_err());
+        });
+        assert_eq!(git(&dir, &["show", ":a.txt"]), "two\n");
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "two\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,528 @@
+//! Embedded lexical syntax highlighter.
+//!
+//! Difftastic only reports highlight kinds for the tokens that changed. To color the
+//! unchanged parts consistently without re-parsing partial buffer content in Neovim,
+//! this module tokenizes whole files with a small language-family lexer and produces
+//! [`SyntaxSpan`]s per line.
+//!
+//! The lexer understands comments (line and block), string literals (including
+//! multi-line forms), numbers, keywords and capitalized type names. Span kinds use the
+//! same vocabulary as difftastic's `highlight` field (`"comment"`, `"string"`,
+//! `"keyword"`, `"type"`) plus `"number"`.
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Every span kind the highlighter produces.
+const KINDS: [&str; 5] = ["comment", "string", "keyword", "type", "number"];
+
+/// A syntax-highlighted byte range within a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SyntaxSpan {
+    /// Start byte offset (inclusive).
+    pub start: u32,
+
+    /// End byte offset (exclusive).
+    pub end: u32,
+
+    /// Highlight kind, e.g. `"keyword"` or `"string"`.
+    pub kind: &'static str,
+}
+
+/// Reads spans back, e.g. from a cached diff, mapping kinds to the highlighter's own.
+impl<'de> Deserialize<'de> for SyntaxSpan {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Span {
+            start: u32,
+            end: u32,
+            kind: String,
+        }
+
+        let span = Span::deserialize(deserializer)?;
+        let kind = KINDS
+            .into_iter()
+            .find(|known| *known == span.kind)
+            .ok_or_else(|| {
+                serde::de::Error::custom(format!("unknown syntax kind '{}'", span.kind))
+            })?;
+        Ok(Self {
+            start: span.start,
+            end: span.end,
+            kind,
+        })
+    }
+}
+
+/// Lexical rules for a family of languages.
+struct Lexicon {
+    line_comment: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    /// Quote characters that delimit single-line strings.
+    quotes: &'static [char],
+    /// Delimiters of strings that may span multiple lines.
+    multiline_strings: &'static [&'static str],
+    keywords: &'static [&'static str],
+    /// Whether capitalized identifiers should be highlighted as types.
+    capitalized_types: bool,
+}
+
+const C_LIKE_KEYWORDS: &[&str] = &[
+    "if",
+    "else",
+    "for",
+    "while",
+    "do",
+    "return",
+    "break",
+    "continue",
+    "switch",
+    "case",
+    "default",
+    "struct",
+    "enum",
+    "union",
+    "class",
+    "interface",
+    "const",
+    "static",
+    "void",
+    "new",
+    "delete",
+    "try",
+    "catch",
+    "finally",
+    "throw",
+    "throws",
+    "public",
+    "private",
+    "protected",
+    "import",
+    "package",
+    "extends",
+    "implements",
+    "true",
+    "false",
+    "null",
+    "nil",
+    "this",
+    "func",
+    "go",
+    "defer",
+    "type",
+    "var",
+    "let",
+    "function",
+    "async",
+    "await",
+    "export",
+    "from",
+    "typeof",
+    "instanceof",
+    "yield",
+    "namespace",
+    "using",
+    "template",
+];
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type",
+    "unsafe", "use", "where", "while",
+];
+
+const SCRIPT_KEYWORDS: &[&str] = &[
+    "def", "class", "if", "elif", "else", "for", "while", "return", "import", "from", "as", "in",
+    "is", "not", "and", "or", "try", "except", "finally", "raise", "with", "yield", "lambda",
+    "pass", "break", "continue", "True", "False", "None", "then", "fi", "do", "done", "case",
+    "esac", "function", "local", "export", "end", "module", "begin", "rescue", "ensure", "unless",
+    "until", "nil", "true", "false", "self",
+];
+
+const LUA_KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+const LISP_KEYWORDS: &[&str] = &[
+    "def", "defn", "defmacro", "fn", "let", "if", "when", "cond", "do", "loop", "recur", "ns",
+    "require", "quote", "lambda", "define", "nil", "true", "false",
+];
+
+const C_LIKE: Lexicon = Lexicon {
+    line_comment: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '\''],
+    multiline_strings: &["`"],
+    keywords: C_LIKE_KEYWORDS,
+    capitalized_types: true,
+};
+
+const RUST: Lexicon = Lexicon {
+    line_comment: &["//"],
+    block_comment: Some(("/*", "*/")),
+    // `'` starts lifetimes as often as chars, so it is not treated as a quote
+    quotes: &['"'],
+    multiline_strings: &[],
+    keywords: RUST_KEYWORDS,
+    capitalized_types: true,
+};
+
+const SCRIPT: Lexicon = Lexicon {
+    line_comment: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+    multiline_strings: &["\"\"\"", "'''"],
+    keywords: SCRIPT_KEYWORDS,
+    capitalized_types: false,
+};
+
+const LUA: Lexicon = Lexicon {
+    line_comment: &["--"],
+    block_comment: Some(("--[[", "]]")),
+    quotes: &['"', '\''],
+    multiline_strings: &[],
+    keywords: LUA_KEYWORDS,
+    capitalized_types: false,
+};
+
+/// Haskell and Elm: only comments and strings, their keywords and block comments aren't
+/// known.
+const HASKELL: Lexicon = Lexicon {
+    line_comment: &["--"],
+    block_comment: None,
+    // `'` ends names like `foldl'` as often as it quotes chars
+    quotes: &['"'],
+    multiline_strings: &[],
+    keywords: &[],
+    capitalized_types: false,
+};
+
+/// Only comments and strings, as SQL keywords are case-insensitive.
+const SQL: Lexicon = Lexicon {
+    line_comment: &["--"],
+    block_comment: None,
+    quotes: &['\'', '"'],
+    multiline_strings: &[],
+    keywords: &[],
+    capitalized_types: false,
+};
+
+const LISP: Lexicon = Lexicon {
+    line_comment: &[";"],
+    block_comment: None,
+    quotes: &['"'],
+    multiline_strings: &[],
+    keywords: LISP_KEYWORDS,
+    capitalized_types: false,
+};
+
+const MARKUP: Lexicon = Lexicon {
+    line_comment: &[],
+    block_comment: Some(("<!--", "-->")),
+    quotes: &['"', '\''],
+    multiline_strings: &[],
+    keywords: &[],
+    capitalized_types: false,
+};
+
+/// Picks lexical rules from difftastic's language name.
+fn lexicon(language: &str) -> &'static Lexicon {
+    match language {
+        "Rust" => &RUST,
+        "Python" | "Shell" | "Bash" | "Ruby" | "YAML" | "TOML" | "Perl" | "R" | "Elixir"
+        | "Nix" | "Make" | "CMake" | "Julia" | "Fish" => &SCRIPT,
+        "Lua" => &LUA,
+        "Haskell" | "Elm" => &HASKELL,
+        "SQL" => &SQL,
+        "Clojure" | "Common Lisp" | "Emacs Lisp" | "Scheme" | "Racket" | "Janet" => &LISP,
+        "HTML" | "XML" | "Vue" => &MARKUP,
+        _ => &C_LIKE,
+    }
+}
+
+/// A construct that continues onto the next line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Carry {
+    None,
+    BlockComment(&'static str),
+    String(&'static str),
+}
+
+/// Highlights every line of a file, carrying block comments and multi-line strings
+/// across line boundaries.
+#[must_use]
+pub fn highlight_lines<S: AsRef<str>>(lines: &[S], language: &str) -> Vec<Vec<SyntaxSpan>> {
+    let lex = lexicon(language);
+    let mut carry = Carry::None;
+    lines
+        .iter()
+        .map(|line| highlight_line(line.as_ref(), lex, &mut carry))
+        .collect()
+}
+
+/// Finds `needle` at or after byte `from`, returning the byte offset just past it.
+#[inline]
+fn find_end(line: &str, from: usize, needle: &str) -> Option<usize> {
+    line[from..].find(needle).map(|i| from + i + needle.len())
+}
+
+/// Tokenizes a single line, updating `carry` for constructs that continue past it.
+fn highlight_line(line: &str, lex: &Lexicon, carry: &mut Carry) -> Vec<SyntaxSpan> {
+    let mut spans = Vec::new();
+    let mut push = |start: usize, end: usize, kind: &'static str| {
+        if end > start {
+            spans.push(SyntaxSpan {
+                start: start as u32,
+                end: end as u32,
+                kind,
+            });
+        }
+    };
+    let bytes = line.as_bytes();
+    let mut i = 0;
+
+    // Finish whatever the previous line left open
+    match *carry {
+        Carry::BlockComment(close) | Carry::String(close) => {
+            let kind = if matches!(carry, Carry::String(_)) {
+                "string"
+            } else {
+                "comment"
+            };
+            match find_end(line, 0, close) {
+                Some(end) => {
+                    push(0, end, kind);
+                    *carry = Carry::None;
+                    i = end;
+                }
+                None => {
+                    push(0, line.len(), kind);
+                    return spans;
+                }
+            }
+        }
+        Carry::None => {}
+    }
+
+    while i < line.len() {
+        let rest = &line[i..];
+
+        if lex.line_comment.iter().any(|m| rest.starts_with(m))
+            && !lex
+                .block_comment
+                .is_some_and(|(open, _)| rest.starts_with(open))
+        {
+            push(i, line.len(), "comment");
+            break;
+        }
+
+        if let Some((open, close)) = lex.block_comment.filter(|(open, _)| rest.starts_with(open)) {
+            match find_end(line, i + open.len(), close) {
+                Some(end) => {
+                    push(i, end, "comment");
+                    i = end;
+                    continue;
+                }
+                None => {
+                    push(i, line.len(), "comment");
+                    *carry = Carry::BlockComment(close);
+                    break;
+                }
+            }
+        }
+
+        if let Some(delim) = lex.multiline_strings.iter().find(|d| rest.starts_with(**d)) {
+            match find_end(line, i + delim.len(), delim) {
+                Some(end) => {
+                    push(i, end, "string");
+                    i = end;
+                    continue;
+                }
+                None => {
+                    push(i, line.len(), "string");
+                    *carry = Carry::String(delim);
+                    break;
+                }
+            }
+        }
+
+        let c = rest.chars().next().unwrap_or_default();
+
+        if lex.quotes.contains(&c) {
+            // Scan to the matching unescaped quote, or the end of the line
+            let mut end = line.len();
+            let mut escaped = false;
+            for (j, ch) in rest.char_indices().skip(1) {
+                if escaped {
+                    escaped = false;
+                } else if ch == '\\' {
+                    escaped = true;
+                } else if ch == c {
+                    end = i + j + ch.len_utf8();
+                    break;
+                }
+            }
+            push(i, end, "string");
+            i = end;
+            continue;
+        }
+
+        let word_boundary = i == 0 || !is_ident_byte(bytes[i - 1]);
+
+        if c.is_ascii_digit() && word_boundary {
+            let len = rest
+                .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '.' || ch == '_'))
+                .unwrap_or(rest.len());
+            push(i, i + len, "number");
+            i += len;
+            continue;
+        }
+
+        if (c.is_alphabetic() || c == '_') && word_boundary {
+            let len = rest
+                .find(|ch: char| !(ch.is_alphanumeric() || ch == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            if lex.keywords.contains(&word) {
+                push(i, i + len, "keyword");
+            } else if lex.capitalized_types && c.is_uppercase() {
+                push(i, i + len, "type");
+            }
+            i += len;
+            continue;
+        }
+
+        i += c.len_utf8().max(1);
+    }
+
+    spans
+}
+
+/// Returns `true` for bytes that can be part of an identifier.
+#[inline]
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(line: &str, language: &str) -> Vec<(&'static str, String)> {
+        highlight_lines(&[line], language)[0]
+            .iter()
+            .map(|s| (s.kind, line[s.start as usize..s.end as usize].to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn rust_keywords_strings_and_comments() {
+        let spans = kinds(r#"let s: String = "a\"b"; // done"#, "Rust");
+        assert_eq!(
+            spans,
+            vec![
+                ("keyword", "let".to_string()),
+                ("type", "String".to_string()),
+                ("string", r#""a\"b""#.to_string()),
+                ("comment", "// done".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rust_lifetimes_are_not_strings() {
+        let spans = kinds("fn f<'a>(x: &'a str)", "Rust");
+        assert!(spans.iter().all(|(kind, _)| *kind != "string"));
+    }
+
+    #[test]
+    fn numbers_only_at_word_boundaries() {
+        let spans = kinds("x1 = 42", "Python");
+        assert_eq!(spans, vec![("number", "42".to_string())]);
+    }
+
+    #[test]
+    fn block_comment_spans_lines() {
+        let lines = ["a /* start", "middle", "end */ b"];
+        let spans = highlight_lines(&lines, "C");
+        assert_eq!(
+            spans[0],
+            vec![SyntaxSpan {
+                start: 2,
+                end: 10,
+                kind: "comment"
+            }]
+        );
+        assert_eq!(
+            spans[1],
+            vec![SyntaxSpan {
+                start: 0,
+                end: 6,
+                kind: "comment"
+            }]
+        );
+        assert_eq!(
+            spans[2],
+            vec![SyntaxSpan {
+                start: 0,
+                end: 6,
+                kind: "comment"
+            }]
+        );
+    }
+
+    #[test]
+    fn python_triple_quoted_string_spans_lines() {
+        let lines = ["x = \"\"\"doc", "still doc\"\"\" + 1"];
+        let spans = highlight_lines(&lines, "Python");
+        assert_eq!(
+            spans[0],
+            vec![SyntaxSpan {
+                start: 4,
+                end: 10,
+                kind: "string"
+            }]
+        );
+        assert_eq!(
+            spans[1][0],
+            SyntaxSpan {
+                start: 0,
+                end: 12,
+                kind: "string"
+            }
+        );
+        assert_eq!(spans[1][1].kind, "number");
+    }
+
+    #[test]
+    fn dash_comment_languages_skip_lua_keywords() {
+        assert_eq!(
+            kinds("if n then foldl' f -- sum", "Haskell"),
+            vec![("comment", "-- sum".to_string())]
+        );
+        assert_eq!(
+            kinds("select 'a' -- end", "SQL"),
+            vec![
+                ("string", "'a'".to_string()),
+                ("comment", "-- end".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn lua_block_comment_not_mistaken_for_line_comment() {
+        let lines = ["--[[ a", "b ]] local x"];
+        let spans = highlight_lines(&lines, "Lua");
+        assert_eq!(spans[0][0].kind, "comment");
+        assert_eq!(
+            spans[1][0],
+            SyntaxSpan {
+                start: 0,
+                end: 4,
+                kind: "comment"
+            }
+        );
+        assert_eq!(spans[1][1].kind, "keyword");
+    }
+}
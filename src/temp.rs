@@ -0,0 +1,240 @@
+//! Temporary files for running difftastic on contents that aren't files yet.
+//!
+//! Diffing strings or conflict stages writes them to a [`TempDir`] first. Each directory
+//! gets a name no other directory (of this or an earlier Neovim) has, and is removed with
+//! everything in it when dropped. Directories still alive when Neovim exits are removed by
+//! [`cleanup`], and ones left behind by a crashed Neovim are swept once they're a day old.
+//!
+//! Files keep the name they're written under, so difftastic detects their language from
+//! the extension; a name already taken in the directory gets a counter before its
+//! extension (`lib.rs`, `lib-1.rs`). All live directories together hold at most
+//! [`MAX_BYTES`], so a runaway caller can't fill the disk.
+
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, Once, PoisonError};
+use std::time::Duration;
+
+/// Bytes all live temporary directories may hold together.
+pub const MAX_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Age after which directories of other processes count as left behind.
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Prefix of every directory name, followed by the process ID and a counter.
+const PREFIX: &str = "difft-nvim-";
+
+static BUDGET: Budget = Budget::new(MAX_BYTES);
+
+/// Directories not dropped yet, for [`cleanup`].
+static LIVE: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Bytes written to temporary files, bounded by a cap.
+struct Budget {
+    used: AtomicU64,
+    cap: u64,
+}
+
+impl Budget {
+    const fn new(cap: u64) -> Self {
+        Self {
+            used: AtomicU64::new(0),
+            cap,
+        }
+    }
+
+    /// Takes `bytes` from the budget, unless that would exceed the cap.
+    fn reserve(&self, bytes: u64) -> Result<(), String> {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.cap)
+            })
+            .map(|_| ())
+            .map_err(|used| {
+                format!(
+                    "Temporary files would exceed {} MiB ({used} bytes in use)",
+                    self.cap / (1024 * 1024)
+                )
+            })
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// A temporary directory, removed with everything in it when dropped.
+pub struct TempDir {
+    path: PathBuf,
+    /// Bytes written, given back to the budget on drop.
+    bytes: AtomicU64,
+    budget: &'static Budget,
+}
+
+impl TempDir {
+    /// Creates a directory under the system's temporary directory.
+    pub fn new() -> Result<Self, String> {
+        Self::with_budget(&BUDGET)
+    }
+
+    fn with_budget(budget: &'static Budget) -> Result<Self, String> {
+        static SWEEP: Once = Once::new();
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        SWEEP.call_once(sweep_stale);
+        loop {
+            let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!("{PREFIX}{}-{n}", std::process::id()));
+            // A directory left by an earlier process with the same ID is never reused
+            match std::fs::create_dir(&path) {
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(format!("Failed to create {}: {e}", path.display())),
+                Ok(()) => {}
+            }
+            LIVE.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_or_insert_with(HashSet::new)
+                .insert(path.clone());
+            return Ok(Self {
+                path,
+                bytes: AtomicU64::new(0),
+                budget,
+            });
+        }
+    }
+
+    /// The directory's path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `content` to `name`, a relative path inside the directory, returning the
+    /// file's path. A name already taken gets a counter before its extension.
+    pub fn write(&self, name: &str, content: &str) -> Result<PathBuf, String> {
+        let name = Path::new(name);
+        if !name
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!(
+                "Temporary file name must be a relative path, got {}",
+                name.display()
+            ));
+        }
+        let path = unique_path(&self.path.join(name));
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+        }
+        let bytes = content.len() as u64;
+        self.budget.reserve(bytes)?;
+        if let Err(e) = std::fs::write(&path, content) {
+            self.budget.release(bytes);
+            return Err(format!("Failed to write {}: {e}", path.display()));
+        }
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        Ok(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+        self.budget.release(*self.bytes.get_mut());
+        if let Some(live) = LIVE.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+            live.remove(&self.path);
+        }
+    }
+}
+
+/// `path`, or the first of `<stem>-1.<ext>`, `<stem>-2.<ext>`, ... that doesn't exist.
+fn unique_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    std::iter::once(path.to_path_buf())
+        .chain((1..).map(|n| path.with_file_name(format!("{stem}-{n}{extension}"))))
+        .find(|candidate| !candidate.exists())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Removes every directory still alive, e.g. when Neovim exits while a diff is running.
+pub fn cleanup() {
+    let live = LIVE.lock().unwrap_or_else(PoisonError::into_inner).take();
+    for path in live.into_iter().flatten() {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+/// Removes directories of other processes older than [`STALE_AFTER`], left behind when
+/// Neovim crashed or was killed.
+fn sweep_stale() {
+    let Ok(entries) = std::fs::read_dir(std::env::temp_dir()) else {
+        return;
+    };
+    let own = format!("{PREFIX}{}-", std::process::id());
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        // Only `<pid>-<n>` names are ours
+        let ours = name.strip_prefix(PREFIX).is_some_and(|rest| {
+            rest.split_once('-').is_some_and(|(pid, n)| {
+                [pid, n]
+                    .iter()
+                    .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+            })
+        });
+        if !ours || name.starts_with(&own) {
+            continue;
+        }
+        let stale = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > STALE_AFTER);
+        if stale && entry.path().is_dir() {
+            let _ = std::fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_dir_removed_on_drop() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.write("old/text", "content").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "content");
+
+        let root = dir.path.clone();
+        drop(dir);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn names_stay_unique_and_keep_extensions() {
+        let dir = TempDir::new().unwrap();
+        let first = dir.write("src/lib.rs", "a").unwrap();
+        let second = dir.write("src/lib.rs", "b").unwrap();
+        assert_eq!(first, dir.path.join("src/lib.rs"));
+        assert_eq!(second, dir.path.join("src/lib-1.rs"));
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "a");
+        assert!(dir.write("../escape.rs", "c").is_err());
+        assert!(dir.write("/etc/escape.rs", "c").is_err());
+    }
+
+    #[test]
+    fn writes_past_the_cap_fail() {
+        static SMALL: Budget = Budget::new(10);
+        let dir = TempDir::with_budget(&SMALL).unwrap();
+        dir.write("a.txt", "12345678").unwrap();
+        assert!(dir.write("b.txt", "123").is_err());
+        drop(dir);
+        assert_eq!(SMALL.used.load(Ordering::Relaxed), 0);
+    }
+}
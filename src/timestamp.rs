@@ -0,0 +1,137 @@
+//! Relative formatting of commit timestamps ("3 days ago").
+//!
+//! Formatting happens here so pickers and headers built on the returned metadata don't each
+//! reimplement it. The words are configurable through [`TimeFormat`], which defaults to
+//! English.
+
+use mlua::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Units from smallest to largest, with their length in seconds.
+const UNITS: [(&str, i64); 7] = [
+    ("second", 1),
+    ("minute", 60),
+    ("hour", 60 * 60),
+    ("day", 24 * 60 * 60),
+    ("week", 7 * 24 * 60 * 60),
+    ("month", 30 * 24 * 60 * 60),
+    ("year", 365 * 24 * 60 * 60),
+];
+
+/// Words used to format relative times.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeFormat {
+    /// Template for past times, `{}` replaced by the duration (e.g. `"{} ago"`).
+    pub past: String,
+
+    /// Template for future times, e.g. when clocks are skewed (e.g. `"in {}"`).
+    pub future: String,
+
+    /// Text for times less than a minute away.
+    pub now: String,
+
+    /// Singular and plural name of each unit, in the order of [`UNITS`].
+    pub units: [(String, String); 7],
+}
+
+impl Default for TimeFormat {
+    fn default() -> Self {
+        Self {
+            past: "{} ago".to_string(),
+            future: "in {}".to_string(),
+            now: "just now".to_string(),
+            units: UNITS.map(|(name, _)| (name.to_string(), format!("{name}s"))),
+        }
+    }
+}
+
+impl FromLua for TimeFormat {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(table) = value else {
+            return Err(LuaError::RuntimeError(format!(
+                "time_format must be a table, got {}",
+                value.type_name()
+            )));
+        };
+        let mut format = Self::default();
+        if let Some(past) = table.get("past")? {
+            format.past = past;
+        }
+        if let Some(future) = table.get("future")? {
+            format.future = future;
+        }
+        if let Some(now) = table.get("now")? {
+            format.now = now;
+        }
+        if let Some(units) = table.get::<Option<LuaTable>>("units")? {
+            for ((name, _), names) in UNITS.iter().zip(&mut format.units) {
+                // `{ "day", "days" }`, or a single name used for both
+                match units.get::<Option<Vec<String>>>(*name)?.as_deref() {
+                    Some([singular, plural]) => *names = (singular.clone(), plural.clone()),
+                    Some([name]) => *names = (name.clone(), name.clone()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(format)
+    }
+}
+
+impl TimeFormat {
+    /// Formats `timestamp` (Unix seconds) relative to `now`, in the largest whole unit.
+    pub fn relative(&self, timestamp: i64, now: i64) -> String {
+        let delta = now - timestamp;
+        let seconds = delta.abs();
+        if seconds < 60 {
+            return self.now.clone();
+        }
+
+        let (i, (_, length)) = UNITS
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, (_, length))| seconds >= *length)
+            .unwrap_or((0, &UNITS[0]));
+        let count = seconds / length;
+        let (singular, plural) = &self.units[i];
+        let duration = format!("{count} {}", if count == 1 { singular } else { plural });
+        let template = if delta >= 0 { &self.past } else { &self.future };
+        template.replace("{}", &duration)
+    }
+
+    /// Formats `timestamp` relative to the current time.
+    pub fn relative_to_now(&self, timestamp: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        self.relative(timestamp, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    #[test]
+    fn formats_largest_whole_unit() {
+        let format = TimeFormat::default();
+        let now = 1_700_000_000;
+        assert_eq!(format.relative(now - 10, now), "just now");
+        assert_eq!(format.relative(now - 60, now), "1 minute ago");
+        assert_eq!(format.relative(now - 3 * DAY - 5, now), "3 days ago");
+        assert_eq!(format.relative(now - 2 * 365 * DAY, now), "2 years ago");
+        assert_eq!(format.relative(now + 2 * 60 * 60, now), "in 2 hours");
+    }
+
+    #[test]
+    fn uses_configured_words() {
+        let mut format = TimeFormat {
+            past: "il y a {}".to_string(),
+            ..TimeFormat::default()
+        };
+        format.units[3] = ("jour".to_string(), "jours".to_string());
+        assert_eq!(format.relative(0, 3 * DAY), "il y a 3 jours");
+    }
+}
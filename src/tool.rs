@@ -0,0 +1,203 @@
+//! The difftastic binary diffs run with, and extra arguments for it.
+//!
+//! By default, difftastic is the `difft` found on `PATH` (see [`crate::difft_binary`]) and
+//! runs with only the arguments the VCS passes it. `set_difft` from Lua selects another
+//! binary and adds arguments such as `--context 5` or `--tab-width 2` to every invocation:
+//!
+//! - git runs the `GIT_EXTERNAL_DIFF` command through a shell, so it gets the quoted binary
+//!   and arguments as one command line (see [`git_external_diff`])
+//! - jj gets them through `--config merge-tools.difft.*` overrides (see [`jj_config`])
+//! - hg gets them through `extdiff`'s `--option`
+//! - files diffed directly get them on difftastic's command line
+//!
+//! Language overrides map globs to the language difftastic parses matching files as, e.g.
+//! `*.tpl` to `Go`, and reach it as `--override` arguments like the other arguments.
+//!
+//! Difftastic falls back to a line-based diff for files past its limits, e.g. large
+//! generated files. The limits can be raised too, through the `DFT_GRAPH_LIMIT`,
+//! `DFT_BYTE_LIMIT` and `DFT_PARSE_ERROR_LIMIT` variables of every command (see [`env`]).
+
+use mlua::prelude::*;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+static TOOL: RwLock<Option<Tool>> = RwLock::new(None);
+
+/// A configured difftastic binary and arguments.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Tool {
+    /// The binary, looked up on `PATH` if it's a bare name. `None` keeps `difft`.
+    pub path: Option<PathBuf>,
+
+    /// Arguments passed before the files to diff.
+    pub args: Vec<String>,
+
+    /// Languages by glob, for files difftastic doesn't detect on its own.
+    pub overrides: BTreeMap<String, String>,
+
+    /// Graph vertices difftastic may explore before falling back (`DFT_GRAPH_LIMIT`).
+    pub graph_limit: Option<u64>,
+
+    /// File size in bytes past which difftastic falls back (`DFT_BYTE_LIMIT`).
+    pub byte_limit: Option<u64>,
+
+    /// Parse errors difftastic tolerates before falling back (`DFT_PARSE_ERROR_LIMIT`).
+    pub parse_error_limit: Option<u64>,
+}
+
+/// The configured tool, if any.
+pub fn current() -> Option<Tool> {
+    TOOL.read().ok()?.clone()
+}
+
+/// Sets the tool for every later diff, or goes back to the default with `None`.
+pub fn set(tool: Option<Tool>) {
+    if let Ok(mut current) = TOOL.write() {
+        *current = tool;
+    }
+}
+
+/// The configured binary, if any.
+pub fn path() -> Option<PathBuf> {
+    current()?.path
+}
+
+/// The configured extra arguments, followed by the language overrides.
+pub fn args() -> Vec<String> {
+    current().map(Tool::all_args).unwrap_or_default()
+}
+
+impl Tool {
+    /// [`Tool::args`] followed by an `--override` argument per language override.
+    fn all_args(self) -> Vec<String> {
+        let overrides = self
+            .overrides
+            .into_iter()
+            .map(|(glob, language)| format!("--override={glob}:{language}"));
+        self.args.into_iter().chain(overrides).collect()
+    }
+}
+
+/// Environment variables setting the configured limits, for every command running
+/// difftastic.
+pub fn env() -> Vec<(&'static str, String)> {
+    let Some(tool) = current() else {
+        return Vec::new();
+    };
+    [
+        ("DFT_GRAPH_LIMIT", tool.graph_limit),
+        ("DFT_BYTE_LIMIT", tool.byte_limit),
+        ("DFT_PARSE_ERROR_LIMIT", tool.parse_error_limit),
+    ]
+    .into_iter()
+    .filter_map(|(name, limit)| Some((name, limit?.to_string())))
+    .collect()
+}
+
+/// The `GIT_EXTERNAL_DIFF` command running `difft` with the configured arguments. Git runs
+/// it through a shell when it contains anything to quote, appending the files to diff.
+pub fn git_external_diff(difft: PathBuf) -> OsString {
+    external_diff_command(difft, args())
+}
+
+fn external_diff_command(difft: PathBuf, args: Vec<String>) -> OsString {
+    if args.is_empty() {
+        return difft.into_os_string();
+    }
+    std::iter::once(difft.to_string_lossy().into_owned())
+        .chain(args)
+        .map(|arg| crate::wrapper::shell_quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+        .into()
+}
+
+/// `--config` arguments making `jj diff --tool difft` run `difft` with the configured
+/// arguments, or none without a configured tool so jj's own `merge-tools.difft` applies.
+pub fn jj_config(difft: PathBuf) -> Vec<String> {
+    current().map_or_else(Vec::new, |tool| jj_overrides(difft, tool.all_args()))
+}
+
+fn jj_overrides(difft: PathBuf, args: Vec<String>) -> Vec<String> {
+    let mut diff_args = args;
+    diff_args.extend(["$left".to_string(), "$right".to_string()]);
+    // TOML strings and arrays of strings are written like JSON ones
+    let program = serde_json::to_string(&difft.to_string_lossy()).unwrap_or_default();
+    let diff_args = serde_json::to_string(&diff_args).unwrap_or_default();
+    vec![
+        "--config".to_string(),
+        format!("merge-tools.difft.program={program}"),
+        "--config".to_string(),
+        format!("merge-tools.difft.diff-args={diff_args}"),
+    ]
+}
+
+impl FromLua for Tool {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(table) = value else {
+            return Err(LuaError::RuntimeError(format!(
+                "difftastic settings must be a table, got {}",
+                value.type_name()
+            )));
+        };
+        Ok(Self {
+            path: table.get::<Option<String>>("path")?.map(PathBuf::from),
+            args: table.get::<Option<_>>("args")?.unwrap_or_default(),
+            overrides: table.get::<Option<_>>("overrides")?.unwrap_or_default(),
+            graph_limit: table.get("graph_limit")?,
+            byte_limit: table.get("byte_limit")?,
+            parse_error_limit: table.get("parse_error_limit")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args_reach_git_and_jj() {
+        let difft = PathBuf::from("/opt/my tools/difft");
+        let args = || vec!["--context".to_string(), "5".to_string()];
+        assert_eq!(
+            external_diff_command(difft.clone(), args()),
+            "'/opt/my tools/difft' '--context' '5'"
+        );
+        assert_eq!(
+            external_diff_command(difft.clone(), Vec::new()),
+            "/opt/my tools/difft"
+        );
+        assert_eq!(
+            jj_overrides(difft, args()),
+            [
+                "--config",
+                r#"merge-tools.difft.program="/opt/my tools/difft""#,
+                "--config",
+                r#"merge-tools.difft.diff-args=["--context","5","$left","$right"]"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn overrides_follow_the_args() {
+        let tool = Tool {
+            args: vec!["--context".to_string(), "5".to_string()],
+            overrides: BTreeMap::from([
+                ("*.tpl".to_string(), "Go".to_string()),
+                ("Jenkinsfile".to_string(), "Groovy".to_string()),
+            ]),
+            ..Tool::default()
+        };
+        assert_eq!(
+            tool.all_args(),
+            [
+                "--context",
+                "5",
+                "--override=*.tpl:Go",
+                "--override=Jenkinsfile:Groovy"
+            ]
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//! Parsing unified diffs, such as `.patch` files from `git format-patch` or an email, and
+//! rebuilding both versions of the files they change.
+//!
+//! A patch only holds the changed lines and a few lines of context. When the version it
+//! applies to is available (the blob named on its `index` line, or the file in the working
+//! tree), the hunks are applied to it with [`crate::patch::apply_hunks`], giving both full
+//! versions. Otherwise both versions are pieced together from the hunks alone, which is
+//! enough to review the changes but leaves out everything between them.
+
+use crate::patch::{self, PatchHunk};
+use crate::processor::ModeChange;
+use std::path::PathBuf;
+
+/// The changes a patch makes to one file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilePatch {
+    /// Path before the patch, `None` for created files.
+    pub old_path: Option<PathBuf>,
+    /// Path after the patch, `None` for deleted files.
+    pub new_path: Option<PathBuf>,
+    /// Blob hash of the old version, from git's `index <old>..<new>` line.
+    pub old_blob: Option<String>,
+    pub hunks: Vec<PatchHunk>,
+    /// Lines the hunks add and remove.
+    pub additions: u32,
+    pub deletions: u32,
+    /// Whether the old and new versions end without a line break
+    /// (`\ No newline at end of file`).
+    pub old_missing_newline: bool,
+    pub new_missing_newline: bool,
+    /// Whether the patch only says the file is binary, or holds a binary patch.
+    pub binary: bool,
+    /// File modes from git's `old mode`/`new mode` lines, when the mode changed.
+    pub mode_change: Option<ModeChange>,
+}
+
+impl FilePatch {
+    /// The path to show the file under: the new path, or the old one for deleted files.
+    #[must_use]
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.new_path.as_ref().or(self.old_path.as_ref())
+    }
+
+    /// Rebuilds the old and new versions, `None` for a side the file doesn't exist on.
+    ///
+    /// `base` is the old version if it's available; it's only used when every hunk applies
+    /// to it. Also returns whether the versions are complete, rather than just the hunks.
+    #[must_use]
+    pub fn rebuild(&self, base: Option<&str>) -> (Option<String>, Option<String>, bool) {
+        let applied = base.filter(|_| self.old_path.is_some()).and_then(|base| {
+            let new = patch::apply_hunks(base, &self.hunks).ok()?;
+            Some((base.to_string(), new))
+        });
+        // Created and deleted files are entirely in their hunks
+        let complete = applied.is_some() || self.old_path.is_none() || self.new_path.is_none();
+        let (old, new) = applied.unwrap_or_else(|| {
+            let side = |lines: fn(&PatchHunk) -> &Vec<String>, missing_newline: bool| {
+                let mut text: String = self
+                    .hunks
+                    .iter()
+                    .flat_map(lines)
+                    .map(|line| format!("{line}\n"))
+                    .collect();
+                if missing_newline {
+                    text.pop();
+                }
+                text
+            };
+            (
+                side(|hunk| &hunk.old, self.old_missing_newline),
+                side(|hunk| &hunk.new, self.new_missing_newline),
+            )
+        });
+        (
+            self.old_path.as_ref().map(|_| old),
+            self.new_path.as_ref().map(|_| new),
+            complete,
+        )
+    }
+}
+
+/// Strips the `a/`/`b/` prefix git adds to paths and the timestamp `diff -u` appends after
+/// a tab. `/dev/null` stands for a missing side.
+fn header_path(path: &str) -> Option<PathBuf> {
+    let path = path.split('\t').next().unwrap_or(path).trim_end();
+    if path == "/dev/null" {
+        return None;
+    }
+    let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path);
+    Some(PathBuf::from(path))
+}
+
+/// Parses a hunk header, `@@ -<start>[,<count>] +<start>[,<count>] @@`, into the old start
+/// and the old and new line counts.
+fn hunk_header(line: &str) -> Option<(u32, u32, u32)> {
+    let ranges = line.strip_prefix("@@ -")?.split(" @@").next()?;
+    let (old, new) = ranges.split_once(" +")?;
+    let range = |range: &str| -> Option<(u32, u32)> {
+        match range.split_once(',') {
+            Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    };
+    let ((old_start, old_count), (_, new_count)) = (range(old)?, range(new)?);
+    Some((old_start, old_count, new_count))
+}
+
+/// Parses the file patches of a unified diff. Anything outside of them, such as the
+/// headers and message of an email, is skipped.
+pub fn parse(text: &str) -> Result<Vec<FilePatch>, String> {
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut lines = text.lines().peekable();
+    // Whether the `---`/`+++` lines of the current file were read, after which another
+    // `---` line starts a new file
+    let mut has_headers = false;
+    while let Some(line) = lines.next() {
+        if let Some(paths) = line.strip_prefix("diff --git ") {
+            // Only a fallback for files without `---`/`+++` lines, as paths may contain " b/"
+            let (old, new) = paths.split_once(" b/").unwrap_or((paths, paths));
+            files.push(FilePatch {
+                old_path: header_path(old),
+                new_path: header_path(&format!("b/{new}")),
+                ..FilePatch::default()
+            });
+            has_headers = false;
+            continue;
+        }
+        if let Some(old) = line.strip_prefix("--- ")
+            && let Some(new) = lines.peek().and_then(|next| next.strip_prefix("+++ "))
+        {
+            if has_headers || files.is_empty() {
+                files.push(FilePatch::default());
+            }
+            let file = files.last_mut().expect("a file was just ensured");
+            file.old_path = header_path(old);
+            file.new_path = header_path(new);
+            has_headers = true;
+            lines.next();
+            continue;
+        }
+        let Some(file) = files.last_mut() else {
+            continue;
+        };
+        if line.starts_with("@@ ") {
+            let (old_start, mut old_count, mut new_count) =
+                hunk_header(line).ok_or_else(|| format!("Invalid hunk header: {line}"))?;
+            let mut hunk = PatchHunk {
+                old_start,
+                old: Vec::new(),
+                new: Vec::new(),
+            };
+            let mut last = ' ';
+            while old_count > 0
+                || new_count > 0
+                || lines.peek().is_some_and(|l| l.starts_with('\\'))
+            {
+                let Some(line) = lines.next() else {
+                    return Err(format!("Hunk at line {old_start} ends early"));
+                };
+                let kind = line.chars().next().unwrap_or(' ');
+                let content = &line[line.len().min(kind.len_utf8())..];
+                match kind {
+                    // Blank context lines lose their space in some mail clients
+                    ' ' => {
+                        hunk.old.push(content.to_string());
+                        hunk.new.push(content.to_string());
+                        old_count = old_count.saturating_sub(1);
+                        new_count = new_count.saturating_sub(1);
+                    }
+                    '-' => {
+                        hunk.old.push(content.to_string());
+                        old_count = old_count.saturating_sub(1);
+                        file.deletions += 1;
+                    }
+                    '+' => {
+                        hunk.new.push(content.to_string());
+                        new_count = new_count.saturating_sub(1);
+                        file.additions += 1;
+                    }
+                    '\\' => {
+                        file.old_missing_newline |= last != '+';
+                        file.new_missing_newline |= last != '-';
+                        continue;
+                    }
+                    _ => return Err(format!("Invalid line in hunk at line {old_start}: {line}")),
+                }
+                last = kind;
+            }
+            file.hunks.push(hunk);
+        } else if let Some(range) = line.strip_prefix("index ") {
+            let old = range.split("..").next().unwrap_or_default();
+            if !old.is_empty() && old.bytes().any(|b| b != b'0') {
+                file.old_blob = Some(old.to_string());
+            }
+        } else if let Some(mode) = line.strip_prefix("old mode ") {
+            let change = file.mode_change.get_or_insert_with(ModeChange::default);
+            change.old = mode.to_string();
+        } else if let Some(mode) = line.strip_prefix("new mode ") {
+            let change = file.mode_change.get_or_insert_with(ModeChange::default);
+            change.new = mode.to_string();
+        } else if line.starts_with("new file mode") {
+            file.old_path = None;
+        } else if line.starts_with("deleted file mode") {
+            file.new_path = None;
+        } else if let Some(path) = line.strip_prefix("rename from ") {
+            file.old_path = Some(PathBuf::from(path));
+        } else if let Some(path) = line.strip_prefix("rename to ") {
+            file.new_path = Some(PathBuf::from(path));
+        } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+            file.binary = true;
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATCH: &str = "\
+From 1234 Mon Sep 17 00:00:00 2001
+Subject: [PATCH] Greet by name
+
+---
+ src/greet.rs | 3 ++-
+ 2 files changed
+
+diff --git a/src/greet.rs b/src/greet.rs
+index 1111111..2222222 100644
+--- a/src/greet.rs
++++ b/src/greet.rs
+@@ -1,3 +1,4 @@
+ fn greet() {
+-    println!(\"hi\");
++    let name = \"you\";
++    println!(\"hi {name}\");
+ }
+diff --git a/NOTES b/NOTES
+new file mode 100644
+index 0000000..3333333
+--- /dev/null
++++ b/NOTES
+@@ -0,0 +1 @@
++todo
+\\ No newline at end of file
+diff --git a/logo.png b/logo.png
+index 4444444..5555555 100644
+Binary files a/logo.png and b/logo.png differ
+--
+2.43.0
+";
+
+    #[test]
+    fn parses_files_of_a_git_patch() {
+        let files = parse(PATCH).unwrap();
+        assert_eq!(files.len(), 3);
+
+        let greet = &files[0];
+        assert_eq!(greet.old_path, Some(PathBuf::from("src/greet.rs")));
+        assert_eq!(greet.new_path, Some(PathBuf::from("src/greet.rs")));
+        assert_eq!(greet.old_blob.as_deref(), Some("1111111"));
+        assert_eq!(greet.hunks.len(), 1);
+        assert_eq!(greet.hunks[0].old_start, 1);
+        assert_eq!(greet.hunks[0].old.len(), 3);
+        assert_eq!(greet.hunks[0].new.len(), 4);
+        assert_eq!((greet.additions, greet.deletions), (2, 1));
+
+        let notes = &files[1];
+        assert_eq!(notes.old_path, None);
+        assert_eq!(notes.old_blob, None);
+        assert!(notes.new_missing_newline);
+        assert_eq!(notes.rebuild(None), (None, Some("todo".to_string()), true));
+
+        assert!(files[2].binary);
+        assert!(files[2].hunks.is_empty());
+    }
+
+    #[test]
+    fn parses_renames_and_mode_changes_without_hunks() {
+        let patch = "\
+diff --git a/run.sh b/bin/run.sh
+old mode 100644
+new mode 100755
+similarity index 100%
+rename from run.sh
+rename to bin/run.sh
+";
+        let files = parse(patch).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].old_path, Some(PathBuf::from("run.sh")));
+        assert_eq!(files[0].new_path, Some(PathBuf::from("bin/run.sh")));
+        let change = files[0].mode_change.as_ref().unwrap();
+        assert_eq!(
+            (change.old.as_str(), change.new.as_str()),
+            ("100644", "100755")
+        );
+        assert!(files[0].hunks.is_empty());
+    }
+
+    #[test]
+    fn rebuilds_full_versions_from_a_base() {
+        let greet = &parse(PATCH).unwrap()[0];
+        let base = "// greeting\nfn greet() {\n    println!(\"hi\");\n}\n";
+        let (old, new, complete) = greet.rebuild(Some(base));
+        assert!(complete);
+        assert_eq!(old.as_deref(), Some(base));
+        assert_eq!(
+            new.as_deref(),
+            Some(
+                "// greeting\nfn greet() {\n    let name = \"you\";\n    println!(\"hi {name}\");\n}\n"
+            )
+        );
+
+        // Without a base the patch applies to, only the hunks are shown
+        let (old, new, complete) = greet.rebuild(Some("unrelated\n"));
+        assert!(!complete);
+        assert_eq!(
+            old.as_deref(),
+            Some("fn greet() {\n    println!(\"hi\");\n}\n")
+        );
+        assert_eq!(new.unwrap().lines().count(), 4);
+    }
+
+    #[test]
+    fn parses_plain_diff_u_output() {
+        let diff = "\
+--- old/a.txt\t2024-01-01 10:00:00
++++ new/a.txt\t2024-01-02 10:00:00
+@@ -1 +1 @@
+-a
++b
+--- old/b.txt
++++ new/b.txt
+@@ -2,0 +3 @@
++c
+";
+        let files = parse(diff).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].old_path, Some(PathBuf::from("old/a.txt")));
+        assert_eq!(files[1].new_path, Some(PathBuf::from("new/b.txt")));
+        assert_eq!(files[1].hunks[0].old_start, 2);
+        assert!(parse("@@ -x +1 @@\n").unwrap().is_empty());
+    }
+}
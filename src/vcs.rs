@@ -0,0 +1,354 @@
+//! Version control backends.
+//!
+//! A backend turns a [`DiffMode`] into a [`DiffPlan`]: it runs difftastic, collects line
+//! stats and says where each side's file contents are read from. git, jj, hg and Sapling's
+//! sl are built in. Other systems (Fossil, SVN, Pijul, ...) can be wired up from Lua with
+//! `register_vcs(name, spec)`, where the spec is a set of command templates.
+//!
+//! ## Command templates
+//!
+//! Templates are argument lists whose placeholders are replaced before running them:
+//!
+//! - `{rev}` - The revision being diffed (the new side)
+//! - `{old}` - The old side of the diff, derived from `{rev}` with `old_rev`
+//! - `{path}` - The repository-relative file path (`show` only)
+//! - `{difft}` - Absolute path of the difftastic binary
+//!
+//! Diff commands run with difftastic's JSON output enabled and must print it on stdout.
+//! Requested paths are appended to the diff and stats commands as trailing arguments.
+
+use crate::cache;
+use crate::cancel::CommandExt;
+use crate::{
+    ContentSource, DiffMode, DiffOptions, DiffPlan, FileStats, GitBackend, HgBackend, JjBackend,
+    difft_binary, parse_numstat,
+};
+use mlua::prelude::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
+
+/// A version control system the plugin can diff.
+pub trait VcsBackend: Send + Sync {
+    /// Runs difftastic for `mode` and works out where each side's contents come from.
+    fn plan(&self, mode: &DiffMode, opts: &DiffOptions) -> Result<DiffPlan, String>;
+
+    /// Resolves what `mode` would diff, e.g. to commit hashes, without running difftastic.
+    /// Processed diffs are reused while the snapshot stays the same. `None`, the default,
+    /// disables caching.
+    fn snapshot(&self, _mode: &DiffMode, _opts: &DiffOptions) -> Option<cache::Snapshot> {
+        None
+    }
+}
+
+/// Backends by VCS name, starting with the built-in ones.
+static REGISTRY: LazyLock<RwLock<HashMap<String, Arc<dyn VcsBackend>>>> = LazyLock::new(|| {
+    let builtins: [(&str, Arc<dyn VcsBackend>); 4] = [
+        ("git", Arc::new(GitBackend)),
+        ("jj", Arc::new(JjBackend)),
+        ("hg", Arc::new(HgBackend { program: "hg" })),
+        ("sl", Arc::new(HgBackend { program: "sl" })),
+    ];
+    let backends = builtins
+        .into_iter()
+        .map(|(name, backend)| (name.to_string(), backend))
+        .collect();
+    RwLock::new(backends)
+});
+
+/// Returns the backend registered as `name`, falling back to jj for unknown names.
+pub fn backend(name: &str) -> Arc<dyn VcsBackend> {
+    REGISTRY
+        .read()
+        .ok()
+        .and_then(|backends| backends.get(name).cloned())
+        .unwrap_or_else(|| Arc::new(JjBackend))
+}
+
+/// Detects the built-in VCS managing `dir` from the metadata directory of its nearest
+/// repository. jj is checked first, since colocated jj repositories also contain `.git`,
+/// and Sapling's `.sl` before `.git`, which Sapling clones of git repositories may have.
+pub fn detect(dir: &Path) -> Option<&'static str> {
+    dir.ancestors().find_map(|dir| {
+        [(".jj", "jj"), (".sl", "sl"), (".hg", "hg"), (".git", "git")]
+            .into_iter()
+            .find(|(marker, _)| dir.join(marker).exists())
+            .map(|(_, name)| name)
+    })
+}
+
+/// Registers `backend` as `name`, replacing any backend with the same name.
+pub fn register(name: String, backend: Arc<dyn VcsBackend>) -> Result<(), String> {
+    REGISTRY
+        .write()
+        .map_err(|e| e.to_string())?
+        .insert(name, backend);
+    Ok(())
+}
+
+/// A user-defined backend described by command templates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandSpec {
+    /// Prints difftastic's JSON for the changes from `{old}` to `{rev}`.
+    pub diff: Vec<String>,
+
+    /// Prints difftastic's JSON for the changes from `{rev}` to the working copy.
+    ///
+    /// Without it, only revisions can be diffed.
+    pub diff_working: Option<Vec<String>>,
+
+    /// Prints the content of `{path}` at `{rev}`, failing if the file doesn't exist.
+    pub show: Vec<String>,
+
+    /// The old side of a diff of `{rev}`, e.g. `"{rev}^"` or `"p1({rev})"`.
+    pub old_rev: String,
+
+    /// The revision uncommitted changes are made against, e.g. `"HEAD"`.
+    pub working_rev: Option<String>,
+
+    /// Prints `--numstat` style stats for the same changes as `diff`.
+    pub stats: Option<Vec<String>>,
+
+    /// Prints `--numstat` style stats for the same changes as `diff_working`.
+    pub stats_working: Option<Vec<String>>,
+
+    /// Prints the repository root, used to read working copy files. Defaults to the
+    /// current directory.
+    pub root: Option<Vec<String>>,
+}
+
+impl VcsBackend for CommandSpec {
+    fn plan(&self, mode: &DiffMode, opts: &DiffOptions) -> Result<DiffPlan, String> {
+        if opts.parent.is_some() {
+            return Err("Parent selection isn't supported by this VCS".to_string());
+        }
+        let paths: Vec<String> = opts
+            .paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        let working_rev = || {
+            self.working_rev
+                .clone()
+                .ok_or_else(|| "This VCS has no `working_rev` to diff against".to_string())
+        };
+
+        match mode {
+            DiffMode::Range(rev) => self.plan_revision(rev, &paths),
+            // No staging area, so show the revision uncommitted changes are made against
+            DiffMode::Staged => self.plan_revision(&working_rev()?, &paths),
+            DiffMode::Unstaged => self.plan_working(&working_rev()?, &paths),
+            DiffMode::WorkingTree(rev) => self.plan_working(rev, &paths),
+        }
+    }
+}
+
+impl CommandSpec {
+    fn plan_revision(&self, rev: &str, paths: &[String]) -> Result<DiffPlan, String> {
+        let old = fill(std::slice::from_ref(&self.old_rev), &[("rev", rev)]).remove(0);
+        let vars = [("rev", rev), ("old", old.as_str())];
+        Ok(DiffPlan {
+            json: run_diff(&self.diff, &vars, paths)?,
+            stats: run_stats(self.stats.as_deref(), &vars, paths),
+            old: self.content(&old),
+            new: self.content(rev),
+            parents: Vec::new(),
+            renames: HashMap::new(),
+            modes: HashMap::new(),
+            submodules: HashMap::new(),
+        })
+    }
+
+    fn plan_working(&self, rev: &str, paths: &[String]) -> Result<DiffPlan, String> {
+        let diff = self
+            .diff_working
+            .as_ref()
+            .ok_or_else(|| "This VCS has no `diff_working` command".to_string())?;
+        let vars = [("rev", rev), ("old", rev)];
+        Ok(DiffPlan {
+            json: run_diff(diff, &vars, paths)?,
+            stats: run_stats(self.stats_working.as_deref(), &vars, paths),
+            old: self.content(rev),
+            new: ContentSource::WorkingTree(self.root()),
+            parents: Vec::new(),
+            renames: HashMap::new(),
+            modes: HashMap::new(),
+            submodules: HashMap::new(),
+        })
+    }
+
+    /// Reads files at `rev` with the `show` command.
+    fn content(&self, rev: &str) -> ContentSource {
+        ContentSource::Command(fill(&self.show, &[("rev", rev)]))
+    }
+
+    fn root(&self) -> Option<PathBuf> {
+        match &self.root {
+            Some(argv) => run_template(argv, false)
+                .ok()
+                .map(|root| crate::wrapper::to_local(PathBuf::from(root.trim()))),
+            None => std::env::current_dir().ok(),
+        }
+    }
+}
+
+impl FromLua for CommandSpec {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(table) = value else {
+            return Err(LuaError::RuntimeError(format!(
+                "VCS spec must be a table, got {}",
+                value.type_name()
+            )));
+        };
+        let required =
+            |key: &str| LuaError::RuntimeError(format!("VCS spec is missing the `{key}` field"));
+        Ok(Self {
+            diff: table
+                .get::<Option<_>>("diff")?
+                .ok_or_else(|| required("diff"))?,
+            diff_working: table.get("diff_working")?,
+            show: table
+                .get::<Option<_>>("show")?
+                .ok_or_else(|| required("show"))?,
+            old_rev: table
+                .get::<Option<_>>("old_rev")?
+                .ok_or_else(|| required("old_rev"))?,
+            working_rev: table.get("working_rev")?,
+            stats: table.get("stats")?,
+            stats_working: table.get("stats_working")?,
+            root: table.get("root")?,
+        })
+    }
+}
+
+/// Replaces `{name}` placeholders in every argument of `argv`. Unknown placeholders are
+/// left untouched so they can be filled later.
+pub fn fill(argv: &[String], vars: &[(&str, &str)]) -> Vec<String> {
+    argv.iter()
+        .map(|arg| {
+            vars.iter().fold(arg.clone(), |arg, (name, value)| {
+                arg.replace(&format!("{{{name}}}"), value)
+            })
+        })
+        .collect()
+}
+
+/// Runs a filled command template and returns its stdout.
+///
+/// With `json_output`, difftastic's JSON mode is enabled and a non-zero exit status is
+/// tolerated as long as something was printed, since diff tools commonly exit with 1 when
+/// files differ.
+pub fn run_template(argv: &[String], json_output: bool) -> Result<String, String> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| "Empty command template".to_string())?;
+    let mut command = crate::repo::command(program);
+    command.args(args);
+    if json_output {
+        command
+            .env("DFT_DISPLAY", "json")
+            .env("DFT_UNSTABLE", "yes")
+            .envs(crate::tool::env());
+    }
+    let output = command
+        .output_or_cancel()
+        .map_err(|e| format!("Failed to run {program}: {e}"))?;
+
+    if !output.status.success() && (!json_output || output.stdout.is_empty()) {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{program} command failed: {stderr}"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn run_diff(
+    template: &[String],
+    vars: &[(&str, &str)],
+    paths: &[String],
+) -> Result<String, String> {
+    let difft = difft_binary();
+    let difft = difft.to_string_lossy();
+    let vars = [vars, &[("difft", difft.as_ref())]].concat();
+    run_template(&[fill(template, &vars), paths.to_vec()].concat(), true)
+}
+
+fn run_stats(template: Option<&[String]>, vars: &[(&str, &str)], paths: &[String]) -> FileStats {
+    template
+        .and_then(|template| {
+            run_template(&[fill(template, vars), paths.to_vec()].concat(), false).ok()
+        })
+        .map(|output| parse_numstat(&output))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn detects_nearest_repository() {
+        let root = std::env::temp_dir().join(format!("difft-nvim-detect-{}", std::process::id()));
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("src/.hg")).unwrap();
+        std::fs::create_dir_all(root.join("colocated/.jj")).unwrap();
+        std::fs::create_dir_all(root.join("colocated/.git")).unwrap();
+        std::fs::create_dir_all(root.join("sapling/.sl")).unwrap();
+
+        assert_eq!(detect(&root.join("docs/api")), Some("git"));
+        assert_eq!(detect(&root.join("src/lib")), Some("hg"));
+        assert_eq!(detect(&root.join("colocated")), Some("jj"));
+        assert_eq!(detect(&root.join("sapling/src")), Some("sl"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sapling_is_built_in() {
+        let backends = REGISTRY.read().unwrap();
+        for name in ["git", "jj", "hg", "sl"] {
+            assert!(backends.contains_key(name), "{name} isn't registered");
+        }
+    }
+
+    #[test]
+    fn fill_replaces_known_placeholders_only() {
+        assert_eq!(
+            fill(
+                &argv(&["cat", "-r", "{rev}", "{path}", "{old}..{rev}"]),
+                &[("rev", "tip"), ("old", "tip^")]
+            ),
+            argv(&["cat", "-r", "tip", "{path}", "tip^..tip"])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_spec_plans_revision_diff() {
+        let spec = CommandSpec {
+            diff: argv(&["echo", "{old}", "{rev}"]),
+            diff_working: None,
+            show: argv(&["echo", "{rev}:{path}"]),
+            old_rev: "{rev}~1".to_string(),
+            working_rev: None,
+            stats: Some(argv(&["printf", "3\t1\tsrc/lib.rs\n"])),
+            stats_working: None,
+            root: None,
+        };
+        let opts = DiffOptions {
+            paths: vec!["src/lib.rs".into()],
+            ..DiffOptions::default()
+        };
+        let plan = spec.plan(&DiffMode::Range("tip".into()), &opts).unwrap();
+
+        assert_eq!(plan.json, "tip~1 tip src/lib.rs\n");
+        assert_eq!(plan.stats.get(&PathBuf::from("src/lib.rs")), Some(&(3, 1)));
+        assert_eq!(
+            plan.new.fetch("a.txt".as_ref()).as_deref(),
+            Some(b"tip:a.txt\n".as_slice())
+        );
+        assert!(spec.plan(&DiffMode::Unstaged, &opts).is_err());
+    }
+}
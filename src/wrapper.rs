@@ -0,0 +1,260 @@
+//! Running commands through a wrapper, for repositories on a remote host or in a container.
+//!
+//! With a wrapper set (`set_command_wrapper` from Lua), every git, jj, hg and difftastic
+//! command runs as the wrapper's prefix followed by
+//!
+//! ```text
+//! sh -c 'cd "$0" && exec "$@"' <dir> env NAME=value... <program> <args>...
+//! ```
+//!
+//! so the command's working directory and environment reach the other side, e.g.
+//! `ssh devbox -- sh -c ...` or `docker exec -i dev sh -c ...`. `ssh` joins its arguments
+//! into one shell command, so for it the wrapper must `quote` them.
+//!
+//! Paths differ between both sides (`/home/me/src/app` locally, `/src/app` in the
+//! container), so [`Wrapper::paths`] lists `(local, remote)` prefixes: working directories
+//! and path arguments are translated on the way out, and repository roots reported by the
+//! VCS on the way back, for reading working tree files locally.
+
+use mlua::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, RwLock};
+
+static WRAPPER: RwLock<Option<Arc<Wrapper>>> = RwLock::new(None);
+
+/// Runs `cd` and `env` on the other side before the actual command.
+const SCRIPT: &str = r#"cd "$0" && exec "$@""#;
+
+/// A command prefix that runs commands elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wrapper {
+    /// Program and arguments running the rest of the command, e.g. `["ssh", "devbox", "--"]`.
+    pub prefix: Vec<String>,
+
+    /// Whether to pass the rest of the command as a single shell-quoted argument, for
+    /// wrappers like `ssh` that hand their arguments to a shell.
+    pub quote: bool,
+
+    /// `(local, remote)` path prefixes, most specific first.
+    pub paths: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Wrapper {
+    /// Translates a local path to the other side, if a rule covers it.
+    #[must_use]
+    pub fn to_remote(&self, path: &Path) -> PathBuf {
+        translate(
+            path,
+            self.paths.iter().map(|(local, remote)| (local, remote)),
+        )
+    }
+
+    /// Translates a path reported by the other side to a local one, if a rule covers it.
+    #[must_use]
+    pub fn to_local(&self, path: &Path) -> PathBuf {
+        translate(
+            path,
+            self.paths.iter().map(|(local, remote)| (remote, local)),
+        )
+    }
+
+    /// Builds the command running `command` through the wrapper.
+    #[must_use]
+    pub fn wrap(&self, command: &Command) -> Command {
+        let remote = |arg: &std::ffi::OsStr| {
+            self.to_remote(Path::new(arg))
+                .to_string_lossy()
+                .into_owned()
+        };
+        let dir = command
+            .get_current_dir()
+            .map_or_else(|| ".".to_string(), |dir| remote(dir.as_os_str()));
+
+        let mut inner = vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            SCRIPT.to_string(),
+            dir,
+            "env".to_string(),
+        ];
+        for (name, value) in command.get_envs() {
+            let name = name.to_string_lossy();
+            match value {
+                Some(value) => inner.push(format!("{name}={}", value.to_string_lossy())),
+                None => inner.extend(["-u".to_string(), name.into_owned()]),
+            }
+        }
+        inner.push(command.get_program().to_string_lossy().into_owned());
+        inner.extend(command.get_args().map(remote));
+
+        let mut wrapped = Command::new(self.prefix.first().map_or("sh", String::as_str));
+        wrapped.args(self.prefix.iter().skip(1));
+        if self.quote {
+            wrapped.arg(
+                inner
+                    .iter()
+                    .map(|arg| shell_quote(arg))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+        } else {
+            wrapped.args(inner);
+        }
+        wrapped
+    }
+}
+
+/// Replaces the first matching `from` prefix of `path` with its `to` prefix.
+fn translate<'a>(path: &Path, rules: impl Iterator<Item = (&'a PathBuf, &'a PathBuf)>) -> PathBuf {
+    for (from, to) in rules {
+        if let Ok(rest) = path.strip_prefix(from) {
+            return if rest.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(rest)
+            };
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Quotes `arg` for a POSIX shell.
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// The configured wrapper, if any.
+pub fn current() -> Option<Arc<Wrapper>> {
+    WRAPPER.read().ok()?.clone()
+}
+
+/// Sets the wrapper for every later command, or removes it with `None`.
+pub fn set(wrapper: Option<Wrapper>) {
+    if let Ok(mut current) = WRAPPER.write() {
+        *current = wrapper.map(Arc::new);
+    }
+}
+
+/// Translates a path reported by a wrapped command to a local one; unchanged without a
+/// wrapper.
+#[must_use]
+pub fn to_local(path: PathBuf) -> PathBuf {
+    match current() {
+        Some(wrapper) => wrapper.to_local(&path),
+        None => path,
+    }
+}
+
+impl FromLua for Wrapper {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(table) = value else {
+            return Err(LuaError::RuntimeError(format!(
+                "Command wrapper must be a table, got {}",
+                value.type_name()
+            )));
+        };
+        let prefix: Vec<String> = table.get::<Option<_>>("command")?.unwrap_or_default();
+        if prefix.is_empty() {
+            return Err(LuaError::RuntimeError(
+                "Command wrapper is missing the `command` field".to_string(),
+            ));
+        }
+        let mut paths: Vec<(PathBuf, PathBuf)> = table
+            .get::<Option<std::collections::HashMap<String, String>>>("paths")?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(local, remote)| (PathBuf::from(local), PathBuf::from(remote)))
+            .collect();
+        // Nested directories take precedence over their parents
+        paths.sort_by_key(|(local, _)| std::cmp::Reverse(local.components().count()));
+        Ok(Self {
+            prefix,
+            quote: table.get::<Option<bool>>("quote")?.unwrap_or_default(),
+            paths,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrapper(prefix: &[&str], quote: bool) -> Wrapper {
+        Wrapper {
+            prefix: prefix.iter().map(ToString::to_string).collect(),
+            quote,
+            paths: vec![(PathBuf::from("/home/me/src"), PathBuf::from("/src"))],
+        }
+    }
+
+    fn argv(command: &Command) -> Vec<String> {
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn wrapped_commands_carry_dir_and_env() {
+        let mut command = Command::new("git");
+        command
+            .args(["diff", "/home/me/src/app/a.rs"])
+            .current_dir("/home/me/src/app")
+            .env("DFT_DISPLAY", "json");
+
+        let wrapped = wrapper(&["docker", "exec", "-i", "dev"], false).wrap(&command);
+        assert_eq!(
+            argv(&wrapped),
+            [
+                "docker",
+                "exec",
+                "-i",
+                "dev",
+                "sh",
+                "-c",
+                SCRIPT,
+                "/src/app",
+                "env",
+                "DFT_DISPLAY=json",
+                "git",
+                "diff",
+                "/src/app/a.rs"
+            ]
+        );
+    }
+
+    #[test]
+    fn quoting_wrappers_get_one_shell_command() {
+        let mut command = Command::new("git");
+        command.args(["log", "-T", "it's"]);
+
+        let wrapped = wrapper(&["ssh", "devbox", "--"], true).wrap(&command);
+        assert_eq!(
+            argv(&wrapped),
+            [
+                "ssh",
+                "devbox",
+                "--",
+                r#"'sh' '-c' 'cd "$0" && exec "$@"' '.' 'env' 'git' 'log' '-T' 'it'\''s'"#
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_translate_both_ways() {
+        let wrapper = wrapper(&["ssh", "devbox"], true);
+        assert_eq!(
+            wrapper.to_local(Path::new("/src/app")),
+            Path::new("/home/me/src/app")
+        );
+        assert_eq!(
+            wrapper.to_remote(Path::new("/home/me/src")),
+            Path::new("/src")
+        );
+        assert_eq!(
+            wrapper.to_remote(Path::new("/etc/hosts")),
+            Path::new("/etc/hosts")
+        );
+    }
+}